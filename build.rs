@@ -0,0 +1,28 @@
+use std::process::Command;
+
+// Embed the git commit and compiler version for localpacketdump_build_info
+fn main() {
+    let git_hash = command_output("git", &["rev-parse", "--short=12", "HEAD"]);
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let rustc_version = command_output(&rustc, &["--version"]);
+
+    println!("cargo:rustc-env=LOCALPACKETDUMP_GIT_HASH={}", git_hash);
+    println!(
+        "cargo:rustc-env=LOCALPACKETDUMP_RUSTC_VERSION={}",
+        rustc_version
+    );
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+}
+
+fn command_output(program: &str, args: &[&str]) -> String {
+    Command::new(program)
+        .args(args)
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .unwrap_or_else(|| "unknown".to_string())
+}
@@ -25,6 +25,7 @@ After=network.target
 [Service]
 Type=simple
 ExecStart=$BINARY_PATH
+ExecReload=/bin/kill -HUP \$MAINPID
 WorkingDirectory=$CURRENT_DIR
 Restart=always
 RestartSec=5
@@ -0,0 +1,299 @@
+use crate::config::{AlertRule, SharedConfig};
+use crate::metrics::{Flush, FlushSink};
+use crate::stats::Direction;
+use prometheus::{IntCounter, IntGaugeVec};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+use tokio::sync::mpsc;
+use tracing::{error, info, warn};
+
+// Delivery attempts per notification, the wait doubles from ALERT_RETRY_INITIAL
+const ALERT_ATTEMPTS: u32 = 5;
+const ALERT_RETRY_INITIAL: Duration = Duration::from_secs(1);
+// Notifications queued while a webhook is being retried, later ones are dropped
+const ALERT_QUEUE_CAPACITY: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum AlertState {
+    Firing,
+    Resolved,
+}
+
+// JSON body posted to the rule's webhook
+#[derive(Debug, Serialize)]
+struct Notification {
+    rule: String,
+    state: AlertState,
+    ip: IpAddr,
+    nic: Arc<str>,
+    direction: &'static str,
+    observed_bps: f64,
+    threshold_bps: f64,
+    for_secs: u64,
+    // Unix time of the interval that changed the state
+    timestamp: f64,
+}
+
+// Time a local IP has spent above one rule's threshold
+#[derive(Debug)]
+struct Streak {
+    above: Duration,
+    active: bool,
+    // NIC that carried most of the IP's traffic, reported in the notifications
+    nic: Arc<str>,
+}
+
+// Evaluates the [[alerts]] rules against every interval snapshot. Notifications are
+// only sent when an IP starts or stops violating a rule and are posted by a
+// background task, so a slow webhook never holds up the flush.
+pub struct AlertSink {
+    rules: Vec<AlertRule>,
+    streaks: HashMap<(usize, IpAddr), Streak>,
+    active: IntGaugeVec,
+    notifications: mpsc::Sender<(String, Notification)>,
+    failures: IntCounter,
+    // A reload swaps in its [[alerts]] before the next evaluation
+    config: SharedConfig,
+}
+
+// Names must be unique, thresholds positive and webhooks valid URLs
+pub fn validate_rules(rules: &[AlertRule]) -> Result<(), String> {
+    let mut names = HashSet::new();
+    for rule in rules {
+        if rule.name.is_empty() {
+            return Err("alert rule without a name".into());
+        }
+        if !names.insert(rule.name.as_str()) {
+            return Err(format!("duplicate alert rule {}", rule.name));
+        }
+        if !(rule.threshold_bps.is_finite() && rule.threshold_bps > 0.0) {
+            return Err(format!(
+                "alert rule {}: threshold_bps must be positive",
+                rule.name
+            ));
+        }
+        reqwest::Url::parse(&rule.webhook_url)
+            .map_err(|e| format!("alert rule {}: bad webhook_url: {}", rule.name, e))?;
+    }
+    Ok(())
+}
+
+impl AlertSink {
+    pub fn spawn(
+        mut config: SharedConfig,
+        active: IntGaugeVec,
+        failures: IntCounter,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let (rules, timeout) = {
+            let config = config.borrow_and_update();
+            (
+                config.alerts.clone(),
+                Duration::from_secs(config.alert_webhook_timeout_secs),
+            )
+        };
+        validate_rules(&rules)?;
+
+        let client = reqwest::Client::builder().timeout(timeout).build()?;
+        let (notifications, notifications_rx) = mpsc::channel(ALERT_QUEUE_CAPACITY);
+        if !rules.is_empty() {
+            info!("Evaluating {} alert rules", rules.len());
+        }
+        tokio::spawn(send_notifications(
+            client,
+            notifications_rx,
+            failures.clone(),
+        ));
+        Ok(Self {
+            rules,
+            streaks: HashMap::new(),
+            active,
+            notifications,
+            failures,
+            config,
+        })
+    }
+
+    // Streaks of rules that keep their name carry over, the others are dropped
+    // without a resolved notification
+    fn replace_rules(&mut self, rules: Vec<AlertRule>) {
+        let index_of: HashMap<&str, usize> = rules
+            .iter()
+            .enumerate()
+            .map(|(index, rule)| (rule.name.as_str(), index))
+            .collect();
+        let mut streaks = HashMap::new();
+        for ((index, ip), streak) in self.streaks.drain() {
+            let name = &self.rules[index].name;
+            match index_of.get(name.as_str()) {
+                Some(&new_index) => {
+                    streaks.insert((new_index, ip), streak);
+                }
+                None if streak.active => {
+                    let _ = self.active.remove_label_values(&[name, &ip.to_string()]);
+                }
+                None => {}
+            }
+        }
+        info!("Evaluating {} alert rules", rules.len());
+        self.streaks = streaks;
+        self.rules = rules;
+    }
+
+    fn notify(&self, rule: &AlertRule, notification: Notification) {
+        match notification.state {
+            AlertState::Firing => warn!(
+                "Alert {} firing: {} {} {:.0} bps > {:.0} bps",
+                rule.name,
+                notification.ip,
+                notification.direction,
+                notification.observed_bps,
+                rule.threshold_bps
+            ),
+            AlertState::Resolved => info!("Alert {} resolved: {}", rule.name, notification.ip),
+        }
+        if self
+            .notifications
+            .try_send((rule.webhook_url.clone(), notification))
+            .is_err()
+        {
+            self.failures.inc();
+            error!(
+                "Alert notification queue is full, dropping a notification for {}",
+                rule.name
+            );
+        }
+    }
+}
+
+impl FlushSink for AlertSink {
+    fn publish(&mut self, flush: &Flush<'_>) {
+        if self.config.has_changed().unwrap_or(false) {
+            let rules = self.config.borrow_and_update().alerts.clone();
+            self.replace_rules(rules);
+        }
+        let snapshot = flush.snapshot;
+        let timestamp = snapshot
+            .timestamp
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs_f64())
+            .unwrap_or(0.0);
+
+        for (index, rule) in self.rules.iter().enumerate() {
+            // Per IP over all of its NICs; local_ip="other" is not a single host
+            let mut observed: HashMap<IpAddr, (f64, f64, Arc<str>)> = HashMap::new();
+            for rate in &snapshot.per_ip {
+                let Some(ip) = rate.key.ip else {
+                    continue;
+                };
+                let bps = match rule.direction {
+                    Direction::Tx => rate.tx_bps(),
+                    Direction::Rx => rate.rx_bps(),
+                };
+                let entry = observed
+                    .entry(ip)
+                    .or_insert_with(|| (0.0, bps, rate.key.nic.clone()));
+                entry.0 += bps;
+                if bps > entry.1 {
+                    entry.1 = bps;
+                    entry.2 = rate.key.nic.clone();
+                }
+            }
+
+            let notification = |ip: IpAddr, nic: &Arc<str>, bps: f64, state| Notification {
+                rule: rule.name.clone(),
+                state,
+                ip,
+                nic: nic.clone(),
+                direction: rule.direction.label(),
+                observed_bps: bps,
+                threshold_bps: rule.threshold_bps,
+                for_secs: rule.for_secs,
+                timestamp,
+            };
+
+            let mut fired = Vec::new();
+            for (&ip, (bps, _, nic)) in &observed {
+                if *bps <= rule.threshold_bps {
+                    continue;
+                }
+                let streak = self.streaks.entry((index, ip)).or_insert_with(|| Streak {
+                    above: Duration::ZERO,
+                    active: false,
+                    nic: nic.clone(),
+                });
+                streak.above += flush.elapsed;
+                streak.nic = nic.clone();
+                if !streak.active && streak.above >= Duration::from_secs(rule.for_secs) {
+                    streak.active = true;
+                    fired.push(notification(ip, nic, *bps, AlertState::Firing));
+                }
+            }
+
+            // Any interval at or below the threshold ends the streak
+            let mut resolved = Vec::new();
+            self.streaks.retain(|&(rule_index, ip), streak| {
+                let bps = observed.get(&ip).map_or(0.0, |(bps, _, _)| *bps);
+                if rule_index != index || bps > rule.threshold_bps {
+                    return true;
+                }
+                if streak.active {
+                    resolved.push(notification(ip, &streak.nic, bps, AlertState::Resolved));
+                }
+                false
+            });
+
+            for notification in fired {
+                self.active
+                    .with_label_values(&[&rule.name, &notification.ip.to_string()])
+                    .set(1);
+                self.notify(rule, notification);
+            }
+            for notification in resolved {
+                let _ = self
+                    .active
+                    .remove_label_values(&[&rule.name, &notification.ip.to_string()]);
+                self.notify(rule, notification);
+            }
+        }
+    }
+}
+
+async fn send_notifications(
+    client: reqwest::Client,
+    mut notifications: mpsc::Receiver<(String, Notification)>,
+    failures: IntCounter,
+) {
+    while let Some((url, notification)) = notifications.recv().await {
+        let mut backoff = ALERT_RETRY_INITIAL;
+        for attempt in 1..=ALERT_ATTEMPTS {
+            let result = client
+                .post(&url)
+                .json(&notification)
+                .send()
+                .await
+                .and_then(|response| response.error_for_status());
+            match result {
+                Ok(_) => break,
+                Err(e) if attempt < ALERT_ATTEMPTS => {
+                    warn!(
+                        "Alert webhook {} failed: {}, retrying in {:?}",
+                        url, e, backoff
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                }
+                Err(e) => {
+                    failures.inc();
+                    error!(
+                        "Alert webhook {} failed {} times, giving up on {} for {}: {}",
+                        url, ALERT_ATTEMPTS, notification.rule, notification.ip, e
+                    );
+                }
+            }
+        }
+    }
+}
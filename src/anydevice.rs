@@ -0,0 +1,83 @@
+use crate::config::OtherInterfaces;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// capture label of frames from interfaces outside capture_interfaces with
+// any_device_others = "other"
+pub const OTHER_INTERFACE_LABEL: &str = "other";
+
+// An ifindex the system did not know stays unknown this long before the interface
+// list is read again
+const UNKNOWN_IFINDEX_RETRY: Duration = Duration::from_secs(10);
+
+// Where a frame came from: the interface it is accounted to and whether that is
+// the primary capture interface; None to ignore the frame
+type Origin = Option<(Arc<str>, bool)>;
+
+#[derive(Debug)]
+enum Cached {
+    Known(Origin),
+    // Not in the interface list when it was read at this time
+    Unknown(Instant),
+}
+
+// Maps the ifindex in the SLL2 header of the frames of one capture on "any" to the
+// capture_interfaces entry they came in on. The first entry is the primary one.
+#[derive(Debug)]
+pub struct AnyDevice {
+    interfaces: Vec<Arc<str>>,
+    other: Option<Arc<str>>,
+    origins: Mutex<HashMap<u32, Cached>>,
+}
+
+impl AnyDevice {
+    pub fn new(interfaces: &[String], others: OtherInterfaces) -> Self {
+        Self {
+            interfaces: interfaces
+                .iter()
+                .map(|name| Arc::from(name.as_str()))
+                .collect(),
+            other: match others {
+                OtherInterfaces::Ignore => None,
+                OtherInterfaces::Other => Some(Arc::from(OTHER_INTERFACE_LABEL)),
+            },
+            origins: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn origin_of(&self, name: &str) -> Origin {
+        match self
+            .interfaces
+            .iter()
+            .position(|interface| **interface == *name)
+        {
+            Some(i) => Some((self.interfaces[i].clone(), i == 0)),
+            None => self.other.clone().map(|other| (other, false)),
+        }
+    }
+
+    // Only a new or recently unknown ifindex reads the interface list, e.g. once
+    // for a bridge recreated under a new index
+    pub fn origin(&self, ifindex: u32) -> Origin {
+        let mut origins = self.origins.lock().unwrap();
+        match origins.get(&ifindex) {
+            Some(Cached::Known(origin)) => return origin.clone(),
+            Some(Cached::Unknown(at)) if at.elapsed() < UNKNOWN_IFINDEX_RETRY => {
+                return self.other.clone().map(|other| (other, false))
+            }
+            _ => {}
+        }
+        for interface in pnet::datalink::interfaces() {
+            let origin = self.origin_of(&interface.name);
+            origins.insert(interface.index, Cached::Known(origin));
+        }
+        match origins.get(&ifindex) {
+            Some(Cached::Known(origin)) => origin.clone(),
+            _ => {
+                origins.insert(ifindex, Cached::Unknown(Instant::now()));
+                self.other.clone().map(|other| (other, false))
+            }
+        }
+    }
+}
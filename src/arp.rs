@@ -0,0 +1,78 @@
+use pnet::datalink::MacAddr;
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+// Local IPs whose last claim is remembered; beyond this, expired claims are
+// dropped and IPs not seen yet are not watched until there is room
+const ARP_WATCH_MAX_IPS: usize = 4096;
+
+// The same IP is reported as conflicting at most this often
+const ARP_CONFLICT_LOG_INTERVAL: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Clone, Copy)]
+struct Claim {
+    mac: MacAddr,
+    seen: Instant,
+}
+
+// The sender MAC that last claimed each local IP in an ARP packet. Another MAC
+// claiming it within `window` is logged as an address conflict, e.g. a static IP
+// inside the DHCP pool or an ARP spoofer.
+#[derive(Debug)]
+pub struct ArpWatch {
+    window: Duration,
+    claims: Mutex<HashMap<Ipv4Addr, Claim>>,
+    logged: Mutex<HashMap<Ipv4Addr, Instant>>,
+}
+
+impl ArpWatch {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            claims: Mutex::new(HashMap::new()),
+            logged: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn observe(&self, ip: Ipv4Addr, mac: MacAddr, nic: &str) {
+        let now = Instant::now();
+        let previous = {
+            let mut claims = self.claims.lock().unwrap();
+            if claims.len() >= ARP_WATCH_MAX_IPS && !claims.contains_key(&ip) {
+                claims.retain(|_, claim| now.duration_since(claim.seen) < self.window);
+                if claims.len() >= ARP_WATCH_MAX_IPS {
+                    return;
+                }
+            }
+            claims.insert(ip, Claim { mac, seen: now })
+        };
+        let Some(previous) = previous
+            .filter(|claim| claim.mac != mac && now.duration_since(claim.seen) < self.window)
+        else {
+            return;
+        };
+        {
+            let mut logged = self.logged.lock().unwrap();
+            if logged
+                .get(&ip)
+                .is_some_and(|last| now.duration_since(*last) < ARP_CONFLICT_LOG_INTERVAL)
+            {
+                return;
+            }
+            if logged.len() >= ARP_WATCH_MAX_IPS {
+                logged.retain(|_, last| now.duration_since(*last) < ARP_CONFLICT_LOG_INTERVAL);
+            }
+            logged.insert(ip, now);
+        }
+        warn!(
+            ip = %ip,
+            nic,
+            previous_mac = %previous.mac,
+            mac = %mac,
+            "Two MACs claim the same local IP in ARP"
+        );
+    }
+}
@@ -0,0 +1,110 @@
+use crate::config::Config;
+use axum::extract::{Request, State};
+use axum::http::{header, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use base64::Engine;
+use std::sync::Arc;
+
+// Environment variables that override the http_auth_* settings of the config file,
+// so credentials never have to appear on the command line
+pub const HTTP_AUTH_TOKEN_ENV: &str = "LOCALPACKETDUMP_HTTP_AUTH_TOKEN";
+pub const HTTP_AUTH_USER_ENV: &str = "LOCALPACKETDUMP_HTTP_AUTH_USER";
+pub const HTTP_AUTH_PASSWORD_ENV: &str = "LOCALPACKETDUMP_HTTP_AUTH_PASSWORD";
+
+#[derive(Debug, Clone)]
+enum Credential {
+    // Expected Authorization header value, "Bearer <token>" or "Basic <base64>"
+    Bearer(String),
+    Basic(String),
+}
+
+// Authentication applied to every route except the exempt paths
+#[derive(Debug, Clone)]
+pub struct HttpAuth {
+    credential: Credential,
+    exempt: Vec<String>,
+}
+
+impl HttpAuth {
+    // None when neither a token nor a user is configured
+    pub fn from_config(config: &Config) -> Result<Option<Self>, String> {
+        let setting = |env: &str, value: &Option<String>| {
+            std::env::var(env)
+                .ok()
+                .filter(|value| !value.is_empty())
+                .or_else(|| value.clone())
+        };
+        let token = setting(HTTP_AUTH_TOKEN_ENV, &config.http_auth_token);
+        let user = setting(HTTP_AUTH_USER_ENV, &config.http_auth_user);
+        let password = setting(HTTP_AUTH_PASSWORD_ENV, &config.http_auth_password);
+
+        let credential = match (token, user, password) {
+            (None, None, None) => return Ok(None),
+            (Some(_), Some(_), _) | (Some(_), _, Some(_)) => {
+                return Err(
+                    "set either http_auth_token or http_auth_user/password, not both".into(),
+                )
+            }
+            (Some(token), None, None) => Credential::Bearer(format!("Bearer {}", token)),
+            (None, Some(user), Some(password)) => {
+                if user.contains(':') {
+                    return Err("http_auth_user must not contain ':'".into());
+                }
+                let encoded = base64::engine::general_purpose::STANDARD
+                    .encode(format!("{}:{}", user, password));
+                Credential::Basic(format!("Basic {}", encoded))
+            }
+            (None, _, _) => return Err("http_auth_user needs http_auth_password".into()),
+        };
+        Ok(Some(Self {
+            credential,
+            exempt: config.http_auth_exempt.clone(),
+        }))
+    }
+
+    pub fn scheme(&self) -> &'static str {
+        match self.credential {
+            Credential::Bearer(_) => "bearer token",
+            Credential::Basic(_) => "basic auth",
+        }
+    }
+
+    fn authorized(&self, authorization: Option<&str>) -> bool {
+        let (Credential::Bearer(expected) | Credential::Basic(expected)) = &self.credential;
+        authorization.is_some_and(|given| constant_time_eq(given.as_bytes(), expected.as_bytes()))
+    }
+}
+
+// Compares without an early exit, so the time taken does not reveal how many
+// leading bytes of a guess were right. Only the length can leak.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+pub async fn require_auth(
+    State(auth): State<Arc<HttpAuth>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if auth.exempt.iter().any(|path| path == request.uri().path()) {
+        return next.run(request).await;
+    }
+    let authorization = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok());
+    if auth.authorized(authorization) {
+        return next.run(request).await;
+    }
+    let challenge = match auth.credential {
+        Credential::Bearer(_) => "Bearer realm=\"localpacketdump\"",
+        Credential::Basic(_) => "Basic realm=\"localpacketdump\", charset=\"UTF-8\"",
+    };
+    (
+        StatusCode::UNAUTHORIZED,
+        [(header::WWW_AUTHENTICATE, challenge)],
+        "missing or wrong credentials",
+    )
+        .into_response()
+}
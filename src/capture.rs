@@ -0,0 +1,1870 @@
+use crate::anydevice::AnyDevice;
+use crate::arp::ArpWatch;
+use crate::config::{CountMode, SharedConfig, TunnelMode};
+use crate::dns::{parse_query, DnsQueries, DNS_PORT, MAX_NAME_LEN};
+use crate::dump::{DumpControl, DumpWriter};
+use crate::feed::{PacketEvent, PacketFeed};
+use crate::health::{CaptureHealth, HealthState};
+use crate::loglimit::LogLimiter;
+use crate::mapping::{NicResolver, UnmappedLog};
+use crate::metrics::Metrics;
+use crate::packet::{
+    cooked_header_overhead, ethertype_label, parse_arp, parse_frame, parse_tunnel, sll2_origin,
+    DscpClasses, Fragment, FrameError, LinkLayer, PacketInfo, PortBucket, QUIC_PROTO_LABEL,
+    SLL_HOST, SLL_OUTGOING, SUPPORTED_LINKTYPES, UDP_HEADER_LEN,
+};
+use crate::sni::SniFlows;
+use crate::source::{CaptureError, PacketSource, PcapSource};
+use crate::stats::{Direction, PacketRecord, OVERFLOW_IP_LABEL};
+use crate::subnets::LocalSubnets;
+use pcap::{Capture, Device, Linktype};
+use pnet::datalink::MacAddr;
+use pnet::packet::ethernet::{EtherTypes, EthernetPacket};
+use pnet::packet::ip::IpNextHeaderProtocols;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::sync::{mpsc, watch};
+use tracing::{error, info, warn};
+
+const PCAP_STATS_INTERVAL: Duration = Duration::from_secs(5);
+
+// Linux pseudo-device capturing on every interface; libpcap refuses promiscuous
+// mode on it
+pub const ANY_DEVICE: &str = "any";
+
+// Backoff while waiting for a capture device to appear
+const CAPTURE_RETRY_INITIAL: Duration = Duration::from_secs(1);
+const CAPTURE_RETRY_MAX: Duration = Duration::from_secs(60);
+
+// Read errors without a packet in between for this long, e.g. after the bridge
+// was recreated by `netplan apply`, drop the handle and open the device again
+const CAPTURE_FAILING_REOPEN: Duration = Duration::from_secs(5);
+
+// After the first capture error of a kind on an interface, repeats are only
+// summarized this often
+const CAPTURE_ERROR_LOG_INTERVAL: Duration = Duration::from_secs(60);
+
+// How often sleeping capture threads check for shutdown
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// Ethernet, two VLAN tags, an IPv6 header and the TCP flags still fit
+pub const MIN_SNAPLEN: i32 = 96;
+
+// Largest --sample N, the most the 14-bit NetFlow v5 sampling interval can carry
+pub const MAX_SAMPLE_RATE: u64 = 0x3fff;
+
+// "1/N" (or just "N"): account one of every N captured frames
+pub fn parse_sample_rate(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let n = match value.split_once('/') {
+        Some((one, n)) if one.trim() == "1" => n.trim(),
+        Some(_) => return Err(format!("invalid sampling rate '{}', use 1/N", value)),
+        None => value,
+    };
+    let n = n
+        .parse::<u64>()
+        .map_err(|_| format!("invalid sampling rate '{}', use 1/N", value))?;
+    if n == 0 || n > MAX_SAMPLE_RATE {
+        return Err(format!(
+            "sampling rate must be between 1/1 and 1/{}, got 1/{}",
+            MAX_SAMPLE_RATE, n
+        ));
+    }
+    Ok(n)
+}
+
+// Deterministic 1-in-N stride over the frames of one capture, taking the first
+#[derive(Debug)]
+struct Sampler {
+    rate: u64,
+    seen: u64,
+}
+
+impl Sampler {
+    fn new(rate: u64) -> Self {
+        Self { rate, seen: 0 }
+    }
+
+    fn take(&mut self) -> bool {
+        let take = self.seen == 0;
+        self.seen += 1;
+        if self.seen >= self.rate {
+            self.seen = 0;
+        }
+        take
+    }
+}
+
+// How a configured interface name is looked up in pcap's device list. Windows
+// devices are named \\Device\\NPF_{GUID}, so there the description or one of
+// the interface's addresses is usually what the status service and config know.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum InterfaceMatch {
+    // The device name, then its description, then an assigned address
+    #[default]
+    Auto,
+    Name,
+    // Case-insensitive description, e.g. "Intel(R) Ethernet Connection I219-V"
+    Description,
+    // An IP address assigned to the device, e.g. 10.40.0.1
+    Address,
+}
+
+fn device_matches(device: &Device, requested: &str, mode: InterfaceMatch) -> bool {
+    match mode {
+        InterfaceMatch::Auto => [
+            InterfaceMatch::Name,
+            InterfaceMatch::Description,
+            InterfaceMatch::Address,
+        ]
+        .into_iter()
+        .any(|mode| device_matches(device, requested, mode)),
+        InterfaceMatch::Name => device.name == requested,
+        InterfaceMatch::Description => device
+            .desc
+            .as_deref()
+            .is_some_and(|desc| desc.eq_ignore_ascii_case(requested)),
+        InterfaceMatch::Address => requested
+            .parse::<IpAddr>()
+            .is_ok_and(|ip| device.addresses.iter().any(|address| address.addr == ip)),
+    }
+}
+
+// The pcap device for a configured interface. In auto mode an exact name wins
+// over any description or address match.
+pub fn resolve_device(requested: &str, mode: InterfaceMatch) -> Result<Device, String> {
+    let devices = Device::list().map_err(|e| format!("failed to list devices: {}", e))?;
+    if mode == InterfaceMatch::Auto {
+        if let Some(device) = devices.iter().find(|device| device.name == requested) {
+            return Ok(device.clone());
+        }
+    }
+    let mut matches: Vec<Device> = devices
+        .into_iter()
+        .filter(|device| device_matches(device, requested, mode))
+        .collect();
+    match matches.len() {
+        0 => Err(format!("device {} not found", requested)),
+        1 => Ok(matches.remove(0)),
+        _ => Err(format!(
+            "{} matches several devices ({}), use its device name",
+            requested,
+            matches
+                .iter()
+                .map(|device| device.name.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        )),
+    }
+}
+
+// list-interfaces: every capture device with what the match modes look at
+pub fn describe_devices() -> Result<String, pcap::Error> {
+    let mut out = String::new();
+    for device in Device::list()? {
+        let mut flags = Vec::new();
+        if device.flags.is_up() {
+            flags.push("up");
+        }
+        if device.flags.is_running() {
+            flags.push("running");
+        }
+        if device.flags.is_loopback() {
+            flags.push("loopback");
+        }
+        if device.flags.is_wireless() {
+            flags.push("wireless");
+        }
+        out.push_str(&format!("{} [{}]\n", device.name, flags.join(",")));
+        if let Some(desc) = &device.desc {
+            out.push_str(&format!("    description: {}\n", desc));
+        }
+        for address in &device.addresses {
+            out.push_str(&format!("    address: {}\n", address.addr));
+        }
+    }
+    Ok(out)
+}
+
+// Options passed to the pcap handle of every live capture
+#[derive(Debug, Clone, Copy)]
+pub struct CaptureSettings {
+    pub interface_match: InterfaceMatch,
+    pub promisc: bool,
+    pub snaplen: i32,
+    // Kernel buffer in bytes, libpcap's default when None
+    pub buffer_size: Option<i32>,
+    // Read timeout in milliseconds, also bounds how late shutdown is noticed
+    pub timeout_ms: i32,
+}
+
+impl Default for CaptureSettings {
+    fn default() -> Self {
+        Self {
+            interface_match: InterfaceMatch::Auto,
+            promisc: true,
+            snaplen: 65535,
+            buffer_size: None,
+            timeout_ms: 1000,
+        }
+    }
+}
+
+pub fn validate_bpf_filter(filter: &str) -> Result<(), pcap::Error> {
+    if filter.is_empty() {
+        return Ok(());
+    }
+    Capture::dead(Linktype::ETHERNET)?.compile(filter, true)?;
+    Ok(())
+}
+
+fn interface_mac(interface_name: &str) -> Option<MacAddr> {
+    pnet::datalink::interfaces()
+        .into_iter()
+        .find(|iface| iface.name == interface_name)
+        .and_then(|iface| iface.mac)
+}
+
+// State shared by every capture task
+#[derive(Clone)]
+pub struct CaptureContext {
+    pub metrics: Arc<Metrics>,
+    pub records: mpsc::Sender<PacketRecord>,
+    pub nics: Arc<NicResolver>,
+    pub local_subnets: Arc<RwLock<LocalSubnets>>,
+    // Read for bpf_filter, which live captures apply again after a reload
+    pub config: SharedConfig,
+    // Wait for room in the channel instead of dropping records (offline replay)
+    pub lossless: bool,
+    pub health: Arc<HealthState>,
+    pub count_mode: CountMode,
+    pub frame_overhead_bytes: u64,
+    pub tunnel_mode: TunnelMode,
+    pub tunnel_max_depth: usize,
+    // UDP destination ports unwrapped as VXLAN
+    pub vxlan_ports: Arc<[u16]>,
+    // Set with vni_metrics: network_vni_*_bps of traffic unwrapped from VXLAN
+    pub vni_metrics: bool,
+    // Set with mpls_metrics: network_mpls_*_bps per top MPLS label
+    pub mpls_metrics: bool,
+    // Set with cast_metrics: network_ip_cast_tx_bps per local sender
+    pub cast_metrics: bool,
+    pub shutdown: watch::Receiver<bool>,
+    pub capture: CaptureSettings,
+    // --sample N: only every Nth frame reaches handle_frame, the aggregator scales
+    // the counts back up
+    pub sample_rate: u64,
+    pub tracked_ports: Arc<[u16]>,
+    // UDP packets to or from these ports are proto="quic"
+    pub quic_ports: Arc<[u16]>,
+    pub unmapped_log: Arc<UnmappedLog>,
+    // Set with wan_labels: the wan label of unmapped traffic ("default")
+    pub default_wan_label: Option<Arc<str>>,
+    pub drop_internal: bool,
+    // Set with dscp_metrics: classifies the DSCP of accounted packets
+    pub dscp_classes: Option<Arc<DscpClasses>>,
+    // Set with --dump-dir: live captures also write their frames to pcap files
+    pub dump: Option<Arc<DumpControl>>,
+    // Set with capture_any_device: the one capture on "any" accounts each frame as
+    // if captured on the interface it came in on
+    pub any_device: Option<Arc<AnyDevice>>,
+    // Set with packet_stream_max_clients > 0: accounted packets for /ws/packets
+    pub packet_feed: Option<Arc<PacketFeed>>,
+    // Set with dns_queries: UDP queries to port 53 from local IPs for /dns/top
+    pub dns: Option<Arc<DnsQueries>>,
+    // Set with domain_metrics: TLS server names of connections to port 443
+    pub sni: Option<Arc<SniFlows>>,
+    // Set with arp_conflict_window_secs > 0: the MACs claiming local IPs in ARP
+    pub arp_watch: Option<Arc<ArpWatch>>,
+    // Every parsed packet of the primary capture is added to the NetFlow table
+    #[cfg(feature = "netflow")]
+    pub flows: Option<crate::netflow::FlowSender>,
+    // Set with geoip_database or geoip_asn_database: the country and AS of the
+    // remote side of WAN traffic
+    #[cfg(feature = "geoip")]
+    pub geoip: Option<Arc<crate::geoip::GeoIp>>,
+}
+
+impl CaptureContext {
+    fn shutting_down(&self) -> bool {
+        *self.shutdown.borrow()
+    }
+
+    // Sleep in short steps so shutdown is not held up; returns false on shutdown
+    fn sleep_unless_shutdown(&self, duration: Duration) -> bool {
+        let deadline = std::time::Instant::now() + duration;
+        loop {
+            if self.shutting_down() {
+                return false;
+            }
+            let now = std::time::Instant::now();
+            if now >= deadline {
+                return true;
+            }
+            std::thread::sleep((deadline - now).min(SHUTDOWN_POLL_INTERVAL));
+        }
+    }
+
+    fn send(&self, record: PacketRecord) {
+        if self.lossless {
+            if self.records.blocking_send(record).is_err() {
+                self.metrics.records_dropped.inc();
+            }
+        } else if self.records.try_send(record).is_err() {
+            self.metrics.records_dropped.inc();
+        }
+    }
+
+    // Only the primary capture does per-IP accounting, so the same packet seen on
+    // several interfaces is not counted twice. `wire_len` is the frame length before
+    // snaplen truncation. `mac` is only given for Ethernet captures.
+    pub fn handle_frame(
+        &self,
+        link: LinkLayer,
+        data: &[u8],
+        wire_len: u32,
+        interface_name: &Arc<str>,
+        mac: Option<MacAddr>,
+        primary: bool,
+    ) {
+        if let Some(mac) = mac {
+            self.account_capture_frame(data, wire_len, interface_name, mac);
+        }
+        if primary {
+            match parse_frame(link, data, wire_len as u64) {
+                Ok(mut info) => {
+                    // Plus what snaplen cut off
+                    info.frame_len += (wire_len as u64).saturating_sub(data.len() as u64);
+                    if info.pppoe {
+                        self.metrics
+                            .capture_pppoe_frames
+                            .with_label_values(&[interface_name])
+                            .inc();
+                    }
+                    let inner = match self.tunnel_mode {
+                        TunnelMode::Outer => Ok(None),
+                        TunnelMode::Inner | TunnelMode::Both => {
+                            self.innermost_packet(data, wire_len as u64, &info)
+                        }
+                    };
+                    let both = self.tunnel_mode == TunnelMode::Both;
+                    match inner {
+                        Ok(None) => self.account(info, data),
+                        Ok(Some(inner)) => {
+                            if both {
+                                self.account(info, data);
+                            }
+                            self.account(inner, data);
+                        }
+                        // The outer header alone would attribute it to the tunnel endpoints
+                        Err(reason) => {
+                            self.metrics
+                                .capture_malformed_tunnel_packets
+                                .with_label_values(&[interface_name, reason])
+                                .inc();
+                            if both {
+                                self.account(info, data);
+                            }
+                        }
+                    }
+                }
+                Err(FrameError::OtherEthertype(EtherTypes::Arp)) => {
+                    self.observe_arp(link, data, interface_name)
+                }
+                Err(error) => self.count_frame_error(interface_name, error),
+            }
+        }
+    }
+
+    fn count_frame_error(&self, interface_name: &str, error: FrameError) {
+        match error {
+            FrameError::Malformed(reason) => self
+                .metrics
+                .capture_malformed_packets
+                .with_label_values(&[interface_name, reason])
+                .inc(),
+            FrameError::OtherEthertype(ethertype) => self
+                .metrics
+                .capture_other_ethertype_packets
+                .with_label_values(&[&ethertype_label(ethertype)])
+                .inc(),
+        }
+    }
+
+    fn observe_arp(&self, link: LinkLayer, data: &[u8], interface_name: &Arc<str>) {
+        let arp = match parse_arp(link, data) {
+            Ok(arp) => arp,
+            Err(error) => return self.count_frame_error(interface_name, error),
+        };
+        self.send(PacketRecord::Arp {
+            nic: interface_name.clone(),
+            op: arp.op,
+        });
+        let sender = IpAddr::V4(arp.sender_ip);
+        let local = self.local_subnets.read().unwrap().is_local(&sender);
+        if arp.is_gratuitous() {
+            let sender_label = if local {
+                sender.to_string()
+            } else {
+                OVERFLOW_IP_LABEL.to_string()
+            };
+            self.metrics
+                .arp_gratuitous
+                .with_label_values(&[&sender_label])
+                .inc();
+        }
+        if let Some(watch) = self.arp_watch.as_ref().filter(|_| local) {
+            watch.observe(arp.sender_ip, arp.sender_mac, interface_name);
+        }
+    }
+
+    // The packet carried by a GRE, IPIP or VXLAN packet, unwrapping nested tunnels
+    // up to tunnel_max_depth; None if `outer` is not a tunnel packet
+    fn innermost_packet(
+        &self,
+        data: &[u8],
+        wire_len: u64,
+        outer: &PacketInfo,
+    ) -> Result<Option<PacketInfo>, &'static str> {
+        let mut innermost: Option<PacketInfo> = None;
+        for _ in 0..self.tunnel_max_depth {
+            let packet = innermost.as_ref().unwrap_or(outer);
+            match parse_tunnel(data, wire_len, packet, &self.vxlan_ports)? {
+                Some(inner) => innermost = Some(inner),
+                None => break,
+            }
+        }
+        Ok(innermost)
+    }
+
+    fn account(&self, mut info: PacketInfo, data: &[u8]) {
+        if self.is_quic(&info) {
+            info.proto = QUIC_PROTO_LABEL;
+        }
+        #[cfg(feature = "netflow")]
+        if let Some(flows) = &self.flows {
+            flows.observe(&info);
+        }
+        self.account_packet(&info, data);
+        if let Some(dns) = &self.dns {
+            self.observe_dns(dns, data, &info);
+        }
+    }
+
+    fn observe_dns(&self, dns: &DnsQueries, data: &[u8], packet: &PacketInfo) {
+        if packet.ip_proto != IpNextHeaderProtocols::Udp.0
+            || packet.ports.is_none_or(|(_, dst)| dst != DNS_PORT)
+            || !self.local_subnets.read().unwrap().is_local(&packet.src_ip)
+        {
+            return;
+        }
+        let Some(payload) = packet
+            .l4_offset
+            .and_then(|offset| data.get(offset + UDP_HEADER_LEN..))
+        else {
+            return;
+        };
+        let mut name = [0; MAX_NAME_LEN];
+        match parse_query(payload, &mut name) {
+            // parse_query only writes printable ASCII
+            Ok((len, qtype)) => {
+                if let Ok(domain) = std::str::from_utf8(&name[..len]) {
+                    dns.observe(packet.src_ip, domain, qtype);
+                }
+            }
+            Err(reason) => self
+                .metrics
+                .dns_malformed_queries
+                .with_label_values(&[reason])
+                .inc(),
+        }
+    }
+
+    fn packet_bytes(&self, packet: &PacketInfo) -> u64 {
+        match self.count_mode {
+            CountMode::L3 => packet.ip_len + self.frame_overhead_bytes,
+            CountMode::L2 => packet.frame_len,
+        }
+    }
+
+    fn is_quic(&self, packet: &PacketInfo) -> bool {
+        packet.ip_proto == IpNextHeaderProtocols::Udp.0
+            && packet.ports.is_some_and(|(src, dst)| {
+                self.quic_ports.contains(&src) || self.quic_ports.contains(&dst)
+            })
+    }
+
+    // The tracked port of either side, preferring the lower one if both are tracked
+    fn tracked_port(&self, packet: &PacketInfo) -> PortBucket {
+        if packet.fragment == Some(Fragment::NonFirst) {
+            return PortBucket::Fragment;
+        }
+        let Some((src, dst)) = packet.ports else {
+            return PortBucket::Other;
+        };
+        [src.min(dst), src.max(dst)]
+            .into_iter()
+            .find(|port| self.tracked_ports.contains(port))
+            .map_or(PortBucket::Other, PortBucket::Tracked)
+    }
+
+    fn account_packet(&self, packet: &PacketInfo, data: &[u8]) {
+        let bytes = self.packet_bytes(packet);
+        let (src_local, dst_local, cast) = {
+            let subnets = self.local_subnets.read().unwrap();
+            (
+                subnets.is_local(&packet.src_ip),
+                subnets.is_local(&packet.dst_ip),
+                subnets.cast_of(&packet.dst_ip),
+            )
+        };
+
+        // Multicast and broadcast stay on the LAN and have no single receiver to
+        // attribute them to
+        if let Some(cast) = cast {
+            let lan = self.nics.lan();
+            let direction = if src_local {
+                Direction::Tx
+            } else {
+                Direction::Rx
+            };
+            self.publish_packet(packet, bytes, &lan, cast.label());
+            self.send(PacketRecord::Cast {
+                nic: lan,
+                cast,
+                direction,
+                sender: Some(packet.src_ip).filter(|_| src_local && self.cast_metrics),
+                bytes,
+                frame_len: packet.frame_len,
+            });
+            return;
+        }
+
+        // Both ends local: the packet never reaches a WAN NIC
+        if src_local && dst_local {
+            if self.drop_internal {
+                return;
+            }
+            let lan = self.nics.lan();
+            self.publish_packet(packet, bytes, &lan, "internal");
+            for (ip, direction) in [
+                (packet.src_ip, Direction::Tx),
+                (packet.dst_ip, Direction::Rx),
+            ] {
+                self.send(PacketRecord::Internal {
+                    nic: lan.clone(),
+                    ip,
+                    direction,
+                    bytes,
+                    frame_len: packet.frame_len,
+                });
+            }
+            return;
+        }
+
+        // Egress: local source (TX), ingress: local destination (RX)
+        let (ip, remote, direction) = if src_local {
+            (packet.src_ip, packet.dst_ip, Direction::Tx)
+        } else if dst_local {
+            (packet.dst_ip, packet.src_ip, Direction::Rx)
+        } else {
+            return;
+        };
+        let (nic, wan) = self.nics.resolve(&ip);
+        if wan.is_none() {
+            // Scaled like the records, which the aggregator multiplies by the sample rate
+            self.metrics
+                .unmapped_bytes
+                .with_label_values(&[direction.label()])
+                .inc_by(bytes * self.sample_rate);
+            self.unmapped_log.observe(ip, &nic);
+        }
+        self.publish_packet(packet, bytes, &nic, direction.label());
+        #[cfg(feature = "geoip")]
+        let (country, asn) = match &self.geoip {
+            Some(geoip) => (geoip.country(remote), geoip.asn(remote)),
+            None => (None, None),
+        };
+        #[cfg(not(feature = "geoip"))]
+        let (country, asn) = (None, None);
+        let wan = self
+            .default_wan_label
+            .as_ref()
+            .map(|default| wan.unwrap_or_else(|| default.clone()));
+        self.send(PacketRecord::Ip {
+            nic,
+            wan,
+            ip,
+            remote,
+            direction,
+            bytes,
+            frame_len: packet.frame_len,
+            proto: packet.proto,
+            tcp_flags: packet.tcp_flags,
+            icmp_type: packet.icmp_type,
+            port: self.tracked_port(packet),
+            fragment: packet.fragment.is_some(),
+            vlan_id: packet.vlan_id,
+            dscp: self
+                .dscp_classes
+                .as_ref()
+                .map(|classes| classes.label(packet.tos)),
+            country,
+            asn,
+            vni: packet.vni.filter(|_| self.vni_metrics),
+            mpls_label: packet.mpls_label.filter(|_| self.mpls_metrics),
+            domain: self
+                .sni
+                .as_ref()
+                .and_then(|sni| sni.classify(packet, data, direction)),
+        });
+    }
+
+    fn publish_packet(
+        &self,
+        packet: &PacketInfo,
+        bytes: u64,
+        nic: &Arc<str>,
+        direction: &'static str,
+    ) {
+        if let Some(feed) = &self.packet_feed {
+            if feed.active() {
+                feed.publish(PacketEvent::new(packet, bytes, nic.clone(), direction));
+            }
+        }
+    }
+
+    // A frame of the "any" device, handled like one captured on the interface it
+    // came in on; frames of other interfaces per any_device_others
+    fn handle_any_frame(&self, any_device: &AnyDevice, data: &[u8], wire_len: u32) {
+        let Some((ifindex, packet_type)) = sll2_origin(data) else {
+            self.metrics
+                .capture_malformed_packets
+                .with_label_values(&[ANY_DEVICE, "short_sll_header"])
+                .inc();
+            return;
+        };
+        let Some((interface, primary)) = any_device.origin(ifindex) else {
+            return;
+        };
+        // Known from the packet type instead of the interface MAC
+        let direction = match packet_type {
+            SLL_OUTGOING => Some(Direction::Tx),
+            SLL_HOST => Some(Direction::Rx),
+            _ => None,
+        };
+        if let Some(direction) = direction {
+            let bytes = (data.len() as u64).max(wire_len as u64)
+                - cooked_header_overhead(LinkLayer::LinuxSll2, data);
+            self.send(PacketRecord::Capture {
+                interface: interface.clone(),
+                direction,
+                bytes,
+            });
+        }
+        self.handle_frame(
+            LinkLayer::LinuxSll2,
+            data,
+            wire_len,
+            &interface,
+            None,
+            primary,
+        );
+    }
+
+    // Count frames this host itself sent or received on the capture interface, identified
+    // by the interface MAC. Unlike the per-IP accounting this is meaningful on WAN
+    // interfaces too, where local addresses are hidden behind NAT.
+    fn account_capture_frame(
+        &self,
+        data: &[u8],
+        wire_len: u32,
+        interface_name: &Arc<str>,
+        mac: MacAddr,
+    ) {
+        let Some(ethernet) = EthernetPacket::new(data) else {
+            return;
+        };
+        let direction = if ethernet.get_source() == mac {
+            Direction::Tx
+        } else if ethernet.get_destination() == mac {
+            Direction::Rx
+        } else {
+            return;
+        };
+        self.send(PacketRecord::Capture {
+            interface: interface_name.clone(),
+            direction,
+            bytes: (data.len() as u64).max(wire_len as u64),
+        });
+    }
+
+    // Run every frame of `source` through the primary accounting until it is
+    // exhausted, fails or shutdown is requested. Returns the number of frames handled.
+    pub fn replay_source<S: PacketSource + ?Sized>(
+        &self,
+        source: &mut S,
+        link: LinkLayer,
+        name: &Arc<str>,
+        replay_timing: bool,
+        health: &CaptureHealth,
+    ) -> u64 {
+        let mut start: Option<(Duration, std::time::Instant)> = None;
+        let mut packets: u64 = 0;
+        let mut sampler = Sampler::new(self.sample_rate);
+
+        while !self.shutting_down() {
+            let result = source.next();
+            health.poll();
+            match result {
+                Ok(packet) => {
+                    if replay_timing {
+                        let (first_ts, started) =
+                            *start.get_or_insert((packet.timestamp, std::time::Instant::now()));
+                        let due = started + packet.timestamp.saturating_sub(first_ts);
+                        let now = std::time::Instant::now();
+                        if due > now && !self.sleep_unless_shutdown(due - now) {
+                            break;
+                        }
+                    }
+                    if sampler.take() {
+                        self.handle_frame(link, &packet.data, packet.len, name, None, true);
+                    }
+                    packets += 1;
+                }
+                Err(CaptureError::NoMorePackets) => break,
+                Err(e) => {
+                    self.metrics
+                        .capture_errors
+                        .with_label_values(&[capture_error_kind(&e)])
+                        .inc();
+                    error!(
+                        file = %name,
+                        kind = capture_error_kind(&e),
+                        error = %e,
+                        "Error reading capture file"
+                    );
+                    break;
+                }
+            }
+        }
+        packets
+    }
+
+    // Returns the filter that was set. Startup only compiled it for Ethernet, so
+    // this is where it fails for another datalink type.
+    fn apply_filter<H: LiveHandle + ?Sized>(
+        &self,
+        cap: &mut H,
+        link: LinkLayer,
+        interface_name: &str,
+    ) -> Result<String, InvalidFilter> {
+        let filter = self.config.borrow().bpf_filter_for(link).to_string();
+        if !filter.is_empty() {
+            if let Err(e) = cap.set_filter(&filter) {
+                return Err(InvalidFilter {
+                    interface: interface_name.to_string(),
+                    datalink: cap.datalink(),
+                    filter,
+                    error: e.to_string(),
+                });
+            }
+        }
+        Ok(filter)
+    }
+}
+
+// Publish the growth of the cumulative pcap counters since the previous sample.
+// libpcap keeps them as u32, so wrapping_sub handles rollover.
+fn record_pcap_stats(
+    metrics: &Metrics,
+    interface_name: &str,
+    previous: &mut pcap::Stat,
+    current: pcap::Stat,
+) {
+    let received = current.received.wrapping_sub(previous.received);
+    let dropped = current.dropped.wrapping_sub(previous.dropped);
+    let if_dropped = current.if_dropped.wrapping_sub(previous.if_dropped);
+
+    metrics
+        .pcap_received
+        .with_label_values(&[interface_name])
+        .inc_by(received as u64);
+    metrics
+        .pcap_dropped
+        .with_label_values(&[interface_name])
+        .inc_by(dropped as u64);
+    metrics
+        .pcap_if_dropped
+        .with_label_values(&[interface_name])
+        .inc_by(if_dropped as u64);
+
+    if dropped > 0 || if_dropped > 0 {
+        warn!(
+            interface = interface_name,
+            dropped,
+            if_dropped,
+            period_secs = PCAP_STATS_INTERVAL.as_secs(),
+            "pcap dropped packets, consider a larger buffer_size"
+        );
+    }
+
+    *previous = current;
+}
+
+pub fn open_capture(
+    interface_name: &str,
+    settings: &CaptureSettings,
+) -> Result<Capture<pcap::Active>, Box<dyn std::error::Error>> {
+    let device = resolve_device(interface_name, settings.interface_match)?;
+    if device.name != interface_name {
+        info!(interface = interface_name, device = %device.name, "Resolved capture device");
+    }
+
+    let any = device.name == ANY_DEVICE;
+    let mut cap = Capture::from_device(device)?
+        .promisc(settings.promisc && !any)
+        .snaplen(settings.snaplen)
+        .timeout(settings.timeout_ms);
+    if let Some(buffer_size) = settings.buffer_size {
+        cap = cap.buffer_size(buffer_size);
+    }
+    let mut cap = cap.open()?;
+    // SLL2 also tells which interface each frame came in on
+    if any
+        && cap
+            .list_datalinks()
+            .is_ok_and(|dlts| dlts.contains(&Linktype::LINUX_SLL2))
+    {
+        cap.set_datalink(Linktype::LINUX_SLL2)?;
+    }
+    select_datalink(&mut cap, interface_name)?;
+    Ok(cap)
+}
+
+// The device's datalink type could not be read by parse_frame()
+#[derive(Debug)]
+pub struct UnsupportedDatalink {
+    pub interface: String,
+    // Every datalink type the device offers, the default first
+    pub found: Vec<Linktype>,
+    pub supported: &'static str,
+}
+
+fn linktype_name(linktype: Linktype) -> String {
+    linktype
+        .get_name()
+        .unwrap_or_else(|_| format!("DLT {}", linktype.0))
+}
+
+impl std::fmt::Display for UnsupportedDatalink {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let found: Vec<String> = self.found.iter().map(|&dlt| linktype_name(dlt)).collect();
+        write!(
+            f,
+            "unsupported datalink type on {} (found {}; supported: {})",
+            self.interface,
+            found.join(", "),
+            self.supported
+        )
+    }
+}
+
+impl std::error::Error for UnsupportedDatalink {}
+
+// Keep the device's default datalink type if it is supported, otherwise switch to
+// the first supported one it offers
+fn select_datalink(
+    cap: &mut Capture<pcap::Active>,
+    interface_name: &str,
+) -> Result<LinkLayer, UnsupportedDatalink> {
+    let default = cap.get_datalink();
+    if let Some(link) = LinkLayer::from_linktype(default) {
+        return Ok(link);
+    }
+    let mut found = vec![default];
+    found.extend(
+        cap.list_datalinks()
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|&dlt| dlt != default),
+    );
+    for &dlt in &found[1..] {
+        if let Some(link) = LinkLayer::from_linktype(dlt) {
+            if cap.set_datalink(dlt).is_ok() {
+                info!(
+                    interface = interface_name,
+                    from = %linktype_name(default),
+                    to = %linktype_name(dlt),
+                    "Switched to a supported datalink type"
+                );
+                return Ok(link);
+            }
+        }
+    }
+    Err(UnsupportedDatalink {
+        interface: interface_name.to_string(),
+        found,
+        supported: SUPPORTED_LINKTYPES,
+    })
+}
+
+// Opening a device failed for lack of privileges, which no retry will fix
+#[derive(Debug)]
+pub struct PermissionDenied {
+    pub interface: String,
+    pub error: String,
+}
+
+impl std::fmt::Display for PermissionDenied {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let exe = std::env::current_exe()
+            .map(|path| path.display().to_string())
+            .unwrap_or_else(|_| "localpacketdump".to_string());
+        write!(
+            f,
+            "no permission to capture on {} ({}); ",
+            self.interface, self.error
+        )?;
+        if cfg!(target_os = "linux") {
+            write!(
+                f,
+                "run as root or grant the capture capabilities, e.g. sudo setcap \
+                 cap_net_raw,cap_net_admin=eip {}",
+                exe
+            )
+        } else if cfg!(windows) {
+            write!(
+                f,
+                "run as Administrator or reinstall Npcap without admin-only mode"
+            )
+        } else {
+            write!(
+                f,
+                "run as root or make /dev/bpf* readable, e.g. with Wireshark's ChmodBPF"
+            )
+        }
+    }
+}
+
+impl std::error::Error for PermissionDenied {}
+
+#[derive(Debug)]
+pub struct InvalidFilter {
+    pub interface: String,
+    pub datalink: Linktype,
+    pub filter: String,
+    pub error: String,
+}
+
+impl std::fmt::Display for InvalidFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "BPF filter '{}' cannot be set on {} ({}): {}",
+            self.filter,
+            self.interface,
+            linktype_name(self.datalink),
+            self.error
+        )
+    }
+}
+
+impl std::error::Error for InvalidFilter {}
+
+// --read-file could not be opened as a pcap file
+#[derive(Debug)]
+pub struct UnreadableFile {
+    pub path: String,
+    pub error: String,
+}
+
+impl std::fmt::Display for UnreadableFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "failed to open {}: {}", self.path, self.error)
+    }
+}
+
+impl std::error::Error for UnreadableFile {}
+
+// A capture task that panicked or was cancelled instead of returning
+#[derive(Debug)]
+pub struct CaptureCrashed {
+    pub interface: String,
+    pub error: String,
+}
+
+impl std::fmt::Display for CaptureCrashed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "capture on {} crashed: {}", self.interface, self.error)
+    }
+}
+
+impl std::error::Error for CaptureCrashed {}
+
+// libpcap reports PCAP_ERROR_PERM_DENIED only through its error text
+pub fn is_permission_denied(error: &(dyn std::error::Error + 'static)) -> bool {
+    match error.downcast_ref::<pcap::Error>() {
+        Some(pcap::Error::PcapError(message)) => {
+            let message = message.to_ascii_lowercase();
+            ["permission", "operation not permitted", "cap_net_raw"]
+                .iter()
+                .any(|needle| message.contains(needle))
+        }
+        Some(pcap::Error::IoError(kind)) => *kind == std::io::ErrorKind::PermissionDenied,
+        _ => false,
+    }
+}
+
+// Why a capture task gave up on its device instead of retrying
+#[derive(Debug)]
+pub enum CaptureFailed {
+    PermissionDenied(PermissionDenied),
+    UnsupportedDatalink(UnsupportedDatalink),
+    InvalidFilter(InvalidFilter),
+    UnreadableFile(UnreadableFile),
+    Crashed(CaptureCrashed),
+}
+
+impl CaptureFailed {
+    pub fn interface(&self) -> &str {
+        match self {
+            Self::PermissionDenied(e) => &e.interface,
+            Self::UnsupportedDatalink(e) => &e.interface,
+            Self::InvalidFilter(e) => &e.interface,
+            Self::UnreadableFile(e) => &e.path,
+            Self::Crashed(e) => &e.interface,
+        }
+    }
+}
+
+impl std::fmt::Display for CaptureFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::PermissionDenied(e) => e.fmt(f),
+            Self::UnsupportedDatalink(e) => e.fmt(f),
+            Self::InvalidFilter(e) => e.fmt(f),
+            Self::UnreadableFile(e) => e.fmt(f),
+            Self::Crashed(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for CaptureFailed {}
+
+// Keep retrying until the device shows up, e.g. a bridge created after boot.
+// Returns None if shutdown is requested while waiting.
+fn open_capture_with_retry(
+    interface_name: &str,
+    ctx: &CaptureContext,
+) -> Result<Option<Capture<pcap::Active>>, CaptureFailed> {
+    let mut delay = CAPTURE_RETRY_INITIAL;
+    loop {
+        match open_capture(interface_name, &ctx.capture) {
+            Ok(cap) => return Ok(Some(cap)),
+            Err(e) if is_permission_denied(e.as_ref()) => {
+                return Err(CaptureFailed::PermissionDenied(PermissionDenied {
+                    interface: interface_name.to_string(),
+                    error: e.to_string(),
+                }))
+            }
+            Err(e) => {
+                let e = match e.downcast::<UnsupportedDatalink>() {
+                    Ok(e) => return Err(CaptureFailed::UnsupportedDatalink(*e)),
+                    Err(e) => e,
+                };
+                warn!(
+                    interface = interface_name,
+                    error = %e,
+                    retry_in = ?delay,
+                    "Failed to open capture"
+                );
+                if !ctx.sleep_unless_shutdown(delay) {
+                    return Ok(None);
+                }
+                delay = (delay * 2).min(CAPTURE_RETRY_MAX);
+            }
+        }
+    }
+}
+
+// kind label of capture_errors_total and the error logs
+fn capture_error_kind(error: &CaptureError) -> &'static str {
+    match error {
+        pcap::Error::TimeoutExpired => "timeout",
+        // A live handle ends when its interface goes away
+        pcap::Error::NoMorePackets => "disconnected",
+        error if is_permission_denied(error) => "permission",
+        error if is_disconnected(error) => "disconnected",
+        _ => "other",
+    }
+}
+
+// The interface went down or was removed under the handle
+fn is_disconnected(error: &CaptureError) -> bool {
+    match error {
+        pcap::Error::PcapError(message) => {
+            let message = message.to_ascii_lowercase();
+            [
+                "went down",
+                "network is down",
+                "no such device",
+                "disappeared",
+                "not up",
+            ]
+            .iter()
+            .any(|needle| message.contains(needle))
+        }
+        pcap::Error::IoError(kind) => matches!(
+            kind,
+            std::io::ErrorKind::NotConnected
+                | std::io::ErrorKind::BrokenPipe
+                | std::io::ErrorKind::NetworkDown
+        ),
+        // ENXIO, ENODEV, ENETDOWN
+        pcap::Error::ErrnoError(errno) => matches!(errno.0, 6 | 19 | 100),
+        _ => false,
+    }
+}
+
+// One capture error per kind right away, then a count of the repeats per minute
+struct CaptureErrorLog {
+    limiter: LogLimiter<&'static str>,
+}
+
+impl CaptureErrorLog {
+    fn new(interval: Duration) -> Self {
+        Self {
+            limiter: LogLimiter::new(interval),
+        }
+    }
+
+    fn log(&mut self, interface_name: &str, error: &pcap::Error) {
+        let kind = capture_error_kind(error);
+        if self.limiter.record(kind, std::time::Instant::now()) {
+            error!(
+                interface = interface_name,
+                kind,
+                error = %error,
+                "Error capturing packet"
+            );
+        }
+    }
+
+    // A reopen denied after --user dropped the privileges, retried after `retry_in`
+    fn log_denied(&mut self, interface_name: &str, error: &PermissionDenied, retry_in: Duration) {
+        if self.limiter.record("permission", std::time::Instant::now()) {
+            warn!(
+                interface = interface_name,
+                error = %error,
+                retry_in = ?retry_in,
+                "Capture reopen denied, retrying"
+            );
+        }
+    }
+
+    fn summarize(&mut self, interface_name: &str, kinds: Vec<(&'static str, u64)>) {
+        for (kind, suppressed) in kinds {
+            warn!(
+                interface = interface_name,
+                kind, suppressed, "Suppressed {} similar capture errors", suppressed
+            );
+        }
+    }
+
+    fn poll(&mut self, interface_name: &str) {
+        let due = self.limiter.due(std::time::Instant::now());
+        self.summarize(interface_name, due);
+    }
+
+    fn flush(&mut self, interface_name: &str) {
+        let pending = self.limiter.drain();
+        self.summarize(interface_name, pending);
+    }
+}
+
+// `opened` is a handle opened up front, before --user drops the privileges needed
+// to open one; later reopens go through open_capture_with_retry and keep being
+// retried while denied. The task ends with CaptureFailed if the device cannot be
+// opened at all or its frames cannot be read.
+pub fn capture_packets(
+    interface_name: String,
+    primary: bool,
+    ctx: CaptureContext,
+    mut opened: Option<Capture<pcap::Active>>,
+) -> tokio::task::JoinHandle<Result<(), CaptureFailed>> {
+    crate::runtime::spawn_blocking(move || {
+        let open = || match opened.take() {
+            Some(cap) => Ok(Some(cap)),
+            None => open_capture_with_retry(&interface_name, &ctx),
+        };
+        run_capture(
+            &interface_name,
+            primary,
+            &ctx,
+            open,
+            CAPTURE_FAILING_REOPEN,
+            CAPTURE_RETRY_INITIAL,
+        )
+    })
+}
+
+// The parts of a pcap handle the capture loop uses, so tests can drive the loop
+// with crafted frames and errors
+trait LiveHandle {
+    fn datalink(&self) -> Linktype;
+    fn set_filter(&mut self, filter: &str) -> Result<(), CaptureError>;
+    fn stats(&mut self) -> Result<pcap::Stat, CaptureError>;
+    fn next_packet(&mut self) -> Result<pcap::Packet<'_>, CaptureError>;
+    // Only asked for Ethernet handles
+    fn device_mac(&self, interface_name: &str, interface_match: InterfaceMatch) -> Option<MacAddr>;
+}
+
+impl<T: pcap::Activated + ?Sized> LiveHandle for Capture<T> {
+    fn datalink(&self) -> Linktype {
+        self.get_datalink()
+    }
+
+    fn set_filter(&mut self, filter: &str) -> Result<(), CaptureError> {
+        self.filter(filter, true)
+    }
+
+    fn stats(&mut self) -> Result<pcap::Stat, CaptureError> {
+        Capture::stats(self)
+    }
+
+    fn next_packet(&mut self) -> Result<pcap::Packet<'_>, CaptureError> {
+        Capture::next_packet(self)
+    }
+
+    // pnet knows the interface by its pcap device name
+    fn device_mac(&self, interface_name: &str, interface_match: InterfaceMatch) -> Option<MacAddr> {
+        let device_name = resolve_device(interface_name, interface_match)
+            .map(|device| device.name)
+            .unwrap_or_else(|_| interface_name.to_string());
+        interface_mac(&device_name)
+    }
+}
+
+// The body of capture_packets. `open` returns a fresh handle, None once shutdown
+// was requested while waiting for the device; `failing_reopen` is how long read
+// errors may last before the handle is dropped, `denied_retry` the first wait
+// before a denied reopen is tried again.
+fn run_capture<H: LiveHandle>(
+    interface_name: &str,
+    primary: bool,
+    ctx: &CaptureContext,
+    mut open: impl FnMut() -> Result<Option<H>, CaptureFailed>,
+    failing_reopen: Duration,
+    denied_retry: Duration,
+) -> Result<(), CaptureFailed> {
+    let running = ctx
+        .metrics
+        .capture_running
+        .with_label_values(&[interface_name]);
+    running.set(0);
+    let health = ctx.health.register_capture(interface_name);
+
+    let interface: Arc<str> = Arc::from(interface_name);
+    let mut error_log = CaptureErrorLog::new(CAPTURE_ERROR_LOG_INTERVAL);
+    let mut sampler = Sampler::new(ctx.sample_rate);
+    let mut config = ctx.config.clone();
+    let mut opened_before = false;
+    let mut denied_delay = denied_retry;
+
+    // Each pass owns one pcap handle; NoMorePackets on a live device (e.g. the
+    // interface went away) or errors that do not stop drop it and open a fresh
+    // one, retried with backoff until the device is back
+    while !ctx.shutting_down() {
+        let mut cap = match open() {
+            Ok(Some(cap)) => cap,
+            Ok(None) => break,
+            // The first handle of --user is opened before the privileges are
+            // dropped and later ones are denied; one flap must not end the daemon
+            Err(CaptureFailed::PermissionDenied(e)) if opened_before => {
+                error_log.log_denied(interface_name, &e, denied_delay);
+                if !ctx.sleep_unless_shutdown(denied_delay) {
+                    break;
+                }
+                denied_delay = (denied_delay * 2).min(CAPTURE_RETRY_MAX);
+                error_log.poll(interface_name);
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        opened_before = true;
+        denied_delay = denied_retry;
+        // open_capture() only returns handles with a supported datalink type
+        let link = LinkLayer::from_linktype(cap.datalink()).unwrap_or(LinkLayer::Ethernet);
+        if ctx.any_device.is_some() && link != LinkLayer::LinuxSll2 {
+            return Err(CaptureFailed::UnsupportedDatalink(UnsupportedDatalink {
+                interface: interface_name.to_string(),
+                found: vec![cap.datalink()],
+                supported: "LINUX_SLL2 for capture_any_device (libpcap 1.10 or later)",
+            }));
+        }
+        config.mark_unchanged();
+        let mut filter = ctx
+            .apply_filter(&mut cap, link, interface_name)
+            .map_err(CaptureFailed::InvalidFilter)?;
+        running.set(1);
+        ctx.health.mark_capture_opened();
+
+        let dump = ctx.dump.as_ref().and_then(|control| {
+            DumpWriter::spawn(control.clone(), interface_name, cap.datalink())
+                .map_err(|e| {
+                    error!(interface = %interface_name, error = %e, "Failed to start dump writer")
+                })
+                .ok()
+        });
+
+        let mac = match link {
+            LinkLayer::Ethernet => cap.device_mac(interface_name, ctx.capture.interface_match),
+            _ => None,
+        };
+        // The "any" device tells the direction from the SLL2 header instead
+        if ctx.any_device.is_none() && link != LinkLayer::Ethernet {
+            info!(
+                interface = %interface_name,
+                datalink = %linktype_name(cap.datalink()),
+                "Not an Ethernet capture, network_capture_* metrics disabled for it"
+            );
+        } else if ctx.any_device.is_none() && mac.is_none() {
+            warn!(
+                interface = %interface_name,
+                "No MAC address found, network_capture_* metrics disabled for it"
+            );
+        }
+
+        info!(
+            interface = %interface_name,
+            role = if primary { "primary" } else { "secondary" },
+            "Started capturing"
+        );
+
+        let mut last_stats = pcap::Stat {
+            received: 0,
+            dropped: 0,
+            if_dropped: 0,
+        };
+        let mut last_stats_at = std::time::Instant::now();
+        // First and latest read error since the last packet
+        let mut failing: Option<(std::time::Instant, std::time::Instant)> = None;
+
+        // The read timeout bounds how long a shutdown request waits here
+        while !ctx.shutting_down() {
+            if last_stats_at.elapsed() >= PCAP_STATS_INTERVAL {
+                match cap.stats() {
+                    Ok(current) => {
+                        record_pcap_stats(&ctx.metrics, interface_name, &mut last_stats, current)
+                    }
+                    Err(e) => {
+                        error!(interface = %interface_name, error = %e, "Failed to read pcap stats")
+                    }
+                }
+                last_stats_at = std::time::Instant::now();
+            }
+
+            // The reload validated the filter, a failure here keeps the old one
+            if config.has_changed().unwrap_or(false) {
+                let next = config.borrow_and_update().bpf_filter_for(link).to_string();
+                if next != filter {
+                    match cap.set_filter(&next) {
+                        Ok(()) => {
+                            info!(interface = %interface_name, filter = %next, "BPF filter changed");
+                            filter = next;
+                        }
+                        Err(e) => {
+                            error!(interface = %interface_name, filter = %next, error = %e, "Failed to set the new BPF filter, keeping the old one")
+                        }
+                    }
+                }
+            }
+
+            // Read straight from the handle rather than through PacketSource to avoid
+            // copying every live frame; the accounting is the same handle_frame
+            let result = cap.next_packet();
+            health.poll();
+            match result {
+                Ok(packet) => {
+                    failing = None;
+                    if let Some(dump) = &dump {
+                        dump.write(packet.header, packet.data);
+                    }
+                    // Dumps keep every frame, sampling only thins the accounting
+                    if sampler.take() {
+                        match &ctx.any_device {
+                            Some(any_device) => {
+                                ctx.handle_any_frame(any_device, packet.data, packet.header.len)
+                            }
+                            None => ctx.handle_frame(
+                                link,
+                                packet.data,
+                                packet.header.len,
+                                &interface,
+                                mac,
+                                primary,
+                            ),
+                        }
+                    }
+                }
+                Err(pcap::Error::TimeoutExpired) => {}
+                Err(e) => {
+                    ctx.metrics
+                        .capture_errors
+                        .with_label_values(&[capture_error_kind(&e)])
+                        .inc();
+                    if let pcap::Error::NoMorePackets = e {
+                        warn!(interface = %interface_name, "Capture ended, reopening");
+                        break;
+                    }
+                    error_log.log(interface_name, &e);
+
+                    // An error after a quiet stretch starts a new failure rather
+                    // than extending the old one
+                    let now = std::time::Instant::now();
+                    let since = match failing {
+                        Some((since, last)) if now.duration_since(last) < failing_reopen => since,
+                        _ => now,
+                    };
+                    failing = Some((since, now));
+                    if now.duration_since(since) >= failing_reopen {
+                        warn!(
+                            interface = %interface_name,
+                            failing_for = ?now.duration_since(since),
+                            "Capture keeps failing, reopening"
+                        );
+                        break;
+                    }
+                }
+            }
+            error_log.poll(interface_name);
+        }
+
+        running.set(0);
+    }
+
+    error_log.flush(interface_name);
+    info!(interface = %interface_name, "Stopped capturing");
+    Ok(())
+}
+
+// Primary capture on the LAN interface named by the status service. When a
+// mapping refresh renames it (e.g. during failover) the running capture is
+// stopped and a new one started on the new name; the metrics of the old one are
+// served until they go idle.
+pub fn capture_lan(
+    mut lan: watch::Receiver<Arc<str>>,
+    ctx: CaptureContext,
+) -> tokio::task::JoinHandle<Result<(), CaptureFailed>> {
+    tokio::spawn(async move {
+        let mut shutdown = ctx.shutdown.clone();
+        // False once the mapping refresh is gone and no rename can come anymore
+        let mut following = true;
+        loop {
+            let interface = lan.borrow_and_update().to_string();
+            let (stop, stopped) = watch::channel(false);
+            let mut capture = capture_packets(
+                interface.clone(),
+                true,
+                CaptureContext {
+                    shutdown: stopped,
+                    ..ctx.clone()
+                },
+                None,
+            );
+            let crashed = |e: tokio::task::JoinError| {
+                CaptureFailed::Crashed(CaptureCrashed {
+                    interface: interface.clone(),
+                    error: e.to_string(),
+                })
+            };
+            let renamed = loop {
+                tokio::select! {
+                    result = &mut capture => return result.map_err(crashed)?,
+                    _ = shutdown.wait_for(|stop| *stop) => break false,
+                    changed = lan.changed(), if following => match changed {
+                        Ok(()) if **lan.borrow() != *interface => break true,
+                        Ok(()) => {}
+                        Err(_) => following = false,
+                    },
+                }
+            };
+            let _ = stop.send(true);
+            let result = capture.await.map_err(crashed)?;
+            if !renamed {
+                return result;
+            }
+            result?;
+            let _ = ctx
+                .metrics
+                .capture_running
+                .remove_label_values(&[&interface]);
+            ctx.health.remove_capture(&interface);
+            info!(
+                from = %interface,
+                to = %*lan.borrow(),
+                "LAN interface renamed, moving the capture"
+            );
+        }
+    })
+}
+
+// Feed a saved capture through the same accounting as a live interface. With
+// `replay_timing` packets are paced by their pcap timestamps, otherwise the file
+// is read at full speed. The HTTP server keeps running after the file ends; the
+// task only fails if the file cannot be read at all.
+pub fn replay_file(
+    path: PathBuf,
+    replay_timing: bool,
+    ctx: CaptureContext,
+) -> tokio::task::JoinHandle<Result<(), CaptureFailed>> {
+    crate::runtime::spawn_blocking(move || {
+        let name: Arc<str> = Arc::from(path.display().to_string());
+        let mut cap = Capture::from_file(&path).map_err(|e| {
+            CaptureFailed::UnreadableFile(UnreadableFile {
+                path: name.to_string(),
+                error: e.to_string(),
+            })
+        })?;
+
+        let link = LinkLayer::from_linktype(cap.get_datalink()).ok_or_else(|| {
+            CaptureFailed::UnsupportedDatalink(UnsupportedDatalink {
+                interface: name.to_string(),
+                found: vec![cap.get_datalink()],
+                supported: SUPPORTED_LINKTYPES,
+            })
+        })?;
+        ctx.apply_filter(&mut cap, link, &name)
+            .map_err(CaptureFailed::InvalidFilter)?;
+
+        let health = ctx.health.register_capture(&name);
+        ctx.health.mark_capture_opened();
+        info!(file = %name, "Started replaying");
+
+        let packets = ctx.replay_source(
+            &mut PcapSource::new(cap),
+            link,
+            &name,
+            replay_timing,
+            &health,
+        );
+
+        health.finished.store(true, Ordering::Relaxed);
+        info!(
+            file = %name,
+            packets,
+            "Finished replaying, metrics are still served"
+        );
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Config, UnmappedNic};
+    use crate::mapping::{NicConfig, StatusResponse};
+    use crate::source::{OwnedPacket, VecSource};
+    use crate::stats::{aggregate_records, FlowKey};
+    use std::collections::VecDeque;
+    use std::sync::Mutex;
+    use tokio::sync::oneshot;
+
+    // lan0 is the LAN, wan0 goes out on eth0 and 10.40.0.5 is mapped to it
+    fn context(records: mpsc::Sender<PacketRecord>) -> CaptureContext {
+        let metrics = Arc::new(Metrics::new("network", false, false, false, false, false).unwrap());
+        let mut status = StatusResponse::new(NicConfig {
+            lan: Arc::from("lan0"),
+            wans: [(Arc::from("wan0"), Arc::from("eth0"))]
+                .into_iter()
+                .collect(),
+        });
+        status
+            .mappings
+            .insert("10.40.0.5".to_string(), "wan0".to_string());
+        status.normalize_mappings();
+        let nics = NicResolver::new(&status, "wan0", UnmappedNic::DefaultWan, &metrics);
+        let mut local_subnets = LocalSubnets::new();
+        local_subnets.add_subnet("10.40.0.0/20").unwrap();
+        CaptureContext {
+            metrics,
+            records,
+            nics: Arc::new(nics),
+            local_subnets: Arc::new(RwLock::new(local_subnets)),
+            config: watch::channel(Arc::new(Config::default())).1,
+            lossless: false,
+            health: Arc::new(HealthState::new(Duration::from_secs(30))),
+            count_mode: CountMode::L3,
+            frame_overhead_bytes: 0,
+            tunnel_mode: TunnelMode::Outer,
+            tunnel_max_depth: 1,
+            vxlan_ports: Arc::from([]),
+            vni_metrics: false,
+            mpls_metrics: false,
+            cast_metrics: false,
+            shutdown: watch::channel(false).1,
+            capture: CaptureSettings::default(),
+            sample_rate: 1,
+            tracked_ports: Arc::from([]),
+            quic_ports: Arc::from([]),
+            unmapped_log: Arc::new(UnmappedLog::default()),
+            default_wan_label: None,
+            drop_internal: false,
+            dscp_classes: None,
+            dump: None,
+            any_device: None,
+            packet_feed: None,
+            dns: None,
+            sni: None,
+            arp_watch: None,
+            #[cfg(feature = "netflow")]
+            flows: None,
+            #[cfg(feature = "geoip")]
+            geoip: None,
+        }
+    }
+
+    // 10.40.0.5:40000 -> 93.184.216.34:53 over UDP with `payload` bytes, tagged with VLAN 40
+    fn vlan_udp_frame(payload: usize) -> Vec<u8> {
+        let total_len = (20 + 8 + payload) as u16;
+        let mut frame = vec![0x02, 0, 0, 0, 0, 0x01, 0x02, 0, 0, 0, 0, 0x02];
+        frame.extend_from_slice(&[0x81, 0x00, 0x00, 40, 0x08, 0x00]);
+        frame.extend_from_slice(&[0x45, 0, 0, 0, 0, 0, 0, 0, 64, 17, 0, 0]);
+        frame[20..22].copy_from_slice(&total_len.to_be_bytes());
+        frame.extend_from_slice(&[10, 40, 0, 5, 93, 184, 216, 34]);
+        frame.extend_from_slice(&40000u16.to_be_bytes());
+        frame.extend_from_slice(&53u16.to_be_bytes());
+        frame.extend_from_slice(&((8 + payload) as u16).to_be_bytes());
+        frame.extend_from_slice(&[0, 0]);
+        frame.resize(frame.len() + payload, 0);
+        frame
+    }
+
+    #[tokio::test]
+    async fn replayed_vlan_frame_reaches_the_nic_totals() {
+        let (records_tx, records_rx) = mpsc::channel(16);
+        let (snapshot_tx, snapshot_rx) = mpsc::channel(1);
+        tokio::spawn(aggregate_records(
+            records_rx,
+            snapshot_rx,
+            false,
+            None,
+            1,
+            Vec::new(),
+        ));
+        let ctx = context(records_tx);
+        let name: Arc<str> = Arc::from("vlan.pcap");
+        let health = ctx.health.register_capture(&name);
+
+        let mut source = VecSource::from_frames([vlan_udp_frame(72)]);
+        let packets = ctx.replay_source(&mut source, LinkLayer::Ethernet, &name, false, &health);
+        assert_eq!(packets, 1);
+
+        let (reply, stats) = oneshot::channel();
+        snapshot_tx.send(reply).await.unwrap();
+        let stats = stats.await.unwrap();
+        // L3 counting: the IP total length, without the Ethernet and VLAN headers
+        assert_eq!(stats.nic_tx_total.get("eth0"), Some(&100));
+        assert_eq!(stats.nic_tx_packets.get("eth0"), Some(&1));
+        assert!(stats.nic_rx_total.is_empty());
+        let key = FlowKey {
+            nic: Arc::from("eth0"),
+            wan: None,
+            ip: Some("10.40.0.5".parse().unwrap()),
+        };
+        assert_eq!(stats.tx_bytes.get(&key), Some(&100));
+    }
+
+    // Replays a script of read results and notes capture_running at every read
+    struct ScriptedHandle {
+        script: VecDeque<Result<OwnedPacket, CaptureError>>,
+        header: pcap::PacketHeader,
+        data: Vec<u8>,
+        running: prometheus::IntGauge,
+        running_at_reads: Arc<Mutex<Vec<i64>>>,
+    }
+
+    impl LiveHandle for ScriptedHandle {
+        fn datalink(&self) -> Linktype {
+            Linktype::ETHERNET
+        }
+
+        fn set_filter(&mut self, _filter: &str) -> Result<(), CaptureError> {
+            Ok(())
+        }
+
+        fn stats(&mut self) -> Result<pcap::Stat, CaptureError> {
+            Ok(pcap::Stat {
+                received: 0,
+                dropped: 0,
+                if_dropped: 0,
+            })
+        }
+
+        fn next_packet(&mut self) -> Result<pcap::Packet<'_>, CaptureError> {
+            self.running_at_reads
+                .lock()
+                .unwrap()
+                .push(self.running.get());
+            let packet = self
+                .script
+                .pop_front()
+                .unwrap_or(Err(CaptureError::TimeoutExpired))?;
+            self.header = pcap::PacketHeader {
+                ts: libc::timeval {
+                    tv_sec: 0,
+                    tv_usec: 0,
+                },
+                caplen: packet.data.len() as u32,
+                len: packet.len,
+            };
+            self.data = packet.data;
+            Ok(pcap::Packet::new(&self.header, &self.data))
+        }
+
+        fn device_mac(
+            &self,
+            _interface_name: &str,
+            _interface_match: InterfaceMatch,
+        ) -> Option<MacAddr> {
+            None
+        }
+    }
+
+    #[test]
+    fn failing_capture_is_reopened_until_it_reads_again() {
+        let (records_tx, mut records_rx) = mpsc::channel(16);
+        let ctx = context(records_tx);
+        let running = ctx.metrics.capture_running.with_label_values(&["lan0"]);
+        let running_at_reads = Arc::new(Mutex::new(Vec::new()));
+        let frame = vlan_udp_frame(72);
+
+        // The first handle only fails, the second reads a frame and then loses the
+        // device; the third open finds shutdown requested
+        let mut scripts = VecDeque::from([
+            vec![Err(CaptureError::PcapError("read failed".to_string()))],
+            vec![
+                Ok(OwnedPacket {
+                    timestamp: Duration::ZERO,
+                    len: frame.len() as u32,
+                    data: frame,
+                }),
+                Err(CaptureError::NoMorePackets),
+            ],
+        ]);
+        let mut running_at_opens = Vec::new();
+        let open = || {
+            running_at_opens.push(running.get());
+            Ok(scripts.pop_front().map(|script| ScriptedHandle {
+                script: script.into(),
+                header: pcap::PacketHeader {
+                    ts: libc::timeval {
+                        tv_sec: 0,
+                        tv_usec: 0,
+                    },
+                    caplen: 0,
+                    len: 0,
+                },
+                data: Vec::new(),
+                running: running.clone(),
+                running_at_reads: running_at_reads.clone(),
+            }))
+        };
+
+        // Any read error is persistent with no grace period
+        run_capture("lan0", true, &ctx, open, Duration::ZERO, Duration::ZERO).unwrap();
+
+        assert_eq!(running_at_opens, [0, 0, 0]);
+        assert_eq!(*running_at_reads.lock().unwrap(), [1, 1, 1]);
+        assert_eq!(running.get(), 0);
+        let errors = |kind| ctx.metrics.capture_errors.with_label_values(&[kind]).get();
+        assert_eq!(errors("other"), 1);
+        assert_eq!(errors("disconnected"), 1);
+        assert!(records_rx.try_recv().is_ok());
+    }
+
+    #[test]
+    fn non_first_fragments_get_the_fragment_port_bucket() {
+        let ctx = context(mpsc::channel(1).0);
+        // IPv6 to UDP behind a fragment header at offset 1448, on a tun device
+        let mut packet = vec![0x60, 0, 0, 0, 0, 16, 44, 64];
+        packet.extend_from_slice(&[0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 5]);
+        packet.extend_from_slice(&[0x20, 0x01, 0x0d, 0xb8, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+        packet.extend_from_slice(&[17, 0, 0x05, 0xa9, 0, 0, 0x12, 0x34]);
+        packet.extend_from_slice(&[0xab; 8]);
+        let info = parse_frame(LinkLayer::Raw, &packet, packet.len() as u64).unwrap();
+        assert_eq!(ctx.tracked_port(&info), PortBucket::Fragment);
+    }
+
+    #[test]
+    fn denied_reopen_is_retried_instead_of_ending_the_capture() {
+        let (records_tx, mut records_rx) = mpsc::channel(16);
+        let ctx = context(records_tx);
+        let running = ctx.metrics.capture_running.with_label_values(&["lan0"]);
+        let running_at_reads = Arc::new(Mutex::new(Vec::new()));
+        let frame = vlan_udp_frame(72);
+        let handle = |script: Vec<Result<OwnedPacket, CaptureError>>| ScriptedHandle {
+            script: script.into(),
+            header: pcap::PacketHeader {
+                ts: libc::timeval {
+                    tv_sec: 0,
+                    tv_usec: 0,
+                },
+                caplen: 0,
+                len: 0,
+            },
+            data: Vec::new(),
+            running: running.clone(),
+            running_at_reads: running_at_reads.clone(),
+        };
+        let denied = || {
+            Err(CaptureFailed::PermissionDenied(PermissionDenied {
+                interface: "lan0".to_string(),
+                error: "Operation not permitted".to_string(),
+            }))
+        };
+
+        // The handle opened before the drop loses its device, the next two opens
+        // are denied and the third reads a frame; then shutdown
+        let mut opens = 0;
+        let open = || {
+            opens += 1;
+            match opens {
+                1 => Ok(Some(handle(vec![Err(CaptureError::NoMorePackets)]))),
+                2 | 3 => denied(),
+                4 => Ok(Some(handle(vec![
+                    Ok(OwnedPacket {
+                        timestamp: Duration::ZERO,
+                        len: frame.len() as u32,
+                        data: frame.clone(),
+                    }),
+                    Err(CaptureError::NoMorePackets),
+                ]))),
+                _ => Ok(None),
+            }
+        };
+
+        run_capture("lan0", true, &ctx, open, Duration::ZERO, Duration::ZERO).unwrap();
+
+        assert_eq!(opens, 5);
+        assert_eq!(*running_at_reads.lock().unwrap(), [1, 1, 1]);
+        assert!(records_rx.try_recv().is_ok());
+    }
+
+    #[test]
+    fn denied_first_open_ends_the_capture() {
+        let ctx = context(mpsc::channel(1).0);
+        let open = || -> Result<Option<ScriptedHandle>, CaptureFailed> {
+            Err(CaptureFailed::PermissionDenied(PermissionDenied {
+                interface: "lan0".to_string(),
+                error: "Operation not permitted".to_string(),
+            }))
+        };
+        let result = run_capture("lan0", true, &ctx, open, Duration::ZERO, Duration::ZERO);
+        assert!(matches!(result, Err(CaptureFailed::PermissionDenied(_))));
+    }
+}
@@ -0,0 +1,515 @@
+use crate::metrics::parse_update_interval;
+use crate::packet::LinkLayer;
+use crate::stats::Direction;
+use serde::{Deserialize, Deserializer};
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+
+// ローカルサブネットのデフォルト定義（CIDR形式で指定）
+// 設定ファイルが指定されない場合、または subnets が省略された場合に使用される
+// (--auto-subnets の場合は使用されない)
+pub const LOCAL_SUBNETS: &[&str] = &[
+    "10.40.0.0/20",
+    // 必要に応じて追加
+    // "192.168.1.0/24",
+    // "172.16.0.0/16",
+];
+
+// Frames the exporter can account, including PPPoE sessions and MPLS; everything
+// else is dropped in the kernel. vlan shifts the offsets of whatever follows it in
+// libpcap, so it comes last.
+pub const DEFAULT_BPF_FILTER: &str =
+    "ip or ip6 or arp or ether proto 0x8864 or ether proto 0x8847 or ether proto 0x8848 or vlan";
+// libpcap has no vlan primitive for cooked captures, e.g. the "any" device, but
+// matches ether proto against their protocol field; the kernel has already
+// removed the VLAN tags there
+pub const DEFAULT_SLL_BPF_FILTER: &str =
+    "ip or ip6 or arp or ether proto 0x8864 or ether proto 0x8847 or ether proto 0x8848";
+// Raw IP and loopback captures carry nothing but IP
+pub const DEFAULT_IP_ONLY_BPF_FILTER: &str = "ip or ip6 or arp";
+
+pub fn default_bpf_filter(link: LinkLayer) -> &'static str {
+    match link {
+        LinkLayer::Ethernet => DEFAULT_BPF_FILTER,
+        LinkLayer::LinuxSll | LinkLayer::LinuxSll2 => DEFAULT_SLL_BPF_FILTER,
+        LinkLayer::Raw | LinkLayer::Loopback { .. } => DEFAULT_IP_ONLY_BPF_FILTER,
+    }
+}
+
+// NIC mapping status service
+pub const DEFAULT_STATUS_URL: &str = "http://localhost:32599/status";
+
+// How many bytes a packet contributes to the per-IP metrics
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CountMode {
+    // IP total length (plus frame_overhead_bytes), unaffected by snaplen and LRO/GRO
+    #[default]
+    L3,
+    // Captured frame length including the Ethernet header
+    L2,
+}
+
+// Which addresses of GRE, IPIP and VXLAN packets are accounted
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TunnelMode {
+    // The tunnel endpoints, like any other packet
+    #[default]
+    Outer,
+    // The hosts in the innermost packet within tunnel_max_depth
+    Inner,
+    // Both, so the traffic is counted twice
+    Both,
+}
+
+// Frames of the "any" device from interfaces outside capture_interfaces
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OtherInterfaces {
+    #[default]
+    Ignore,
+    // network_capture_* with capture="other"
+    Other,
+}
+
+// NIC label of traffic whose local IP has no mapping to a known wan
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UnmappedNic {
+    // The interface of default_wan
+    #[default]
+    DefaultWan,
+    // nic="unmapped", so the traffic is visible instead of misattributed
+    Unmapped,
+    // The LAN interface from the status service
+    Lan,
+}
+
+// One [[alerts]] table: POST to webhook_url when a local IP stays above
+// threshold_bps in `direction` for for_secs, and again when it drops below
+#[derive(Debug, Clone, Deserialize)]
+pub struct AlertRule {
+    pub name: String,
+    pub direction: Direction,
+    pub threshold_bps: f64,
+    #[serde(default)]
+    pub for_secs: u64,
+    pub webhook_url: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    // LOCAL_SUBNETS when omitted, except with --auto-subnets
+    pub subnets: Option<Vec<String>>,
+    // Subnets changed through /api/subnets are written here; when it exists it
+    // replaces `subnets` at startup
+    pub subnets_file: Option<PathBuf>,
+    // Prefix of the traffic metric names (network_ip_tx_bps, ...), empty for none
+    pub metric_namespace: String,
+    // How often --auto-subnets re-reads the capture interface's addresses
+    pub auto_subnets_refresh_secs: u64,
+    // Ignore IPv6 link-local (fe80::/10) addresses even if a subnet covers them
+    pub exclude_link_local: bool,
+    // Publish per-VLAN totals (network_vlan_{tx,rx}_bps)
+    pub vlan_metrics: bool,
+    // Publish network_ip_{tx,rx}_bps_by_dscp and the per-NIC network_dscp_{tx,rx}_bps
+    pub dscp_metrics: bool,
+    // Class label -> DSCP values; unlisted values are counted as "other"
+    pub dscp_classes: BTreeMap<String, Vec<u8>>,
+    // Add the mapped wan name (wan0, wan1, "default" if unmapped) as a wan label
+    // to the per-IP and total metrics
+    pub wan_labels: bool,
+    // Per-IP series without traffic for this long are removed from the registry
+    pub series_idle_timeout_secs: u64,
+    // Interfaces to capture on; the first one feeds the per-IP metrics.
+    // Defaults to the LAN interface reported by the status service.
+    pub capture_interfaces: Vec<String>,
+    // Capture all of capture_interfaces through one handle on Linux's "any" device,
+    // attributing each frame to the interface it came in on
+    pub capture_any_device: bool,
+    pub any_device_others: OtherInterfaces,
+    // BPF filter expression, an empty string captures everything
+    pub bpf_filter: Option<String>,
+    // Flush interval like --update-interval ("250ms", "5s"), which overrides it
+    #[serde(deserialize_with = "deserialize_update_interval")]
+    pub update_interval: Option<Duration>,
+    // tracing filter directives, e.g. "info" or "localpacketdump=debug"; RUST_LOG
+    // overrides it
+    pub log_level: Option<String>,
+    // /healthz reports a component as stale after this many seconds without progress
+    pub health_timeout_secs: u64,
+    // NIC mapping status service, defaults to http://localhost:32599/status
+    pub status_url: Option<String>,
+    pub count_mode: CountMode,
+    // GRE, IPIP and VXLAN packets: the outer addresses, the inner ones or both.
+    // Inner packets are unwrapped up to tunnel_max_depth levels of nesting.
+    pub tunnel_accounting: TunnelMode,
+    pub tunnel_max_depth: usize,
+    // UDP destination ports of VXLAN
+    pub vxlan_ports: Vec<u16>,
+    // network_vni_{tx,rx}_bps: NIC totals per VXLAN network identifier
+    pub vni_metrics: bool,
+    // network_mpls_{tx,rx}_bps: NIC totals per label of the top MPLS label stack entry
+    pub mpls_metrics: bool,
+    // network_ip_cast_tx_bps: multicast and broadcast TX per local sender
+    pub cast_metrics: bool,
+    // Log a local IP claimed in ARP by a different MAC within this many seconds of
+    // the last claim, 0 disables it
+    pub arp_conflict_window_secs: u64,
+    // Fixed per-packet overhead added in l3 mode, e.g. 14 for the Ethernet header
+    pub frame_overhead_bytes: u64,
+    // Per-IP series published per interval, the rest go to local_ip="other"; 0 = no limit
+    pub max_tracked_ips: usize,
+    // Intervals kept for /history, 0 disables it
+    pub history_intervals: usize,
+    // GET /ws/packets: clients streaming accounted packets at once, 0 disables it
+    pub packet_stream_max_clients: usize,
+    // Packets sent per client and second, the rest are counted as skipped; 0 = no limit
+    pub packet_stream_max_rate: u32,
+    // GET /devices: remember every local IP seen. Setting devices_file turns it on as
+    // well and keeps the list across restarts, rewritten every devices_save_secs.
+    pub track_devices: bool,
+    pub devices_file: Option<PathBuf>,
+    pub devices_save_secs: u64,
+    // New IPs beyond this are not remembered; 0 = no limit
+    pub max_devices: usize,
+    // GET /dns/top and network_dns_queries_total: the names local IPs query over
+    // UDP port 53. Off by default since it records what every host looks up.
+    pub dns_queries: bool,
+    // Intervals /dns/top covers
+    pub dns_top_intervals: usize,
+    // SQLite file with daily per-IP byte totals for /usage, needs the sqlite cargo feature
+    pub usage_db: Option<PathBuf>,
+    // Days kept in usage_db, 0 keeps everything
+    pub usage_retention_days: u32,
+    // network_ip_{tx,rx}_bytes_today: per-IP bytes since daily_reset_time (HH:MM) in
+    // daily_timezone, an IANA name; the system time zone when unset
+    pub daily_totals: bool,
+    pub daily_reset_time: String,
+    pub daily_timezone: Option<String>,
+    // network_ip_quota_exceeded: TX+RX GB per day for every IP, overridden per IP.
+    // Setting either turns on daily_totals.
+    pub daily_quota_gb: Option<f64>,
+    pub daily_quotas_gb: BTreeMap<String, f64>,
+    // Today's counters are written here every daily_checkpoint_secs and restored
+    // after a restart within the same day
+    pub daily_checkpoint_file: Option<PathBuf>,
+    pub daily_checkpoint_secs: u64,
+    // Threshold rules evaluated on every interval, see AlertRule
+    pub alerts: Vec<AlertRule>,
+    pub alert_webhook_timeout_secs: u64,
+    // Ports broken out in network_ip_{tx,rx}_bps_by_port, others are "other"
+    pub tracked_ports: Vec<u16>,
+    // UDP to or from these ports is proto="quic" rather than "udp"
+    pub quic_ports: Vec<u16>,
+    // MaxMind GeoLite2 Country (or City) database for network_country_*_bps,
+    // reloaded when the file changes
+    pub geoip_database: Option<PathBuf>,
+    // MaxMind GeoLite2 ASN database for network_asn_*_bps
+    pub geoip_asn_database: Option<PathBuf>,
+    // ASNs with their own series per interval, the rest are asn="other" (0 = no limit)
+    pub geoip_asn_max: usize,
+    // network_domain_bps: WAN traffic of TCP connections to port 443 by the server
+    // name in their TLS ClientHello
+    pub domain_metrics: bool,
+    // Domains with their own series per interval, the rest are domain="other"
+    // (0 = no limit)
+    pub domain_max: usize,
+    // Connections whose server name is remembered, the least recently active are
+    // forgotten first
+    pub domain_flows_max: usize,
+    // Local IPs whose WAN traffic is broken out per remote IP in network_ip_remote_bps
+    pub track_remotes_for: Vec<IpAddr>,
+    // Remote IPs with their own series per tracked IP and direction, the rest are
+    // remote_ip="other"
+    pub track_remotes_max: usize,
+    // Wan for IPs without a mapping and for mappings to unknown wan names
+    pub default_wan: String,
+    // Where that traffic is counted, see UnmappedNic
+    pub unmapped_nic: UnmappedNic,
+    // Ignore traffic between two local IPs instead of publishing it as internal
+    pub drop_internal: bool,
+    // --resolve-hostnames: how long a resolved name is kept before it is looked up again
+    pub hostname_ttl_secs: u64,
+    // How long a failed PTR lookup is cached
+    pub hostname_negative_ttl_secs: u64,
+    pub hostname_max_concurrent_lookups: usize,
+    // --mac-labels: how often the kernel ARP table is read
+    pub neighbor_refresh_secs: u64,
+    // --dump-dir: rotate pcap files after this many megabytes or seconds
+    pub dump_max_file_mb: u64,
+    pub dump_rotate_secs: u64,
+    // Files kept per interface, the oldest are deleted; 0 keeps everything
+    pub dump_max_files: usize,
+    // Start writing right away, otherwise wait for POST /dump/start
+    pub dump_on_start: bool,
+    // Authentication for every HTTP endpoint: a bearer token, or a user and password for
+    // basic auth. The LOCALPACKETDUMP_HTTP_AUTH_* environment variables take precedence.
+    pub http_auth_token: Option<String>,
+    pub http_auth_user: Option<String>,
+    pub http_auth_password: Option<String>,
+    // Paths served without credentials, e.g. for load balancer health checks
+    pub http_auth_exempt: Vec<String>,
+    // GET /pcap: capture on the primary interface and return a pcap file
+    pub pcap_download: bool,
+    pub pcap_download_max_secs: u64,
+    // Bearer token required by /pcap, no authentication when unset
+    pub pcap_download_token: Option<String>,
+    // OTLP/HTTP metrics endpoint to push every flush to, e.g. http://collector:4318/v1/metrics
+    pub otlp_endpoint: Option<String>,
+    // Extra request headers, e.g. for collector authentication
+    pub otlp_headers: BTreeMap<String, String>,
+    pub otlp_timeout_secs: u64,
+    // InfluxDB v2 base URL to write every flush to as line protocol, e.g. http://influx:8086
+    pub influx_url: Option<String>,
+    pub influx_org: String,
+    pub influx_bucket: String,
+    pub influx_token: Option<String>,
+    pub influx_timeout_secs: u64,
+    // statsd listener (host:port) to send every flush to as gauges over UDP
+    pub statsd_address: Option<String>,
+    pub statsd_prefix: String,
+    // Plain metric names built from {metric}, {nic} and {local_ip} instead of
+    // Datadog-style tags, e.g. "{metric}.{nic}.{local_ip}"
+    pub statsd_template: Option<String>,
+    // Carbon plaintext listener (host:port) to write every flush to over TCP
+    pub graphite_address: Option<String>,
+    pub graphite_prefix: String,
+    // Lines buffered while the connection is down, the oldest are dropped beyond this
+    pub graphite_buffer_lines: usize,
+    pub graphite_timeout_secs: u64,
+    // MQTT broker (host:port) to publish the per-NIC totals to, needs the mqtt cargo feature
+    pub mqtt_broker: Option<String>,
+    pub mqtt_client_id: String,
+    pub mqtt_user: Option<String>,
+    pub mqtt_password: Option<String>,
+    pub mqtt_topic_prefix: String,
+    // IPs published on <prefix>/top, 0 disables it
+    pub mqtt_top_ips: usize,
+    pub mqtt_keep_alive_secs: u64,
+    // Connect over TLS; the system roots verify the broker without mqtt_ca_file
+    pub mqtt_tls: bool,
+    pub mqtt_ca_file: Option<PathBuf>,
+    pub mqtt_client_cert: Option<PathBuf>,
+    pub mqtt_client_key: Option<PathBuf>,
+    // NetFlow v5 collector (host:port), needs the netflow cargo feature
+    pub netflow_collector: Option<String>,
+    pub netflow_active_timeout_secs: u64,
+    pub netflow_inactive_timeout_secs: u64,
+    pub netflow_max_flows: usize,
+    // Nominal speed in Mbit/s per interface for network_nic_{tx,rx}_utilization_ratio
+    pub link_speeds_mbps: BTreeMap<String, f64>,
+    // Read the speed of the other interfaces from /sys/class/net/<nic>/speed (Linux)
+    pub link_speed_auto: bool,
+    // Bucket length for network_ip_{tx,rx}_bps_peak; 0 disables peak tracking
+    pub peak_bucket_ms: u64,
+    // Serve --listen over HTTPS with this PEM certificate chain and key; the files are
+    // checked every tls_reload_secs and reloaded when they change
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub tls_reload_secs: u64,
+    // --listen-unix: socket mode (e.g. 0o660) and owner, user and group names or ids
+    pub unix_socket_mode: Option<u32>,
+    pub unix_socket_owner: Option<String>,
+    pub unix_socket_group: Option<String>,
+    // pcap handle options of the live captures
+    pub promisc: bool,
+    // Bytes kept per frame, at least 96; l3 counting still sees the full IP length
+    pub snaplen: i32,
+    // Kernel capture buffer in bytes, libpcap's default when unset
+    pub buffer_size: Option<i32>,
+    pub timeout_ms: i32,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            subnets: None,
+            subnets_file: None,
+            metric_namespace: "network".to_string(),
+            auto_subnets_refresh_secs: 60,
+            exclude_link_local: false,
+            vlan_metrics: false,
+            dscp_metrics: false,
+            dscp_classes: BTreeMap::from([
+                ("EF".to_string(), vec![46]),
+                ("AF4x".to_string(), vec![34, 36, 38]),
+                ("CS0".to_string(), vec![0]),
+            ]),
+            wan_labels: false,
+            series_idle_timeout_secs: 300,
+            capture_interfaces: Vec::new(),
+            capture_any_device: false,
+            any_device_others: OtherInterfaces::Ignore,
+            bpf_filter: None,
+            update_interval: None,
+            log_level: None,
+            health_timeout_secs: 10,
+            status_url: None,
+            count_mode: CountMode::L3,
+            tunnel_accounting: TunnelMode::Outer,
+            tunnel_max_depth: 2,
+            vxlan_ports: vec![4789],
+            vni_metrics: false,
+            mpls_metrics: false,
+            cast_metrics: false,
+            arp_conflict_window_secs: 60,
+            frame_overhead_bytes: 0,
+            max_tracked_ips: 512,
+            history_intervals: 300,
+            packet_stream_max_clients: 4,
+            packet_stream_max_rate: 1000,
+            track_devices: false,
+            devices_file: None,
+            devices_save_secs: 60,
+            max_devices: 4096,
+            dns_queries: false,
+            dns_top_intervals: 300,
+            usage_db: None,
+            usage_retention_days: 400,
+            daily_totals: false,
+            daily_reset_time: "00:00".to_string(),
+            daily_timezone: None,
+            daily_quota_gb: None,
+            daily_quotas_gb: BTreeMap::new(),
+            daily_checkpoint_file: None,
+            daily_checkpoint_secs: 60,
+            alerts: Vec::new(),
+            alert_webhook_timeout_secs: 5,
+            tracked_ports: vec![80, 443, 53, 22],
+            quic_ports: vec![443],
+            geoip_database: None,
+            geoip_asn_database: None,
+            geoip_asn_max: 50,
+            domain_metrics: false,
+            domain_max: 50,
+            domain_flows_max: 16384,
+            track_remotes_for: Vec::new(),
+            track_remotes_max: 20,
+            default_wan: "wan0".to_string(),
+            unmapped_nic: UnmappedNic::DefaultWan,
+            drop_internal: false,
+            hostname_ttl_secs: 3600,
+            hostname_negative_ttl_secs: 300,
+            hostname_max_concurrent_lookups: 4,
+            neighbor_refresh_secs: 30,
+            dump_max_file_mb: 100,
+            dump_rotate_secs: 600,
+            dump_max_files: 10,
+            dump_on_start: true,
+            http_auth_token: None,
+            http_auth_user: None,
+            http_auth_password: None,
+            http_auth_exempt: vec!["/healthz".to_string()],
+            pcap_download: false,
+            pcap_download_max_secs: 60,
+            pcap_download_token: None,
+            otlp_endpoint: None,
+            otlp_headers: BTreeMap::new(),
+            otlp_timeout_secs: 5,
+            influx_url: None,
+            influx_org: String::new(),
+            influx_bucket: "localpacketdump".to_string(),
+            influx_token: None,
+            influx_timeout_secs: 5,
+            statsd_address: None,
+            statsd_prefix: "lpd".to_string(),
+            statsd_template: None,
+            graphite_address: None,
+            graphite_prefix: "lpd".to_string(),
+            graphite_buffer_lines: 100_000,
+            graphite_timeout_secs: 5,
+            mqtt_broker: None,
+            mqtt_client_id: "localpacketdump".to_string(),
+            mqtt_user: None,
+            mqtt_password: None,
+            mqtt_topic_prefix: "localpacketdump".to_string(),
+            mqtt_top_ips: 0,
+            mqtt_keep_alive_secs: 30,
+            mqtt_tls: false,
+            mqtt_ca_file: None,
+            mqtt_client_cert: None,
+            mqtt_client_key: None,
+            netflow_collector: None,
+            netflow_active_timeout_secs: 60,
+            netflow_inactive_timeout_secs: 15,
+            netflow_max_flows: 65536,
+            link_speeds_mbps: BTreeMap::new(),
+            link_speed_auto: true,
+            peak_bucket_ms: 100,
+            tls_cert: None,
+            tls_key: None,
+            tls_reload_secs: 60,
+            unix_socket_mode: None,
+            unix_socket_owner: None,
+            unix_socket_group: None,
+            promisc: true,
+            snaplen: 65535,
+            buffer_size: None,
+            timeout_ms: 1000,
+        }
+    }
+}
+
+impl Config {
+    // With auto-detected subnets an omitted list adds nothing to them
+    pub fn subnets(&self, auto_subnets: bool) -> Vec<String> {
+        match &self.subnets {
+            Some(subnets) => subnets.clone(),
+            None if auto_subnets => Vec::new(),
+            None => LOCAL_SUBNETS.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+
+    pub fn bpf_filter(&self) -> &str {
+        self.bpf_filter.as_deref().unwrap_or(DEFAULT_BPF_FILTER)
+    }
+
+    // The filter for a capture, whose datalink type decides the default
+    pub fn bpf_filter_for(&self, link: LinkLayer) -> &str {
+        self.bpf_filter
+            .as_deref()
+            .unwrap_or_else(|| default_bpf_filter(link))
+    }
+
+    pub fn status_url(&self) -> &str {
+        self.status_url.as_deref().unwrap_or(DEFAULT_STATUS_URL)
+    }
+}
+
+// The config as last loaded, replaced on SIGHUP; holders re-read it at their loop
+// boundaries
+pub type SharedConfig = watch::Receiver<Arc<Config>>;
+
+fn deserialize_update_interval<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Duration>, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    parse_update_interval(&value)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+// The parsed config and its top-level table, which tells a reload what changed
+pub fn read_config(path: &Path) -> Result<(Config, toml::Table), Box<dyn std::error::Error>> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+    let config = toml::from_str(&contents)
+        .map_err(|e| format!("failed to parse {}: {}", path.display(), e))?;
+    let table = toml::from_str(&contents)
+        .map_err(|e| format!("failed to parse {}: {}", path.display(), e))?;
+    Ok((config, table))
+}
+
+pub fn load_config(path: Option<&Path>) -> Result<Config, Box<dyn std::error::Error>> {
+    match path {
+        Some(path) => Ok(read_config(path)?.0),
+        None => Ok(Config::default()),
+    }
+}
@@ -0,0 +1,190 @@
+use crate::metrics::{Flush, FlushSink};
+use crate::runtime::spawn_blocking;
+use prometheus::IntCounter;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+// One local IP seen in the per-IP stats, as served by /devices and kept in devices_file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Device {
+    pub ip: IpAddr,
+    // NIC of the most recent interval with traffic
+    pub nic: Arc<str>,
+    pub first_seen_unix: u64,
+    pub last_seen_unix: u64,
+    pub tx_bytes: u64,
+    pub rx_bytes: u64,
+}
+
+// Every local IP seen since the first start when devices_file is set, otherwise
+// since this process started. Updated by DeviceSink once per interval and written
+// to the file by persist_devices, never from the updater itself.
+pub struct DeviceTable {
+    devices: Mutex<HashMap<IpAddr, Device>>,
+    max_devices: usize,
+    file: Option<PathBuf>,
+    // Changed since the last save
+    dirty: AtomicBool,
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+impl DeviceTable {
+    // A missing file starts an empty table, an unreadable one is an error so a
+    // typo does not silently throw the history away on the next save
+    pub fn load(file: Option<PathBuf>, max_devices: usize) -> Result<Self, String> {
+        let mut devices = HashMap::new();
+        if let Some(path) = &file {
+            match std::fs::read(path) {
+                Ok(data) => {
+                    let list: Vec<Device> = serde_json::from_slice(&data)
+                        .map_err(|e| format!("failed to parse {}: {}", path.display(), e))?;
+                    devices.extend(list.into_iter().map(|device| (device.ip, device)));
+                    info!(
+                        "Loaded {} known devices from {}",
+                        devices.len(),
+                        path.display()
+                    );
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    info!(
+                        "{} does not exist yet, starting with no known devices",
+                        path.display()
+                    );
+                }
+                Err(e) => return Err(format!("failed to read {}: {}", path.display(), e)),
+            }
+        }
+        Ok(Self {
+            devices: Mutex::new(devices),
+            max_devices,
+            file,
+            dirty: AtomicBool::new(false),
+        })
+    }
+
+    // Sorted by IP
+    pub fn list(&self) -> Vec<Device> {
+        let mut list: Vec<Device> = self.devices.lock().unwrap().values().cloned().collect();
+        list.sort_by_key(|device| device.ip);
+        list
+    }
+
+    // Write to a temporary file next to devices_file and rename it over the old one,
+    // so a crash mid-write leaves the previous state. Blocks on the file system.
+    pub fn save(&self) -> Result<(), String> {
+        let Some(path) = &self.file else {
+            return Ok(());
+        };
+        if !self.dirty.swap(false, Ordering::Relaxed) {
+            return Ok(());
+        }
+        let data = serde_json::to_vec_pretty(&self.list()).map_err(|e| e.to_string());
+        let result = data.and_then(|data| write_atomic(path, &data));
+        if result.is_err() {
+            // Retry on the next tick
+            self.dirty.store(true, Ordering::Relaxed);
+        }
+        result
+    }
+}
+
+// Also used for the other small state files
+pub fn write_atomic(path: &Path, data: &[u8]) -> Result<(), String> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    std::fs::write(&tmp, data)
+        .and_then(|()| std::fs::rename(&tmp, path))
+        .map_err(|e| format!("failed to write {}: {}", path.display(), e))
+}
+
+pub struct DeviceSink {
+    devices: Arc<DeviceTable>,
+    new_devices: IntCounter,
+    // Log the cap once rather than on every interval
+    full_logged: bool,
+}
+
+impl DeviceSink {
+    pub fn new(devices: Arc<DeviceTable>, new_devices: IntCounter) -> Self {
+        Self {
+            devices,
+            new_devices,
+            full_logged: false,
+        }
+    }
+}
+
+impl FlushSink for DeviceSink {
+    fn publish(&mut self, flush: &Flush<'_>) {
+        let now = unix_secs(flush.timestamp);
+        let table = &self.devices;
+        let mut devices = table.devices.lock().unwrap();
+        for rate in &flush.snapshot.per_ip {
+            // local_ip="other" folds many IPs together
+            let Some(ip) = rate.key.ip else {
+                continue;
+            };
+            let tx = rate.tx.map_or(0, |rate| rate.bytes);
+            let rx = rate.rx.map_or(0, |rate| rate.bytes);
+            if let Some(device) = devices.get_mut(&ip) {
+                device.last_seen_unix = now;
+                device.tx_bytes += tx;
+                device.rx_bytes += rx;
+                if device.nic != rate.key.nic {
+                    device.nic = rate.key.nic.clone();
+                }
+                continue;
+            }
+            if table.max_devices > 0 && devices.len() >= table.max_devices {
+                if !self.full_logged {
+                    warn!(
+                        "{} known devices, not tracking new ones (max_devices)",
+                        devices.len()
+                    );
+                    self.full_logged = true;
+                }
+                continue;
+            }
+            info!("New device {} on {}", ip, rate.key.nic);
+            self.new_devices.inc();
+            devices.insert(
+                ip,
+                Device {
+                    ip,
+                    nic: rate.key.nic.clone(),
+                    first_seen_unix: now,
+                    last_seen_unix: now,
+                    tx_bytes: tx,
+                    rx_bytes: rx,
+                },
+            );
+        }
+        if !flush.snapshot.per_ip.is_empty() {
+            table.dirty.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+pub async fn persist_devices(devices: Arc<DeviceTable>, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let devices = devices.clone();
+        match spawn_blocking(move || devices.save()).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => warn!("Failed to save known devices: {}", e),
+            Err(e) => warn!("Device save task failed: {}", e),
+        }
+    }
+}
@@ -0,0 +1,319 @@
+use crate::metrics::{Flush, FlushSink};
+use prometheus::IntCounterVec;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+pub const DNS_PORT: u16 = 53;
+
+// Longest name in presentation form (RFC 1035 allows 255 bytes on the wire)
+pub const MAX_NAME_LEN: usize = 253;
+
+// Compression pointers followed per name before it counts as a loop. A query
+// normally has none, its question comes first.
+const MAX_POINTER_JUMPS: usize = 8;
+
+const HEADER_LEN: usize = 12;
+
+// Distinct (local IP, domain) entries remembered per interval, queries for new
+// ones are only counted in network_dns_queries_total after that
+const MAX_ENTRIES_PER_INTERVAL: usize = 4096;
+
+// Label of the common query types, the rest are "other"
+pub fn qtype_label(qtype: u16) -> &'static str {
+    match qtype {
+        1 => "A",
+        2 => "NS",
+        5 => "CNAME",
+        6 => "SOA",
+        12 => "PTR",
+        15 => "MX",
+        16 => "TXT",
+        28 => "AAAA",
+        33 => "SRV",
+        64 => "SVCB",
+        65 => "HTTPS",
+        255 => "ANY",
+        _ => "other",
+    }
+}
+
+// The first question of a DNS query: the name is written lowercased and without
+// the trailing dot into `name`, "." for the root. Returns its length and the QTYPE,
+// or the reason label of a malformed payload.
+pub fn parse_query(
+    payload: &[u8],
+    name: &mut [u8; MAX_NAME_LEN],
+) -> Result<(usize, u16), &'static str> {
+    if payload.len() < HEADER_LEN {
+        return Err("short_header");
+    }
+    // QR set: a response; opcode other than 0: not a standard query
+    if payload[2] & 0x80 != 0 {
+        return Err("not_query");
+    }
+    if (payload[2] >> 3) & 0x0f != 0 {
+        return Err("not_standard_query");
+    }
+    if u16::from_be_bytes([payload[4], payload[5]]) == 0 {
+        return Err("no_question");
+    }
+
+    let mut pos = HEADER_LEN;
+    let mut len = 0;
+    let mut jumps = 0;
+    // Where the question continues after the name once a pointer was followed
+    let mut after_name = None;
+    loop {
+        let &byte = payload.get(pos).ok_or("truncated_name")?;
+        match byte & 0xc0 {
+            0x00 if byte == 0 => {
+                pos += 1;
+                break;
+            }
+            0x00 => {
+                let label_len = byte as usize;
+                let label = payload
+                    .get(pos + 1..pos + 1 + label_len)
+                    .ok_or("truncated_name")?;
+                let dot = usize::from(len > 0);
+                if len + dot + label_len > MAX_NAME_LEN {
+                    return Err("name_too_long");
+                }
+                if dot == 1 {
+                    name[len] = b'.';
+                    len += 1;
+                }
+                for &c in label {
+                    // Leaves the name safe to print and unambiguous to split on dots
+                    if !c.is_ascii_graphic() || c == b'.' {
+                        return Err("bad_label");
+                    }
+                    name[len] = c.to_ascii_lowercase();
+                    len += 1;
+                }
+                pos += 1 + label_len;
+            }
+            0xc0 => {
+                let &low = payload.get(pos + 1).ok_or("truncated_name")?;
+                jumps += 1;
+                if jumps > MAX_POINTER_JUMPS {
+                    return Err("pointer_loop");
+                }
+                after_name.get_or_insert(pos + 2);
+                let target = (usize::from(byte & 0x3f) << 8) | usize::from(low);
+                // Only earlier names can be referred to
+                if target >= pos {
+                    return Err("bad_pointer");
+                }
+                pos = target;
+            }
+            _ => return Err("bad_label_type"),
+        }
+    }
+    let pos = after_name.unwrap_or(pos);
+    let qtype = payload.get(pos..pos + 2).ok_or("truncated_question")?;
+    if len == 0 {
+        name[0] = b'.';
+        len = 1;
+    }
+    Ok((len, u16::from_be_bytes([qtype[0], qtype[1]])))
+}
+
+#[derive(Debug, Default)]
+struct DomainCounts {
+    queries: u64,
+    qtypes: BTreeMap<&'static str, u64>,
+}
+
+#[derive(Debug, Default)]
+struct IntervalQueries {
+    per_ip: HashMap<IpAddr, u64>,
+    domains: HashMap<IpAddr, HashMap<Box<str>, DomainCounts>>,
+    entries: usize,
+}
+
+// One entry of GET /dns/top
+#[derive(Debug, Serialize)]
+pub struct TopDomain {
+    pub domain: String,
+    pub queries: u64,
+    pub qtypes: BTreeMap<&'static str, u64>,
+    // Local IPs that asked for it
+    pub clients: usize,
+}
+
+// Queries seen by the capture threads in the current interval, plus the last
+// `window` finished intervals for /dns/top
+pub struct DnsQueries {
+    window: usize,
+    current: Mutex<IntervalQueries>,
+    finished: Mutex<VecDeque<IntervalQueries>>,
+}
+
+impl DnsQueries {
+    pub fn new(window: usize) -> Self {
+        Self {
+            window: window.max(1),
+            current: Mutex::new(IntervalQueries::default()),
+            finished: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    // Only allocates for the first query of a domain by an IP in an interval
+    pub fn observe(&self, ip: IpAddr, domain: &str, qtype: u16) {
+        let mut current = self.current.lock().unwrap();
+        let current = &mut *current;
+        *current.per_ip.entry(ip).or_insert(0) += 1;
+        let domains = current.domains.entry(ip).or_default();
+        let counts = match domains.get_mut(domain) {
+            Some(counts) => counts,
+            None if current.entries >= MAX_ENTRIES_PER_INTERVAL => return,
+            None => {
+                current.entries += 1;
+                domains.entry(Box::from(domain)).or_default()
+            }
+        };
+        counts.queries += 1;
+        *counts.qtypes.entry(qtype_label(qtype)).or_insert(0) += 1;
+    }
+
+    // Ends the current interval, returning its queries per local IP
+    fn rotate(&self) -> HashMap<IpAddr, u64> {
+        let mut interval = std::mem::take(&mut *self.current.lock().unwrap());
+        let per_ip = std::mem::take(&mut interval.per_ip);
+        let mut finished = self.finished.lock().unwrap();
+        if finished.len() >= self.window {
+            finished.pop_front();
+        }
+        finished.push_back(interval);
+        per_ip
+    }
+
+    // The most queried domains over the window, optionally of one local IP
+    pub fn top(&self, n: usize, ip: Option<IpAddr>) -> Vec<TopDomain> {
+        let mut domains: HashMap<&str, (DomainCounts, HashSet<IpAddr>)> = HashMap::new();
+        let finished = self.finished.lock().unwrap();
+        for interval in finished.iter() {
+            for (client, queries) in &interval.domains {
+                if ip.is_some_and(|ip| ip != *client) {
+                    continue;
+                }
+                for (domain, counts) in queries {
+                    let (total, clients) = domains.entry(domain).or_default();
+                    total.queries += counts.queries;
+                    for (qtype, queries) in &counts.qtypes {
+                        *total.qtypes.entry(qtype).or_insert(0) += queries;
+                    }
+                    clients.insert(*client);
+                }
+            }
+        }
+        let mut top: Vec<TopDomain> = domains
+            .into_iter()
+            .map(|(domain, (counts, clients))| TopDomain {
+                domain: domain.to_string(),
+                queries: counts.queries,
+                qtypes: counts.qtypes,
+                clients: clients.len(),
+            })
+            .collect();
+        top.sort_by(|a, b| {
+            b.queries
+                .cmp(&a.queries)
+                .then_with(|| a.domain.cmp(&b.domain))
+        });
+        top.truncate(n);
+        top
+    }
+}
+
+pub struct DnsSink {
+    queries: Arc<DnsQueries>,
+    counter: IntCounterVec,
+}
+
+impl DnsSink {
+    pub fn new(queries: Arc<DnsQueries>, counter: IntCounterVec) -> Self {
+        Self { queries, counter }
+    }
+}
+
+impl FlushSink for DnsSink {
+    fn publish(&mut self, _flush: &Flush<'_>) {
+        for (ip, queries) in self.queries.rotate() {
+            self.counter
+                .with_label_values(&[&ip.to_string()])
+                .inc_by(queries);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(qname: &[u8], qtype: u16) -> Vec<u8> {
+        // id, flags (RD), one question, no other records
+        let mut payload = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        payload.extend_from_slice(qname);
+        payload.extend_from_slice(&qtype.to_be_bytes());
+        payload.extend_from_slice(&[0, 1]);
+        payload
+    }
+
+    fn parse(payload: &[u8]) -> Result<(String, u16), &'static str> {
+        let mut name = [0; MAX_NAME_LEN];
+        let (len, qtype) = parse_query(payload, &mut name)?;
+        Ok((String::from_utf8(name[..len].to_vec()).unwrap(), qtype))
+    }
+
+    #[test]
+    fn plain_query_is_lowercased() {
+        let payload = query(b"\x03www\x07Example\x03COM\x00", 1);
+        assert_eq!(parse(&payload), Ok(("www.example.com".to_string(), 1)));
+        assert_eq!(parse(&query(b"\x00", 2)), Ok((".".to_string(), 2)));
+    }
+
+    #[test]
+    fn compression_pointer_is_followed_and_qtype_read_after_it() {
+        // "com" in the unused record counts of the header, the question points to it
+        let mut payload = query(b"\x07example\xc0\x06", 28);
+        payload[6..11].copy_from_slice(b"\x03com\x00");
+        assert_eq!(parse(&payload), Ok(("example.com".to_string(), 28)));
+    }
+
+    #[test]
+    fn pointer_loops_and_forward_pointers_are_rejected() {
+        // Pointing at itself or ahead breaks the backward-only rule
+        assert_eq!(parse(&query(b"\xc0\x0c", 1)), Err("bad_pointer"));
+        assert_eq!(parse(&query(b"\x01a\xc0\x10\x00", 1)), Err("bad_pointer"));
+        // Back to its own first label, forever
+        assert_eq!(parse(&query(b"\x01a\xc0\x0c", 1)), Err("pointer_loop"));
+    }
+
+    #[test]
+    fn truncated_queries_are_rejected() {
+        let payload = query(b"\x03www\x07example\x03com\x00", 1);
+        assert_eq!(parse(&payload[..11]), Err("short_header"));
+        // Inside a label and before the terminating zero
+        assert_eq!(parse(&payload[..18]), Err("truncated_name"));
+        assert_eq!(parse(&payload[..28]), Err("truncated_name"));
+        // Name complete, QTYPE cut
+        assert_eq!(parse(&payload[..29]), Err("truncated_question"));
+        assert_eq!(parse(&payload[..30]), Err("truncated_question"));
+        // A pointer without its second byte
+        assert_eq!(parse(&query(b"\xc0", 1)[..13]), Err("truncated_name"));
+    }
+
+    #[test]
+    fn responses_and_empty_questions_are_not_queries() {
+        let mut payload = query(b"\x00", 1);
+        payload[2] |= 0x80;
+        assert_eq!(parse(&payload), Err("not_query"));
+        let mut payload = query(b"\x00", 1);
+        payload[5] = 0;
+        assert_eq!(parse(&payload), Err("no_question"));
+    }
+}
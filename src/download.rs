@@ -0,0 +1,160 @@
+use crate::auth::constant_time_eq;
+use crate::capture::ANY_DEVICE;
+use pcap::{Active, Capture, Linktype};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+
+// Read timeout of the download capture, bounds how late the deadline is noticed
+const DOWNLOAD_POLL_TIMEOUT_MS: i32 = 100;
+
+const DOWNLOAD_SNAPLEN: u32 = 65535;
+
+// Chunks buffered between the capture thread and the HTTP response
+const DOWNLOAD_QUEUE_CAPACITY: usize = 256;
+
+// Settings of GET /pcap, enabled with pcap_download = true
+#[derive(Debug)]
+pub struct PcapDownload {
+    pub interface: String,
+    pub max_duration: Duration,
+    // Required as "Authorization: Bearer <token>" when set
+    pub token: Option<String>,
+    pub promisc: bool,
+    busy: Arc<AtomicBool>,
+}
+
+// Held while a download runs; only one may run at a time
+#[derive(Debug)]
+pub struct DownloadGuard {
+    busy: Arc<AtomicBool>,
+}
+
+impl Drop for DownloadGuard {
+    fn drop(&mut self) {
+        self.busy.store(false, Ordering::Release);
+    }
+}
+
+impl PcapDownload {
+    pub fn new(
+        interface: String,
+        max_duration: Duration,
+        token: Option<String>,
+        promisc: bool,
+    ) -> Self {
+        Self {
+            interface,
+            max_duration,
+            token,
+            promisc,
+            busy: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn try_start(&self) -> Option<DownloadGuard> {
+        self.busy
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| DownloadGuard {
+                busy: self.busy.clone(),
+            })
+    }
+
+    pub fn authorized(&self, authorization: Option<&str>) -> bool {
+        match &self.token {
+            None => true,
+            Some(token) => authorization
+                .and_then(|value| value.strip_prefix("Bearer "))
+                .is_some_and(|given| constant_time_eq(given.as_bytes(), token.as_bytes())),
+        }
+    }
+}
+
+// A second, short-lived handle on the interface, independent of the metrics capture
+pub fn open_download_capture(
+    interface: &str,
+    filter: &str,
+    promisc: bool,
+) -> Result<Capture<Active>, pcap::Error> {
+    let mut cap = Capture::from_device(interface)?
+        .promisc(promisc && interface != ANY_DEVICE)
+        .snaplen(DOWNLOAD_SNAPLEN as i32)
+        .timeout(DOWNLOAD_POLL_TIMEOUT_MS)
+        .open()?;
+    if !filter.is_empty() {
+        cap.filter(filter, true)?;
+    }
+    Ok(cap)
+}
+
+// Classic pcap file header (microsecond timestamps, little endian)
+fn file_header(linktype: Linktype) -> Vec<u8> {
+    let mut header = Vec::with_capacity(24);
+    header.extend_from_slice(&0xa1b2_c3d4u32.to_le_bytes());
+    header.extend_from_slice(&2u16.to_le_bytes());
+    header.extend_from_slice(&4u16.to_le_bytes());
+    header.extend_from_slice(&0i32.to_le_bytes());
+    header.extend_from_slice(&0u32.to_le_bytes());
+    header.extend_from_slice(&DOWNLOAD_SNAPLEN.to_le_bytes());
+    header.extend_from_slice(&(linktype.0 as u32).to_le_bytes());
+    header
+}
+
+fn record(header: &pcap::PacketHeader, data: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(16 + data.len());
+    record.extend_from_slice(&(header.ts.tv_sec as u32).to_le_bytes());
+    record.extend_from_slice(&(header.ts.tv_usec as u32).to_le_bytes());
+    record.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    record.extend_from_slice(&header.len.to_le_bytes());
+    record.extend_from_slice(data);
+    record
+}
+
+// Stream a pcap file of everything `cap` sees for `duration` into `chunks`.
+// Stops early if the client goes away; `guard` is released when it returns.
+pub fn stream_capture(
+    mut cap: Capture<Active>,
+    duration: Duration,
+    chunks: mpsc::Sender<Vec<u8>>,
+    guard: DownloadGuard,
+) {
+    let _guard = guard;
+    let deadline = Instant::now() + duration;
+    if chunks
+        .blocking_send(file_header(cap.get_datalink()))
+        .is_err()
+    {
+        return;
+    }
+    let mut packets: u64 = 0;
+    while Instant::now() < deadline {
+        match cap.next_packet() {
+            Ok(packet) => {
+                if chunks
+                    .blocking_send(record(packet.header, packet.data))
+                    .is_err()
+                {
+                    info!(
+                        "pcap download cancelled by the client after {} packets",
+                        packets
+                    );
+                    return;
+                }
+                packets += 1;
+            }
+            Err(pcap::Error::TimeoutExpired) => {}
+            Err(e) => {
+                warn!("pcap download ended early: {}", e);
+                break;
+            }
+        }
+    }
+    info!("pcap download finished ({} packets)", packets);
+}
+
+pub fn download_channel() -> (mpsc::Sender<Vec<u8>>, mpsc::Receiver<Vec<u8>>) {
+    mpsc::channel(DOWNLOAD_QUEUE_CAPACITY)
+}
@@ -0,0 +1,264 @@
+use pcap::{Capture, Linktype, Packet, PacketHeader, Savefile};
+use prometheus::IntCounter;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{error, info, warn};
+
+// Frames a capture thread may queue ahead of its writer
+const DUMP_QUEUE_CAPACITY: usize = 4096;
+
+// How often an idle writer checks for stop requests and due rotations
+const DUMP_IDLE_CHECK: Duration = Duration::from_secs(1);
+
+// Pause between attempts to open a dump file after a failure
+const DUMP_OPEN_RETRY: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone)]
+pub struct DumpSettings {
+    pub dir: PathBuf,
+    pub max_file_bytes: u64,
+    pub rotate_interval: Duration,
+    // Oldest files per interface are deleted beyond this; 0 keeps everything
+    pub max_files: usize,
+}
+
+// Runtime switch shared by the capture threads and the HTTP server
+#[derive(Debug)]
+pub struct DumpControl {
+    pub settings: DumpSettings,
+    active: AtomicBool,
+    // Frames lost because a writer fell behind or could not open its file
+    dropped: IntCounter,
+}
+
+impl DumpControl {
+    pub fn new(settings: DumpSettings, active: bool, dropped: IntCounter) -> Self {
+        Self {
+            settings,
+            active: AtomicBool::new(active),
+            dropped,
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    pub fn set_active(&self, active: bool) {
+        if self.active.swap(active, Ordering::Relaxed) != active {
+            info!(
+                "Packet dump {} ({})",
+                if active { "started" } else { "stopped" },
+                self.settings.dir.display()
+            );
+        }
+    }
+}
+
+struct DumpFrame {
+    ts: Duration,
+    len: u32,
+    data: Vec<u8>,
+}
+
+// Capture side of one interface's dump. Frames are copied into a bounded queue and
+// written by a dedicated thread, so file I/O and rotation never stall the capture.
+// Dropping the writer closes the current file.
+pub struct DumpWriter {
+    control: Arc<DumpControl>,
+    frames: SyncSender<DumpFrame>,
+}
+
+impl DumpWriter {
+    pub fn spawn(
+        control: Arc<DumpControl>,
+        interface_name: &str,
+        linktype: Linktype,
+    ) -> std::io::Result<Self> {
+        let (frames, frames_rx) = mpsc::sync_channel(DUMP_QUEUE_CAPACITY);
+        let mut files = DumpFiles::new(control.clone(), interface_name, linktype);
+        std::thread::Builder::new()
+            .name(format!("dump-{}", interface_name))
+            .spawn(move || files.run(frames_rx))?;
+        Ok(Self { control, frames })
+    }
+
+    pub fn write(&self, header: &PacketHeader, data: &[u8]) {
+        if !self.control.is_active() {
+            return;
+        }
+        let frame = DumpFrame {
+            ts: Duration::new(header.ts.tv_sec as u64, header.ts.tv_usec as u32 * 1000),
+            len: header.len,
+            data: data.to_vec(),
+        };
+        match self.frames.try_send(frame) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) | Err(TrySendError::Disconnected(_)) => {
+                self.control.dropped.inc()
+            }
+        }
+    }
+}
+
+struct OpenDump {
+    savefile: Savefile,
+    opened: Instant,
+    bytes: u64,
+}
+
+// Writer thread state: the open file of one interface and its rotation
+struct DumpFiles {
+    control: Arc<DumpControl>,
+    prefix: String,
+    linktype: Linktype,
+    current: Option<OpenDump>,
+    retry_at: Option<Instant>,
+}
+
+impl DumpFiles {
+    fn new(control: Arc<DumpControl>, interface_name: &str, linktype: Linktype) -> Self {
+        Self {
+            control,
+            prefix: format!("{}-", interface_name.replace('/', "_")),
+            linktype,
+            current: None,
+            retry_at: None,
+        }
+    }
+
+    fn run(&mut self, frames: mpsc::Receiver<DumpFrame>) {
+        loop {
+            match frames.recv_timeout(DUMP_IDLE_CHECK) {
+                Ok(frame) => {
+                    if self.control.is_active() {
+                        self.write(frame);
+                    } else {
+                        self.close();
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    if !self.control.is_active() || self.rotation_due() {
+                        self.close();
+                    }
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    self.close();
+                    return;
+                }
+            }
+        }
+    }
+
+    fn rotation_due(&self) -> bool {
+        let settings = &self.control.settings;
+        self.current.as_ref().is_some_and(|dump| {
+            dump.bytes >= settings.max_file_bytes
+                || dump.opened.elapsed() >= settings.rotate_interval
+        })
+    }
+
+    fn write(&mut self, frame: DumpFrame) {
+        if self.rotation_due() {
+            self.close();
+        }
+        if self.current.is_none() && !self.open() {
+            self.control.dropped.inc();
+            return;
+        }
+        let Some(dump) = self.current.as_mut() else {
+            return;
+        };
+        let header = PacketHeader {
+            ts: libc::timeval {
+                tv_sec: frame.ts.as_secs() as libc::time_t,
+                tv_usec: frame.ts.subsec_micros() as libc::suseconds_t,
+            },
+            caplen: frame.data.len() as u32,
+            len: frame.len,
+        };
+        dump.savefile.write(&Packet::new(&header, &frame.data));
+        // pcap record header plus the frame
+        dump.bytes += 16 + frame.data.len() as u64;
+    }
+
+    fn open(&mut self) -> bool {
+        if self.retry_at.is_some_and(|at| Instant::now() < at) {
+            return false;
+        }
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or(0);
+        let path = self
+            .control
+            .settings
+            .dir
+            .join(format!("{}{}.pcap", self.prefix, millis));
+        let savefile = match Capture::dead(self.linktype).and_then(|cap| cap.savefile(&path)) {
+            Ok(savefile) => savefile,
+            Err(e) => {
+                error!("Failed to open dump file {}: {}", path.display(), e);
+                self.retry_at = Some(Instant::now() + DUMP_OPEN_RETRY);
+                return false;
+            }
+        };
+        self.retry_at = None;
+        info!("Dumping packets to {}", path.display());
+        self.current = Some(OpenDump {
+            savefile,
+            opened: Instant::now(),
+            // pcap file header
+            bytes: 24,
+        });
+        self.prune();
+        true
+    }
+
+    fn close(&mut self) {
+        if let Some(mut dump) = self.current.take() {
+            if let Err(e) = dump.savefile.flush() {
+                error!("Failed to flush dump file: {}", e);
+            }
+        }
+    }
+
+    // Delete the oldest files of this interface beyond max_files
+    fn prune(&self) {
+        let max_files = self.control.settings.max_files;
+        if max_files == 0 {
+            return;
+        }
+        let dir = &self.control.settings.dir;
+        let entries = match std::fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("Failed to list {}: {}", dir.display(), e);
+                return;
+            }
+        };
+        let mut files: Vec<PathBuf> = entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| self.is_own_file(path))
+            .collect();
+        // Names differ only in the fixed-width timestamp, so they sort by age
+        files.sort();
+        let excess = files.len().saturating_sub(max_files);
+        for path in &files[..excess] {
+            if let Err(e) = std::fs::remove_file(path) {
+                warn!("Failed to remove old dump {}: {}", path.display(), e);
+            }
+        }
+    }
+
+    fn is_own_file(&self, path: &Path) -> bool {
+        path.file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_prefix(self.prefix.as_str()))
+            .and_then(|rest| rest.strip_suffix(".pcap"))
+            .is_some_and(|stamp| !stamp.is_empty() && stamp.bytes().all(|b| b.is_ascii_digit()))
+    }
+}
@@ -0,0 +1,116 @@
+use crate::packet::PacketInfo;
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+
+// Events buffered per subscriber; a client further behind skips the oldest ones
+const PACKET_FEED_CAPACITY: usize = 1024;
+
+// One accounted packet as sent on /ws/packets
+#[derive(Debug, Clone, Serialize)]
+pub struct PacketEvent {
+    // Unix time in seconds when the capture parsed it
+    pub timestamp: f64,
+    pub src: IpAddr,
+    pub dst: IpAddr,
+    pub proto: &'static str,
+    // Bytes as counted by count_mode
+    pub length: u64,
+    pub nic: Arc<str>,
+    // tx / rx from the local IP's view, internal between two local IPs
+    pub direction: &'static str,
+}
+
+impl PacketEvent {
+    pub fn new(packet: &PacketInfo, length: u64, nic: Arc<str>, direction: &'static str) -> Self {
+        Self {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0.0, |d| d.as_secs_f64()),
+            src: packet.src_ip,
+            dst: packet.dst_ip,
+            proto: packet.proto,
+            length,
+            nic,
+            direction,
+        }
+    }
+}
+
+// Query parameters of /ws/packets, all of them must match
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct PacketFilter {
+    // Source or destination
+    pub ip: Option<IpAddr>,
+    pub nic: Option<String>,
+    pub proto: Option<String>,
+}
+
+impl PacketFilter {
+    pub fn matches(&self, event: &PacketEvent) -> bool {
+        self.ip.is_none_or(|ip| event.src == ip || event.dst == ip)
+            && self.nic.as_deref().is_none_or(|nic| *event.nic == *nic)
+            && self
+                .proto
+                .as_deref()
+                .is_none_or(|proto| event.proto.eq_ignore_ascii_case(proto))
+    }
+}
+
+// Broadcast of accounted packets to the /ws/packets clients. The capture threads
+// check `active` on every packet and build no event while nobody listens.
+pub struct PacketFeed {
+    sender: broadcast::Sender<Arc<PacketEvent>>,
+    subscribers: AtomicUsize,
+    max_clients: usize,
+    // Events sent per client and second, 0 = no limit
+    pub max_rate: u32,
+}
+
+impl PacketFeed {
+    pub fn new(max_clients: usize, max_rate: u32) -> Self {
+        let (sender, _) = broadcast::channel(PACKET_FEED_CAPACITY);
+        Self {
+            sender,
+            subscribers: AtomicUsize::new(0),
+            max_clients,
+            max_rate,
+        }
+    }
+
+    pub fn active(&self) -> bool {
+        self.subscribers.load(Ordering::Relaxed) > 0
+    }
+
+    pub fn publish(&self, event: PacketEvent) {
+        let _ = self.sender.send(Arc::new(event));
+    }
+
+    // None once max_clients are connected
+    pub fn subscribe(self: &Arc<Self>) -> Option<PacketSubscription> {
+        self.subscribers
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |count| {
+                (count < self.max_clients).then_some(count + 1)
+            })
+            .ok()?;
+        Some(PacketSubscription {
+            events: self.sender.subscribe(),
+            feed: self.clone(),
+        })
+    }
+}
+
+// A connected client, counted until it is dropped
+pub struct PacketSubscription {
+    pub events: broadcast::Receiver<Arc<PacketEvent>>,
+    feed: Arc<PacketFeed>,
+}
+
+impl Drop for PacketSubscription {
+    fn drop(&mut self) {
+        self.feed.subscribers.fetch_sub(1, Ordering::Relaxed);
+    }
+}
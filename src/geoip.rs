@@ -0,0 +1,182 @@
+use ipnet::IpNet;
+use lru::LruCache;
+use maxminddb::{geoip2, Reader};
+use std::net::IpAddr;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+use tracing::{error, info};
+
+// country and as_org label of addresses the database has no entry for
+pub const UNKNOWN_LABEL: &str = "unknown";
+
+// AS number of addresses the ASN database has no entry for (AS0 is reserved)
+pub const UNKNOWN_ASN: u32 = 0;
+
+// Remote networks whose result is remembered, per database
+const GEOIP_CACHE_ENTRIES: usize = 4096;
+
+// How often the database files are checked for changes; GeoLite2 is updated weekly
+const GEOIP_CHECK_INTERVAL: Duration = Duration::from_secs(300);
+
+// AS number and organization of a remote address
+pub type AsnInfo = (u32, Arc<str>);
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .ok()
+}
+
+fn open_reader(path: &Path) -> Result<Reader<Vec<u8>>, String> {
+    Reader::open_readfile(path).map_err(|e| format!("failed to open {}: {}", path.display(), e))
+}
+
+// Addresses in one /24 (IPv4) or /48 (IPv6) share a cache entry
+fn cache_key(ip: IpAddr) -> IpNet {
+    let prefix = match ip {
+        IpAddr::V4(_) => 24,
+        IpAddr::V6(_) => 48,
+    };
+    IpNet::new(ip, prefix).map_or(IpNet::from(ip), |net| net.trunc())
+}
+
+fn decode_country(reader: &Reader<Vec<u8>>, ip: IpAddr) -> Arc<str> {
+    reader
+        .lookup(ip)
+        .and_then(|result| result.decode::<geoip2::Country>())
+        .ok()
+        .flatten()
+        .and_then(|record| record.country.iso_code.map(Arc::from))
+        .unwrap_or_else(|| Arc::from(UNKNOWN_LABEL))
+}
+
+fn decode_asn(reader: &Reader<Vec<u8>>, ip: IpAddr) -> AsnInfo {
+    let record = reader
+        .lookup(ip)
+        .and_then(|result| result.decode::<geoip2::Asn>())
+        .ok()
+        .flatten();
+    let number = record
+        .as_ref()
+        .and_then(|record| record.autonomous_system_number)
+        .unwrap_or(UNKNOWN_ASN);
+    let org = record
+        .and_then(|record| record.autonomous_system_organization)
+        .unwrap_or(UNKNOWN_LABEL);
+    (number, Arc::from(org))
+}
+
+// One mmdb file with an LRU cache of decoded results in front of it. The
+// capture thread asks for every packet, so only a cache miss reads the database.
+pub struct Database<V> {
+    path: PathBuf,
+    reader: RwLock<Arc<Reader<Vec<u8>>>>,
+    // Modification time of the file at the last load
+    modified: Mutex<Option<SystemTime>>,
+    cache: Mutex<LruCache<IpNet, V>>,
+    decode: fn(&Reader<Vec<u8>>, IpAddr) -> V,
+}
+
+impl<V: Clone> Database<V> {
+    fn open(path: &Path, decode: fn(&Reader<Vec<u8>>, IpAddr) -> V) -> Result<Self, String> {
+        let modified = modified(path);
+        let reader = open_reader(path)?;
+        info!(
+            "GeoIP database {} ({}) loaded",
+            path.display(),
+            reader.metadata().database_type
+        );
+        Ok(Self {
+            path: path.to_path_buf(),
+            reader: RwLock::new(Arc::new(reader)),
+            modified: Mutex::new(modified),
+            cache: Mutex::new(LruCache::new(
+                NonZeroUsize::new(GEOIP_CACHE_ENTRIES).unwrap(),
+            )),
+            decode,
+        })
+    }
+
+    fn get(&self, ip: IpAddr) -> V {
+        let key = cache_key(ip);
+        if let Some(value) = self.cache.lock().unwrap().get(&key) {
+            return value.clone();
+        }
+        let reader = self.reader.read().unwrap().clone();
+        let value = (self.decode)(&reader, ip);
+        self.cache.lock().unwrap().put(key, value.clone());
+        value
+    }
+
+    // Reload when the file's modification time changed. A file that cannot be
+    // read (e.g. half written by the updater) keeps the previous database in use.
+    fn reload_if_changed(&self) {
+        let current = modified(&self.path);
+        {
+            let mut last = self.modified.lock().unwrap();
+            if *last == current {
+                return;
+            }
+            *last = current;
+        }
+        match open_reader(&self.path) {
+            Ok(reader) => {
+                *self.reader.write().unwrap() = Arc::new(reader);
+                self.cache.lock().unwrap().clear();
+                info!("Reloaded GeoIP database {}", self.path.display());
+            }
+            Err(e) => error!(
+                "Failed to reload the GeoIP database, keeping the previous one: {}",
+                e
+            ),
+        }
+    }
+}
+
+pub fn open_country(path: &Path) -> Result<Database<Arc<str>>, String> {
+    Database::open(path, decode_country)
+}
+
+pub fn open_asn(path: &Path) -> Result<Database<AsnInfo>, String> {
+    Database::open(path, decode_asn)
+}
+
+// Classifies the remote side of accounted packets with a MaxMind GeoLite2
+// Country (or City) and/or ASN database
+pub struct GeoIp {
+    pub country: Option<Database<Arc<str>>>,
+    pub asn: Option<Database<AsnInfo>>,
+}
+
+impl GeoIp {
+    // ISO 3166-1 alpha-2 code or UNKNOWN_LABEL, None without a country database
+    pub fn country(&self, ip: IpAddr) -> Option<Arc<str>> {
+        self.country.as_ref().map(|database| database.get(ip))
+    }
+
+    // None without an ASN database
+    pub fn asn(&self, ip: IpAddr) -> Option<AsnInfo> {
+        self.asn.as_ref().map(|database| database.get(ip))
+    }
+
+    fn reload_if_changed(&self) {
+        if let Some(database) = &self.country {
+            database.reload_if_changed();
+        }
+        if let Some(database) = &self.asn {
+            database.reload_if_changed();
+        }
+    }
+}
+
+pub async fn watch_databases(geoip: Arc<GeoIp>) {
+    let mut interval = tokio::time::interval(GEOIP_CHECK_INTERVAL);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let geoip = geoip.clone();
+        let _ = crate::runtime::spawn_blocking(move || geoip.reload_if_changed()).await;
+    }
+}
@@ -0,0 +1,191 @@
+use crate::metrics::{Flush, FlushSink, Rate};
+use prometheus::IntCounter;
+use std::collections::VecDeque;
+use std::time::{Duration, UNIX_EPOCH};
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::time::{timeout, Instant};
+use tracing::{info, warn};
+
+// Wait between connection attempts, doubled after every failure
+const GRAPHITE_RETRY_INITIAL: Duration = Duration::from_secs(1);
+const GRAPHITE_RETRY_MAX: Duration = Duration::from_secs(60);
+
+// Flushes queued for the writer; it only falls behind while connecting or writing
+const GRAPHITE_QUEUE: usize = 8;
+
+// Path component of the per-NIC totals in place of the IP
+const TOTAL_IP_COMPONENT: &str = "total";
+
+#[derive(Debug, Clone)]
+pub struct GraphiteSettings {
+    // Carbon plaintext listener, host:port, resolved again on every reconnect
+    pub address: String,
+    pub prefix: String,
+    // Lines kept while disconnected, the oldest are dropped beyond this
+    pub buffer_lines: usize,
+    pub timeout: Duration,
+}
+
+// Writes every flush as carbon plaintext lines over one TCP connection, buffering
+// them while the connection is down
+pub struct GraphiteSink {
+    prefix: String,
+    batches: mpsc::Sender<Vec<String>>,
+    dropped: IntCounter,
+}
+
+impl GraphiteSink {
+    pub fn spawn(settings: GraphiteSettings, dropped: IntCounter) -> Self {
+        let (batches, batches_rx) = mpsc::channel(GRAPHITE_QUEUE);
+        info!(
+            "Writing metrics to Graphite {} (prefix {})",
+            settings.address, settings.prefix
+        );
+        let prefix = settings.prefix.clone();
+        tokio::spawn(write_lines(settings, batches_rx, dropped.clone()));
+        Self {
+            prefix,
+            batches,
+            dropped,
+        }
+    }
+}
+
+// Dots separate path components, so they become dashes like the colons of IPv6
+// addresses; whitespace would end the path
+fn path_component(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| match c {
+            '.' | ':' => '-',
+            c if c.is_whitespace() => '_',
+            c => c,
+        })
+        .collect()
+}
+
+// <prefix>.<nic>.<ip>.{tx,rx}_{bps,pps} <value> <ts> per IP, <ip> = total per NIC
+fn encode_flush(prefix: &str, flush: &Flush<'_>) -> Vec<String> {
+    let snapshot = flush.snapshot;
+    let ts = snapshot
+        .timestamp
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let mut lines = Vec::new();
+    let mut push = |nic: &str, ip: &str, tx: Option<Rate>, rx: Option<Rate>| {
+        let (tx, rx) = (tx.unwrap_or_default(), rx.unwrap_or_default());
+        let path = format!("{}.{}.{}", prefix, path_component(nic), path_component(ip));
+        for (metric, value) in [
+            ("tx_bps", tx.bps),
+            ("rx_bps", rx.bps),
+            ("tx_pps", tx.pps),
+            ("rx_pps", rx.pps),
+        ] {
+            lines.push(format!("{}.{} {} {}\n", path, metric, value, ts));
+        }
+    };
+    for rate in &snapshot.per_ip {
+        push(&rate.key.nic, &rate.key.ip_label(), rate.tx, rate.rx);
+    }
+    for rate in &snapshot.per_nic {
+        if rate.tx.is_none() && rate.rx.is_none() {
+            continue;
+        }
+        push(&rate.nic, TOTAL_IP_COMPONENT, rate.tx, rate.rx);
+    }
+    lines
+}
+
+impl FlushSink for GraphiteSink {
+    fn publish(&mut self, flush: &Flush<'_>) {
+        let lines = encode_flush(&self.prefix, flush);
+        if lines.is_empty() {
+            return;
+        }
+        match self.batches.try_send(lines) {
+            Ok(()) => {}
+            Err(TrySendError::Full(lines) | TrySendError::Closed(lines)) => {
+                self.dropped.inc_by(lines.len() as u64);
+            }
+        }
+    }
+}
+
+async fn connect(settings: &GraphiteSettings) -> Result<TcpStream, String> {
+    match timeout(settings.timeout, TcpStream::connect(&settings.address)).await {
+        Ok(Ok(stream)) => Ok(stream),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Err("connect timed out".into()),
+    }
+}
+
+async fn write_lines(
+    settings: GraphiteSettings,
+    mut batches: mpsc::Receiver<Vec<String>>,
+    dropped: IntCounter,
+) {
+    let mut buffer: VecDeque<String> = VecDeque::new();
+    let mut stream: Option<TcpStream> = None;
+    let mut backoff = GRAPHITE_RETRY_INITIAL;
+    let mut retry_at = Instant::now();
+
+    while let Some(batch) = batches.recv().await {
+        buffer.extend(batch);
+        let overflow = buffer.len().saturating_sub(settings.buffer_lines);
+        if overflow > 0 {
+            buffer.drain(..overflow);
+            dropped.inc_by(overflow as u64);
+        }
+
+        if stream.is_none() {
+            if Instant::now() < retry_at {
+                continue;
+            }
+            match connect(&settings).await {
+                Ok(connected) => {
+                    info!("Connected to Graphite {}", settings.address);
+                    stream = Some(connected);
+                    backoff = GRAPHITE_RETRY_INITIAL;
+                }
+                Err(e) => {
+                    warn!(
+                        "Failed to connect to Graphite {}: {}, retrying in {:?} ({} lines buffered)",
+                        settings.address,
+                        e,
+                        backoff,
+                        buffer.len()
+                    );
+                    retry_at = Instant::now() + backoff;
+                    backoff = (backoff * 2).min(GRAPHITE_RETRY_MAX);
+                    continue;
+                }
+            }
+        }
+
+        let Some(connected) = stream.as_mut() else {
+            continue;
+        };
+        let payload: String = buffer.iter().map(String::as_str).collect();
+        let result = match timeout(settings.timeout, connected.write_all(payload.as_bytes())).await
+        {
+            Ok(result) => result.map_err(|e| e.to_string()),
+            Err(_) => Err("write timed out".into()),
+        };
+        match result {
+            Ok(()) => buffer.clear(),
+            // Lines written before the error are sent again after reconnecting;
+            // carbon keeps the last value per timestamp, so that is harmless
+            Err(e) => {
+                warn!(
+                    "Graphite connection to {} lost: {}, reconnecting",
+                    settings.address, e
+                );
+                stream = None;
+                retry_at = Instant::now();
+            }
+        }
+    }
+}
@@ -0,0 +1,126 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Heartbeat of one capture task. Timestamps are milliseconds since process start,
+// 0 means "never".
+#[derive(Debug)]
+pub struct CaptureHealth {
+    pub started: std::time::Instant,
+    pub last_poll_ms: AtomicU64,
+    pub finished: AtomicBool,
+}
+
+impl CaptureHealth {
+    // Called after every next_packet(), including timeouts
+    pub fn poll(&self) {
+        let ms = self.started.elapsed().as_millis() as u64;
+        self.last_poll_ms.store(ms.max(1), Ordering::Relaxed);
+    }
+}
+
+// Liveness of the background components, reported by /healthz
+#[derive(Debug)]
+pub struct HealthState {
+    pub started: std::time::Instant,
+    pub timeout: Duration,
+    pub captures: Mutex<Vec<(String, Arc<CaptureHealth>)>>,
+    pub mapping_fetch_ok: AtomicBool,
+    // Unix seconds of the last successful mapping fetch, 0 means "never"
+    pub mapping_last_ok_unix: AtomicU64,
+    pub updater_last_flush_ms: AtomicU64,
+    // Readiness (/ready): set once and never cleared, liveness is /healthz's job
+    pub capture_opened: AtomicBool,
+    // A mapping fetch succeeded, or the fallback mappings were accepted at startup
+    pub mappings_ready: AtomicBool,
+}
+
+impl HealthState {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            started: std::time::Instant::now(),
+            timeout,
+            captures: Mutex::new(Vec::new()),
+            mapping_fetch_ok: AtomicBool::new(false),
+            mapping_last_ok_unix: AtomicU64::new(0),
+            updater_last_flush_ms: AtomicU64::new(0),
+            capture_opened: AtomicBool::new(false),
+            mappings_ready: AtomicBool::new(false),
+        }
+    }
+
+    pub fn now_ms(&self) -> u64 {
+        self.started.elapsed().as_millis() as u64
+    }
+
+    pub fn register_capture(&self, name: &str) -> Arc<CaptureHealth> {
+        let capture = Arc::new(CaptureHealth {
+            started: self.started,
+            last_poll_ms: AtomicU64::new(0),
+            finished: AtomicBool::new(false),
+        });
+        self.captures
+            .lock()
+            .unwrap()
+            .push((name.to_string(), capture.clone()));
+        capture
+    }
+
+    // A capture that moved to another interface is no longer reported
+    pub fn remove_capture(&self, name: &str) {
+        self.captures
+            .lock()
+            .unwrap()
+            .retain(|(capture, _)| capture != name);
+    }
+
+    pub fn record_mapping_fetch(&self, ok: bool) {
+        self.mapping_fetch_ok.store(ok, Ordering::Relaxed);
+        if ok {
+            self.mappings_ready.store(true, Ordering::Relaxed);
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0);
+            self.mapping_last_ok_unix.store(now, Ordering::Relaxed);
+        }
+    }
+
+    pub fn mark_capture_opened(&self) {
+        self.capture_opened.store(true, Ordering::Relaxed);
+    }
+
+    pub fn accept_fallback_mappings(&self) {
+        self.mappings_ready.store(true, Ordering::Relaxed);
+    }
+
+    // (capture, mappings) readiness
+    pub fn readiness(&self) -> (bool, bool) {
+        (
+            self.capture_opened.load(Ordering::Relaxed),
+            self.mappings_ready.load(Ordering::Relaxed),
+        )
+    }
+
+    pub fn record_flush(&self) {
+        self.updater_last_flush_ms
+            .store(self.now_ms().max(1), Ordering::Relaxed);
+    }
+
+    // Seconds since `ms`, or None if the heartbeat never happened or is too old
+    pub fn fresh(&self, ms: u64) -> (bool, Option<f64>) {
+        if ms == 0 {
+            return (false, None);
+        }
+        let ago = self.now_ms().saturating_sub(ms) as f64 / 1000.0;
+        (ago <= self.timeout.as_secs_f64(), Some(ago))
+    }
+}
+
+pub fn component_status(ok: bool) -> &'static str {
+    if ok {
+        "ok"
+    } else {
+        "degraded"
+    }
+}
@@ -0,0 +1,114 @@
+use crate::metrics::{IntervalSnapshot, Rate};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// The last `capacity` interval snapshots for GET /history. Each snapshot holds at
+// most max_tracked_ips (+ overflow) per-IP entries, which bounds the memory.
+#[derive(Debug)]
+pub struct IntervalHistory {
+    capacity: usize,
+    snapshots: Mutex<VecDeque<Arc<IntervalSnapshot>>>,
+}
+
+// Parallel arrays, one element per retained interval, oldest first
+#[derive(Debug, Default, Serialize)]
+pub struct HistorySeries {
+    pub timestamps: Vec<f64>,
+    pub tx_bps: Vec<f64>,
+    pub rx_bps: Vec<f64>,
+    pub tx_pps: Vec<f64>,
+    pub rx_pps: Vec<f64>,
+}
+
+impl HistorySeries {
+    fn push(&mut self, snapshot: &IntervalSnapshot, tx: Rate, rx: Rate) {
+        self.timestamps.push(unix_secs(snapshot.timestamp));
+        self.tx_bps.push(tx.bps);
+        self.rx_bps.push(rx.bps);
+        self.tx_pps.push(tx.pps);
+        self.rx_pps.push(rx.pps);
+    }
+}
+
+fn unix_secs(time: SystemTime) -> f64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs_f64())
+        .unwrap_or(0.0)
+}
+
+fn add(total: &mut Rate, rate: Option<Rate>) {
+    if let Some(rate) = rate {
+        total.bps += rate.bps;
+        total.pps += rate.pps;
+        total.bytes += rate.bytes;
+    }
+}
+
+impl IntervalHistory {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            snapshots: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    pub fn push(&self, snapshot: Arc<IntervalSnapshot>) {
+        let mut snapshots = self.snapshots.lock().unwrap();
+        while snapshots.len() >= self.capacity.max(1) {
+            snapshots.pop_front();
+        }
+        snapshots.push_back(snapshot);
+    }
+
+    // Snapshots that ended within the last `window`, or all of them
+    fn recent(&self, window: Option<Duration>) -> Vec<Arc<IntervalSnapshot>> {
+        let since = window.and_then(|window| SystemTime::now().checked_sub(window));
+        self.snapshots
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|snapshot| since.is_none_or(|since| snapshot.timestamp >= since))
+            .cloned()
+            .collect()
+    }
+
+    // Rates of one local IP, summed over the NICs it was attributed to. Intervals
+    // without its traffic are zero so the arrays line up with /history/totals.
+    pub fn ip(&self, ip: IpAddr, window: Option<Duration>) -> HistorySeries {
+        let mut series = HistorySeries::default();
+        for snapshot in self.recent(window) {
+            let (mut tx, mut rx) = (Rate::default(), Rate::default());
+            for rate in snapshot
+                .per_ip
+                .iter()
+                .filter(|rate| rate.key.ip == Some(ip))
+            {
+                add(&mut tx, rate.tx);
+                add(&mut rx, rate.rx);
+            }
+            series.push(&snapshot, tx, rx);
+        }
+        series
+    }
+
+    // NIC totals, summed over every NIC when `nic` is None
+    pub fn totals(&self, nic: Option<&str>, window: Option<Duration>) -> HistorySeries {
+        let mut series = HistorySeries::default();
+        for snapshot in self.recent(window) {
+            let (mut tx, mut rx) = (Rate::default(), Rate::default());
+            for rate in snapshot
+                .per_nic
+                .iter()
+                .filter(|rate| nic.is_none_or(|nic| *rate.nic == *nic))
+            {
+                add(&mut tx, rate.tx);
+                add(&mut rx, rate.rx);
+            }
+            series.push(&snapshot, tx, rx);
+        }
+        series
+    }
+}
@@ -0,0 +1,155 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, Semaphore};
+use tracing::debug;
+
+// hostname label until the PTR lookup of an IP succeeds
+pub const UNKNOWN_HOSTNAME: &str = "unknown";
+
+// Pending lookups beyond this are retried on a later interval
+const LOOKUP_QUEUE_CAPACITY: usize = 1024;
+
+#[derive(Debug)]
+struct Entry {
+    // None until resolved, or when the last lookup failed
+    name: Option<Arc<str>>,
+    expires: Instant,
+    last_seen: Instant,
+    pending: bool,
+}
+
+#[derive(Debug)]
+struct Inner {
+    entries: HashMap<IpAddr, Entry>,
+    // IPs whose hostname label changed since the last take_changed()
+    changed: Vec<IpAddr>,
+}
+
+// IP -> hostname cache filled by resolve_hostnames(). Readers never wait for DNS:
+// unresolved IPs read as UNKNOWN_HOSTNAME and are queued for a lookup.
+#[derive(Debug)]
+pub struct HostnameCache {
+    inner: Mutex<Inner>,
+    lookups: mpsc::Sender<IpAddr>,
+    ttl: Duration,
+    negative_ttl: Duration,
+    idle_timeout: Duration,
+}
+
+impl HostnameCache {
+    // Returns the cache and the receiver to hand to resolve_hostnames()
+    pub fn new(
+        ttl: Duration,
+        negative_ttl: Duration,
+        idle_timeout: Duration,
+    ) -> (Self, mpsc::Receiver<IpAddr>) {
+        let (lookups, lookups_rx) = mpsc::channel(LOOKUP_QUEUE_CAPACITY);
+        let cache = Self {
+            inner: Mutex::new(Inner {
+                entries: HashMap::new(),
+                changed: Vec::new(),
+            }),
+            lookups,
+            ttl,
+            negative_ttl,
+            idle_timeout,
+        };
+        (cache, lookups_rx)
+    }
+
+    // Mark IPs as active, queueing lookups for new and expired entries, and forget
+    // IPs that have not been seen for idle_timeout
+    pub fn observe(&self, ips: impl IntoIterator<Item = IpAddr>) {
+        let now = Instant::now();
+        let mut inner = self.inner.lock().unwrap();
+        for ip in ips {
+            let entry = inner.entries.entry(ip).or_insert_with(|| Entry {
+                name: None,
+                expires: now,
+                last_seen: now,
+                pending: false,
+            });
+            entry.last_seen = now;
+            if entry.pending || entry.expires > now {
+                continue;
+            }
+            // A full queue leaves the entry due, so it is retried next time
+            if self.lookups.try_send(ip).is_ok() {
+                entry.pending = true;
+            }
+        }
+        let idle_timeout = self.idle_timeout;
+        inner
+            .entries
+            .retain(|_, entry| now.duration_since(entry.last_seen) <= idle_timeout);
+    }
+
+    pub fn hostname(&self, ip: &IpAddr) -> Arc<str> {
+        self.inner
+            .lock()
+            .unwrap()
+            .entries
+            .get(ip)
+            .and_then(|entry| entry.name.clone())
+            .unwrap_or_else(|| Arc::from(UNKNOWN_HOSTNAME))
+    }
+
+    pub fn take_changed(&self) -> Vec<IpAddr> {
+        std::mem::take(&mut self.inner.lock().unwrap().changed)
+    }
+
+    // Failed lookups keep the previous name until the negative TTL runs out
+    fn store(&self, ip: IpAddr, name: Option<String>) {
+        let now = Instant::now();
+        let mut inner = self.inner.lock().unwrap();
+        let Inner { entries, changed } = &mut *inner;
+        let Some(entry) = entries.get_mut(&ip) else {
+            return;
+        };
+        entry.pending = false;
+        match name {
+            Some(name) => {
+                entry.expires = now + self.ttl;
+                if entry.name.as_deref() != Some(name.as_str()) {
+                    entry.name = Some(Arc::from(name));
+                    changed.push(ip);
+                }
+            }
+            None => {
+                if entry.name.is_some() && entry.expires + self.negative_ttl <= now {
+                    entry.name = None;
+                    changed.push(ip);
+                }
+                entry.expires = now + self.negative_ttl;
+            }
+        }
+    }
+}
+
+// Resolve queued IPs with the system resolver, at most `max_concurrent` at a time
+pub async fn resolve_hostnames(
+    cache: Arc<HostnameCache>,
+    mut lookups: mpsc::Receiver<IpAddr>,
+    max_concurrent: usize,
+) {
+    let limit = Arc::new(Semaphore::new(max_concurrent.max(1)));
+    while let Some(ip) = lookups.recv().await {
+        let Ok(permit) = limit.clone().acquire_owned().await else {
+            return;
+        };
+        let cache = cache.clone();
+        crate::runtime::spawn_blocking(move || {
+            let name = match dns_lookup::lookup_addr(&ip) {
+                Ok(name) => Some(name),
+                Err(e) => {
+                    debug!("No hostname for {}: {}", ip, e);
+                    None
+                }
+            };
+            cache.store(ip, name);
+            drop(permit);
+        });
+    }
+}
@@ -0,0 +1,145 @@
+use crate::metrics::{Flush, FlushSink};
+use prometheus::IntCounter;
+use std::fmt::Write;
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+use tokio::sync::mpsc;
+use tracing::{error, info, warn};
+
+#[derive(Debug, Clone)]
+pub struct InfluxSettings {
+    // Base URL of the InfluxDB v2 API, e.g. http://influx:8086
+    pub url: String,
+    pub org: String,
+    pub bucket: String,
+    pub token: Option<String>,
+    pub timeout: Duration,
+}
+
+// Writes every flush as one line protocol batch. Batches are posted by a background
+// task; a flush that arrives while the previous one is still in flight is skipped.
+pub struct InfluxSink {
+    batches: mpsc::Sender<String>,
+}
+
+impl InfluxSink {
+    pub fn spawn(settings: InfluxSettings, failures: IntCounter) -> reqwest::Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(settings.timeout)
+            .build()?;
+        let (batches, batches_rx) = mpsc::channel(1);
+        info!(
+            "Writing metrics to InfluxDB {} (bucket {})",
+            settings.url, settings.bucket
+        );
+        tokio::spawn(write_batches(
+            client,
+            Arc::new(settings),
+            batches_rx,
+            failures,
+        ));
+        Ok(Self { batches })
+    }
+}
+
+impl FlushSink for InfluxSink {
+    fn publish(&mut self, flush: &Flush<'_>) {
+        let batch = encode_flush(flush);
+        if !batch.is_empty() {
+            let _ = self.batches.try_send(batch);
+        }
+    }
+}
+
+// Tag values escape commas, spaces and equals signs
+fn escape_tag(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, ',' | ' ' | '=') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+// traffic,ip=..,nic=.. tx_bps=..,rx_bps=.. <ns> per IP and traffic_total,nic=.. per NIC
+fn encode_flush(flush: &Flush<'_>) -> String {
+    let snapshot = flush.snapshot;
+    let ts = snapshot
+        .timestamp
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+
+    let mut lines = String::new();
+    for rate in &snapshot.per_ip {
+        let _ = writeln!(
+            lines,
+            "traffic,ip={},nic={} tx_bps={},rx_bps={} {}",
+            escape_tag(&rate.key.ip_label()),
+            escape_tag(&rate.key.nic),
+            rate.tx_bps(),
+            rate.rx_bps(),
+            ts
+        );
+    }
+
+    for rate in &snapshot.per_nic {
+        if rate.tx.is_none() && rate.rx.is_none() {
+            continue;
+        }
+        let _ = writeln!(
+            lines,
+            "traffic_total,nic={} tx_bps={},rx_bps={} {}",
+            escape_tag(&rate.nic),
+            rate.tx.map_or(0.0, |tx| tx.bps),
+            rate.rx.map_or(0.0, |rx| rx.bps),
+            ts
+        );
+    }
+    lines
+}
+
+async fn post_batch(
+    client: &reqwest::Client,
+    settings: &InfluxSettings,
+    batch: &str,
+) -> reqwest::Result<reqwest::Response> {
+    let mut request = client
+        .post(format!(
+            "{}/api/v2/write",
+            settings.url.trim_end_matches('/')
+        ))
+        .query(&[
+            ("org", settings.org.as_str()),
+            ("bucket", settings.bucket.as_str()),
+            ("precision", "ns"),
+        ])
+        .header(reqwest::header::CONTENT_TYPE, "text/plain; charset=utf-8")
+        .body(batch.to_string());
+    if let Some(token) = &settings.token {
+        request = request.header(reqwest::header::AUTHORIZATION, format!("Token {}", token));
+    }
+    request.send().await
+}
+
+async fn write_batches(
+    client: reqwest::Client,
+    settings: Arc<InfluxSettings>,
+    mut batches: mpsc::Receiver<String>,
+    failures: IntCounter,
+) {
+    while let Some(batch) = batches.recv().await {
+        let mut result = post_batch(&client, &settings, &batch).await;
+        // One retry for server-side errors, anything else is reported right away
+        if matches!(&result, Ok(response) if response.status().is_server_error()) {
+            warn!("InfluxDB write returned a server error, retrying once");
+            result = post_batch(&client, &settings, &batch).await;
+        }
+        if let Err(e) = result.and_then(|response| response.error_for_status()) {
+            failures.inc();
+            error!("InfluxDB write to {} failed: {}", settings.url, e);
+        }
+    }
+}
@@ -0,0 +1,45 @@
+pub mod alerts;
+pub mod anydevice;
+pub mod arp;
+pub mod auth;
+pub mod capture;
+pub mod config;
+pub mod devices;
+pub mod dns;
+pub mod download;
+pub mod dump;
+pub mod feed;
+#[cfg(feature = "geoip")]
+pub mod geoip;
+pub mod graphite;
+pub mod health;
+pub mod history;
+pub mod hostnames;
+pub mod influx;
+pub mod loglimit;
+pub mod mapping;
+pub mod metrics;
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+pub mod neighbors;
+#[cfg(feature = "netflow")]
+pub mod netflow;
+pub mod otlp;
+pub mod packet;
+pub mod privileges;
+pub mod probe;
+#[cfg(feature = "quic-sni")]
+pub mod quic;
+pub mod quota;
+pub mod reload;
+pub mod runtime;
+pub mod server;
+pub mod sni;
+pub mod source;
+pub mod stats;
+pub mod statsd;
+pub mod subnets;
+pub mod tls;
+#[cfg(feature = "sqlite")]
+pub mod usage;
+pub mod utilization;
@@ -0,0 +1,138 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+#[derive(Debug)]
+struct Window {
+    started: Instant,
+    suppressed: u64,
+}
+
+// Logs the first occurrence of each kind and folds the repeats that follow into
+// one summary per interval. A kind that stays quiet for a whole interval is
+// forgotten, so its next occurrence is logged in full again.
+#[derive(Debug)]
+pub struct LogLimiter<K> {
+    interval: Duration,
+    windows: HashMap<K, Window>,
+}
+
+impl<K: Eq + Hash + Clone> LogLimiter<K> {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            windows: HashMap::new(),
+        }
+    }
+
+    // True if this occurrence should be logged, false if it was counted instead
+    pub fn record(&mut self, kind: K, now: Instant) -> bool {
+        match self.windows.get_mut(&kind) {
+            Some(window) => {
+                window.suppressed += 1;
+                false
+            }
+            None => {
+                self.windows.insert(
+                    kind,
+                    Window {
+                        started: now,
+                        suppressed: 0,
+                    },
+                );
+                true
+            }
+        }
+    }
+
+    // Kinds whose interval has passed with the number of occurrences suppressed
+    // in it, to be logged as a summary; their next interval starts now
+    pub fn due(&mut self, now: Instant) -> Vec<(K, u64)> {
+        if self.windows.is_empty() {
+            return Vec::new();
+        }
+        let mut due = Vec::new();
+        self.windows.retain(|kind, window| {
+            if now.duration_since(window.started) < self.interval {
+                return true;
+            }
+            if window.suppressed == 0 {
+                return false;
+            }
+            due.push((kind.clone(), std::mem::take(&mut window.suppressed)));
+            window.started = now;
+            true
+        });
+        due
+    }
+
+    // Every pending count, e.g. before the caller stops
+    pub fn drain(&mut self) -> Vec<(K, u64)> {
+        self.windows
+            .drain()
+            .filter(|(_, window)| window.suppressed > 0)
+            .map(|(kind, window)| (kind, window.suppressed))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INTERVAL: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn repeats_within_the_window_are_suppressed() {
+        let start = Instant::now();
+        let mut limiter = LogLimiter::new(INTERVAL);
+        assert!(limiter.record("timeout", start));
+        assert!(!limiter.record("timeout", start + Duration::from_secs(1)));
+        assert!(!limiter.record("timeout", start + Duration::from_secs(59)));
+        // Kinds are limited independently
+        assert!(limiter.record("io", start + Duration::from_secs(2)));
+        assert!(limiter.due(start + Duration::from_secs(59)).is_empty());
+    }
+
+    #[test]
+    fn summary_counts_the_suppressed_occurrences() {
+        let start = Instant::now();
+        let mut limiter = LogLimiter::new(INTERVAL);
+        limiter.record("timeout", start);
+        for i in 1..=5 {
+            limiter.record("timeout", start + Duration::from_secs(i));
+        }
+        limiter.record("io", start);
+
+        // io was only logged once, there is nothing to summarize for it
+        assert_eq!(limiter.due(start + INTERVAL), [("timeout", 5)]);
+        // The next window starts at the summary
+        let next = start + INTERVAL + Duration::from_secs(1);
+        assert!(!limiter.record("timeout", next));
+        assert!(limiter.due(next + Duration::from_secs(30)).is_empty());
+        assert_eq!(limiter.due(start + 2 * INTERVAL), [("timeout", 1)]);
+    }
+
+    #[test]
+    fn quiet_kinds_are_logged_in_full_again() {
+        let start = Instant::now();
+        let mut limiter = LogLimiter::new(INTERVAL);
+        limiter.record("timeout", start);
+        limiter.record("timeout", start + Duration::from_secs(1));
+        assert_eq!(limiter.due(start + INTERVAL), [("timeout", 1)]);
+        // A whole window without repeats forgets the kind
+        assert!(limiter.due(start + 2 * INTERVAL).is_empty());
+        assert!(limiter.record("timeout", start + 2 * INTERVAL));
+    }
+
+    #[test]
+    fn drain_returns_only_pending_counts() {
+        let start = Instant::now();
+        let mut limiter = LogLimiter::new(INTERVAL);
+        limiter.record("timeout", start);
+        limiter.record("timeout", start);
+        limiter.record("io", start);
+        assert_eq!(limiter.drain(), [("timeout", 1)]);
+        assert!(limiter.record("timeout", start));
+    }
+}
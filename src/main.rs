@@ -1,375 +1,1293 @@
-use axum::{routing::get, Router};
-use lazy_static::lazy_static;
-use pcap::{Capture, Device};
-use pnet::packet::ethernet::{EtherTypes, EthernetPacket};
-use pnet::packet::ipv4::Ipv4Packet;
-use pnet::packet::Packet;
-use prometheus::{Encoder, GaugeVec, Opts, Registry, TextEncoder};
-use reqwest;
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::net::Ipv4Addr;
-use std::sync::{Arc, Mutex};
+use clap::{Parser, Subcommand, ValueEnum};
+use localpacketdump::alerts::AlertSink;
+use localpacketdump::anydevice::AnyDevice;
+use localpacketdump::arp::ArpWatch;
+use localpacketdump::auth::HttpAuth;
+use localpacketdump::capture::{
+    capture_lan, capture_packets, describe_devices, is_permission_denied, open_capture,
+    parse_sample_rate, replay_file, resolve_device, validate_bpf_filter, CaptureContext,
+    CaptureFailed, CaptureSettings, InterfaceMatch, PermissionDenied, ANY_DEVICE, MIN_SNAPLEN,
+};
+use localpacketdump::config::{read_config, Config};
+use localpacketdump::devices::{persist_devices, DeviceSink, DeviceTable};
+use localpacketdump::dns::{DnsQueries, DnsSink};
+use localpacketdump::download::PcapDownload;
+use localpacketdump::dump::{DumpControl, DumpSettings};
+use localpacketdump::feed::PacketFeed;
+use localpacketdump::graphite::{GraphiteSettings, GraphiteSink};
+use localpacketdump::health::HealthState;
+use localpacketdump::history::IntervalHistory;
+use localpacketdump::hostnames::{resolve_hostnames, HostnameCache};
+use localpacketdump::influx::{InfluxSettings, InfluxSink};
+use localpacketdump::mapping::{
+    build_status_client, fetch_initial_mappings, record_mapping_fetch, refresh_mappings, NicConfig,
+    NicResolver, StatusResponse, UnmappedLog, DEFAULT_WAN_LABEL,
+};
+use localpacketdump::metrics::{
+    parse_update_interval, update_metrics, FlushSink, IntervalSnapshot, Metrics, UpdaterContext,
+    DEFAULT_UPDATE_INTERVAL, LIVE_SNAPSHOT_CAPACITY,
+};
+use localpacketdump::neighbors::{refresh_neighbors, NeighborCache, PROC_NET_ARP};
+use localpacketdump::otlp::{OtlpSettings, OtlpSink};
+use localpacketdump::packet::DscpClasses;
+use localpacketdump::privileges::drop_privileges;
+use localpacketdump::probe::probe;
+use localpacketdump::quota::{
+    checkpoint_daily, DailyCounters, DailySchedule, DailySink, QuotaMetrics, Quotas,
+};
+use localpacketdump::reload::{reload_on_sighup, Reloader, DEFAULT_LOG_LEVEL};
+use localpacketdump::server::{self, AppState, UnixSocketSettings};
+use localpacketdump::sni::SniFlows;
+use localpacketdump::stats::{aggregate_records, RECORD_CHANNEL_CAPACITY};
+use localpacketdump::statsd::{StatsdSettings, StatsdSink};
+use localpacketdump::subnets::{
+    interface_subnets, load_subnets_file, refresh_auto_subnets, LocalSubnets, SubnetControl,
+};
+use localpacketdump::tls::{watch_certificate, TlsConfig, TlsSettings};
+use localpacketdump::utilization::UtilizationSink;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
 use std::time::Duration;
-use tokio::time;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{broadcast, mpsc, oneshot, watch};
 use tracing::{error, info};
-
-// ローカルサブネットの定義（CIDR形式で指定）
-const LOCAL_SUBNETS: &[&str] = &[
-    "10.40.0.0/20",
-    // 必要に応じて追加
-    // "192.168.1.0/24",
-    // "172.16.0.0/16",
-];
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{reload, EnvFilter};
 
 const VERSION: &str = "1.0.0";
 
-lazy_static! {
-    static ref REGISTRY: Registry = Registry::new();
-    static ref IP_TX_BPS: GaugeVec = GaugeVec::new(
-        Opts::new("network_ip_tx_bps", "TX bits per second per IP"),
-        &["local_ip", "nic"]
-    )
-    .unwrap();
-    static ref IP_RX_BPS: GaugeVec = GaugeVec::new(
-        Opts::new("network_ip_rx_bps", "RX bits per second per IP"),
-        &["local_ip", "nic"]
-    )
-    .unwrap();
-    static ref TOTAL_TX_BPS: GaugeVec = GaugeVec::new(
-        Opts::new(
-            "network_ip_tx_bps_total",
-            "Total TX bits per second per NIC"
-        ),
-        &["nic"]
-    )
-    .unwrap();
-    static ref TOTAL_RX_BPS: GaugeVec = GaugeVec::new(
-        Opts::new(
-            "network_ip_rx_bps_total",
-            "Total RX bits per second per NIC"
-        ),
-        &["nic"]
-    )
-    .unwrap();
+// TCP listener used when neither --listen nor --listen-unix is given
+const DEFAULT_LISTEN: &str = "0.0.0.0:59122";
+
+// Runs before the HTTP server and the periodic refreshers start; continuing as
+// root after a failed drop is never an option
+fn drop_privileges_or_exit(user: &str, group: Option<&str>) {
+    match drop_privileges(user, group) {
+        Ok((uid, gid)) => info!("Dropped privileges to uid {} gid {}", uid, gid),
+        Err(e) => {
+            error!("Failed to drop privileges to {}: {}", user, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum LogFormat {
+    Text,
+    Json,
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
-struct NicConfig {
-    lan: String,
-    wan0: String,
-    wan1: String,
+#[derive(Debug, Parser)]
+#[command(version = VERSION, about = "Per-IP traffic exporter for Prometheus")]
+struct Args {
+    /// Path to the TOML config file
+    #[arg(long, env = "LOCALPACKETDUMP_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// Address for the metrics HTTP server, e.g. 127.0.0.1:59122 or [::1]:59122.
+    /// Defaults to 0.0.0.0:59122 unless --listen-unix is given.
+    #[arg(long, env = "LOCALPACKETDUMP_LISTEN")]
+    listen: Option<SocketAddr>,
+
+    /// Also (or, without --listen, only) serve HTTP on this Unix domain socket
+    #[arg(long, env = "LOCALPACKETDUMP_LISTEN_UNIX", value_name = "PATH")]
+    listen_unix: Option<PathBuf>,
+
+    /// URL of the NIC mapping status service (overrides status_url in the config file)
+    #[arg(long, env = "LOCALPACKETDUMP_STATUS_URL")]
+    status_url: Option<String>,
+
+    /// Log output format; json writes one object per line with structured fields
+    #[arg(
+        long,
+        env = "LOCALPACKETDUMP_LOG_FORMAT",
+        value_enum,
+        default_value = "text"
+    )]
+    log_format: LogFormat,
+
+    /// Read packets from a pcap file instead of capturing on an interface
+    #[arg(long, value_name = "PATH")]
+    read_file: Option<PathBuf>,
+
+    /// Pace packets from --read-file by their capture timestamps
+    #[arg(long, requires = "read_file")]
+    replay_timing: bool,
+
+    /// Metrics update interval, e.g. 250ms or 5s (100ms to 60s); overrides update_interval in the config file [default: 1s]
+    #[arg(long, env = "LOCALPACKETDUMP_UPDATE_INTERVAL", value_parser = parse_update_interval)]
+    update_interval: Option<Duration>,
+
+    /// Report ready on /ready with the built-in fallback mappings when the initial
+    /// mapping fetch fails, instead of waiting for a later refresh to succeed
+    #[arg(long, env = "LOCALPACKETDUMP_ACCEPT_FALLBACK_MAPPINGS")]
+    accept_fallback_mappings: bool,
+
+    /// Add a hostname label (reverse DNS of local_ip) to the per-IP metrics
+    #[arg(long)]
+    resolve_hostnames: bool,
+
+    /// Add a mac label (from the kernel ARP table) to the per-IP metrics
+    #[arg(long)]
+    mac_labels: bool,
+
+    /// Account only every Nth captured frame (1/N) and scale the counts by N
+    #[arg(long, env = "LOCALPACKETDUMP_SAMPLE", value_name = "1/N", default_value = "1/1", value_parser = parse_sample_rate)]
+    sample: u64,
+
+    /// Add the prefixes assigned to the primary capture interface to the local
+    /// subnets, re-read every auto_subnets_refresh_secs
+    #[arg(long, conflicts_with = "read_file")]
+    auto_subnets: bool,
+
+    /// Also write captured frames to rotating pcap files in this directory
+    #[arg(long, value_name = "DIR", conflicts_with = "read_file")]
+    dump_dir: Option<PathBuf>,
+
+    /// Keep serving HTTP when a capture device cannot be opened for lack of
+    /// permissions, instead of exiting
+    #[arg(long)]
+    keep_running_without_capture: bool,
+
+    /// How interfaces from the config and the status service are looked up among
+    /// the pcap devices; Windows names them \\Device\\NPF_{GUID}
+    #[arg(long, env = "LOCALPACKETDUMP_INTERFACE_MATCH", value_enum, default_value_t = InterfaceMatch::Auto)]
+    interface_match: InterfaceMatch,
+
+    /// Switch to this user (name or uid) once the capture handles are open
+    #[arg(long, env = "LOCALPACKETDUMP_USER")]
+    user: Option<String>,
+
+    /// Group for --user (name or gid), defaults to the user's primary group
+    #[arg(long, env = "LOCALPACKETDUMP_GROUP", requires = "user")]
+    group: Option<String>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
-struct StatusResponse {
-    config: NicConfig,
-    mappings: HashMap<String, String>,
+// Diagnostics for setting up a new host; neither starts the HTTP server or
+// contacts the status service
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Print the capture devices with their flags, descriptions and addresses
+    ListInterfaces,
+    /// Capture on one interface for a few seconds and print what was seen
+    Probe {
+        /// Interface to capture on, looked up like the configured ones
+        interface: String,
+        /// How long to capture
+        #[arg(long, default_value_t = 5)]
+        seconds: u64,
+    },
 }
 
-#[derive(Debug, Clone)]
-struct LocalSubnets {
-    subnets: Vec<ipnet::Ipv4Net>,
+async fn shutdown_signal() {
+    let mut sigterm = signal(SignalKind::terminate()).expect("Failed to install SIGTERM handler");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = sigterm.recv() => {}
+    }
 }
 
-impl LocalSubnets {
-    fn new() -> Self {
-        Self {
-            subnets: Vec::new(),
+#[tokio::main]
+async fn main() {
+    let args = Args::parse();
+
+    // RUST_LOG wins over log_level in the config, which is applied once it is read
+    let rust_log = std::env::var_os("RUST_LOG").is_some();
+    let (log_filter_layer, log_filter) = reload::Layer::new(if rust_log {
+        EnvFilter::from_default_env()
+    } else {
+        EnvFilter::new(DEFAULT_LOG_LEVEL)
+    });
+    let logging = tracing_subscriber::registry().with(log_filter_layer);
+    match args.log_format {
+        LogFormat::Text => logging.with(tracing_subscriber::fmt::layer()).init(),
+        LogFormat::Json => logging.with(tracing_subscriber::fmt::layer().json()).init(),
+    }
+
+    if let Some(Command::ListInterfaces) = args.command {
+        match describe_devices() {
+            Ok(devices) => print!("{}", devices),
+            Err(e) => {
+                error!("Failed to list capture devices: {}", e);
+                std::process::exit(1);
+            }
         }
+        return;
     }
 
-    fn add_subnet(&mut self, subnet: &str) -> Result<(), Box<dyn std::error::Error>> {
-        let net: ipnet::Ipv4Net = subnet.parse()?;
-        self.subnets.push(net);
-        Ok(())
+    // The neighbor table is read from /proc/net/arp
+    let mac_labels = args.mac_labels && cfg!(target_os = "linux");
+    if args.mac_labels && !mac_labels {
+        tracing::warn!("--mac-labels needs the Linux ARP table, ignoring it");
     }
 
-    fn is_local(&self, ip: &str) -> bool {
-        if let Ok(addr) = ip.parse::<Ipv4Addr>() {
-            for subnet in &self.subnets {
-                if subnet.contains(&addr) {
-                    return true;
+    let (mut config, config_table) = match args.config.as_deref().map(read_config) {
+        Some(Ok(loaded)) => loaded,
+        Some(Err(e)) => {
+            error!("Failed to load config: {}", e);
+            std::process::exit(1);
+        }
+        None => (Config::default(), toml::Table::new()),
+    };
+    if args.update_interval.is_some() {
+        config.update_interval = args.update_interval;
+    }
+    let update_interval = config.update_interval.unwrap_or(DEFAULT_UPDATE_INTERVAL);
+    if let (Some(level), false) = (&config.log_level, rust_log) {
+        match EnvFilter::try_new(level) {
+            Ok(filter) => {
+                let _ = log_filter.reload(filter);
+            }
+            Err(e) => {
+                error!("Invalid log_level '{}': {}", level, e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    // Uses the capture options and BPF filter of the config, before anything
+    // talks to the status service or binds a listener
+    if let Some(Command::Probe { interface, seconds }) = &args.command {
+        let settings = CaptureSettings {
+            interface_match: args.interface_match,
+            promisc: config.promisc,
+            snaplen: config.snaplen.max(MIN_SNAPLEN),
+            buffer_size: config.buffer_size,
+            timeout_ms: config.timeout_ms.max(1),
+        };
+        let interface = interface.clone();
+        let filter = config.bpf_filter.clone();
+        let duration = Duration::from_secs(*seconds);
+        info!("Probing {} for {:?}", interface, duration);
+        let result = localpacketdump::runtime::spawn_blocking(move || {
+            probe(&interface, &settings, filter.as_deref(), duration).map_err(|e| {
+                if is_permission_denied(e.as_ref()) {
+                    PermissionDenied {
+                        interface,
+                        error: e.to_string(),
+                    }
+                    .to_string()
+                } else {
+                    format!("{}: {}", interface, e)
                 }
+            })
+        })
+        .await;
+        match result {
+            Ok(Ok(summary)) => print!("{}", summary),
+            Ok(Err(e)) => {
+                error!("Probe failed: {}", e);
+                std::process::exit(1);
+            }
+            Err(e) => {
+                error!("Probe task failed: {}", e);
+                std::process::exit(1);
             }
         }
-        false
+        return;
     }
-}
 
-#[derive(Debug, Clone)]
-struct TrafficStats {
-    tx_bytes: HashMap<String, u64>,     // key: "nic:ip"
-    rx_bytes: HashMap<String, u64>,     // key: "nic:ip"
-    nic_tx_total: HashMap<String, u64>, // key: nic
-    nic_rx_total: HashMap<String, u64>, // key: nic
-}
+    let http_auth = match HttpAuth::from_config(&config) {
+        Ok(auth) => auth.map(Arc::new),
+        Err(e) => {
+            error!("Invalid HTTP authentication settings: {}", e);
+            std::process::exit(1);
+        }
+    };
+    // Both would need the one Authorization header of a /pcap request
+    if http_auth.is_some() && config.pcap_download_token.is_some() {
+        error!(
+            "pcap_download_token cannot be combined with http_auth_*, which already covers /pcap"
+        );
+        std::process::exit(1);
+    }
+    if let Some(auth) = &http_auth {
+        info!(
+            "HTTP endpoints require {}, exempt: {:?}",
+            auth.scheme(),
+            config.http_auth_exempt
+        );
+    }
+
+    // Parse local subnets from config
+    let mut local_subnets_obj = LocalSubnets::new();
+    local_subnets_obj.exclude_link_local = config.exclude_link_local;
+    let mut failed_subnets = Vec::new();
 
-impl TrafficStats {
-    fn new() -> Self {
-        Self {
-            tx_bytes: HashMap::new(),
-            rx_bytes: HashMap::new(),
-            nic_tx_total: HashMap::new(),
-            nic_rx_total: HashMap::new(),
+    let saved_subnets = match config.subnets_file.as_deref().map(load_subnets_file) {
+        Some(Ok(saved)) => saved,
+        Some(Err(e)) => {
+            error!("Failed to load subnets_file: {}", e);
+            std::process::exit(1);
         }
+        None => None,
+    };
+    if let (Some(saved), Some(path)) = (&saved_subnets, &config.subnets_file) {
+        info!(
+            "Using the {} subnets saved in {}",
+            saved.len(),
+            path.display()
+        );
     }
+    for subnet in saved_subnets.unwrap_or_else(|| config.subnets(args.auto_subnets)) {
+        match local_subnets_obj.add_subnet(&subnet) {
+            Ok(_) => info!("Added local subnet: {}", subnet),
+            Err(e) => {
+                error!("Failed to parse subnet '{}': {}", subnet, e);
+                failed_subnets.push(subnet);
+            }
+        }
+    }
+
+    let metrics = match Metrics::new(
+        &config.metric_namespace,
+        config.vlan_metrics,
+        config.dscp_metrics,
+        args.resolve_hostnames,
+        mac_labels,
+        config.wan_labels,
+    ) {
+        Ok(metrics) => Arc::new(metrics),
+        Err(e) => {
+            error!("Failed to register metrics: {}", e);
+            std::process::exit(1);
+        }
+    };
 
-    fn reset(&mut self) {
-        self.tx_bytes.clear();
-        self.rx_bytes.clear();
-        self.nic_tx_total.clear();
-        self.nic_rx_total.clear();
+    metrics.set_build_info(VERSION);
+    metrics.capture_sample_rate.set(args.sample as i64);
+    if args.sample > 1 {
+        info!(
+            "Sampling 1/{} of captured frames, traffic metrics are estimates",
+            args.sample
+        );
     }
-}
 
-async fn fetch_nic_mappings() -> Result<StatusResponse, Box<dyn std::error::Error>> {
-    let response = reqwest::get("http://localhost:32599/status").await?;
-    let status: StatusResponse = response.json().await?;
-    Ok(status)
-}
+    // A slow update interval must not make the updater look stale
+    let health_timeout = Duration::from_secs(config.health_timeout_secs).max(update_interval * 2);
+    let health = Arc::new(HealthState::new(health_timeout));
 
-fn get_nic_for_ip(ip: &str, status: &StatusResponse) -> String {
-    // Check if IP is in mappings
-    if let Some(wan) = status.mappings.get(ip) {
-        // Convert wan name to nic name
-        match wan.as_str() {
-            "wan0" => status.config.wan0.clone(),
-            "wan1" => status.config.wan1.clone(),
-            _ => status.config.wan0.clone(),
+    let status_url = args
+        .status_url
+        .clone()
+        .unwrap_or_else(|| config.status_url().to_string());
+    let status_client = match build_status_client() {
+        Ok(client) => client,
+        Err(e) => {
+            error!("Failed to build HTTP client: {}", e);
+            std::process::exit(1);
         }
-    } else {
-        // Default to wan0
-        status.config.wan0.clone()
-    }
-}
+    };
 
-async fn metrics_handler() -> String {
-    let encoder = TextEncoder::new();
-    let metric_families = REGISTRY.gather();
-    let mut buffer = vec![];
-    encoder.encode(&metric_families, &mut buffer).unwrap();
-    String::from_utf8(buffer).unwrap()
-}
+    // Bind the HTTP listeners before starting capture so a bad address fails fast
+    let listen = match (args.listen, &args.listen_unix) {
+        (Some(addr), _) => Some(addr),
+        (None, None) => Some(DEFAULT_LISTEN.parse().unwrap()),
+        (None, Some(_)) => None,
+    };
+    // Fail before binding if the certificate cannot be used
+    let tls = match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) => {
+            let settings = TlsSettings {
+                cert: cert.clone(),
+                key: key.clone(),
+                reload_interval: Duration::from_secs(config.tls_reload_secs.max(1)),
+            };
+            match TlsConfig::load(settings) {
+                Ok(tls) => Some(Arc::new(tls)),
+                Err(e) => {
+                    error!("Failed to set up TLS: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        (None, None) => None,
+        _ => {
+            error!("TLS needs both tls_cert and tls_key");
+            std::process::exit(1);
+        }
+    };
+    let listener = match listen {
+        Some(addr) => match tokio::net::TcpListener::bind(addr).await {
+            Ok(listener) => {
+                let addr = listener.local_addr().unwrap_or(addr);
+                info!(
+                    "Prometheus metrics server listening on {}://{}/metrics",
+                    if tls.is_some() { "https" } else { "http" },
+                    addr
+                );
+                Some(listener)
+            }
+            Err(e) => {
+                error!("Failed to bind {}: {}", addr, e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+    let unix_socket = args.listen_unix.clone().map(|path| UnixSocketSettings {
+        path,
+        mode: config.unix_socket_mode,
+        owner: config.unix_socket_owner.clone(),
+        group: config.unix_socket_group.clone(),
+    });
+    let unix_listener = match &unix_socket {
+        Some(settings) => match server::bind_unix(settings) {
+            Ok(listener) => {
+                info!(
+                    "Prometheus metrics server listening on unix:{}",
+                    settings.path.display()
+                );
+                Some(listener)
+            }
+            Err(e) => {
+                error!("Failed to bind {}: {}", settings.path.display(), e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
 
-async fn update_metrics(stats: Arc<Mutex<TrafficStats>>, _status: Arc<Mutex<StatusResponse>>) {
-    let mut interval = time::interval(Duration::from_secs(1));
+    if config.snaplen < MIN_SNAPLEN {
+        error!(
+            "snaplen {} is too small, at least {} bytes are needed for the headers",
+            config.snaplen, MIN_SNAPLEN
+        );
+        std::process::exit(1);
+    }
 
-    loop {
-        interval.tick().await;
+    let capture_settings = CaptureSettings {
+        interface_match: args.interface_match,
+        promisc: config.promisc,
+        snaplen: config.snaplen,
+        buffer_size: config.buffer_size,
+        timeout_ms: config.timeout_ms.max(1),
+    };
 
-        let mut stats_guard = stats.lock().unwrap();
+    // With --user the listeners above are bound and every capture handle is opened
+    // here, while still privileged; the status service and everything else on the
+    // network is only contacted after the drop
+    let mut opened = Vec::new();
+    if let Some(user) = &args.user {
+        // Reading a file needs no privileges
+        if args.read_file.is_none() {
+            if config.capture_interfaces.is_empty() && !config.capture_any_device {
+                error!("--user needs capture_interfaces or capture_any_device, the LAN named by the status service is only known after dropping privileges");
+                std::process::exit(1);
+            }
+            let handles = if config.capture_any_device {
+                vec![ANY_DEVICE.to_string()]
+            } else {
+                config.capture_interfaces.clone()
+            };
+            for interface in &handles {
+                match open_capture(interface, &capture_settings) {
+                    Ok(cap) => opened.push(Some(cap)),
+                    Err(e) => {
+                        if is_permission_denied(e.as_ref()) {
+                            error!(
+                                "{}",
+                                PermissionDenied {
+                                    interface: interface.clone(),
+                                    error: e.to_string(),
+                                }
+                            );
+                        } else {
+                            error!("Failed to open capture on {}: {}", interface, e);
+                        }
+                        if !args.keep_running_without_capture {
+                            std::process::exit(1);
+                        }
+                        opened.push(None);
+                    }
+                }
+            }
+        }
+        drop_privileges_or_exit(user, args.group.as_deref());
+        if config.pcap_download && args.read_file.is_none() {
+            tracing::warn!(
+                "/pcap opens its own capture handle and fails after dropping privileges"
+            );
+        }
+    }
 
-        // Update per-IP metrics
-        for (key, &bytes) in &stats_guard.tx_bytes {
-            let parts: Vec<&str> = key.split(':').collect();
-            if parts.len() == 2 {
-                let nic = parts[0];
-                let ip = parts[1];
-                let bps = (bytes * 8) as f64; // Convert bytes to bits
-                IP_TX_BPS.with_label_values(&[ip, nic]).set(bps);
+    // Fetch initial NIC mappings
+    let initial_status = match fetch_initial_mappings(&status_client, &status_url).await {
+        Ok(status) => {
+            info!("Fetched NIC mappings: {:?}", status);
+            record_mapping_fetch(&health, &metrics, Some(&status));
+            status
+        }
+        Err(e) => {
+            record_mapping_fetch(&health, &metrics, None);
+            error!("Failed to fetch initial NIC mappings: {}", e);
+            error!("Using default configuration");
+            if args.accept_fallback_mappings {
+                health.accept_fallback_mappings();
             }
+            StatusResponse::new(NicConfig {
+                lan: Arc::from("eth2"),
+                wans: BTreeMap::from([
+                    (Arc::from("wan0"), Arc::from("eth0")),
+                    (Arc::from("wan1"), Arc::from("eth1")),
+                ]),
+            })
         }
+    };
+
+    let status = Arc::new(Mutex::new(initial_status.clone()));
+    let (lan_updates, lan) = watch::channel(initial_status.config.lan.clone());
+    let nics = Arc::new(NicResolver::new(
+        &initial_status,
+        &config.default_wan,
+        config.unmapped_nic,
+        &metrics,
+    ));
 
-        for (key, &bytes) in &stats_guard.rx_bytes {
-            let parts: Vec<&str> = key.split(':').collect();
-            if parts.len() == 2 {
-                let nic = parts[0];
-                let ip = parts[1];
-                let bps = (bytes * 8) as f64; // Convert bytes to bits
-                IP_RX_BPS.with_label_values(&[ip, nic]).set(bps);
+    // --auto-subnets merges the prefixes of the primary capture interface into the
+    // configured subnets and keeps re-reading them
+    let configured_subnets = local_subnets_obj.clone();
+    let auto_subnets_interface = args.auto_subnets.then(|| {
+        config
+            .capture_interfaces
+            .first()
+            .cloned()
+            .unwrap_or_else(|| initial_status.config.lan.to_string())
+    });
+    let mut detected_subnets = Vec::new();
+    if let Some(interface) = &auto_subnets_interface {
+        match interface_subnets(interface) {
+            Some(detected) => {
+                info!(
+                    interface = %interface,
+                    detected = ?detected.iter().map(|net| net.to_string()).collect::<Vec<_>>(),
+                    "Detected local subnets"
+                );
+                local_subnets_obj = local_subnets_obj.merged(&detected);
+                detected_subnets = detected;
             }
+            None => tracing::warn!(interface = %interface, "Interface for auto subnets not found"),
         }
+    }
+
+    if local_subnets_obj.is_empty() {
+        error!(
+            "No valid local subnets configured (failed entries: {:?}), refusing to start",
+            failed_subnets
+        );
+        std::process::exit(1);
+    }
+    info!("Local subnets: {:?}", local_subnets_obj.to_strings());
 
-        // Update total metrics
-        for (nic, &bytes) in &stats_guard.nic_tx_total {
-            let bps = (bytes * 8) as f64;
-            TOTAL_TX_BPS.with_label_values(&[nic]).set(bps);
+    let local_subnets = Arc::new(RwLock::new(local_subnets_obj));
+    let subnet_control = Arc::new(SubnetControl::new(
+        local_subnets.clone(),
+        configured_subnets,
+        detected_subnets,
+        config.subnets_file.clone(),
+    ));
+    if let Some(interface) = auto_subnets_interface {
+        tokio::spawn(refresh_auto_subnets(
+            subnet_control.clone(),
+            interface,
+            Duration::from_secs(config.auto_subnets_refresh_secs.max(1)),
+        ));
+    }
+
+    // Start the aggregator before any capture produces records
+    let (record_tx, record_rx) = mpsc::channel(RECORD_CHANNEL_CAPACITY);
+    let (snapshot_tx, snapshot_rx) = mpsc::channel(1);
+    tokio::spawn(aggregate_records(
+        record_rx,
+        snapshot_rx,
+        config.vlan_metrics,
+        (config.peak_bucket_ms > 0).then(|| Duration::from_millis(config.peak_bucket_ms)),
+        args.sample,
+        config.track_remotes_for.clone(),
+    ));
+
+    // Check the filter here so a typo fails startup instead of a capture thread
+    let bpf_filter = config.bpf_filter().to_string();
+    if let Err(e) = validate_bpf_filter(&bpf_filter) {
+        error!("Invalid BPF filter '{}': {}", bpf_filter, e);
+        std::process::exit(1);
+    }
+
+    // SIGHUP re-reads the config file and publishes it here
+    let (config_updates, shared_config) = watch::channel(Arc::new(config.clone()));
+    tokio::spawn(reload_on_sighup(Reloader {
+        path: args.config.clone(),
+        table: config_table,
+        config: config_updates,
+        subnets: subnet_control.clone(),
+        log_filter: log_filter.clone(),
+        auto_subnets: args.auto_subnets,
+        update_interval_override: args.update_interval,
+        log_level_override: rust_log,
+        reloads: metrics.config_reloads.clone(),
+    }));
+
+    let dscp_classes = if config.dscp_metrics {
+        match DscpClasses::new(&config.dscp_classes) {
+            Ok(classes) => Some(Arc::new(classes)),
+            Err(e) => {
+                error!("Invalid dscp_classes: {}", e);
+                std::process::exit(1);
+            }
         }
+    } else {
+        None
+    };
 
-        for (nic, &bytes) in &stats_guard.nic_rx_total {
-            let bps = (bytes * 8) as f64;
-            TOTAL_RX_BPS.with_label_values(&[nic]).set(bps);
+    let dump = args.dump_dir.clone().map(|dir| {
+        if let Err(e) = std::fs::create_dir_all(&dir) {
+            error!("Failed to create dump directory {}: {}", dir.display(), e);
+            std::process::exit(1);
         }
+        Arc::new(DumpControl::new(
+            DumpSettings {
+                dir,
+                max_file_bytes: config.dump_max_file_mb * 1024 * 1024,
+                rotate_interval: Duration::from_secs(config.dump_rotate_secs),
+                max_files: config.dump_max_files,
+            },
+            config.dump_on_start,
+            metrics.dump_frames_dropped.clone(),
+        ))
+    });
+
+    let packet_feed = (config.packet_stream_max_clients > 0).then(|| {
+        Arc::new(PacketFeed::new(
+            config.packet_stream_max_clients,
+            config.packet_stream_max_rate,
+        ))
+    });
+
+    // Filled by the capture threads, rotated by DnsSink on every interval
+    let dns = config
+        .dns_queries
+        .then(|| Arc::new(DnsQueries::new(config.dns_top_intervals)));
+    let quic_ports: Arc<[u16]> = config.quic_ports.clone().into();
+    let sni = config
+        .domain_metrics
+        .then(|| Arc::new(SniFlows::new(config.domain_flows_max, quic_ports.clone())));
 
-        // Reset stats for next interval
-        stats_guard.reset();
+    #[cfg(feature = "netflow")]
+    let flows = if let Some(collector) = config.netflow_collector.as_deref() {
+        use localpacketdump::netflow::{
+            export_flows, flow_channel, NetflowMetrics, NetflowSettings,
+        };
+        let collector = match tokio::net::lookup_host(collector)
+            .await
+            .ok()
+            .and_then(|mut addrs| addrs.next())
+        {
+            Some(addr) => addr,
+            None => {
+                error!("Failed to resolve NetFlow collector '{}'", collector);
+                std::process::exit(1);
+            }
+        };
+        let netflow_metrics = match NetflowMetrics::register(&metrics.registry) {
+            Ok(netflow_metrics) => netflow_metrics,
+            Err(e) => {
+                error!("Failed to register NetFlow metrics: {}", e);
+                std::process::exit(1);
+            }
+        };
+        let settings = NetflowSettings {
+            collector,
+            active_timeout: Duration::from_secs(config.netflow_active_timeout_secs),
+            inactive_timeout: Duration::from_secs(config.netflow_inactive_timeout_secs),
+            max_flows: config.netflow_max_flows.max(1),
+            sample_rate: args.sample,
+        };
+        let (flows, samples) = flow_channel(&netflow_metrics);
+        tokio::spawn(async move {
+            if let Err(e) = export_flows(settings, netflow_metrics, samples).await {
+                error!("NetFlow export stopped: {}", e);
+            }
+        });
+        Some(flows)
+    } else {
+        None
+    };
+    #[cfg(not(feature = "netflow"))]
+    if config.netflow_collector.is_some() {
+        tracing::warn!(
+            "netflow_collector is set but this build has no netflow feature, ignoring it"
+        );
     }
-}
 
-fn capture_packets(
-    interface_name: String,
-    stats: Arc<Mutex<TrafficStats>>,
-    status: Arc<Mutex<StatusResponse>>,
-    local_subnets: Arc<LocalSubnets>,
-) {
-    tokio::task::spawn_blocking(move || {
-        let device = Device::list()
-            .expect("Failed to list devices")
-            .into_iter()
-            .find(|d| d.name == interface_name)
-            .expect(&format!("Device {} not found", interface_name));
-
-        let mut cap = Capture::from_device(device)
-            .expect("Failed to open device")
-            .promisc(true)
-            .snaplen(65535)
-            .timeout(1000)
-            .open()
-            .expect("Failed to activate capture");
-
-        info!("Started capturing on {}", interface_name);
-
-        loop {
-            match cap.next_packet() {
-                Ok(packet) => {
-                    if let Some(ethernet) = EthernetPacket::new(packet.data) {
-                        if ethernet.get_ethertype() == EtherTypes::Ipv4 {
-                            if let Some(ipv4) = Ipv4Packet::new(ethernet.payload()) {
-                                let src_ip = ipv4.get_source().to_string();
-                                let dst_ip = ipv4.get_destination().to_string();
-                                let packet_len = packet.data.len() as u64;
-
-                                let status_guard = status.lock().unwrap();
-
-                                // Determine if this is TX or RX based on source/destination
-                                // TX: local IP is source
-                                // RX: local IP is destination
-
-                                // Check if source is local (TX)
-                                if local_subnets.is_local(&src_ip) {
-                                    let nic = get_nic_for_ip(&src_ip, &status_guard);
-                                    let key = format!("{}:{}", nic, src_ip);
-                                    let mut stats_guard = stats.lock().unwrap();
-                                    *stats_guard.tx_bytes.entry(key).or_insert(0) += packet_len;
-                                    *stats_guard.nic_tx_total.entry(nic).or_insert(0) += packet_len;
-                                }
+    // Without a usable database its metrics stay empty
+    #[cfg(feature = "geoip")]
+    let geoip = {
+        use localpacketdump::geoip::{open_asn, open_country, watch_databases, GeoIp};
+        let disabled = |e: String| tracing::warn!("GeoIP database disabled: {}", e);
+        let geoip = GeoIp {
+            country: config
+                .geoip_database
+                .as_ref()
+                .and_then(|path| open_country(path).map_err(disabled).ok()),
+            asn: config
+                .geoip_asn_database
+                .as_ref()
+                .and_then(|path| open_asn(path).map_err(disabled).ok()),
+        };
+        (geoip.country.is_some() || geoip.asn.is_some()).then(|| {
+            let geoip = Arc::new(geoip);
+            tokio::spawn(watch_databases(geoip.clone()));
+            geoip
+        })
+    };
+    #[cfg(not(feature = "geoip"))]
+    if config.geoip_database.is_some() || config.geoip_asn_database.is_some() {
+        tracing::warn!(
+            "geoip_database or geoip_asn_database is set but this build has no geoip feature, ignoring it"
+        );
+    }
 
-                                // Check if destination is local (RX)
-                                if local_subnets.is_local(&dst_ip) {
-                                    let nic = get_nic_for_ip(&dst_ip, &status_guard);
-                                    let key = format!("{}:{}", nic, dst_ip);
-                                    let mut stats_guard = stats.lock().unwrap();
-                                    *stats_guard.rx_bytes.entry(key).or_insert(0) += packet_len;
-                                    *stats_guard.nic_rx_total.entry(nic).or_insert(0) += packet_len;
-                                }
-                            }
-                        }
-                    }
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let capture_ctx = CaptureContext {
+        metrics: metrics.clone(),
+        records: record_tx,
+        nics: nics.clone(),
+        local_subnets: local_subnets.clone(),
+        config: shared_config.clone(),
+        lossless: false,
+        health: health.clone(),
+        count_mode: config.count_mode,
+        tunnel_mode: config.tunnel_accounting,
+        tunnel_max_depth: config.tunnel_max_depth,
+        vxlan_ports: config.vxlan_ports.clone().into(),
+        vni_metrics: config.vni_metrics,
+        mpls_metrics: config.mpls_metrics,
+        cast_metrics: config.cast_metrics,
+        frame_overhead_bytes: config.frame_overhead_bytes,
+        shutdown: shutdown_rx,
+        capture: capture_settings,
+        sample_rate: args.sample,
+        tracked_ports: config.tracked_ports.clone().into(),
+        quic_ports,
+        unmapped_log: Arc::new(UnmappedLog::default()),
+        default_wan_label: config.wan_labels.then(|| Arc::from(DEFAULT_WAN_LABEL)),
+        drop_internal: config.drop_internal,
+        dscp_classes,
+        dump: dump.clone(),
+        any_device: None,
+        packet_feed: packet_feed.clone(),
+        dns: dns.clone(),
+        sni,
+        arp_watch: (config.arp_conflict_window_secs > 0).then(|| {
+            Arc::new(ArpWatch::new(Duration::from_secs(
+                config.arp_conflict_window_secs,
+            )))
+        }),
+        #[cfg(feature = "netflow")]
+        flows,
+        #[cfg(feature = "geoip")]
+        geoip,
+    };
+
+    // Start packet capture
+    let mut captures = Vec::new();
+    let capture_interfaces: Vec<String> = if let Some(path) = args.read_file.clone() {
+        let name = path.display().to_string();
+        let replay_ctx = CaptureContext {
+            lossless: true,
+            ..capture_ctx
+        };
+        let replay = replay_file(path, args.replay_timing, replay_ctx);
+        captures.push(tokio::spawn(async move {
+            match replay.await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => {
+                    error!("{}", e);
+                    std::process::exit(1);
                 }
                 Err(e) => {
-                    if !e.to_string().contains("timeout") {
-                        error!("Error capturing packet: {}", e);
-                    }
+                    error!("Replay task failed: {}", e);
+                    std::process::exit(1);
                 }
             }
+        }));
+        vec![name]
+    } else {
+        // Without capture_interfaces the capture follows renames of the LAN; --user
+        // requires them
+        let follow_lan = config.capture_interfaces.is_empty() && !config.capture_any_device;
+        let capture_interfaces = if config.capture_interfaces.is_empty() {
+            vec![initial_status.config.lan.to_string()]
+        } else {
+            config.capture_interfaces.clone()
+        };
+        // With capture_any_device one handle on "any" stands in for all of them
+        let (handles, capture_ctx) = if config.capture_any_device {
+            if !cfg!(target_os = "linux") {
+                error!("capture_any_device needs the \"any\" device of Linux");
+                std::process::exit(1);
+            }
+            let any_device = AnyDevice::new(&capture_interfaces, config.any_device_others);
+            let ctx = CaptureContext {
+                any_device: Some(Arc::new(any_device)),
+                ..capture_ctx
+            };
+            (vec![ANY_DEVICE.to_string()], ctx)
+        } else {
+            (capture_interfaces.clone(), capture_ctx)
+        };
+        // Handles opened before --user dropped the privileges, none otherwise
+        let mut opened = opened.into_iter();
+        for (i, interface) in handles.iter().enumerate() {
+            let cap = opened.next().flatten();
+            let capture = if follow_lan {
+                capture_lan(lan.clone(), capture_ctx.clone())
+            } else {
+                capture_packets(interface.clone(), i == 0, capture_ctx.clone(), cap)
+            };
+            let keep_running = args.keep_running_without_capture;
+            captures.push(tokio::spawn(async move {
+                match capture.await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => {
+                        error!("{}", e);
+                        // A crash inside capture_lan is a bug like the one below
+                        if !keep_running || matches!(e, CaptureFailed::Crashed(_)) {
+                            std::process::exit(1);
+                        }
+                        tracing::warn!(
+                            "Serving metrics without {} (--keep-running-without-capture)",
+                            e.interface()
+                        );
+                    }
+                    // A panicked capture thread is a bug, not a missing device
+                    Err(e) => {
+                        error!("Capture task failed: {}", e);
+                        std::process::exit(1);
+                    }
+                }
+            }));
         }
+        capture_interfaces
+    };
+
+    // Live captures only; a replayed file has no interface to open again
+    let pcap_download = (config.pcap_download && args.read_file.is_none()).then(|| {
+        let interface = &capture_interfaces[0];
+        Arc::new(PcapDownload::new(
+            resolve_device(interface, args.interface_match)
+                .map(|device| device.name)
+                .unwrap_or_else(|_| interface.clone()),
+            Duration::from_secs(config.pcap_download_max_secs.max(1)),
+            config.pcap_download_token.clone(),
+            config.promisc,
+        ))
     });
-}
 
-async fn refresh_mappings(status: Arc<Mutex<StatusResponse>>) {
-    let mut interval = time::interval(Duration::from_secs(10));
+    // Start the reverse DNS resolver
+    let hostnames = if args.resolve_hostnames {
+        let (cache, lookups) = HostnameCache::new(
+            Duration::from_secs(config.hostname_ttl_secs),
+            Duration::from_secs(config.hostname_negative_ttl_secs),
+            Duration::from_secs(config.series_idle_timeout_secs),
+        );
+        let cache = Arc::new(cache);
+        tokio::spawn(resolve_hostnames(
+            cache.clone(),
+            lookups,
+            config.hostname_max_concurrent_lookups,
+        ));
+        Some(cache)
+    } else {
+        None
+    };
 
-    loop {
-        interval.tick().await;
-        match fetch_nic_mappings().await {
-            Ok(new_status) => {
-                let mut status_guard = status.lock().unwrap();
-                *status_guard = new_status;
-                info!("Updated NIC mappings");
+    // Start the neighbor table reader
+    let neighbors = if mac_labels {
+        let cache = Arc::new(NeighborCache::new(Duration::from_secs(
+            config.series_idle_timeout_secs,
+        )));
+        tokio::spawn(refresh_neighbors(
+            cache.clone(),
+            PathBuf::from(PROC_NET_ARP),
+            Duration::from_secs(config.neighbor_refresh_secs.max(1)),
+        ));
+        Some(cache)
+    } else {
+        None
+    };
+
+    // Push exporters next to the /metrics registry
+    let mut sinks: Vec<Box<dyn FlushSink>> = Vec::new();
+    if let Some(endpoint) = &config.otlp_endpoint {
+        let settings = OtlpSettings {
+            endpoint: endpoint.clone(),
+            headers: config.otlp_headers.clone(),
+            timeout: Duration::from_secs(config.otlp_timeout_secs),
+            namespace: config.metric_namespace.clone(),
+        };
+        match OtlpSink::spawn(settings, metrics.otlp_export_failures.clone()) {
+            Ok(sink) => sinks.push(Box::new(sink)),
+            Err(e) => {
+                error!("Failed to set up OTLP export: {}", e);
+                std::process::exit(1);
             }
+        }
+    }
+
+    if let Some(url) = &config.influx_url {
+        let settings = InfluxSettings {
+            url: url.clone(),
+            org: config.influx_org.clone(),
+            bucket: config.influx_bucket.clone(),
+            token: config.influx_token.clone(),
+            timeout: Duration::from_secs(config.influx_timeout_secs),
+        };
+        match InfluxSink::spawn(settings, metrics.influx_write_failures.clone()) {
+            Ok(sink) => sinks.push(Box::new(sink)),
             Err(e) => {
-                error!("Failed to fetch NIC mappings: {}", e);
+                error!("Failed to set up InfluxDB output: {}", e);
+                std::process::exit(1);
             }
         }
     }
-}
 
-#[tokio::main]
-async fn main() {
-    tracing_subscriber::fmt::init();
+    if let Some(address) = config.statsd_address.as_deref() {
+        let address = match tokio::net::lookup_host(address)
+            .await
+            .ok()
+            .and_then(|mut addrs| addrs.next())
+        {
+            Some(addr) => addr,
+            None => {
+                error!("Failed to resolve statsd address '{}'", address);
+                std::process::exit(1);
+            }
+        };
+        let settings = StatsdSettings {
+            address,
+            prefix: config.statsd_prefix.clone(),
+            template: config.statsd_template.clone(),
+        };
+        match StatsdSink::spawn(settings, metrics.statsd_send_errors.clone()) {
+            Ok(sink) => sinks.push(Box::new(sink)),
+            Err(e) => {
+                error!("Failed to set up statsd output: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
 
-    // Parse local subnets from constant
-    let mut local_subnets_obj = LocalSubnets::new();
+    if let Some(address) = &config.graphite_address {
+        let settings = GraphiteSettings {
+            address: address.clone(),
+            prefix: config.graphite_prefix.clone(),
+            buffer_lines: config.graphite_buffer_lines.max(1),
+            timeout: Duration::from_secs(config.graphite_timeout_secs),
+        };
+        sinks.push(Box::new(GraphiteSink::spawn(
+            settings,
+            metrics.graphite_lines_dropped.clone(),
+        )));
+    }
 
-    for subnet in LOCAL_SUBNETS {
-        match local_subnets_obj.add_subnet(subnet) {
-            Ok(_) => info!("Added local subnet: {}", subnet),
-            Err(e) => error!("Failed to parse subnet '{}': {}", subnet, e),
+    #[cfg(feature = "mqtt")]
+    if let Some(broker) = &config.mqtt_broker {
+        use localpacketdump::mqtt::{MqttSettings, MqttSink};
+        let Some((host, port)) = broker
+            .rsplit_once(':')
+            .and_then(|(host, port)| Some((host, port.parse().ok()?)))
+        else {
+            error!("Invalid mqtt_broker '{}', expected host:port", broker);
+            std::process::exit(1);
+        };
+        let settings = MqttSettings {
+            host: host.trim_matches(['[', ']']).to_string(),
+            port,
+            client_id: config.mqtt_client_id.clone(),
+            user: config.mqtt_user.clone(),
+            password: config.mqtt_password.clone(),
+            topic_prefix: config.mqtt_topic_prefix.trim_end_matches('/').to_string(),
+            top_ips: config.mqtt_top_ips,
+            keep_alive: Duration::from_secs(config.mqtt_keep_alive_secs.max(5)),
+            tls: config.mqtt_tls,
+            ca_file: config.mqtt_ca_file.clone(),
+            client_cert: config.mqtt_client_cert.clone(),
+            client_key: config.mqtt_client_key.clone(),
+        };
+        match MqttSink::spawn(settings, &metrics.registry) {
+            Ok(sink) => sinks.push(Box::new(sink)),
+            Err(e) => {
+                error!("Failed to set up MQTT publishing: {}", e);
+                std::process::exit(1);
+            }
         }
     }
+    #[cfg(not(feature = "mqtt"))]
+    if config.mqtt_broker.is_some() {
+        tracing::warn!("mqtt_broker is set but this build has no mqtt feature, ignoring it");
+    }
 
-    let local_subnets = Arc::new(local_subnets_obj);
-
-    // Register metrics
-    REGISTRY
-        .register(Box::new(IP_TX_BPS.clone()))
-        .expect("Failed to register IP_TX_BPS");
-    REGISTRY
-        .register(Box::new(IP_RX_BPS.clone()))
-        .expect("Failed to register IP_RX_BPS");
-    REGISTRY
-        .register(Box::new(TOTAL_TX_BPS.clone()))
-        .expect("Failed to register TOTAL_TX_BPS");
-    REGISTRY
-        .register(Box::new(TOTAL_RX_BPS.clone()))
-        .expect("Failed to register TOTAL_RX_BPS");
+    // Also without rules, a reload may add some
+    match AlertSink::spawn(
+        shared_config.clone(),
+        metrics.alert_active.clone(),
+        metrics.alert_webhook_failures.clone(),
+    ) {
+        Ok(sink) => sinks.push(Box::new(sink)),
+        Err(e) => {
+            error!("Failed to set up alerts: {}", e);
+            std::process::exit(1);
+        }
+    }
 
-    // Fetch initial NIC mappings
-    let initial_status = match fetch_nic_mappings().await {
-        Ok(status) => {
-            info!("Fetched NIC mappings: {:?}", status);
-            status
+    // Link speeds are only readable from sysfs on Linux
+    let link_speed_auto = config.link_speed_auto && cfg!(target_os = "linux");
+    if !config.link_speeds_mbps.is_empty() || link_speed_auto {
+        match UtilizationSink::new(
+            &config.link_speeds_mbps,
+            link_speed_auto,
+            metrics.nic_tx_utilization.clone(),
+            metrics.nic_rx_utilization.clone(),
+        ) {
+            Ok(sink) => sinks.push(Box::new(sink)),
+            Err(e) => {
+                error!("Invalid link_speeds_mbps: {}", e);
+                std::process::exit(1);
+            }
         }
-        Err(e) => {
-            error!("Failed to fetch initial NIC mappings: {}", e);
-            error!("Using default configuration");
-            StatusResponse {
-                config: NicConfig {
-                    lan: "eth2".to_string(),
-                    wan0: "eth0".to_string(),
-                    wan1: "eth1".to_string(),
-                },
-                mappings: HashMap::new(),
+    }
+
+    // Known local IPs for /devices, written back to devices_file off the updater
+    let devices = if config.track_devices || config.devices_file.is_some() {
+        let devices = match DeviceTable::load(config.devices_file.clone(), config.max_devices) {
+            Ok(devices) => Arc::new(devices),
+            Err(e) => {
+                error!("Failed to load devices_file: {}", e);
+                std::process::exit(1);
             }
+        };
+        sinks.push(Box::new(DeviceSink::new(
+            devices.clone(),
+            metrics.new_devices.clone(),
+        )));
+        if config.devices_file.is_some() {
+            tokio::spawn(persist_devices(
+                devices.clone(),
+                Duration::from_secs(config.devices_save_secs.max(1)),
+            ));
         }
+        Some(devices)
+    } else {
+        None
     };
 
-    let stats = Arc::new(Mutex::new(TrafficStats::new()));
-    let status = Arc::new(Mutex::new(initial_status.clone()));
+    if let Some(dns) = &dns {
+        sinks.push(Box::new(DnsSink::new(
+            dns.clone(),
+            metrics.dns_queries.clone(),
+        )));
+    }
 
-    // Start packet capture
-    let capture_interface = initial_status.config.lan.clone();
-    capture_packets(
-        capture_interface,
-        stats.clone(),
-        status.clone(),
-        local_subnets.clone(),
-    );
+    // Per-IP counters of the current day, checkpointed off the updater
+    let daily = if config.daily_totals
+        || config.daily_quota_gb.is_some()
+        || !config.daily_quotas_gb.is_empty()
+    {
+        let daily = DailySchedule::new(config.daily_timezone.as_deref(), &config.daily_reset_time)
+            .and_then(|schedule| {
+                let quotas = Quotas::new(config.daily_quota_gb, &config.daily_quotas_gb)?;
+                let counters =
+                    DailyCounters::load(config.daily_checkpoint_file.clone(), &schedule)?;
+                Ok((schedule, quotas, Arc::new(counters)))
+            });
+        let (schedule, quotas, counters) = match daily {
+            Ok(daily) => daily,
+            Err(e) => {
+                error!("Invalid daily totals settings: {}", e);
+                std::process::exit(1);
+            }
+        };
+        let quota_metrics =
+            match QuotaMetrics::register(&metrics.registry, &config.metric_namespace) {
+                Ok(quota_metrics) => quota_metrics,
+                Err(e) => {
+                    error!("Failed to register daily totals metrics: {}", e);
+                    std::process::exit(1);
+                }
+            };
+        sinks.push(Box::new(DailySink::new(
+            counters.clone(),
+            schedule,
+            quotas,
+            quota_metrics,
+        )));
+        if config.daily_checkpoint_file.is_some() {
+            tokio::spawn(checkpoint_daily(
+                counters.clone(),
+                Duration::from_secs(config.daily_checkpoint_secs.max(1)),
+            ));
+        }
+        Some(counters)
+    } else {
+        None
+    };
 
-    // Start metrics updater
-    let stats_clone = stats.clone();
-    let status_clone = status.clone();
-    tokio::spawn(async move {
-        update_metrics(stats_clone, status_clone).await;
+    // Daily per-IP totals for /usage, written on a blocking task
+    #[cfg(feature = "sqlite")]
+    let usage = config.usage_db.as_ref().map(|path| {
+        use localpacketdump::usage::{UsageSink, UsageStore};
+        let store = match UsageStore::open(path, config.usage_retention_days) {
+            Ok(store) => Arc::new(store),
+            Err(e) => {
+                error!("Failed to open usage_db {}: {}", path.display(), e);
+                std::process::exit(1);
+            }
+        };
+        sinks.push(Box::new(UsageSink::spawn(
+            store.clone(),
+            metrics.usage_db_write_failures.clone(),
+        )));
+        store
     });
+    #[cfg(not(feature = "sqlite"))]
+    if config.usage_db.is_some() {
+        tracing::warn!("usage_db is set but this build has no sqlite feature, ignoring it");
+    }
+
+    // Start metrics updater
+    let last_interval = Arc::new(RwLock::new(Arc::new(IntervalSnapshot::empty())));
+    let (live, _) = broadcast::channel(LIVE_SNAPSHOT_CAPACITY);
+    let history = (config.history_intervals > 0)
+        .then(|| Arc::new(IntervalHistory::new(config.history_intervals)));
+    let (stop_updater, stop_updater_rx) = oneshot::channel();
+    let updater = tokio::spawn(update_metrics(
+        UpdaterContext {
+            metrics: metrics.clone(),
+            config: shared_config.clone(),
+            snapshots: snapshot_tx,
+            idle_timeout: Duration::from_secs(config.series_idle_timeout_secs),
+            max_tracked_ips: config.max_tracked_ips,
+            max_remotes: config.track_remotes_max,
+            max_asns: config.geoip_asn_max,
+            max_domains: config.domain_max,
+            health: health.clone(),
+            last_interval: last_interval.clone(),
+            live: live.clone(),
+            history: history.clone(),
+            hostnames,
+            neighbors,
+            sinks,
+        },
+        stop_updater_rx,
+    ));
 
     // Start periodic mappings refresh
     let status_clone = status.clone();
+    let health_clone = health.clone();
+    let metrics_clone = metrics.clone();
     tokio::spawn(async move {
-        refresh_mappings(status_clone).await;
+        refresh_mappings(
+            status_clone,
+            health_clone,
+            metrics_clone,
+            status_client,
+            status_url,
+            nics,
+            lan_updates,
+        )
+        .await;
     });
 
-    // Start HTTP server
-    let app = Router::new().route("/metrics", get(metrics_handler));
-
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:59122")
-        .await
-        .unwrap();
+    // Start HTTP server. Serving stops once capture has stopped and the last interval
+    // is published; /stream responses end then and in-flight scrapes finish.
+    let (stop_serving_tx, stop_serving_rx) = watch::channel(false);
+    let app = server::router(AppState {
+        metrics,
+        health,
+        last_interval,
+        live,
+        stopping: stop_serving_rx.clone(),
+        history,
+        devices: devices.clone(),
+        dns,
+        #[cfg(feature = "sqlite")]
+        usage,
+        auth: http_auth,
+        status,
+        local_subnets,
+        subnets: subnet_control,
+        capture_interfaces: capture_interfaces.into(),
+        dump,
+        pcap_download,
+        packet_feed,
+    });
 
     info!("version: {}", VERSION);
 
-    info!("Prometheus metrics server listening on http://0.0.0.0:59122/metrics");
-
-    axum::serve(listener, app).await.unwrap();
+    let shutdown = async move {
+        shutdown_signal().await;
+        info!("Shutting down");
+        let _ = shutdown_tx.send(true);
+        for capture in captures {
+            let _ = capture.await;
+        }
+        let _ = stop_updater.send(());
+        let _ = updater.await;
+        // Keep the last interval's devices
+        if let Some(devices) = devices {
+            match localpacketdump::runtime::spawn_blocking(move || devices.save()).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => error!("Failed to save known devices: {}", e),
+                Err(e) => error!("Device save task failed: {}", e),
+            }
+        }
+        if let Some(daily) = daily {
+            match localpacketdump::runtime::spawn_blocking(move || daily.save()).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => error!("Failed to checkpoint daily counters: {}", e),
+                Err(e) => error!("Daily checkpoint task failed: {}", e),
+            }
+        }
+        let _ = stop_serving_tx.send(true);
+    };
+    let tcp = {
+        let app = app.clone();
+        let mut stop = stop_serving_rx.clone();
+        async move {
+            let Some(listener) = listener else {
+                return Ok(());
+            };
+            if let Some(tls) = tls {
+                tokio::spawn(watch_certificate(tls.clone()));
+                server::serve_tls(listener, tls, app, stop).await;
+                return Ok(());
+            }
+            axum::serve(listener, app)
+                .with_graceful_shutdown(async move {
+                    let _ = stop.wait_for(|stop| *stop).await;
+                })
+                .await
+        }
+    };
+    let unix = async move {
+        if let Some(listener) = unix_listener {
+            server::serve_unix(listener, app, stop_serving_rx).await;
+        }
+    };
+    let ((), tcp_result, ()) = tokio::join!(shutdown, tcp, unix);
+    if let Some(settings) = &unix_socket {
+        let _ = std::fs::remove_file(&settings.path);
+    }
+    if let Err(e) = tcp_result {
+        error!("HTTP server error: {}", e);
+        std::process::exit(1);
+    }
+    info!("Shutdown complete");
 }
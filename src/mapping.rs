@@ -0,0 +1,498 @@
+use crate::config::UnmappedNic;
+use crate::health::HealthState;
+use crate::metrics::Metrics;
+use prometheus::IntCounter;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::IpAddr;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tokio::time;
+use tracing::{error, info, warn};
+
+// Every key besides `lan` is a wan name, so the original {lan, wan0, wan1} form
+// and routers with more uplinks (wan2, ...) share one representation
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct NicConfig {
+    pub lan: Arc<str>,
+    #[serde(flatten)]
+    pub wans: BTreeMap<Arc<str>, Arc<str>>,
+}
+
+impl NicConfig {
+    // Interface of `default_wan`, or of the first wan if that name is not configured
+    pub fn default_interface(&self, default_wan: &str) -> Arc<str> {
+        self.wans
+            .get(default_wan)
+            .or_else(|| self.wans.values().next())
+            .unwrap_or(&self.lan)
+            .clone()
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct StatusResponse {
+    pub config: NicConfig,
+    // Keys are IP addresses or CIDR prefixes
+    pub mappings: HashMap<String, String>,
+    // Address keys of `mappings`, so lookups need no string formatting
+    #[serde(skip)]
+    pub exact: HashMap<IpAddr, String>,
+    // CIDR keys of `mappings`, longest prefix first
+    #[serde(skip)]
+    pub prefixes: Vec<(ipnet::IpNet, String)>,
+}
+
+impl StatusResponse {
+    pub fn new(config: NicConfig) -> Self {
+        Self {
+            config,
+            mappings: HashMap::new(),
+            exact: HashMap::new(),
+            prefixes: Vec::new(),
+        }
+    }
+
+    // Canonicalize the mapping keys and index the CIDR ones. Keys that are neither an
+    // address nor a prefix are dropped on their own so the rest still applies.
+    pub fn normalize_mappings(&mut self) {
+        let mut mappings = HashMap::with_capacity(self.mappings.len());
+        let mut exact = HashMap::new();
+        let mut prefixes = Vec::new();
+        for (key, wan) in self.mappings.drain() {
+            if let Ok(addr) = key.parse::<IpAddr>() {
+                exact.insert(addr, wan.clone());
+                mappings.insert(addr.to_string(), wan);
+            } else if let Ok(net) = key.parse::<ipnet::IpNet>() {
+                let net = net.trunc();
+                prefixes.push((net, wan.clone()));
+                mappings.insert(net.to_string(), wan);
+            } else {
+                warn!(key = %key, "Ignoring NIC mapping with an invalid key");
+            }
+        }
+        prefixes.sort_by_key(|(net, _)| std::cmp::Reverse(net.prefix_len()));
+        self.mappings = mappings;
+        self.exact = exact;
+        self.prefixes = prefixes;
+    }
+
+    // Exact address entries win over prefixes, then the longest matching prefix
+    pub fn wan_for_ip(&self, ip: &IpAddr) -> Option<&str> {
+        if let Some(wan) = self.exact.get(ip) {
+            return Some(wan);
+        }
+        self.prefixes
+            .iter()
+            .find(|(net, _)| net.contains(ip))
+            .map(|(_, wan)| wan.as_str())
+    }
+}
+
+const STATUS_CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+const STATUS_REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const INITIAL_FETCH_ATTEMPTS: u32 = 3;
+const INITIAL_FETCH_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+pub fn build_status_client() -> reqwest::Result<reqwest::Client> {
+    reqwest::Client::builder()
+        .connect_timeout(STATUS_CONNECT_TIMEOUT)
+        .timeout(STATUS_REQUEST_TIMEOUT)
+        .build()
+}
+
+pub async fn fetch_nic_mappings(
+    client: &reqwest::Client,
+    url: &str,
+) -> Result<StatusResponse, Box<dyn std::error::Error>> {
+    let response = client.get(url).send().await?.error_for_status()?;
+    let mut status: StatusResponse = response.json().await?;
+    // Normalize IP keys so they match the canonical form produced by the capture path
+    // (mainly for IPv6, which can be written in several equivalent ways)
+    status.normalize_mappings();
+    Ok(status)
+}
+
+// wan label of traffic that no mapping sends to a known wan
+pub const DEFAULT_WAN_LABEL: &str = "default";
+
+// nic label of unmapped traffic with unmapped_nic = "unmapped"
+pub const UNMAPPED_NIC_LABEL: &str = "unmapped";
+
+// Interface and wan name an IP is accounted to, see get_nic_for_ip
+type NicRoute = (Arc<str>, Option<Arc<str>>);
+
+fn wan_route(config: &NicConfig, wan: &str, default_wan: &str, unmapped: UnmappedNic) -> NicRoute {
+    match config.wans.get_key_value(wan) {
+        Some((wan, nic)) => (nic.clone(), Some(wan.clone())),
+        None => unmapped_route(config, default_wan, unmapped),
+    }
+}
+
+fn unmapped_route(config: &NicConfig, default_wan: &str, unmapped: UnmappedNic) -> NicRoute {
+    let nic = match unmapped {
+        UnmappedNic::DefaultWan => config.default_interface(default_wan),
+        UnmappedNic::Unmapped => Arc::from(UNMAPPED_NIC_LABEL),
+        UnmappedNic::Lan => config.lan.clone(),
+    };
+    (nic, None)
+}
+
+// Interface and wan name of the IP. Unmapped IPs and mappings to unknown wan names
+// have no wan name (wan="default") and go to the NIC picked by `unmapped`.
+pub fn get_nic_for_ip(
+    ip: &IpAddr,
+    status: &StatusResponse,
+    default_wan: &str,
+    unmapped: UnmappedNic,
+) -> NicRoute {
+    match status.wan_for_ip(ip) {
+        Some(wan) => wan_route(&status.config, wan, default_wan, unmapped),
+        None => unmapped_route(&status.config, default_wan, unmapped),
+    }
+}
+
+// get_nic_for_ip evaluated for every mapping entry of one StatusResponse
+#[derive(Debug)]
+struct NicTable {
+    exact: HashMap<IpAddr, NicRoute>,
+    prefixes: Vec<(ipnet::IpNet, NicRoute)>,
+    unmapped: NicRoute,
+    lan: Arc<str>,
+}
+
+// Resolves the NIC of accounted packets without the status mutex. The table is
+// rebuilt whenever a refresh installs new mappings, so a lookup is one read lock
+// and a hash lookup with no allocation.
+#[derive(Debug)]
+pub struct NicResolver {
+    default_wan: String,
+    unmapped: UnmappedNic,
+    table: RwLock<NicTable>,
+    // Exact address entries are hits, prefix matches and unmapped IPs misses
+    hits: IntCounter,
+    misses: IntCounter,
+}
+
+impl NicResolver {
+    pub fn new(
+        status: &StatusResponse,
+        default_wan: &str,
+        unmapped: UnmappedNic,
+        metrics: &Metrics,
+    ) -> Self {
+        Self {
+            default_wan: default_wan.to_string(),
+            unmapped,
+            table: RwLock::new(Self::build(status, default_wan, unmapped)),
+            hits: metrics.mapping_cache_lookups.with_label_values(&["hit"]),
+            misses: metrics.mapping_cache_lookups.with_label_values(&["miss"]),
+        }
+    }
+
+    fn build(status: &StatusResponse, default_wan: &str, unmapped: UnmappedNic) -> NicTable {
+        let config = &status.config;
+        let route = |wan: &String| wan_route(config, wan, default_wan, unmapped);
+        NicTable {
+            exact: status
+                .exact
+                .iter()
+                .map(|(ip, wan)| (*ip, route(wan)))
+                .collect(),
+            prefixes: status
+                .prefixes
+                .iter()
+                .map(|(net, wan)| (*net, route(wan)))
+                .collect(),
+            unmapped: unmapped_route(config, default_wan, unmapped),
+            lan: config.lan.clone(),
+        }
+    }
+
+    // Called with every StatusResponse that replaces the current one
+    pub fn install(&self, status: &StatusResponse) {
+        let table = Self::build(status, &self.default_wan, self.unmapped);
+        *self.table.write().unwrap() = table;
+    }
+
+    // Same result as get_nic_for_ip on the installed mappings
+    pub fn resolve(&self, ip: &IpAddr) -> (Arc<str>, Option<Arc<str>>) {
+        let table = self.table.read().unwrap();
+        if let Some(route) = table.exact.get(ip) {
+            self.hits.inc();
+            return route.clone();
+        }
+        self.misses.inc();
+        table
+            .prefixes
+            .iter()
+            .find(|(net, _)| net.contains(ip))
+            .map_or(&table.unmapped, |(_, route)| route)
+            .clone()
+    }
+
+    pub fn lan(&self) -> Arc<str> {
+        self.table.read().unwrap().lan.clone()
+    }
+}
+
+// Each unmapped local IP is logged at most once per interval
+const UNMAPPED_LOG_INTERVAL: Duration = Duration::from_secs(3600);
+// IPs remembered at once; beyond that, new IPs are not logged until entries expire
+const UNMAPPED_LOG_MAX_IPS: usize = 4096;
+
+// Rate-limited log of local IPs missing from the mapping service, so the upstream
+// mappings can be fixed without a log line per packet
+#[derive(Debug, Default)]
+pub struct UnmappedLog {
+    logged: Mutex<HashMap<IpAddr, Instant>>,
+}
+
+impl UnmappedLog {
+    pub fn observe(&self, ip: IpAddr, nic: &str) {
+        let now = Instant::now();
+        let mut logged = self.logged.lock().unwrap();
+        if logged
+            .get(&ip)
+            .is_some_and(|last| now.duration_since(*last) < UNMAPPED_LOG_INTERVAL)
+        {
+            return;
+        }
+        if logged.len() >= UNMAPPED_LOG_MAX_IPS {
+            logged.retain(|_, last| now.duration_since(*last) < UNMAPPED_LOG_INTERVAL);
+            if logged.len() >= UNMAPPED_LOG_MAX_IPS {
+                return;
+            }
+        }
+        logged.insert(ip, now);
+        drop(logged);
+        warn!(ip = %ip, nic, "Local IP has no NIC mapping");
+    }
+}
+
+// Log each wan name that mappings refer to but config does not define, once per name
+pub fn warn_unknown_wans(status: &StatusResponse, default_wan: &str, warned: &mut HashSet<String>) {
+    for wan in status.mappings.values() {
+        if !status.config.wans.contains_key(wan.as_str()) && warned.insert(wan.clone()) {
+            warn!(
+                wan = %wan,
+                default_wan,
+                "NIC mappings refer to an unknown wan, counting it as the default"
+            );
+        }
+    }
+}
+
+// The status service may still be starting, so give it a few tries
+pub async fn fetch_initial_mappings(
+    client: &reqwest::Client,
+    url: &str,
+) -> Result<StatusResponse, Box<dyn std::error::Error>> {
+    let mut attempt = 1;
+    loop {
+        match fetch_nic_mappings(client, url).await {
+            Ok(status) => return Ok(status),
+            Err(e) if attempt < INITIAL_FETCH_ATTEMPTS => {
+                warn!(
+                    url,
+                    attempt,
+                    attempts = INITIAL_FETCH_ATTEMPTS,
+                    error = %e,
+                    "Failed to fetch NIC mappings"
+                );
+                time::sleep(INITIAL_FETCH_RETRY_DELAY).await;
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// Publish the outcome of a mapping fetch to /healthz and the mapping_* metrics;
+// `fetched` is the new mapping, None if the fetch failed
+pub fn record_mapping_fetch(
+    health: &HealthState,
+    metrics: &Metrics,
+    fetched: Option<&StatusResponse>,
+) {
+    health.record_mapping_fetch(fetched.is_some());
+    match fetched {
+        Some(status) => {
+            metrics.mapping_refresh_success.inc();
+            metrics
+                .mapping_last_refresh
+                .set(health.mapping_last_ok_unix.load(Ordering::Relaxed) as f64);
+            metrics
+                .mapping_entries
+                .set((status.exact.len() + status.prefixes.len()) as i64);
+        }
+        None => metrics.mapping_refresh_failures.inc(),
+    }
+}
+
+pub async fn refresh_mappings(
+    status: Arc<Mutex<StatusResponse>>,
+    health: Arc<HealthState>,
+    metrics: Arc<Metrics>,
+    client: reqwest::Client,
+    url: String,
+    resolver: Arc<NicResolver>,
+    // Current config.lan, followed by the LAN capture
+    lan: watch::Sender<Arc<str>>,
+) {
+    let mut interval = time::interval(Duration::from_secs(10));
+    let mut warned = HashSet::new();
+    let default_wan = resolver.default_wan.clone();
+    warn_unknown_wans(&status.lock().unwrap(), &default_wan, &mut warned);
+
+    loop {
+        interval.tick().await;
+        match fetch_nic_mappings(&client, &url).await {
+            Ok(new_status) => {
+                warn_unknown_wans(&new_status, &default_wan, &mut warned);
+                record_mapping_fetch(&health, &metrics, Some(&new_status));
+                resolver.install(&new_status);
+                lan.send_if_modified(|current| {
+                    let renamed = *current != new_status.config.lan;
+                    if renamed {
+                        info!(
+                            from = %current,
+                            to = %new_status.config.lan,
+                            "LAN interface changed"
+                        );
+                        *current = new_status.config.lan.clone();
+                    }
+                    renamed
+                });
+                let mut status_guard = status.lock().unwrap();
+                *status_guard = new_status;
+                info!("Updated NIC mappings");
+            }
+            Err(e) => {
+                record_mapping_fetch(&health, &metrics, None);
+                error!(error = %e, "Failed to fetch NIC mappings");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status(mappings: &[(&str, &str)]) -> StatusResponse {
+        let config = NicConfig {
+            lan: Arc::from("eth0"),
+            wans: [("wan0", "eth1"), ("wan1", "eth2")]
+                .into_iter()
+                .map(|(wan, nic)| (Arc::from(wan), Arc::from(nic)))
+                .collect(),
+        };
+        let mut status = StatusResponse::new(config);
+        status.mappings = mappings
+            .iter()
+            .map(|(key, wan)| (key.to_string(), wan.to_string()))
+            .collect();
+        status.normalize_mappings();
+        status
+    }
+
+    fn resolver(status: &StatusResponse, unmapped: UnmappedNic) -> NicResolver {
+        let metrics = Metrics::new("network", false, false, false, false, false).unwrap();
+        NicResolver::new(status, "wan0", unmapped, &metrics)
+    }
+
+    fn route(nic: &str, wan: Option<&str>) -> NicRoute {
+        (Arc::from(nic), wan.map(Arc::from))
+    }
+
+    fn ip(addr: &str) -> IpAddr {
+        addr.parse().unwrap()
+    }
+
+    #[test]
+    fn exact_entries_win_over_prefixes() {
+        let status = status(&[
+            ("10.40.0.5", "wan1"),
+            ("10.40.0.0/24", "wan0"),
+            ("10.40.0.0/16", "wan1"),
+        ]);
+        let resolver = resolver(&status, UnmappedNic::DefaultWan);
+        assert_eq!(
+            resolver.resolve(&ip("10.40.0.5")),
+            route("eth2", Some("wan1"))
+        );
+        // Longest prefix first
+        assert_eq!(
+            resolver.resolve(&ip("10.40.0.6")),
+            route("eth1", Some("wan0"))
+        );
+        assert_eq!(
+            resolver.resolve(&ip("10.40.1.6")),
+            route("eth2", Some("wan1"))
+        );
+    }
+
+    #[test]
+    fn unknown_wan_falls_back_like_unmapped() {
+        let status = status(&[("10.40.0.5", "wan9"), ("10.40.1.0/24", "wan9")]);
+        for (unmapped, nic) in [
+            (UnmappedNic::DefaultWan, "eth1"),
+            (UnmappedNic::Unmapped, UNMAPPED_NIC_LABEL),
+            (UnmappedNic::Lan, "eth0"),
+        ] {
+            let resolver = resolver(&status, unmapped);
+            assert_eq!(resolver.resolve(&ip("10.40.0.5")), route(nic, None));
+            assert_eq!(resolver.resolve(&ip("10.40.1.1")), route(nic, None));
+            assert_eq!(resolver.resolve(&ip("10.40.2.1")), route(nic, None));
+        }
+    }
+
+    #[test]
+    fn resolver_matches_get_nic_for_ip() {
+        let status = status(&[
+            ("10.40.0.5", "wan1"),
+            ("10.40.0.0/24", "wan0"),
+            ("10.40.2.0/24", "wan9"),
+            ("2001:db8:0:0::1", "wan1"),
+        ]);
+        let resolver = resolver(&status, UnmappedNic::Unmapped);
+        for addr in [
+            "10.40.0.5",
+            "10.40.0.9",
+            "10.40.2.1",
+            "10.40.3.1",
+            "2001:db8::1",
+        ] {
+            let addr = ip(addr);
+            assert_eq!(
+                resolver.resolve(&addr),
+                get_nic_for_ip(&addr, &status, "wan0", UnmappedNic::Unmapped),
+                "{}",
+                addr
+            );
+        }
+    }
+
+    #[test]
+    fn install_replaces_the_table() {
+        let resolver = resolver(&status(&[("10.40.0.5", "wan1")]), UnmappedNic::DefaultWan);
+        resolver.install(&status(&[("10.40.0.5", "wan0")]));
+        assert_eq!(
+            resolver.resolve(&ip("10.40.0.5")),
+            route("eth1", Some("wan0"))
+        );
+    }
+
+    #[test]
+    fn default_wan_missing_from_config_uses_the_first_wan() {
+        let status = status(&[]);
+        assert_eq!(&*status.config.default_interface("wan7"), "eth1");
+        assert_eq!(
+            get_nic_for_ip(&ip("10.40.0.5"), &status, "wan7", UnmappedNic::DefaultWan),
+            route("eth1", None)
+        );
+    }
+}
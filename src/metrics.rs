@@ -0,0 +1,2200 @@
+use crate::config::SharedConfig;
+use crate::health::HealthState;
+use crate::history::IntervalHistory;
+use crate::hostnames::HostnameCache;
+use crate::neighbors::NeighborCache;
+use crate::packet::{port_label, vlan_label, PortBucket};
+use crate::stats::{
+    Direction, FlowKey, PacketSizeMap, SnapshotRequest, TrafficStats, OTHER_ASN_LABEL,
+    OTHER_DOMAIN_LABEL, OTHER_REMOTE_LABEL, OVERFLOW_IP_LABEL, PACKET_SIZE_BUCKETS,
+    UNKNOWN_ASN_LABEL,
+};
+use crate::subnets::Cast;
+use prometheus::{
+    proto, Encoder, Gauge, GaugeVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio::time;
+use tracing::{error, info};
+
+// Prometheus metrics of the exporter, registered in their own registry
+#[derive(Debug, Clone)]
+pub struct Metrics {
+    pub registry: Registry,
+    started: std::time::Instant,
+    pub build_info: GaugeVec,
+    pub start_time: Gauge,
+    pub uptime: Gauge,
+    pub tokio_workers: IntGauge,
+    pub tokio_alive_tasks: IntGauge,
+    pub tokio_global_queue_depth: IntGauge,
+    pub tokio_blocking_tasks: IntGauge,
+    pub traffic_stats_entries: IntGaugeVec,
+    pub ip_tx_bps: GaugeVec,
+    pub ip_rx_bps: GaugeVec,
+    pub total_tx_bps: GaugeVec,
+    pub total_rx_bps: GaugeVec,
+    pub ip_tx_pps: GaugeVec,
+    pub ip_rx_pps: GaugeVec,
+    pub total_tx_pps: GaugeVec,
+    pub total_rx_pps: GaugeVec,
+    pub peak_tx_bps: GaugeVec,
+    pub peak_rx_bps: GaugeVec,
+    pub nic_tx_utilization: GaugeVec,
+    pub nic_rx_utilization: GaugeVec,
+    pub new_devices: IntCounter,
+    pub dns_queries: IntCounterVec,
+    pub ip_tx_bytes: IntCounterVec,
+    pub ip_rx_bytes: IntCounterVec,
+    pub ip_tx_bps_by_proto: GaugeVec,
+    pub ip_rx_bps_by_proto: GaugeVec,
+    pub ip_tx_bps_by_port: GaugeVec,
+    pub ip_rx_bps_by_port: GaugeVec,
+    pub ip_tx_bps_by_dscp: GaugeVec,
+    pub ip_rx_bps_by_dscp: GaugeVec,
+    pub ip_remote_peers: GaugeVec,
+    pub ip_remote_bps: GaugeVec,
+    pub ip_tcp_syn_pps: GaugeVec,
+    pub ip_tcp_synack_pps: GaugeVec,
+    pub ip_tcp_rst_pps: GaugeVec,
+    pub ip_tcp_fin_pps: GaugeVec,
+    pub ip_icmp_pps: GaugeVec,
+    pub ip_fragments_pps: GaugeVec,
+    pub internal_tx_bps: GaugeVec,
+    pub internal_rx_bps: GaugeVec,
+    pub ip_cast_tx_bps: GaugeVec,
+    pub multicast_bps: GaugeVec,
+    pub broadcast_bps: GaugeVec,
+    pub arp_pps: GaugeVec,
+    pub arp_gratuitous: IntCounterVec,
+    pub capture_tx_bps: GaugeVec,
+    pub capture_rx_bps: GaugeVec,
+    pub capture_running: IntGaugeVec,
+    pub capture_errors: IntCounterVec,
+    pub capture_malformed_packets: IntCounterVec,
+    pub capture_malformed_tunnel_packets: IntCounterVec,
+    pub capture_pppoe_frames: IntCounterVec,
+    pub capture_other_ethertype_packets: IntCounterVec,
+    pub dns_malformed_queries: IntCounterVec,
+    pub capture_sample_rate: IntGauge,
+    pub ips_overflowed: IntCounter,
+    pub unmapped_bytes: IntCounterVec,
+    pub records_dropped: IntCounter,
+    pub config_reloads: IntCounterVec,
+    pub dump_frames_dropped: IntCounter,
+    pub otlp_export_failures: IntCounter,
+    pub influx_write_failures: IntCounter,
+    pub usage_db_write_failures: IntCounter,
+    pub statsd_send_errors: IntCounter,
+    pub graphite_lines_dropped: IntCounter,
+    pub alert_active: IntGaugeVec,
+    pub alert_webhook_failures: IntCounter,
+    pub mapping_refresh_success: IntCounter,
+    pub mapping_refresh_failures: IntCounter,
+    pub mapping_last_refresh: Gauge,
+    pub mapping_entries: IntGauge,
+    pub mapping_cache_lookups: IntCounterVec,
+    pub pcap_received: IntCounterVec,
+    pub pcap_dropped: IntCounterVec,
+    pub pcap_if_dropped: IntCounterVec,
+    pub vlan_tx_bps: GaugeVec,
+    pub vlan_rx_bps: GaugeVec,
+    pub vni_tx_bps: GaugeVec,
+    pub vni_rx_bps: GaugeVec,
+    pub mpls_tx_bps: GaugeVec,
+    pub mpls_rx_bps: GaugeVec,
+    pub dscp_tx_bps: GaugeVec,
+    pub country_tx_bps: GaugeVec,
+    pub country_rx_bps: GaugeVec,
+    pub asn_tx_bps: GaugeVec,
+    pub asn_rx_bps: GaugeVec,
+    pub domain_bps: GaugeVec,
+    pub dscp_rx_bps: GaugeVec,
+    pub packet_sizes: PacketSizeHistogram,
+    // Per-IP WAN families and the NIC totals carry a wan label
+    wan_labels: bool,
+}
+
+impl Metrics {
+    // The traffic families are named <namespace>_..., "network" unless configured.
+    // With `hostnames` every per-IP family gets a trailing hostname label, with
+    // `macs` a mac label after it. With `wans` the per-IP WAN families get a wan
+    // label after nic and the NIC totals are split by wan.
+    pub fn new(
+        namespace: &str,
+        vlan_metrics: bool,
+        dscp_metrics: bool,
+        hostnames: bool,
+        macs: bool,
+        wans: bool,
+    ) -> prometheus::Result<Self> {
+        let namespace = namespace.trim_end_matches('_');
+        let ns_opts = |name: &str, help: &str| Opts::new(name, help).namespace(namespace);
+        let ip_labels = |labels: &[&'static str]| {
+            let mut labels = labels.to_vec();
+            if hostnames {
+                labels.push("hostname");
+            }
+            if macs {
+                labels.push("mac");
+            }
+            labels
+        };
+        let wan_ip_labels = |labels: &[&'static str]| {
+            let mut labels = labels.to_vec();
+            if wans {
+                labels.insert(2, "wan");
+            }
+            ip_labels(&labels)
+        };
+        let total_labels: &[&str] = if wans { &["nic", "wan"] } else { &["nic"] };
+        let build_info = GaugeVec::new(
+            Opts::new(
+                "localpacketdump_build_info",
+                "Always 1, labeled with the version, git commit and compiler of this build",
+            ),
+            &["version", "git", "rustc"],
+        )?;
+        let start_time = Gauge::new(
+            "localpacketdump_start_time_seconds",
+            "Unix time the exporter started",
+        )?;
+        let uptime = Gauge::new(
+            "localpacketdump_uptime_seconds",
+            "Seconds since the exporter started",
+        )?;
+        let tokio_workers = IntGauge::new(
+            "localpacketdump_tokio_workers",
+            "Worker threads of the tokio runtime",
+        )?;
+        let tokio_alive_tasks = IntGauge::new(
+            "localpacketdump_tokio_alive_tasks",
+            "Tasks alive in the tokio runtime",
+        )?;
+        let tokio_global_queue_depth = IntGauge::new(
+            "localpacketdump_tokio_global_queue_depth",
+            "Tasks waiting in the global queue of the tokio runtime",
+        )?;
+        let tokio_blocking_tasks = IntGauge::new(
+            "localpacketdump_tokio_blocking_tasks",
+            "spawn_blocking tasks queued or running, including one per capture",
+        )?;
+        let traffic_stats_entries = IntGaugeVec::new(
+            Opts::new(
+                "localpacketdump_traffic_stats_entries",
+                "Entries in the aggregator maps at the end of the last interval, before max_tracked_ips folding",
+            ),
+            &["map"],
+        )?;
+        start_time.set(
+            SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_secs_f64())
+                .unwrap_or(0.0),
+        );
+        let ip_tx_bps = GaugeVec::new(
+            ns_opts(
+                "ip_tx_bps",
+                "TX bits per second per IP, counted per count_mode",
+            ),
+            &wan_ip_labels(&["local_ip", "nic"]),
+        )?;
+        let ip_rx_bps = GaugeVec::new(
+            ns_opts(
+                "ip_rx_bps",
+                "RX bits per second per IP, counted per count_mode",
+            ),
+            &wan_ip_labels(&["local_ip", "nic"]),
+        )?;
+        let total_tx_bps = GaugeVec::new(
+            ns_opts(
+                "ip_tx_bps_total",
+                "Total TX bits per second per NIC, counted per count_mode",
+            ),
+            total_labels,
+        )?;
+        let total_rx_bps = GaugeVec::new(
+            ns_opts(
+                "ip_rx_bps_total",
+                "Total RX bits per second per NIC, counted per count_mode",
+            ),
+            total_labels,
+        )?;
+        let ip_tx_pps = GaugeVec::new(
+            ns_opts("ip_tx_pps", "TX packets per second per IP"),
+            &wan_ip_labels(&["local_ip", "nic"]),
+        )?;
+        let ip_rx_pps = GaugeVec::new(
+            ns_opts("ip_rx_pps", "RX packets per second per IP"),
+            &wan_ip_labels(&["local_ip", "nic"]),
+        )?;
+        let total_tx_pps = GaugeVec::new(
+            ns_opts("ip_tx_pps_total", "Total TX packets per second per NIC"),
+            total_labels,
+        )?;
+        let total_rx_pps = GaugeVec::new(
+            ns_opts("ip_rx_pps_total", "Total RX packets per second per NIC"),
+            total_labels,
+        )?;
+        let peak_tx_bps = GaugeVec::new(
+            ns_opts(
+                "ip_tx_bps_peak",
+                "TX bits per second per NIC in the busiest peak_bucket_ms bucket of the interval",
+            ),
+            &["nic"],
+        )?;
+        let peak_rx_bps = GaugeVec::new(
+            ns_opts(
+                "ip_rx_bps_peak",
+                "RX bits per second per NIC in the busiest peak_bucket_ms bucket of the interval",
+            ),
+            &["nic"],
+        )?;
+        let nic_tx_utilization = GaugeVec::new(
+            ns_opts(
+                "nic_tx_utilization_ratio",
+                "TX bits per second per NIC divided by its link speed, omitted for NICs without a known speed",
+            ),
+            &["nic"],
+        )?;
+        let nic_rx_utilization = GaugeVec::new(
+            ns_opts(
+                "nic_rx_utilization_ratio",
+                "RX bits per second per NIC divided by its link speed, omitted for NICs without a known speed",
+            ),
+            &["nic"],
+        )?;
+        let new_devices = IntCounter::with_opts(ns_opts(
+            "new_devices_total",
+            "Local IPs seen for the first time, since the first start with devices_file",
+        ))?;
+        let dns_queries = IntCounterVec::new(
+            ns_opts(
+                "dns_queries_total",
+                "DNS queries sent over UDP by each local IP, with dns_queries enabled",
+            ),
+            &["local_ip"],
+        )?;
+        let ip_tx_bytes = IntCounterVec::new(
+            ns_opts(
+                "ip_tx_bytes_total",
+                "Total TX bytes per IP, counted per count_mode",
+            ),
+            &wan_ip_labels(&["local_ip", "nic"]),
+        )?;
+        let ip_rx_bytes = IntCounterVec::new(
+            ns_opts(
+                "ip_rx_bytes_total",
+                "Total RX bytes per IP, counted per count_mode",
+            ),
+            &wan_ip_labels(&["local_ip", "nic"]),
+        )?;
+        let ip_tx_bps_by_proto = GaugeVec::new(
+            ns_opts(
+                "ip_tx_bps_by_proto",
+                "TX bits per second per IP and protocol, counted per count_mode",
+            ),
+            &wan_ip_labels(&["local_ip", "nic", "proto"]),
+        )?;
+        let ip_rx_bps_by_proto = GaugeVec::new(
+            ns_opts(
+                "ip_rx_bps_by_proto",
+                "RX bits per second per IP and protocol, counted per count_mode",
+            ),
+            &wan_ip_labels(&["local_ip", "nic", "proto"]),
+        )?;
+        let ip_tx_bps_by_port = GaugeVec::new(
+            ns_opts(
+                "ip_tx_bps_by_port",
+                "TX bits per second per IP and tracked TCP/UDP port, counted per count_mode",
+            ),
+            &wan_ip_labels(&["local_ip", "nic", "port"]),
+        )?;
+        let ip_rx_bps_by_port = GaugeVec::new(
+            ns_opts(
+                "ip_rx_bps_by_port",
+                "RX bits per second per IP and tracked TCP/UDP port, counted per count_mode",
+            ),
+            &wan_ip_labels(&["local_ip", "nic", "port"]),
+        )?;
+        let ip_remote_peers = GaugeVec::new(
+            ns_opts(
+                "ip_remote_peers",
+                "Distinct remote IPs per local IP and direction in the last interval, estimated above 256",
+            ),
+            &wan_ip_labels(&["local_ip", "nic", "direction"]),
+        )?;
+        let ip_remote_bps = GaugeVec::new(
+            ns_opts(
+                "ip_remote_bps",
+                "Bits per second per remote IP of the local IPs in track_remotes_for, counted per count_mode",
+            ),
+            &["local_ip", "remote_ip", "direction"],
+        )?;
+        let ip_icmp_pps = GaugeVec::new(
+            ns_opts(
+                "ip_icmp_pps",
+                "ICMP and ICMPv6 packets per second per IP and message type, both directions",
+            ),
+            &wan_ip_labels(&["local_ip", "nic", "type"]),
+        )?;
+        let ip_fragments_pps = GaugeVec::new(
+            ns_opts(
+                "ip_fragments_pps",
+                "IPv4 and IPv6 fragments per second per IP and direction, the first fragment included",
+            ),
+            &wan_ip_labels(&["local_ip", "nic", "direction"]),
+        )?;
+        let tcp_flag_gauge = |name: &str, help: &str| {
+            GaugeVec::new(
+                ns_opts(name, help),
+                &wan_ip_labels(&["local_ip", "nic", "direction"]),
+            )
+        };
+        let ip_tcp_syn_pps = tcp_flag_gauge(
+            "ip_tcp_syn_pps",
+            "TCP packets per second with SYN and without ACK (connection attempts) per IP",
+        )?;
+        let ip_tcp_synack_pps = tcp_flag_gauge(
+            "ip_tcp_synack_pps",
+            "TCP packets per second with SYN and ACK per IP",
+        )?;
+        let ip_tcp_rst_pps =
+            tcp_flag_gauge("ip_tcp_rst_pps", "TCP packets per second with RST per IP")?;
+        let ip_tcp_fin_pps =
+            tcp_flag_gauge("ip_tcp_fin_pps", "TCP packets per second with FIN per IP")?;
+        let internal_tx_bps = GaugeVec::new(
+            ns_opts(
+                "ip_internal_tx_bps",
+                "TX bits per second per IP to other local IPs, counted per count_mode",
+            ),
+            &ip_labels(&["local_ip", "nic"]),
+        )?;
+        let internal_rx_bps = GaugeVec::new(
+            ns_opts(
+                "ip_internal_rx_bps",
+                "RX bits per second per IP from other local IPs, counted per count_mode",
+            ),
+            &ip_labels(&["local_ip", "nic"]),
+        )?;
+        let ip_cast_tx_bps = GaugeVec::new(
+            ns_opts(
+                "ip_cast_tx_bps",
+                "Multicast and broadcast TX bits per second per local sender, counted per count_mode",
+            ),
+            &ip_labels(&["local_ip", "nic", "cast"]),
+        )?;
+        let multicast_bps = GaugeVec::new(
+            ns_opts(
+                "multicast_bps",
+                "Bits per second to multicast addresses per NIC, tx from local senders, counted per count_mode",
+            ),
+            &["nic", "direction"],
+        )?;
+        let broadcast_bps = GaugeVec::new(
+            ns_opts(
+                "broadcast_bps",
+                "Bits per second to 255.255.255.255 and local subnet broadcast addresses per NIC, counted per count_mode",
+            ),
+            &["nic"],
+        )?;
+        let arp_pps = GaugeVec::new(
+            ns_opts(
+                "arp_pps",
+                "ARP packets per second of the primary captures per interface and operation",
+            ),
+            &["nic", "op"],
+        )?;
+        let arp_gratuitous = IntCounterVec::new(
+            ns_opts(
+                "arp_gratuitous_total",
+                "Gratuitous ARP requests and replies of the primary captures per local sender IP",
+            ),
+            &["sender_ip"],
+        )?;
+        let capture_tx_bps = GaugeVec::new(ns_opts("capture_tx_bps", "Bits per second sent by this host on the capture interface, counted as captured frame length"), &["capture"])?;
+        let capture_rx_bps = GaugeVec::new(ns_opts("capture_rx_bps", "Bits per second received by this host on the capture interface, counted as captured frame length"), &["capture"])?;
+        let capture_running = IntGaugeVec::new(
+            Opts::new(
+                "capture_running",
+                "1 while the capture on this interface is active, 0 while waiting for the device",
+            ),
+            &["nic"],
+        )?;
+        let capture_errors = IntCounterVec::new(
+            Opts::new(
+                "capture_errors_total",
+                "Errors returned by the pcap handle while reading packets, excluding timeouts",
+            ),
+            &["kind"],
+        )?;
+        let config_reloads = IntCounterVec::new(
+            Opts::new(
+                "config_reloads_total",
+                "Config reloads on SIGHUP, result=failure when the new config was rejected",
+            ),
+            &["result"],
+        )?;
+        let capture_malformed_packets = IntCounterVec::new(
+            Opts::new(
+                "capture_malformed_packets_total",
+                "Frames of the primary capture not accounted because their headers are truncated or inconsistent",
+            ),
+            &["nic", "reason"],
+        )?;
+        let capture_malformed_tunnel_packets = IntCounterVec::new(
+            Opts::new(
+                "capture_malformed_tunnel_packets_total",
+                "GRE, IPIP and VXLAN packets whose encapsulated packet is truncated or inconsistent, not accounted by their inner addresses",
+            ),
+            &["nic", "reason"],
+        )?;
+        let capture_pppoe_frames = IntCounterVec::new(
+            Opts::new(
+                "capture_pppoe_frames_total",
+                "PPPoE session frames of the primary captures accounted by the IPv4 or IPv6 packet they carry",
+            ),
+            &["nic"],
+        )?;
+        let capture_other_ethertype_packets = IntCounterVec::new(
+            Opts::new(
+                "capture_other_ethertype_packets_total",
+                "Frames of the primary capture not accounted because they carry neither IPv4 nor IPv6",
+            ),
+            &["ethertype"],
+        )?;
+        let dns_malformed_queries = IntCounterVec::new(
+            Opts::new(
+                "dns_malformed_queries_total",
+                "UDP packets to port 53 from local IPs skipped because they are not a well-formed DNS query",
+            ),
+            &["reason"],
+        )?;
+        let capture_sample_rate = IntGauge::new(
+            "capture_sample_rate",
+            "N of --sample 1/N; above 1 the traffic metrics are estimates scaled from every Nth frame",
+        )?;
+        let ips_overflowed = IntCounter::new(
+            "traffic_ips_overflowed_total",
+            "Per-IP flows folded into local_ip=\"other\" because max_tracked_ips was exceeded",
+        )?;
+        let unmapped_bytes = IntCounterVec::new(
+            Opts::new(
+                "traffic_unmapped_bytes_total",
+                "Bytes of local IPs without a mapping to a known wan, counted on the unmapped_nic fallback",
+            ),
+            &["direction"],
+        )?;
+        let records_dropped = IntCounter::new(
+            "capture_records_dropped_total",
+            "Packet records dropped because the aggregator channel was full",
+        )?;
+        let dump_frames_dropped = IntCounter::new(
+            "dump_frames_dropped_total",
+            "Frames not written to the --dump-dir pcap files because the writer fell behind or failed",
+        )?;
+        let otlp_export_failures = IntCounter::new(
+            "otlp_export_failures_total",
+            "Failed pushes to the OTLP metrics endpoint",
+        )?;
+        let influx_write_failures = IntCounter::new(
+            "influx_write_failures_total",
+            "Failed line protocol writes to InfluxDB",
+        )?;
+        let statsd_send_errors = IntCounter::new(
+            "statsd_send_errors_total",
+            "statsd datagrams that could not be sent",
+        )?;
+        let graphite_lines_dropped = IntCounter::new(
+            "graphite_lines_dropped_total",
+            "Graphite lines dropped because the writer fell behind or graphite_buffer_lines was exceeded while disconnected",
+        )?;
+        let usage_db_write_failures = IntCounter::new(
+            "usage_db_write_failures_total",
+            "Failed transactions writing per-IP usage to the usage_db SQLite file",
+        )?;
+        let alert_active = IntGaugeVec::new(
+            Opts::new(
+                "alert_active",
+                "1 while a local IP violates an alert rule; removed when it recovers",
+            ),
+            &["rule", "local_ip"],
+        )?;
+        let alert_webhook_failures = IntCounter::new(
+            "alert_webhook_failures_total",
+            "Alert notifications that could not be delivered to their webhook",
+        )?;
+        let mapping_refresh_success = IntCounter::new(
+            "mapping_refresh_success_total",
+            "Successful NIC mapping fetches from the status service",
+        )?;
+        let mapping_refresh_failures = IntCounter::new(
+            "mapping_refresh_failures_total",
+            "Failed NIC mapping fetches from the status service",
+        )?;
+        let mapping_last_refresh = Gauge::new(
+            "mapping_last_refresh_timestamp_seconds",
+            "Unix time of the last successful NIC mapping fetch",
+        )?;
+        let mapping_entries = IntGauge::new(
+            "mapping_entries",
+            "IP and CIDR entries in the NIC mappings in use",
+        )?;
+        let mapping_cache_lookups = IntCounterVec::new(
+            Opts::new(
+                "mapping_cache_lookups_total",
+                "NIC lookups of accounted packets; hit for an exact address entry, miss for a prefix match or an unmapped IP",
+            ),
+            &["result"],
+        )?;
+        let pcap_received = IntCounterVec::new(
+            Opts::new(
+                "pcap_packets_received_total",
+                "Packets received by the pcap handle",
+            ),
+            &["interface"],
+        )?;
+        let pcap_dropped = IntCounterVec::new(
+            Opts::new(
+                "pcap_packets_dropped_total",
+                "Packets dropped because the capture buffer was full",
+            ),
+            &["interface"],
+        )?;
+        let pcap_if_dropped = IntCounterVec::new(
+            Opts::new(
+                "pcap_packets_if_dropped_total",
+                "Packets dropped by the network interface or its driver",
+            ),
+            &["interface"],
+        )?;
+        let vlan_tx_bps = GaugeVec::new(
+            ns_opts(
+                "vlan_tx_bps",
+                "TX bits per second per VLAN, counted per count_mode",
+            ),
+            &["vlan", "nic"],
+        )?;
+        let vlan_rx_bps = GaugeVec::new(
+            ns_opts(
+                "vlan_rx_bps",
+                "RX bits per second per VLAN, counted per count_mode",
+            ),
+            &["vlan", "nic"],
+        )?;
+        let vni_tx_bps = GaugeVec::new(
+            ns_opts(
+                "vni_tx_bps",
+                "TX bits per second per NIC and VXLAN network identifier, counted per count_mode",
+            ),
+            &["vni", "nic"],
+        )?;
+        let vni_rx_bps = GaugeVec::new(
+            ns_opts(
+                "vni_rx_bps",
+                "RX bits per second per NIC and VXLAN network identifier, counted per count_mode",
+            ),
+            &["vni", "nic"],
+        )?;
+        let mpls_tx_bps = GaugeVec::new(
+            ns_opts(
+                "mpls_tx_bps",
+                "TX bits per second per NIC and top MPLS label, counted per count_mode",
+            ),
+            &["label", "nic"],
+        )?;
+        let mpls_rx_bps = GaugeVec::new(
+            ns_opts(
+                "mpls_rx_bps",
+                "RX bits per second per NIC and top MPLS label, counted per count_mode",
+            ),
+            &["label", "nic"],
+        )?;
+        let ip_tx_bps_by_dscp = GaugeVec::new(
+            ns_opts(
+                "ip_tx_bps_by_dscp",
+                "TX bits per second per IP and DSCP class, counted per count_mode",
+            ),
+            &wan_ip_labels(&["local_ip", "nic", "dscp"]),
+        )?;
+        let ip_rx_bps_by_dscp = GaugeVec::new(
+            ns_opts(
+                "ip_rx_bps_by_dscp",
+                "RX bits per second per IP and DSCP class, counted per count_mode",
+            ),
+            &wan_ip_labels(&["local_ip", "nic", "dscp"]),
+        )?;
+        let dscp_tx_bps = GaugeVec::new(
+            ns_opts(
+                "dscp_tx_bps",
+                "TX bits per second per NIC and DSCP class, counted per count_mode",
+            ),
+            &["dscp", "nic"],
+        )?;
+        let dscp_rx_bps = GaugeVec::new(
+            ns_opts(
+                "dscp_rx_bps",
+                "RX bits per second per NIC and DSCP class, counted per count_mode",
+            ),
+            &["dscp", "nic"],
+        )?;
+        let country_tx_bps = GaugeVec::new(
+            ns_opts(
+                "country_tx_bps",
+                "TX bits per second per NIC and country of the remote IP, counted per count_mode",
+            ),
+            &["country", "nic"],
+        )?;
+        let country_rx_bps = GaugeVec::new(
+            ns_opts(
+                "country_rx_bps",
+                "RX bits per second per NIC and country of the remote IP, counted per count_mode",
+            ),
+            &["country", "nic"],
+        )?;
+        let asn_tx_bps = GaugeVec::new(
+            ns_opts(
+                "asn_tx_bps",
+                "TX bits per second per NIC and AS of the remote IP, counted per count_mode",
+            ),
+            &["asn", "as_org", "nic"],
+        )?;
+        let asn_rx_bps = GaugeVec::new(
+            ns_opts(
+                "asn_rx_bps",
+                "RX bits per second per NIC and AS of the remote IP, counted per count_mode",
+            ),
+            &["asn", "as_org", "nic"],
+        )?;
+        let domain_bps = GaugeVec::new(
+            ns_opts(
+                "domain_bps",
+                "Bits per second of WAN connections to TCP port 443 per TLS server name, counted per count_mode",
+            ),
+            &["domain", "direction"],
+        )?;
+        let packet_sizes = PacketSizeHistogram::new(ns_opts(
+            "packet_size_bytes",
+            "Frame length of accounted packets per NIC and direction",
+        ))?;
+
+        let registry = Registry::new();
+        let collectors: Vec<Box<dyn prometheus::core::Collector>> = vec![
+            Box::new(build_info.clone()),
+            Box::new(start_time.clone()),
+            Box::new(uptime.clone()),
+            Box::new(tokio_workers.clone()),
+            Box::new(tokio_alive_tasks.clone()),
+            Box::new(tokio_global_queue_depth.clone()),
+            Box::new(tokio_blocking_tasks.clone()),
+            Box::new(traffic_stats_entries.clone()),
+            Box::new(ip_tx_bps.clone()),
+            Box::new(ip_rx_bps.clone()),
+            Box::new(total_tx_bps.clone()),
+            Box::new(total_rx_bps.clone()),
+            Box::new(ip_tx_pps.clone()),
+            Box::new(ip_rx_pps.clone()),
+            Box::new(total_tx_pps.clone()),
+            Box::new(total_rx_pps.clone()),
+            Box::new(peak_tx_bps.clone()),
+            Box::new(peak_rx_bps.clone()),
+            Box::new(nic_tx_utilization.clone()),
+            Box::new(nic_rx_utilization.clone()),
+            Box::new(new_devices.clone()),
+            Box::new(dns_queries.clone()),
+            Box::new(ip_tx_bytes.clone()),
+            Box::new(ip_rx_bytes.clone()),
+            Box::new(ip_tx_bps_by_proto.clone()),
+            Box::new(ip_rx_bps_by_proto.clone()),
+            Box::new(ip_tx_bps_by_port.clone()),
+            Box::new(ip_rx_bps_by_port.clone()),
+            Box::new(ip_remote_peers.clone()),
+            Box::new(ip_remote_bps.clone()),
+            Box::new(country_tx_bps.clone()),
+            Box::new(country_rx_bps.clone()),
+            Box::new(asn_tx_bps.clone()),
+            Box::new(asn_rx_bps.clone()),
+            Box::new(domain_bps.clone()),
+            Box::new(vni_tx_bps.clone()),
+            Box::new(vni_rx_bps.clone()),
+            Box::new(mpls_tx_bps.clone()),
+            Box::new(mpls_rx_bps.clone()),
+            Box::new(ip_tcp_syn_pps.clone()),
+            Box::new(ip_tcp_synack_pps.clone()),
+            Box::new(ip_tcp_rst_pps.clone()),
+            Box::new(ip_tcp_fin_pps.clone()),
+            Box::new(ip_icmp_pps.clone()),
+            Box::new(ip_fragments_pps.clone()),
+            Box::new(internal_tx_bps.clone()),
+            Box::new(internal_rx_bps.clone()),
+            Box::new(ip_cast_tx_bps.clone()),
+            Box::new(multicast_bps.clone()),
+            Box::new(broadcast_bps.clone()),
+            Box::new(arp_pps.clone()),
+            Box::new(arp_gratuitous.clone()),
+            Box::new(capture_tx_bps.clone()),
+            Box::new(capture_rx_bps.clone()),
+            Box::new(capture_running.clone()),
+            Box::new(capture_errors.clone()),
+            Box::new(capture_malformed_packets.clone()),
+            Box::new(capture_malformed_tunnel_packets.clone()),
+            Box::new(capture_pppoe_frames.clone()),
+            Box::new(capture_other_ethertype_packets.clone()),
+            Box::new(dns_malformed_queries.clone()),
+            Box::new(capture_sample_rate.clone()),
+            Box::new(ips_overflowed.clone()),
+            Box::new(unmapped_bytes.clone()),
+            Box::new(records_dropped.clone()),
+            Box::new(config_reloads.clone()),
+            Box::new(dump_frames_dropped.clone()),
+            Box::new(otlp_export_failures.clone()),
+            Box::new(influx_write_failures.clone()),
+            Box::new(usage_db_write_failures.clone()),
+            Box::new(statsd_send_errors.clone()),
+            Box::new(graphite_lines_dropped.clone()),
+            Box::new(alert_active.clone()),
+            Box::new(alert_webhook_failures.clone()),
+            Box::new(mapping_refresh_success.clone()),
+            Box::new(mapping_refresh_failures.clone()),
+            Box::new(mapping_last_refresh.clone()),
+            Box::new(mapping_entries.clone()),
+            Box::new(mapping_cache_lookups.clone()),
+            Box::new(pcap_received.clone()),
+            Box::new(pcap_dropped.clone()),
+            Box::new(pcap_if_dropped.clone()),
+            Box::new(packet_sizes.clone()),
+        ];
+        for collector in collectors {
+            registry.register(collector)?;
+        }
+        if vlan_metrics {
+            registry.register(Box::new(vlan_tx_bps.clone()))?;
+            registry.register(Box::new(vlan_rx_bps.clone()))?;
+        }
+        if dscp_metrics {
+            registry.register(Box::new(ip_tx_bps_by_dscp.clone()))?;
+            registry.register(Box::new(ip_rx_bps_by_dscp.clone()))?;
+            registry.register(Box::new(dscp_tx_bps.clone()))?;
+            registry.register(Box::new(dscp_rx_bps.clone()))?;
+        }
+        // CPU seconds, resident memory and open file descriptors from /proc/self
+        #[cfg(target_os = "linux")]
+        registry.register(Box::new(
+            prometheus::process_collector::ProcessCollector::for_self(),
+        ))?;
+
+        Ok(Self {
+            registry,
+            started: std::time::Instant::now(),
+            build_info,
+            start_time,
+            uptime,
+            tokio_workers,
+            tokio_alive_tasks,
+            tokio_global_queue_depth,
+            tokio_blocking_tasks,
+            traffic_stats_entries,
+            ip_tx_bps,
+            ip_rx_bps,
+            total_tx_bps,
+            total_rx_bps,
+            ip_tx_pps,
+            ip_rx_pps,
+            total_tx_pps,
+            total_rx_pps,
+            peak_tx_bps,
+            peak_rx_bps,
+            nic_tx_utilization,
+            nic_rx_utilization,
+            new_devices,
+            dns_queries,
+            ip_tx_bytes,
+            ip_rx_bytes,
+            ip_tx_bps_by_proto,
+            ip_rx_bps_by_proto,
+            ip_tx_bps_by_port,
+            ip_rx_bps_by_port,
+            ip_tx_bps_by_dscp,
+            ip_rx_bps_by_dscp,
+            ip_remote_peers,
+            ip_remote_bps,
+            ip_tcp_syn_pps,
+            ip_tcp_synack_pps,
+            ip_tcp_rst_pps,
+            ip_tcp_fin_pps,
+            ip_icmp_pps,
+            ip_fragments_pps,
+            internal_tx_bps,
+            internal_rx_bps,
+            ip_cast_tx_bps,
+            multicast_bps,
+            broadcast_bps,
+            arp_pps,
+            arp_gratuitous,
+            capture_tx_bps,
+            capture_rx_bps,
+            capture_running,
+            capture_errors,
+            capture_malformed_packets,
+            capture_malformed_tunnel_packets,
+            capture_pppoe_frames,
+            capture_other_ethertype_packets,
+            dns_malformed_queries,
+            capture_sample_rate,
+            ips_overflowed,
+            unmapped_bytes,
+            records_dropped,
+            config_reloads,
+            dump_frames_dropped,
+            otlp_export_failures,
+            influx_write_failures,
+            usage_db_write_failures,
+            statsd_send_errors,
+            graphite_lines_dropped,
+            alert_active,
+            alert_webhook_failures,
+            mapping_refresh_success,
+            mapping_refresh_failures,
+            mapping_last_refresh,
+            mapping_entries,
+            mapping_cache_lookups,
+            pcap_received,
+            pcap_dropped,
+            pcap_if_dropped,
+            vlan_tx_bps,
+            vlan_rx_bps,
+            vni_tx_bps,
+            vni_rx_bps,
+            mpls_tx_bps,
+            mpls_rx_bps,
+            dscp_tx_bps,
+            country_tx_bps,
+            country_rx_bps,
+            asn_tx_bps,
+            asn_rx_bps,
+            domain_bps,
+            dscp_rx_bps,
+            packet_sizes,
+            wan_labels: wans,
+        })
+    }
+
+    // git and rustc come from build.rs
+    pub fn set_build_info(&self, version: &str) {
+        self.build_info
+            .with_label_values(&[
+                version,
+                env!("LOCALPACKETDUMP_GIT_HASH"),
+                env!("LOCALPACKETDUMP_RUSTC_VERSION"),
+            ])
+            .set(1.0);
+    }
+
+    // Gauges sampled at scrape time rather than on every flush
+    fn refresh_self_metrics(&self) {
+        self.uptime.set(self.started.elapsed().as_secs_f64());
+        if let Ok(handle) = tokio::runtime::Handle::try_current() {
+            let runtime = handle.metrics();
+            self.tokio_workers.set(runtime.num_workers() as i64);
+            self.tokio_alive_tasks.set(runtime.num_alive_tasks() as i64);
+            self.tokio_global_queue_depth
+                .set(runtime.global_queue_depth() as i64);
+        }
+        self.tokio_blocking_tasks
+            .set(crate::runtime::blocking_tasks());
+    }
+
+    // Text exposition of everything registered
+    pub fn encode(&self) -> Result<String, Box<dyn std::error::Error>> {
+        self.refresh_self_metrics();
+        let mut buffer = vec![];
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(String::from_utf8(buffer)?)
+    }
+
+    // OpenMetrics 1.0 text, derived from the Prometheus text format one family at a
+    // time: counters are named without the _total suffix their samples carry, help
+    // texts also escape double quotes, and the exposition ends with # EOF.
+    pub fn encode_openmetrics(&self) -> Result<String, Box<dyn std::error::Error>> {
+        self.refresh_self_metrics();
+        let encoder = TextEncoder::new();
+        let mut output = String::new();
+        for mut family in self.registry.gather() {
+            let counter = family.get_field_type() == proto::MetricType::COUNTER;
+            let name = family.get_name().to_string();
+            let base = name.strip_suffix("_total").unwrap_or(&name).to_string();
+            if counter {
+                family.set_name(format!("{}_total", base));
+            }
+            let mut buffer = vec![];
+            encoder.encode(&[family], &mut buffer)?;
+            for line in String::from_utf8(buffer)?.lines() {
+                if let Some(rest) = line.strip_prefix("# HELP ") {
+                    let help = rest.split_once(' ').map_or("", |(_, help)| help);
+                    let name = if counter { &base } else { &name };
+                    output.push_str(&format!("# HELP {} {}\n", name, help.replace('"', "\\\"")));
+                } else if counter && line.starts_with("# TYPE ") {
+                    output.push_str(&format!("# TYPE {} counter\n", base));
+                } else if let Some(untyped) = line.strip_suffix(" untyped") {
+                    output.push_str(&format!("{} unknown\n", untyped));
+                } else {
+                    output.push_str(line);
+                    output.push('\n');
+                }
+            }
+        }
+        output.push_str("# EOF\n");
+        Ok(output)
+    }
+}
+
+// network_packet_size_bytes{nic,direction}. Fed with the pre-bucketed counts of each
+// interval, since a HistogramVec can only take one observation at a time.
+#[derive(Debug, Clone)]
+pub struct PacketSizeHistogram {
+    desc: prometheus::core::Desc,
+    totals: Arc<Mutex<PacketSizeMap>>,
+}
+
+impl PacketSizeHistogram {
+    fn new(opts: Opts) -> prometheus::Result<Self> {
+        Ok(Self {
+            desc: prometheus::core::Desc::new(
+                opts.fq_name(),
+                opts.help,
+                vec!["nic".to_string(), "direction".to_string()],
+                HashMap::new(),
+            )?,
+            totals: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    pub fn add(&self, sizes: &PacketSizeMap) {
+        let mut totals = self.totals.lock().unwrap();
+        for (key, interval) in sizes {
+            totals.entry(key.clone()).or_default().merge(interval);
+        }
+    }
+}
+
+fn label_pair(name: &str, value: &str) -> proto::LabelPair {
+    let mut pair = proto::LabelPair::default();
+    pair.set_name(name.to_string());
+    pair.set_value(value.to_string());
+    pair
+}
+
+impl prometheus::core::Collector for PacketSizeHistogram {
+    fn desc(&self) -> Vec<&prometheus::core::Desc> {
+        vec![&self.desc]
+    }
+
+    fn collect(&self) -> Vec<proto::MetricFamily> {
+        let totals = self.totals.lock().unwrap();
+        let mut metrics = Vec::with_capacity(totals.len());
+        for ((nic, direction), sizes) in totals.iter() {
+            let mut cumulative = 0;
+            let mut buckets = Vec::with_capacity(PACKET_SIZE_BUCKETS.len());
+            for (bound, count) in PACKET_SIZE_BUCKETS.iter().zip(sizes.counts) {
+                cumulative += count;
+                let mut bucket = proto::Bucket::default();
+                bucket.set_upper_bound(*bound as f64);
+                bucket.set_cumulative_count(cumulative);
+                buckets.push(bucket);
+            }
+            let mut histogram = proto::Histogram::default();
+            histogram.set_sample_count(sizes.counts.iter().sum());
+            histogram.set_sample_sum(sizes.sum as f64);
+            histogram.set_bucket(buckets.into());
+
+            let mut metric = proto::Metric::default();
+            metric.set_label(
+                vec![
+                    label_pair("direction", direction.label()),
+                    label_pair("nic", nic),
+                ]
+                .into(),
+            );
+            metric.set_histogram(histogram);
+            metrics.push(metric);
+        }
+
+        let mut family = proto::MetricFamily::default();
+        family.set_name(self.desc.fq_name.clone());
+        family.set_help(self.desc.help.clone());
+        family.set_field_type(proto::MetricType::HISTOGRAM);
+        family.set_metric(metrics.into());
+        vec![family]
+    }
+}
+
+// Children of one label set, resolved once instead of on every flush
+#[derive(Debug)]
+struct Series {
+    labels: Vec<String>,
+    last_seen: time::Instant,
+    gauges: Vec<Gauge>,
+    counters: Vec<IntCounter>,
+}
+
+// Per-IP series of one family: gauge and counter vecs sharing the same label names,
+// keyed by the accounting key. Series of devices that went quiet are zeroed and
+// eventually removed.
+#[derive(Debug)]
+struct SeriesTracker<K> {
+    gauge_vecs: Vec<GaugeVec>,
+    counter_vecs: Vec<IntCounterVec>,
+    series: HashMap<K, Series>,
+}
+
+impl<K: Clone + Eq + Hash> SeriesTracker<K> {
+    fn new(gauge_vecs: &[&GaugeVec], counter_vecs: &[&IntCounterVec]) -> Self {
+        Self {
+            gauge_vecs: gauge_vecs.iter().map(|v| (*v).clone()).collect(),
+            counter_vecs: counter_vecs.iter().map(|v| (*v).clone()).collect(),
+            series: HashMap::new(),
+        }
+    }
+
+    // Mark `key` as active at `now`; `labels` is only evaluated for a new series.
+    // Children are in the order the vecs were passed to new().
+    fn touch(
+        &mut self,
+        key: &K,
+        now: time::Instant,
+        labels: impl FnOnce() -> Vec<String>,
+    ) -> &Series {
+        let Self {
+            gauge_vecs,
+            counter_vecs,
+            series,
+        } = self;
+        let series = series.entry(key.clone()).or_insert_with(|| {
+            let labels = labels();
+            let values: Vec<&str> = labels.iter().map(String::as_str).collect();
+            Series {
+                gauges: gauge_vecs
+                    .iter()
+                    .map(|v| v.with_label_values(&values))
+                    .collect(),
+                counters: counter_vecs
+                    .iter()
+                    .map(|v| v.with_label_values(&values))
+                    .collect(),
+                labels,
+                last_seen: now,
+            }
+        });
+        series.last_seen = now;
+        series
+    }
+
+    fn contains(&self, key: &K) -> bool {
+        self.series.contains_key(key)
+    }
+
+    fn count_where(&self, pred: impl Fn(&K) -> bool) -> usize {
+        self.series.keys().filter(|key| pred(key)).count()
+    }
+
+    // Remove the series of every key matching `pred`, e.g. after its labels changed
+    fn remove_where(&mut self, pred: impl Fn(&K) -> bool) {
+        let Self {
+            gauge_vecs,
+            counter_vecs,
+            series,
+        } = self;
+        series.retain(|key, series| {
+            if !pred(key) {
+                return true;
+            }
+            let values: Vec<&str> = series.labels.iter().map(String::as_str).collect();
+            for vec in gauge_vecs.iter() {
+                let _ = vec.remove_label_values(&values);
+            }
+            for vec in counter_vecs.iter() {
+                let _ = vec.remove_label_values(&values);
+            }
+            false
+        });
+    }
+
+    // Zero series that saw no traffic at `now` and drop the ones idle for longer than `idle_timeout`
+    fn sweep(&mut self, now: time::Instant, idle_timeout: Duration) {
+        let Self {
+            gauge_vecs,
+            counter_vecs,
+            series,
+        } = self;
+        series.retain(|_, series| {
+            if series.last_seen == now {
+                return true;
+            }
+            if now.duration_since(series.last_seen) > idle_timeout {
+                let values: Vec<&str> = series.labels.iter().map(String::as_str).collect();
+                for vec in gauge_vecs.iter() {
+                    let _ = vec.remove_label_values(&values);
+                }
+                for vec in counter_vecs.iter() {
+                    let _ = vec.remove_label_values(&values);
+                }
+                false
+            } else {
+                for gauge in &series.gauges {
+                    gauge.set(0.0);
+                }
+                true
+            }
+        });
+    }
+}
+
+// Gauge/counter positions in the ip_tx and ip_rx trackers
+const SERIES_BPS: usize = 0;
+const SERIES_PPS: usize = 1;
+const SERIES_BYTES: usize = 0;
+
+// All series families that come and go with traffic, per IP and per NIC
+#[derive(Debug)]
+struct IpSeries {
+    idle_timeout: Duration,
+    hostnames: Option<Arc<HostnameCache>>,
+    neighbors: Option<Arc<NeighborCache>>,
+    ip_tx: SeriesTracker<FlowKey>,
+    ip_rx: SeriesTracker<FlowKey>,
+    proto_tx: SeriesTracker<(FlowKey, &'static str)>,
+    proto_rx: SeriesTracker<(FlowKey, &'static str)>,
+    port_tx: SeriesTracker<(FlowKey, PortBucket)>,
+    port_rx: SeriesTracker<(FlowKey, PortBucket)>,
+    dscp_tx: SeriesTracker<(FlowKey, Arc<str>)>,
+    dscp_rx: SeriesTracker<(FlowKey, Arc<str>)>,
+    peers: SeriesTracker<(FlowKey, Direction)>,
+    tcp_flags: SeriesTracker<(FlowKey, Direction)>,
+    icmp: SeriesTracker<(FlowKey, &'static str)>,
+    fragments: SeriesTracker<(FlowKey, Direction)>,
+    internal_tx: SeriesTracker<FlowKey>,
+    internal_rx: SeriesTracker<FlowKey>,
+    cast_tx: SeriesTracker<(FlowKey, Cast)>,
+    multicast: SeriesTracker<(Arc<str>, Direction)>,
+    broadcast: SeriesTracker<Arc<str>>,
+    arp: SeriesTracker<(Arc<str>, &'static str)>,
+    country_tx: SeriesTracker<(Arc<str>, Arc<str>)>,
+    country_rx: SeriesTracker<(Arc<str>, Arc<str>)>,
+    asn_tx: SeriesTracker<(Arc<str>, Option<u32>)>,
+    asn_rx: SeriesTracker<(Arc<str>, Option<u32>)>,
+    // A None domain is the "other" series
+    domains: SeriesTracker<(Option<Arc<str>>, Direction)>,
+    vni_tx: SeriesTracker<(Arc<str>, u32)>,
+    vni_rx: SeriesTracker<(Arc<str>, u32)>,
+    mpls_tx: SeriesTracker<(Arc<str>, u32)>,
+    mpls_rx: SeriesTracker<(Arc<str>, u32)>,
+    // A None remote is the "other" series
+    remotes: SeriesTracker<(IpAddr, Option<IpAddr>, Direction)>,
+    // Keyed by (nic, wan), the wan is None without wan_labels
+    total_tx: SeriesTracker<(Arc<str>, Option<Arc<str>>)>,
+    total_rx: SeriesTracker<(Arc<str>, Option<Arc<str>>)>,
+    peak_tx: SeriesTracker<Arc<str>>,
+    peak_rx: SeriesTracker<Arc<str>>,
+    capture_tx: SeriesTracker<Arc<str>>,
+    capture_rx: SeriesTracker<Arc<str>>,
+    vlan_tx: SeriesTracker<(Arc<str>, Option<u16>)>,
+    vlan_rx: SeriesTracker<(Arc<str>, Option<u16>)>,
+    nic_dscp_tx: SeriesTracker<(Arc<str>, Arc<str>)>,
+    nic_dscp_rx: SeriesTracker<(Arc<str>, Arc<str>)>,
+    max_remotes: usize,
+}
+
+impl IpSeries {
+    fn new(
+        metrics: &Metrics,
+        idle_timeout: Duration,
+        hostnames: Option<Arc<HostnameCache>>,
+        neighbors: Option<Arc<NeighborCache>>,
+        max_remotes: usize,
+    ) -> Self {
+        Self {
+            idle_timeout,
+            hostnames,
+            neighbors,
+            ip_tx: SeriesTracker::new(
+                &[&metrics.ip_tx_bps, &metrics.ip_tx_pps],
+                &[&metrics.ip_tx_bytes],
+            ),
+            ip_rx: SeriesTracker::new(
+                &[&metrics.ip_rx_bps, &metrics.ip_rx_pps],
+                &[&metrics.ip_rx_bytes],
+            ),
+            proto_tx: SeriesTracker::new(&[&metrics.ip_tx_bps_by_proto], &[]),
+            proto_rx: SeriesTracker::new(&[&metrics.ip_rx_bps_by_proto], &[]),
+            port_tx: SeriesTracker::new(&[&metrics.ip_tx_bps_by_port], &[]),
+            port_rx: SeriesTracker::new(&[&metrics.ip_rx_bps_by_port], &[]),
+            dscp_tx: SeriesTracker::new(&[&metrics.ip_tx_bps_by_dscp], &[]),
+            dscp_rx: SeriesTracker::new(&[&metrics.ip_rx_bps_by_dscp], &[]),
+            peers: SeriesTracker::new(&[&metrics.ip_remote_peers], &[]),
+            tcp_flags: SeriesTracker::new(
+                &[
+                    &metrics.ip_tcp_syn_pps,
+                    &metrics.ip_tcp_synack_pps,
+                    &metrics.ip_tcp_rst_pps,
+                    &metrics.ip_tcp_fin_pps,
+                ],
+                &[],
+            ),
+            icmp: SeriesTracker::new(&[&metrics.ip_icmp_pps], &[]),
+            fragments: SeriesTracker::new(&[&metrics.ip_fragments_pps], &[]),
+            internal_tx: SeriesTracker::new(&[&metrics.internal_tx_bps], &[]),
+            internal_rx: SeriesTracker::new(&[&metrics.internal_rx_bps], &[]),
+            cast_tx: SeriesTracker::new(&[&metrics.ip_cast_tx_bps], &[]),
+            multicast: SeriesTracker::new(&[&metrics.multicast_bps], &[]),
+            broadcast: SeriesTracker::new(&[&metrics.broadcast_bps], &[]),
+            arp: SeriesTracker::new(&[&metrics.arp_pps], &[]),
+            country_tx: SeriesTracker::new(&[&metrics.country_tx_bps], &[]),
+            country_rx: SeriesTracker::new(&[&metrics.country_rx_bps], &[]),
+            asn_tx: SeriesTracker::new(&[&metrics.asn_tx_bps], &[]),
+            asn_rx: SeriesTracker::new(&[&metrics.asn_rx_bps], &[]),
+            domains: SeriesTracker::new(&[&metrics.domain_bps], &[]),
+            vni_tx: SeriesTracker::new(&[&metrics.vni_tx_bps], &[]),
+            vni_rx: SeriesTracker::new(&[&metrics.vni_rx_bps], &[]),
+            mpls_tx: SeriesTracker::new(&[&metrics.mpls_tx_bps], &[]),
+            mpls_rx: SeriesTracker::new(&[&metrics.mpls_rx_bps], &[]),
+            remotes: SeriesTracker::new(&[&metrics.ip_remote_bps], &[]),
+            total_tx: SeriesTracker::new(&[&metrics.total_tx_bps, &metrics.total_tx_pps], &[]),
+            total_rx: SeriesTracker::new(&[&metrics.total_rx_bps, &metrics.total_rx_pps], &[]),
+            peak_tx: SeriesTracker::new(&[&metrics.peak_tx_bps], &[]),
+            peak_rx: SeriesTracker::new(&[&metrics.peak_rx_bps], &[]),
+            capture_tx: SeriesTracker::new(&[&metrics.capture_tx_bps], &[]),
+            capture_rx: SeriesTracker::new(&[&metrics.capture_rx_bps], &[]),
+            vlan_tx: SeriesTracker::new(&[&metrics.vlan_tx_bps], &[]),
+            vlan_rx: SeriesTracker::new(&[&metrics.vlan_rx_bps], &[]),
+            nic_dscp_tx: SeriesTracker::new(&[&metrics.dscp_tx_bps], &[]),
+            nic_dscp_rx: SeriesTracker::new(&[&metrics.dscp_rx_bps], &[]),
+            max_remotes,
+        }
+    }
+
+    // Drop the series of an IP whose hostname or mac label changed; the next flush
+    // recreates them with the new value
+    fn forget_ip(&mut self, ip: IpAddr) {
+        let ip = Some(ip);
+        self.ip_tx.remove_where(|key| key.ip == ip);
+        self.ip_rx.remove_where(|key| key.ip == ip);
+        self.proto_tx.remove_where(|(key, _)| key.ip == ip);
+        self.proto_rx.remove_where(|(key, _)| key.ip == ip);
+        self.port_tx.remove_where(|(key, _)| key.ip == ip);
+        self.port_rx.remove_where(|(key, _)| key.ip == ip);
+        self.dscp_tx.remove_where(|(key, _)| key.ip == ip);
+        self.dscp_rx.remove_where(|(key, _)| key.ip == ip);
+        self.peers.remove_where(|(key, _)| key.ip == ip);
+        self.tcp_flags.remove_where(|(key, _)| key.ip == ip);
+        self.icmp.remove_where(|(key, _)| key.ip == ip);
+        self.fragments.remove_where(|(key, _)| key.ip == ip);
+        self.internal_tx.remove_where(|key| key.ip == ip);
+        self.internal_rx.remove_where(|key| key.ip == ip);
+        self.cast_tx.remove_where(|(key, _)| key.ip == ip);
+    }
+
+    fn sweep(&mut self, now: time::Instant) {
+        let idle = self.idle_timeout;
+        self.ip_tx.sweep(now, idle);
+        self.ip_rx.sweep(now, idle);
+        self.proto_tx.sweep(now, idle);
+        self.proto_rx.sweep(now, idle);
+        self.port_tx.sweep(now, idle);
+        self.port_rx.sweep(now, idle);
+        self.dscp_tx.sweep(now, idle);
+        self.dscp_rx.sweep(now, idle);
+        self.peers.sweep(now, idle);
+        self.tcp_flags.sweep(now, idle);
+        self.icmp.sweep(now, idle);
+        self.fragments.sweep(now, idle);
+        self.internal_tx.sweep(now, idle);
+        self.internal_rx.sweep(now, idle);
+        self.cast_tx.sweep(now, idle);
+        self.multicast.sweep(now, idle);
+        self.broadcast.sweep(now, idle);
+        self.arp.sweep(now, idle);
+        self.country_tx.sweep(now, idle);
+        self.country_rx.sweep(now, idle);
+        self.asn_tx.sweep(now, idle);
+        self.asn_rx.sweep(now, idle);
+        self.domains.sweep(now, idle);
+        self.vni_tx.sweep(now, idle);
+        self.vni_rx.sweep(now, idle);
+        self.mpls_tx.sweep(now, idle);
+        self.mpls_rx.sweep(now, idle);
+        self.remotes.sweep(now, idle);
+        self.total_tx.sweep(now, idle);
+        self.total_rx.sweep(now, idle);
+        self.peak_tx.sweep(now, idle);
+        self.peak_rx.sweep(now, idle);
+        self.capture_tx.sweep(now, idle);
+        self.capture_rx.sweep(now, idle);
+        self.vlan_tx.sweep(now, idle);
+        self.vlan_rx.sweep(now, idle);
+        self.nic_dscp_tx.sweep(now, idle);
+        self.nic_dscp_rx.sweep(now, idle);
+    }
+}
+
+pub const DEFAULT_UPDATE_INTERVAL: Duration = Duration::from_secs(1);
+pub const MIN_UPDATE_INTERVAL: Duration = Duration::from_millis(100);
+pub const MAX_UPDATE_INTERVAL: Duration = Duration::from_secs(60);
+
+// "250ms", "5s" or plain seconds ("0.5"), within MIN/MAX_UPDATE_INTERVAL
+pub fn parse_update_interval(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let interval = if let Some(ms) = value.strip_suffix("ms") {
+        ms.trim()
+            .parse::<u64>()
+            .map(Duration::from_millis)
+            .map_err(|_| format!("invalid milliseconds '{}'", ms))?
+    } else {
+        let secs = value.strip_suffix('s').unwrap_or(value).trim();
+        secs.parse::<f64>()
+            .ok()
+            .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+            .ok_or_else(|| format!("invalid interval '{}', use e.g. 250ms or 5s", value))?
+    };
+    if interval < MIN_UPDATE_INTERVAL || interval > MAX_UPDATE_INTERVAL {
+        return Err(format!(
+            "update interval must be between {:?} and {:?}, got {:?}",
+            MIN_UPDATE_INTERVAL, MAX_UPDATE_INTERVAL, interval
+        ));
+    }
+    Ok(interval)
+}
+
+pub fn bytes_to_bps(bytes: u64, elapsed_secs: f64) -> f64 {
+    (bytes * 8) as f64 / elapsed_secs
+}
+
+pub fn per_second(count: u64, elapsed_secs: f64) -> f64 {
+    count as f64 / elapsed_secs
+}
+
+// One direction of a series over the last completed interval
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Rate {
+    pub bps: f64,
+    pub pps: f64,
+    pub bytes: u64,
+}
+
+// A direction without traffic in the interval is None
+#[derive(Debug, Clone)]
+pub struct IpRate {
+    pub key: FlowKey,
+    pub tx: Option<Rate>,
+    pub rx: Option<Rate>,
+}
+
+impl IpRate {
+    pub fn tx_bps(&self) -> f64 {
+        self.tx.map_or(0.0, |rate| rate.bps)
+    }
+
+    pub fn rx_bps(&self) -> f64 {
+        self.rx.map_or(0.0, |rate| rate.bps)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct NicRate {
+    pub nic: Arc<str>,
+    pub tx: Option<Rate>,
+    pub rx: Option<Rate>,
+    // Busiest peak_bucket_ms bucket, None with peak tracking off
+    pub tx_peak_bps: Option<f64>,
+    pub rx_peak_bps: Option<f64>,
+}
+
+// NIC totals split by wan, only with wan_labels
+#[derive(Debug, Clone)]
+pub struct WanRate {
+    pub nic: Arc<str>,
+    pub wan: Arc<str>,
+    pub tx: Option<Rate>,
+    pub rx: Option<Rate>,
+}
+
+// The per-IP and per-NIC results of the last completed interval. Built once per
+// flush and never changed afterwards, so HTTP handlers and sinks can read it
+// without TrafficStats or the prometheus types.
+#[derive(Debug, Clone)]
+pub struct IntervalSnapshot {
+    // Wall clock time at the end of the interval
+    pub timestamp: SystemTime,
+    pub elapsed: Duration,
+    pub per_ip: Vec<IpRate>,
+    pub per_nic: Vec<NicRate>,
+    pub per_wan: Vec<WanRate>,
+}
+
+// The last snapshot, swapped as a whole on every flush
+pub type SharedSnapshot = Arc<RwLock<Arc<IntervalSnapshot>>>;
+
+// Every snapshot is also sent here for /stream. Subscribers that fall more than
+// LIVE_SNAPSHOT_CAPACITY snapshots behind miss the oldest ones.
+pub type LiveSnapshots = broadcast::Sender<Arc<IntervalSnapshot>>;
+pub const LIVE_SNAPSHOT_CAPACITY: usize = 4;
+
+type DirectionRates = (Option<Rate>, Option<Rate>);
+
+// Join the byte and packet maps of both directions per key
+fn direction_rates<K: Clone + Eq + Hash>(
+    tx: (&HashMap<K, u64>, &HashMap<K, u64>),
+    rx: (&HashMap<K, u64>, &HashMap<K, u64>),
+    secs: f64,
+) -> HashMap<K, DirectionRates> {
+    let mut rates: HashMap<K, DirectionRates> = HashMap::new();
+    for (is_tx, (bytes, packets)) in [(true, tx), (false, rx)] {
+        for (key, &bytes) in bytes {
+            let rate = Rate {
+                bps: bytes_to_bps(bytes, secs),
+                pps: per_second(packets.get(key).copied().unwrap_or(0), secs),
+                bytes,
+            };
+            let entry = rates.entry(key.clone()).or_default();
+            if is_tx {
+                entry.0 = Some(rate);
+            } else {
+                entry.1 = Some(rate);
+            }
+        }
+    }
+    rates
+}
+
+impl IntervalSnapshot {
+    pub fn empty() -> Self {
+        Self {
+            timestamp: SystemTime::now(),
+            elapsed: Duration::ZERO,
+            per_ip: Vec::new(),
+            per_nic: Vec::new(),
+            per_wan: Vec::new(),
+        }
+    }
+
+    pub fn from_stats(stats: &TrafficStats, elapsed: Duration, timestamp: SystemTime) -> Self {
+        let secs = elapsed.as_secs_f64();
+        let per_ip = direction_rates(
+            (&stats.tx_bytes, &stats.tx_packets),
+            (&stats.rx_bytes, &stats.rx_packets),
+            secs,
+        )
+        .into_iter()
+        .map(|(key, (tx, rx))| IpRate { key, tx, rx })
+        .collect();
+        let mut nics = direction_rates(
+            (&stats.nic_tx_total, &stats.nic_tx_packets),
+            (&stats.nic_rx_total, &stats.nic_rx_packets),
+            secs,
+        );
+        // A NIC can have a peak bucket without traffic left in the final interval
+        for nic in stats
+            .nic_tx_peak_bps
+            .keys()
+            .chain(stats.nic_rx_peak_bps.keys())
+        {
+            nics.entry(nic.clone()).or_default();
+        }
+        let per_nic = nics
+            .into_iter()
+            .map(|(nic, (tx, rx))| NicRate {
+                tx_peak_bps: stats.nic_tx_peak_bps.get(&nic).copied(),
+                rx_peak_bps: stats.nic_rx_peak_bps.get(&nic).copied(),
+                nic,
+                tx,
+                rx,
+            })
+            .collect();
+        let per_wan = direction_rates(
+            (&stats.wan_tx_total, &stats.wan_tx_packets),
+            (&stats.wan_rx_total, &stats.wan_rx_packets),
+            secs,
+        )
+        .into_iter()
+        .map(|((nic, wan), (tx, rx))| WanRate { nic, wan, tx, rx })
+        .collect();
+        Self {
+            timestamp,
+            elapsed,
+            per_ip,
+            per_nic,
+            per_wan,
+        }
+    }
+}
+
+// Publish the interval: the per-IP and NIC totals from `snapshot`, the finer
+// breakdowns straight from `stats`
+fn flush_stats(
+    metrics: &Metrics,
+    stats: &TrafficStats,
+    snapshot: &IntervalSnapshot,
+    ip_series: &mut IpSeries,
+    now: time::Instant,
+) {
+    let secs = snapshot.elapsed.as_secs_f64();
+
+    let hostnames = ip_series.hostnames.clone();
+    if let Some(hostnames) = &hostnames {
+        let keys = stats
+            .tx_bytes
+            .keys()
+            .chain(stats.rx_bytes.keys())
+            .chain(stats.internal_tx_bytes.keys())
+            .chain(stats.internal_rx_bytes.keys());
+        hostnames.observe(keys.filter_map(|key| key.ip));
+        for ip in hostnames.take_changed() {
+            ip_series.forget_ip(ip);
+        }
+    }
+    let neighbors = ip_series.neighbors.clone();
+    if let Some(neighbors) = &neighbors {
+        for ip in neighbors.take_changed() {
+            ip_series.forget_ip(ip);
+        }
+    }
+
+    // local_ip, nic, wan of WAN traffic and the family's own label if any, then
+    // hostname and mac when enabled
+    let flow_labels = |key: &FlowKey, extra: Option<String>| {
+        let mut labels = vec![key.ip_label(), key.nic.to_string()];
+        labels.extend(key.wan.as_deref().map(str::to_string));
+        labels.extend(extra);
+        if let Some(hostnames) = &hostnames {
+            labels.push(match key.ip {
+                Some(ip) => hostnames.hostname(&ip).to_string(),
+                None => OVERFLOW_IP_LABEL.to_string(),
+            });
+        }
+        if let Some(neighbors) = &neighbors {
+            labels.push(match key.ip {
+                Some(ip) => neighbors.mac(&ip).to_string(),
+                None => OVERFLOW_IP_LABEL.to_string(),
+            });
+        }
+        labels
+    };
+
+    // Update per-IP metrics
+    for rate in &snapshot.per_ip {
+        let key = &rate.key;
+        for (side, tracker) in [
+            (rate.tx, &mut ip_series.ip_tx),
+            (rate.rx, &mut ip_series.ip_rx),
+        ] {
+            let Some(side) = side else {
+                continue;
+            };
+            let series = tracker.touch(key, now, || flow_labels(key, None));
+            series.gauges[SERIES_BPS].set(side.bps);
+            series.gauges[SERIES_PPS].set(side.pps);
+            // Counters get the bytes of this interval only, each snapshot starts empty
+            series.counters[SERIES_BYTES].inc_by(side.bytes);
+        }
+    }
+
+    // Update total metrics
+    let totals = snapshot
+        .per_wan
+        .iter()
+        .filter(|_| metrics.wan_labels)
+        .map(|rate| (rate.nic.clone(), Some(rate.wan.clone()), rate.tx, rate.rx));
+    let nic_totals = snapshot
+        .per_nic
+        .iter()
+        .filter(|_| !metrics.wan_labels)
+        .map(|rate| (rate.nic.clone(), None, rate.tx, rate.rx));
+    for (nic, wan, tx, rx) in totals.chain(nic_totals) {
+        let key = (nic, wan);
+        let labels = || {
+            let (nic, wan) = &key;
+            std::iter::once(nic.to_string())
+                .chain(wan.as_deref().map(str::to_string))
+                .collect()
+        };
+        for (side, tracker) in [(tx, &mut ip_series.total_tx), (rx, &mut ip_series.total_rx)] {
+            let Some(side) = side else {
+                continue;
+            };
+            let series = tracker.touch(&key, now, labels);
+            series.gauges[SERIES_BPS].set(side.bps);
+            series.gauges[SERIES_PPS].set(side.pps);
+        }
+    }
+    for rate in &snapshot.per_nic {
+        for (peak, tracker) in [
+            (rate.tx_peak_bps, &mut ip_series.peak_tx),
+            (rate.rx_peak_bps, &mut ip_series.peak_rx),
+        ] {
+            if let Some(bps) = peak {
+                let series = tracker.touch(&rate.nic, now, || vec![rate.nic.to_string()]);
+                series.gauges[SERIES_BPS].set(bps);
+            }
+        }
+    }
+
+    for (key @ (flow, proto), &bytes) in &stats.tx_bytes_by_proto {
+        let series = ip_series
+            .proto_tx
+            .touch(key, now, || flow_labels(flow, Some(proto.to_string())));
+        series.gauges[SERIES_BPS].set(bytes_to_bps(bytes, secs));
+    }
+
+    for (key @ (flow, proto), &bytes) in &stats.rx_bytes_by_proto {
+        let series = ip_series
+            .proto_rx
+            .touch(key, now, || flow_labels(flow, Some(proto.to_string())));
+        series.gauges[SERIES_BPS].set(bytes_to_bps(bytes, secs));
+    }
+
+    for (key @ (flow, port), &bytes) in &stats.tx_bytes_by_port {
+        let series = ip_series
+            .port_tx
+            .touch(key, now, || flow_labels(flow, Some(port_label(*port))));
+        series.gauges[SERIES_BPS].set(bytes_to_bps(bytes, secs));
+    }
+
+    for (key @ (flow, port), &bytes) in &stats.rx_bytes_by_port {
+        let series = ip_series
+            .port_rx
+            .touch(key, now, || flow_labels(flow, Some(port_label(*port))));
+        series.gauges[SERIES_BPS].set(bytes_to_bps(bytes, secs));
+    }
+
+    for (key @ (flow, dscp), &bytes) in &stats.tx_bytes_by_dscp {
+        let series = ip_series
+            .dscp_tx
+            .touch(key, now, || flow_labels(flow, Some(dscp.to_string())));
+        series.gauges[SERIES_BPS].set(bytes_to_bps(bytes, secs));
+    }
+
+    for (key @ (flow, dscp), &bytes) in &stats.rx_bytes_by_dscp {
+        let series = ip_series
+            .dscp_rx
+            .touch(key, now, || flow_labels(flow, Some(dscp.to_string())));
+        series.gauges[SERIES_BPS].set(bytes_to_bps(bytes, secs));
+    }
+
+    for (direction, peers) in [
+        (Direction::Tx, &stats.tx_peers),
+        (Direction::Rx, &stats.rx_peers),
+    ] {
+        for (flow, set) in peers {
+            let series = ip_series.peers.touch(&(flow.clone(), direction), now, || {
+                flow_labels(flow, Some(direction.label().to_string()))
+            });
+            series.gauges[0].set(set.count().round());
+        }
+    }
+
+    for (direction, flags) in [
+        (Direction::Tx, &stats.tx_tcp_flags),
+        (Direction::Rx, &stats.rx_tcp_flags),
+    ] {
+        for (flow, counts) in flags {
+            let series = ip_series
+                .tcp_flags
+                .touch(&(flow.clone(), direction), now, || {
+                    flow_labels(flow, Some(direction.label().to_string()))
+                });
+            for (gauge, packets) in
+                series
+                    .gauges
+                    .iter()
+                    .zip([counts.syn, counts.syn_ack, counts.rst, counts.fin])
+            {
+                gauge.set(per_second(packets, secs));
+            }
+        }
+    }
+
+    for (key @ (flow, icmp_type), &packets) in &stats.icmp_packets {
+        let series = ip_series
+            .icmp
+            .touch(key, now, || flow_labels(flow, Some(icmp_type.to_string())));
+        series.gauges[0].set(per_second(packets, secs));
+    }
+
+    for (direction, fragments) in [
+        (Direction::Tx, &stats.tx_fragments),
+        (Direction::Rx, &stats.rx_fragments),
+    ] {
+        for (flow, &packets) in fragments {
+            let series = ip_series
+                .fragments
+                .touch(&(flow.clone(), direction), now, || {
+                    flow_labels(flow, Some(direction.label().to_string()))
+                });
+            series.gauges[0].set(per_second(packets, secs));
+        }
+    }
+
+    for (key, &bytes) in &stats.internal_tx_bytes {
+        let series = ip_series
+            .internal_tx
+            .touch(key, now, || flow_labels(key, None));
+        series.gauges[SERIES_BPS].set(bytes_to_bps(bytes, secs));
+    }
+
+    for (key, &bytes) in &stats.internal_rx_bytes {
+        let series = ip_series
+            .internal_rx
+            .touch(key, now, || flow_labels(key, None));
+        series.gauges[SERIES_BPS].set(bytes_to_bps(bytes, secs));
+    }
+
+    for (key @ (flow, cast), &bytes) in &stats.tx_bytes_by_cast {
+        let series = ip_series.cast_tx.touch(key, now, || {
+            flow_labels(flow, Some(cast.label().to_string()))
+        });
+        series.gauges[SERIES_BPS].set(bytes_to_bps(bytes, secs));
+    }
+
+    for (key @ (nic, direction), &bytes) in &stats.multicast_total {
+        let series = ip_series.multicast.touch(key, now, || {
+            vec![nic.to_string(), direction.label().to_string()]
+        });
+        series.gauges[SERIES_BPS].set(bytes_to_bps(bytes, secs));
+    }
+
+    for (nic, &bytes) in &stats.broadcast_total {
+        let series = ip_series
+            .broadcast
+            .touch(nic, now, || vec![nic.to_string()]);
+        series.gauges[SERIES_BPS].set(bytes_to_bps(bytes, secs));
+    }
+
+    for (key @ (nic, op), &packets) in &stats.arp_packets {
+        let series = ip_series
+            .arp
+            .touch(key, now, || vec![nic.to_string(), op.to_string()]);
+        series.gauges[0].set(per_second(packets, secs));
+    }
+
+    for (tracker, totals) in [
+        (&mut ip_series.capture_tx, &stats.capture_tx_total),
+        (&mut ip_series.capture_rx, &stats.capture_rx_total),
+    ] {
+        for (capture, &bytes) in totals {
+            let series = tracker.touch(capture, now, || vec![capture.to_string()]);
+            series.gauges[SERIES_BPS].set(bytes_to_bps(bytes, secs));
+        }
+    }
+
+    for (tracker, totals) in [
+        (&mut ip_series.vlan_tx, &stats.vlan_tx_total),
+        (&mut ip_series.vlan_rx, &stats.vlan_rx_total),
+    ] {
+        for (key @ (nic, vlan_id), &bytes) in totals {
+            let series = tracker.touch(key, now, || vec![vlan_label(*vlan_id), nic.to_string()]);
+            series.gauges[SERIES_BPS].set(bytes_to_bps(bytes, secs));
+        }
+    }
+
+    for (tracker, totals) in [
+        (&mut ip_series.nic_dscp_tx, &stats.nic_tx_bytes_by_dscp),
+        (&mut ip_series.nic_dscp_rx, &stats.nic_rx_bytes_by_dscp),
+    ] {
+        for (key @ (nic, dscp), &bytes) in totals {
+            let series = tracker.touch(key, now, || vec![dscp.to_string(), nic.to_string()]);
+            series.gauges[SERIES_BPS].set(bytes_to_bps(bytes, secs));
+        }
+    }
+
+    for (tracker, totals) in [
+        (&mut ip_series.vni_tx, &stats.vni_tx_total),
+        (&mut ip_series.vni_rx, &stats.vni_rx_total),
+        (&mut ip_series.mpls_tx, &stats.mpls_tx_total),
+        (&mut ip_series.mpls_rx, &stats.mpls_rx_total),
+    ] {
+        for (key @ (nic, id), &bytes) in totals {
+            let series = tracker.touch(key, now, || vec![id.to_string(), nic.to_string()]);
+            series.gauges[SERIES_BPS].set(bytes_to_bps(bytes, secs));
+        }
+    }
+
+    for (tracker, totals) in [
+        (&mut ip_series.country_tx, &stats.tx_bytes_by_country),
+        (&mut ip_series.country_rx, &stats.rx_bytes_by_country),
+    ] {
+        for (key @ (nic, country), &bytes) in totals {
+            let series = tracker.touch(key, now, || vec![country.to_string(), nic.to_string()]);
+            series.gauges[SERIES_BPS].set(bytes_to_bps(bytes, secs));
+        }
+    }
+
+    // AS0 is what an address without an entry gets
+    let asn_labels = |asn: &Option<u32>| match asn {
+        Some(0) => (UNKNOWN_ASN_LABEL.to_string(), UNKNOWN_ASN_LABEL.to_string()),
+        Some(asn) => (
+            format!("AS{}", asn),
+            stats
+                .asn_orgs
+                .get(asn)
+                .map_or(UNKNOWN_ASN_LABEL.to_string(), |org| org.to_string()),
+        ),
+        None => (OTHER_ASN_LABEL.to_string(), OTHER_ASN_LABEL.to_string()),
+    };
+    for (tracker, totals) in [
+        (&mut ip_series.asn_tx, &stats.tx_bytes_by_asn),
+        (&mut ip_series.asn_rx, &stats.rx_bytes_by_asn),
+    ] {
+        for (key @ (nic, asn), &bytes) in totals {
+            let series = tracker.touch(key, now, || {
+                let (asn, org) = asn_labels(asn);
+                vec![asn, org, nic.to_string()]
+            });
+            series.gauges[SERIES_BPS].set(bytes_to_bps(bytes, secs));
+        }
+    }
+
+    for (direction, totals) in [
+        (Direction::Tx, &stats.tx_bytes_by_domain),
+        (Direction::Rx, &stats.rx_bytes_by_domain),
+    ] {
+        for (domain, &bytes) in totals {
+            let series = ip_series
+                .domains
+                .touch(&(domain.clone(), direction), now, || {
+                    vec![
+                        domain.as_deref().unwrap_or(OTHER_DOMAIN_LABEL).to_string(),
+                        direction.label().to_string(),
+                    ]
+                });
+            series.gauges[SERIES_BPS].set(bytes_to_bps(bytes, secs));
+        }
+    }
+
+    // Remotes that already have a series keep it; new ones get one while the local
+    // IP has fewer than max_remotes in that direction, busiest first
+    let mut remotes: Vec<_> = stats.remote_bytes.iter().collect();
+    remotes.sort_unstable_by_key(|(_, &bytes)| std::cmp::Reverse(bytes));
+    let mut other_remotes: HashMap<(IpAddr, Direction), u64> = HashMap::new();
+    for (&(local, remote, direction), &bytes) in remotes {
+        let key = (local, Some(remote), direction);
+        if !ip_series.remotes.contains(&key)
+            && ip_series.remotes.count_where(|(ip, remote, side)| {
+                *ip == local && remote.is_some() && *side == direction
+            }) >= ip_series.max_remotes
+        {
+            *other_remotes.entry((local, direction)).or_insert(0) += bytes;
+            continue;
+        }
+        let series = ip_series.remotes.touch(&key, now, || {
+            vec![
+                local.to_string(),
+                remote.to_string(),
+                direction.label().to_string(),
+            ]
+        });
+        series.gauges[SERIES_BPS].set(bytes_to_bps(bytes, secs));
+    }
+    for ((local, direction), bytes) in other_remotes {
+        let series = ip_series.remotes.touch(&(local, None, direction), now, || {
+            vec![
+                local.to_string(),
+                OTHER_REMOTE_LABEL.to_string(),
+                direction.label().to_string(),
+            ]
+        });
+        series.gauges[SERIES_BPS].set(bytes_to_bps(bytes, secs));
+    }
+
+    metrics.packet_sizes.add(&stats.packet_sizes);
+
+    ip_series.sweep(now);
+}
+
+// One completed interval, handed to every sink
+pub struct Flush<'a> {
+    pub stats: &'a TrafficStats,
+    pub snapshot: &'a IntervalSnapshot,
+    pub elapsed: Duration,
+    pub now: time::Instant,
+    // Wall clock time at the end of the interval
+    pub timestamp: SystemTime,
+}
+
+// A destination for flushed intervals besides the Prometheus registry, e.g. a push
+// exporter. publish() runs on the updater task and must not block.
+pub trait FlushSink: Send {
+    fn publish(&mut self, flush: &Flush<'_>);
+}
+
+// The registry served on /metrics
+struct PrometheusSink {
+    metrics: Arc<Metrics>,
+    ip_series: IpSeries,
+}
+
+impl FlushSink for PrometheusSink {
+    fn publish(&mut self, flush: &Flush<'_>) {
+        flush_stats(
+            &self.metrics,
+            flush.stats,
+            flush.snapshot,
+            &mut self.ip_series,
+            flush.now,
+        );
+    }
+}
+
+// Everything the metrics updater reads from or publishes to
+pub struct UpdaterContext {
+    pub metrics: Arc<Metrics>,
+    // Read for update_interval, a reload restarts the ticks at the new interval
+    pub config: SharedConfig,
+    pub snapshots: mpsc::Sender<SnapshotRequest>,
+    pub idle_timeout: Duration,
+    pub max_tracked_ips: usize,
+    // Remote IPs with their own series per tracked IP and direction
+    pub max_remotes: usize,
+    // ASNs with their own series per interval, 0 = no limit
+    pub max_asns: usize,
+    // Domains with their own series per interval, 0 = no limit
+    pub max_domains: usize,
+    pub health: Arc<HealthState>,
+    pub last_interval: SharedSnapshot,
+    pub live: LiveSnapshots,
+    // Every snapshot is also appended here for /history
+    pub history: Option<Arc<IntervalHistory>>,
+    pub hostnames: Option<Arc<HostnameCache>>,
+    pub neighbors: Option<Arc<NeighborCache>>,
+    // Published after the Prometheus registry on every flush
+    pub sinks: Vec<Box<dyn FlushSink>>,
+}
+
+pub async fn update_metrics(ctx: UpdaterContext, mut stop: oneshot::Receiver<()>) {
+    let UpdaterContext {
+        metrics,
+        mut config,
+        snapshots,
+        idle_timeout,
+        max_tracked_ips,
+        max_remotes,
+        max_asns,
+        max_domains,
+        health,
+        last_interval,
+        live,
+        history,
+        hostnames,
+        neighbors,
+        sinks,
+    } = ctx;
+
+    // Skip the immediate first tick so the first interval has a real length
+    let ticks = |every: Duration| time::interval_at(time::Instant::now() + every, every);
+    let mut update_interval = config
+        .borrow_and_update()
+        .update_interval
+        .unwrap_or(DEFAULT_UPDATE_INTERVAL);
+    let mut interval = ticks(update_interval);
+    let mut last_flush = time::Instant::now();
+    let mut sinks: Vec<Box<dyn FlushSink>> = std::iter::once(Box::new(PrometheusSink {
+        metrics: metrics.clone(),
+        ip_series: IpSeries::new(&metrics, idle_timeout, hostnames, neighbors, max_remotes),
+    }) as Box<dyn FlushSink>)
+    .chain(sinks)
+    .collect();
+
+    loop {
+        // A stop request publishes the partial interval one last time
+        let stopping = tokio::select! {
+            _ = interval.tick() => false,
+            _ = &mut stop => true,
+        };
+
+        let (reply, snapshot) = oneshot::channel();
+        if snapshots.send(reply).await.is_err() {
+            error!("Aggregator stopped, metrics are no longer updated");
+            return;
+        }
+        let Ok(mut stats) = snapshot.await else {
+            error!("Aggregator stopped, metrics are no longer updated");
+            return;
+        };
+
+        for (map, entries) in stats.map_sizes() {
+            metrics
+                .traffic_stats_entries
+                .with_label_values(&[map])
+                .set(entries as i64);
+        }
+        if max_tracked_ips > 0 {
+            let folded = stats.limit_flows(max_tracked_ips);
+            metrics.ips_overflowed.inc_by(folded as u64);
+        }
+        if max_asns > 0 {
+            stats.limit_asns(max_asns);
+        }
+        if max_domains > 0 {
+            stats.limit_domains(max_domains);
+        }
+
+        // Ticks can fire late under load, so scale by the measured interval
+        let now = time::Instant::now();
+        let elapsed = now.duration_since(last_flush);
+        last_flush = now;
+
+        let timestamp = SystemTime::now();
+        let snapshot = Arc::new(IntervalSnapshot::from_stats(&stats, elapsed, timestamp));
+        let flush = Flush {
+            stats: &stats,
+            snapshot: &snapshot,
+            elapsed,
+            now,
+            timestamp,
+        };
+        for sink in &mut sinks {
+            sink.publish(&flush);
+        }
+        if let Some(history) = &history {
+            history.push(snapshot.clone());
+        }
+        // No subscribers is not an error
+        let _ = live.send(snapshot.clone());
+        *last_interval.write().unwrap() = snapshot;
+        health.record_flush();
+
+        if stopping {
+            info!("Published final metrics");
+            return;
+        }
+
+        if config.has_changed().unwrap_or(false) {
+            let every = config
+                .borrow_and_update()
+                .update_interval
+                .unwrap_or(DEFAULT_UPDATE_INTERVAL);
+            if every != update_interval {
+                info!(
+                    "Update interval changed from {:?} to {:?}",
+                    update_interval, every
+                );
+                update_interval = every;
+                interval = ticks(every);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use tokio::sync::watch;
+
+    fn updater_context(
+        metrics: Arc<Metrics>,
+        config: SharedConfig,
+        snapshots: mpsc::Sender<SnapshotRequest>,
+        live: LiveSnapshots,
+    ) -> UpdaterContext {
+        UpdaterContext {
+            metrics,
+            config,
+            snapshots,
+            idle_timeout: Duration::from_secs(300),
+            max_tracked_ips: 0,
+            max_remotes: 0,
+            max_asns: 0,
+            max_domains: 0,
+            health: Arc::new(HealthState::new(Duration::from_secs(300))),
+            last_interval: Arc::new(RwLock::new(Arc::new(IntervalSnapshot::empty()))),
+            live,
+            history: None,
+            hostnames: None,
+            neighbors: None,
+            sinks: Vec::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn bps_is_scaled_by_the_measured_interval() {
+        time::pause();
+        let metrics = Arc::new(Metrics::new("network", false, false, false, false, false).unwrap());
+        let config = Config {
+            update_interval: Some(Duration::from_millis(1500)),
+            ..Config::default()
+        };
+        let (_config_tx, config_rx) = watch::channel(Arc::new(config));
+        let (snapshot_tx, mut snapshot_rx) = mpsc::channel(1);
+        let (live, mut published) = broadcast::channel(LIVE_SNAPSHOT_CAPACITY);
+        let (_stop_tx, stop_rx) = oneshot::channel();
+        let ctx = updater_context(metrics.clone(), config_rx, snapshot_tx, live);
+        let updater = tokio::spawn(update_metrics(ctx, stop_rx));
+
+        // Let the updater arm its ticks, then move the clock to the first one
+        tokio::task::yield_now().await;
+        time::advance(Duration::from_millis(1500)).await;
+        let reply = snapshot_rx.recv().await.unwrap();
+
+        let eth0: Arc<str> = Arc::from("eth0");
+        let mut stats = TrafficStats::new();
+        stats.nic_tx_total.insert(eth0.clone(), 30_000);
+        stats.nic_tx_packets.insert(eth0, 30);
+        reply.send(stats).unwrap();
+
+        let snapshot = published.recv().await.unwrap();
+        // The timer wheel rounds deadlines up to the next millisecond
+        let secs = snapshot.elapsed.as_secs_f64();
+        assert!((1.5..1.51).contains(&secs), "elapsed = {secs}");
+        // 30000 bytes * 8 over ~1.5 s, not over 1 s
+        let tx_bps = metrics.total_tx_bps.with_label_values(&["eth0"]).get();
+        assert!(
+            (tx_bps - 240_000.0 / secs).abs() < 1e-6,
+            "tx_bps = {tx_bps}"
+        );
+        assert!((tx_bps - 160_000.0).abs() < 1_000.0, "tx_bps = {tx_bps}");
+        let tx_pps = metrics.total_tx_pps.with_label_values(&["eth0"]).get();
+        assert!((tx_pps - 30.0 / secs).abs() < 1e-9, "tx_pps = {tx_pps}");
+
+        updater.abort();
+    }
+
+    #[test]
+    fn rates_use_the_measured_interval() {
+        assert_eq!(bytes_to_bps(1_500, 1.0), 12_000.0);
+        assert_eq!(bytes_to_bps(1_500, 1.5), 8_000.0);
+        assert_eq!(bytes_to_bps(1_500, 0.25), 48_000.0);
+        assert_eq!(per_second(30, 1.5), 20.0);
+        assert_eq!(bytes_to_bps(0, 1.5), 0.0);
+    }
+
+    #[test]
+    fn quiet_nic_totals_are_zeroed() {
+        let metrics = Metrics::new("network", false, false, false, false, false).unwrap();
+        let mut ip_series = IpSeries::new(&metrics, Duration::from_secs(300), None, None, 0);
+        let start = time::Instant::now();
+        let elapsed = Duration::from_secs(1);
+
+        let eth0: Arc<str> = Arc::from("eth0");
+        let mut stats = TrafficStats::new();
+        stats.nic_tx_total.insert(eth0.clone(), 1_000);
+        stats.nic_tx_packets.insert(eth0.clone(), 10);
+        stats.capture_tx_total.insert(eth0, 1_000);
+        let snapshot = IntervalSnapshot::from_stats(&stats, elapsed, SystemTime::now());
+        flush_stats(&metrics, &stats, &snapshot, &mut ip_series, start);
+        let tx_bps = || metrics.total_tx_bps.with_label_values(&["eth0"]).get();
+        let capture_bps = || metrics.capture_tx_bps.with_label_values(&["eth0"]).get();
+        assert_eq!(tx_bps(), 8_000.0);
+        assert_eq!(capture_bps(), 8_000.0);
+
+        let stats = TrafficStats::new();
+        let snapshot = IntervalSnapshot::from_stats(&stats, elapsed, SystemTime::now());
+        flush_stats(&metrics, &stats, &snapshot, &mut ip_series, start + elapsed);
+        assert_eq!(tx_bps(), 0.0);
+        assert_eq!(capture_bps(), 0.0);
+    }
+}
@@ -0,0 +1,220 @@
+use crate::metrics::{Flush, FlushSink, IpRate, Rate};
+use prometheus::{IntCounter, Registry};
+use rumqttc::{
+    AsyncClient, Event, EventLoop, LastWill, MqttOptions, Packet, QoS, TlsConfiguration, Transport,
+};
+use serde::Serialize;
+use std::path::PathBuf;
+use std::time::{Duration, UNIX_EPOCH};
+use tracing::{info, warn};
+
+// Wait before the event loop reconnects, doubled after every failure
+const MQTT_RETRY_INITIAL: Duration = Duration::from_secs(1);
+const MQTT_RETRY_MAX: Duration = Duration::from_secs(60);
+
+// Publishes queued while the broker is unreachable; later ones are skipped
+const MQTT_QUEUE: usize = 64;
+
+const AVAILABLE: &str = "online";
+const UNAVAILABLE: &str = "offline";
+
+#[derive(Debug, Clone)]
+pub struct MqttSettings {
+    pub host: String,
+    pub port: u16,
+    pub client_id: String,
+    pub user: Option<String>,
+    pub password: Option<String>,
+    // Topics are <prefix>/nic/<nic>, <prefix>/top and <prefix>/availability
+    pub topic_prefix: String,
+    // IPs in the <prefix>/top payload, 0 publishes no top list
+    pub top_ips: usize,
+    pub keep_alive: Duration,
+    pub tls: bool,
+    // PEM CA bundle for the broker, the system roots when unset
+    pub ca_file: Option<PathBuf>,
+    // PEM client certificate and key for brokers that require them
+    pub client_cert: Option<PathBuf>,
+    pub client_key: Option<PathBuf>,
+}
+
+#[derive(Debug, Serialize)]
+struct NicPayload<'a> {
+    nic: &'a str,
+    tx_bps: f64,
+    rx_bps: f64,
+    tx_pps: f64,
+    rx_pps: f64,
+    timestamp: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct TopPayload<'a> {
+    ip: String,
+    nic: &'a str,
+    tx_bps: f64,
+    rx_bps: f64,
+}
+
+fn tls_configuration(settings: &MqttSettings) -> Result<TlsConfiguration, String> {
+    let read = |path: &PathBuf| {
+        std::fs::read(path).map_err(|e| format!("failed to read {}: {}", path.display(), e))
+    };
+    let client_auth = match (&settings.client_cert, &settings.client_key) {
+        (Some(cert), Some(key)) => Some((read(cert)?, read(key)?)),
+        (None, None) => None,
+        _ => return Err("mqtt_client_cert and mqtt_client_key must be set together".into()),
+    };
+    match &settings.ca_file {
+        Some(ca) => Ok(TlsConfiguration::Simple {
+            ca: read(ca)?,
+            alpn: None,
+            client_auth,
+        }),
+        None if client_auth.is_some() => {
+            Err("mqtt_client_cert needs mqtt_ca_file for the broker".into())
+        }
+        None => Ok(TlsConfiguration::default()),
+    }
+}
+
+// Publishes the per-NIC totals (and optionally the top IPs) of every flush as JSON
+// with QoS 0. A retained <prefix>/availability of online/offline, the latter as the
+// last will, lets dashboards show when the exporter is gone.
+pub struct MqttSink {
+    client: AsyncClient,
+    topic_prefix: String,
+    top_ips: usize,
+}
+
+impl MqttSink {
+    pub fn spawn(settings: MqttSettings, registry: &Registry) -> Result<Self, String> {
+        let errors = IntCounter::new(
+            "mqtt_connection_errors_total",
+            "Failed or lost connections to the MQTT broker",
+        )
+        .map_err(|e| e.to_string())?;
+        registry
+            .register(Box::new(errors.clone()))
+            .map_err(|e| e.to_string())?;
+
+        let availability = format!("{}/availability", settings.topic_prefix);
+        let mut options = MqttOptions::new(&settings.client_id, &settings.host, settings.port);
+        options
+            .set_keep_alive(settings.keep_alive)
+            .set_last_will(LastWill::new(
+                &availability,
+                UNAVAILABLE,
+                QoS::AtLeastOnce,
+                true,
+            ));
+        if let Some(user) = &settings.user {
+            options.set_credentials(user, settings.password.clone().unwrap_or_default());
+        }
+        if settings.tls {
+            options.set_transport(Transport::Tls(tls_configuration(&settings)?));
+        }
+        let (client, eventloop) = AsyncClient::new(options, MQTT_QUEUE);
+        info!(
+            "Publishing to MQTT broker {}:{}{} under {}/",
+            settings.host,
+            settings.port,
+            if settings.tls { " (TLS)" } else { "" },
+            settings.topic_prefix
+        );
+        tokio::spawn(run_event_loop(
+            eventloop,
+            client.clone(),
+            availability,
+            errors,
+        ));
+        Ok(Self {
+            client,
+            topic_prefix: settings.topic_prefix,
+            top_ips: settings.top_ips,
+        })
+    }
+
+    fn publish_json<T: Serialize>(&self, topic: String, payload: &T) {
+        if let Ok(payload) = serde_json::to_vec(payload) {
+            // Full while disconnected: this interval is skipped
+            let _ = self
+                .client
+                .try_publish(topic, QoS::AtMostOnce, false, payload);
+        }
+    }
+}
+
+impl FlushSink for MqttSink {
+    fn publish(&mut self, flush: &Flush<'_>) {
+        let snapshot = flush.snapshot;
+        let timestamp = snapshot
+            .timestamp
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        for rate in &snapshot.per_nic {
+            let (tx, rx) = (rate.tx.unwrap_or_default(), rate.rx.unwrap_or_default());
+            self.publish_json(
+                format!("{}/nic/{}", self.topic_prefix, rate.nic),
+                &NicPayload {
+                    nic: &rate.nic,
+                    tx_bps: tx.bps,
+                    rx_bps: rx.bps,
+                    tx_pps: tx.pps,
+                    rx_pps: rx.pps,
+                    timestamp,
+                },
+            );
+        }
+
+        if self.top_ips == 0 {
+            return;
+        }
+        let total = |rate: &IpRate| {
+            let bps = |side: Option<Rate>| side.map_or(0.0, |rate| rate.bps);
+            bps(rate.tx) + bps(rate.rx)
+        };
+        let mut top: Vec<&IpRate> = snapshot.per_ip.iter().collect();
+        top.sort_by(|a, b| total(b).total_cmp(&total(a)));
+        top.truncate(self.top_ips);
+        let top: Vec<TopPayload<'_>> = top
+            .into_iter()
+            .map(|rate| TopPayload {
+                ip: rate.key.ip_label(),
+                nic: &rate.key.nic,
+                tx_bps: rate.tx_bps(),
+                rx_bps: rate.rx_bps(),
+            })
+            .collect();
+        self.publish_json(format!("{}/top", self.topic_prefix), &top);
+    }
+}
+
+// Drives the connection; polling again after an error reconnects
+async fn run_event_loop(
+    mut eventloop: EventLoop,
+    client: AsyncClient,
+    availability: String,
+    errors: IntCounter,
+) {
+    let mut backoff = MQTT_RETRY_INITIAL;
+    loop {
+        match eventloop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                info!("Connected to MQTT broker");
+                backoff = MQTT_RETRY_INITIAL;
+                let _ = client.try_publish(&availability, QoS::AtLeastOnce, true, AVAILABLE);
+            }
+            Ok(_) => {}
+            Err(e) => {
+                errors.inc();
+                warn!(
+                    "MQTT connection failed: {}, reconnecting in {:?}",
+                    e, backoff
+                );
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MQTT_RETRY_MAX);
+            }
+        }
+    }
+}
@@ -0,0 +1,138 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::time;
+use tracing::{debug, warn};
+
+// mac label of IPs missing from the neighbor table (IPv6, remote or not yet seen)
+pub const UNKNOWN_MAC: &str = "unknown";
+
+pub const PROC_NET_ARP: &str = "/proc/net/arp";
+
+// ATF_COM: the kernel has a resolved hardware address for the entry
+const ATF_COM: u32 = 0x2;
+
+#[derive(Debug)]
+struct Entry {
+    mac: Arc<str>,
+    // Last refresh that still listed the IP
+    last_listed: Instant,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    entries: HashMap<IpAddr, Entry>,
+    // IPs whose mac label changed since the last take_changed()
+    changed: Vec<IpAddr>,
+}
+
+// IP -> MAC cache filled from the kernel neighbor table by refresh_neighbors().
+// An IP keeps its MAC while the entry ages out of the table, so series only move
+// when another device takes over the address or it is gone for idle_timeout.
+#[derive(Debug)]
+pub struct NeighborCache {
+    inner: Mutex<Inner>,
+    idle_timeout: Duration,
+}
+
+impl NeighborCache {
+    pub fn new(idle_timeout: Duration) -> Self {
+        Self {
+            inner: Mutex::new(Inner::default()),
+            idle_timeout,
+        }
+    }
+
+    pub fn mac(&self, ip: &IpAddr) -> Arc<str> {
+        self.inner
+            .lock()
+            .unwrap()
+            .entries
+            .get(ip)
+            .map(|entry| entry.mac.clone())
+            .unwrap_or_else(|| Arc::from(UNKNOWN_MAC))
+    }
+
+    pub fn take_changed(&self) -> Vec<IpAddr> {
+        std::mem::take(&mut self.inner.lock().unwrap().changed)
+    }
+
+    // Merge one reading of the neighbor table
+    pub fn update(&self, table: HashMap<IpAddr, String>) {
+        let now = Instant::now();
+        let mut inner = self.inner.lock().unwrap();
+        let Inner { entries, changed } = &mut *inner;
+        for (ip, mac) in table {
+            match entries.get_mut(&ip) {
+                Some(entry) => {
+                    entry.last_listed = now;
+                    if *entry.mac != *mac {
+                        entry.mac = Arc::from(mac);
+                        changed.push(ip);
+                    }
+                }
+                None => {
+                    entries.insert(
+                        ip,
+                        Entry {
+                            mac: Arc::from(mac),
+                            last_listed: now,
+                        },
+                    );
+                    changed.push(ip);
+                }
+            }
+        }
+        let idle_timeout = self.idle_timeout;
+        entries.retain(|ip, entry| {
+            let keep = now.duration_since(entry.last_listed) <= idle_timeout;
+            if !keep {
+                changed.push(*ip);
+            }
+            keep
+        });
+    }
+}
+
+// Complete entries of /proc/net/arp:
+// "IP address  HW type  Flags  HW address  Mask  Device", one header line
+pub fn parse_proc_net_arp(contents: &str) -> HashMap<IpAddr, String> {
+    let mut table = HashMap::new();
+    for line in contents.lines().skip(1) {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let [ip, _, flags, mac, ..] = fields[..] else {
+            continue;
+        };
+        let Ok(ip) = ip.parse::<IpAddr>() else {
+            continue;
+        };
+        let flags = u32::from_str_radix(flags.trim_start_matches("0x"), 16).unwrap_or(0);
+        if flags & ATF_COM == 0 || mac == "00:00:00:00:00:00" {
+            continue;
+        }
+        table.insert(ip, mac.to_ascii_lowercase());
+    }
+    table
+}
+
+// Re-read the neighbor table every `interval`
+pub async fn refresh_neighbors(cache: Arc<NeighborCache>, path: PathBuf, interval: Duration) {
+    let mut ticker = time::interval(interval);
+    loop {
+        ticker.tick().await;
+        match tokio::fs::read_to_string(&path).await {
+            Ok(contents) => {
+                let table = parse_proc_net_arp(&contents);
+                debug!(
+                    "Read {} neighbor entries from {}",
+                    table.len(),
+                    path.display()
+                );
+                cache.update(table);
+            }
+            Err(e) => warn!("Failed to read {}: {}", path.display(), e),
+        }
+    }
+}
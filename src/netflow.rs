@@ -0,0 +1,400 @@
+use crate::packet::PacketInfo;
+use lru::LruCache;
+use prometheus::{IntCounter, IntGauge, Registry};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::num::NonZeroUsize;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::time;
+use tracing::{error, info};
+
+// Samples queued between the capture threads and the flow table
+const FLOW_QUEUE_CAPACITY: usize = 8192;
+
+// How often expired flows are looked for
+const FLOW_EXPIRY_INTERVAL: Duration = Duration::from_secs(1);
+
+const V5_HEADER_LEN: usize = 24;
+const V5_RECORD_LEN: usize = 48;
+// Most records a NetFlow v5 datagram may carry
+const V5_MAX_RECORDS: usize = 30;
+
+#[derive(Debug, Clone)]
+pub struct NetflowSettings {
+    pub collector: SocketAddr,
+    // A flow still seeing packets is exported (and restarted) after this long
+    pub active_timeout: Duration,
+    // A flow without packets for this long is exported and forgotten
+    pub inactive_timeout: Duration,
+    // Flows tracked at once; beyond this the least recently seen one is exported early
+    pub max_flows: usize,
+    // --sample N, announced in the header so collectors scale the counts
+    pub sample_rate: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct NetflowMetrics {
+    pub exported: IntCounter,
+    pub evicted: IntCounter,
+    pub dropped: IntCounter,
+    pub errors: IntCounter,
+    pub active: IntGauge,
+}
+
+impl NetflowMetrics {
+    pub fn register(registry: &Registry) -> prometheus::Result<Self> {
+        let metrics = Self {
+            exported: IntCounter::new(
+                "netflow_flows_exported_total",
+                "Flow records sent to the NetFlow collector",
+            )?,
+            evicted: IntCounter::new(
+                "netflow_flows_evicted_total",
+                "Flows exported before their timeout because netflow_max_flows was reached",
+            )?,
+            dropped: IntCounter::new(
+                "netflow_samples_dropped_total",
+                "Packets not added to the flow table because its queue was full",
+            )?,
+            errors: IntCounter::new(
+                "netflow_export_errors_total",
+                "NetFlow datagrams that could not be sent",
+            )?,
+            active: IntGauge::new("netflow_flows_active", "Flows currently tracked")?,
+        };
+        registry.register(Box::new(metrics.exported.clone()))?;
+        registry.register(Box::new(metrics.evicted.clone()))?;
+        registry.register(Box::new(metrics.dropped.clone()))?;
+        registry.register(Box::new(metrics.errors.clone()))?;
+        registry.register(Box::new(metrics.active.clone()))?;
+        Ok(metrics)
+    }
+}
+
+// NetFlow v5 carries IPv4 only; IPv6 flows wait for IPFIX
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FlowTuple {
+    pub src: Ipv4Addr,
+    pub dst: Ipv4Addr,
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub proto: u8,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct FlowSample {
+    tuple: FlowTuple,
+    bytes: u64,
+    tcp_flags: u8,
+    tos: u8,
+    at: Instant,
+}
+
+// Capture side of the flow table
+#[derive(Debug, Clone)]
+pub struct FlowSender {
+    samples: mpsc::Sender<FlowSample>,
+    dropped: IntCounter,
+}
+
+impl FlowSender {
+    pub fn observe(&self, packet: &PacketInfo) {
+        let (IpAddr::V4(src), IpAddr::V4(dst)) = (packet.src_ip, packet.dst_ip) else {
+            return;
+        };
+        let (src_port, dst_port) = packet.ports.unwrap_or((0, 0));
+        let sample = FlowSample {
+            tuple: FlowTuple {
+                src,
+                dst,
+                src_port,
+                dst_port,
+                proto: packet.ip_proto,
+            },
+            bytes: packet.ip_len,
+            tcp_flags: packet.tcp_flags,
+            tos: packet.tos,
+            at: Instant::now(),
+        };
+        if self.samples.try_send(sample).is_err() {
+            self.dropped.inc();
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Flow {
+    first: Instant,
+    last: Instant,
+    packets: u64,
+    bytes: u64,
+    tcp_flags: u8,
+    tos: u8,
+}
+
+struct Exporter {
+    settings: NetflowSettings,
+    metrics: NetflowMetrics,
+    socket: UdpSocket,
+    // sysUptime zero point of the v5 header
+    boot: Instant,
+    sequence: u32,
+    // Least recently seen first, so reaching max_flows evicts in constant time
+    flows: LruCache<FlowTuple, Flow>,
+    pending: Vec<(FlowTuple, Flow)>,
+}
+
+impl Exporter {
+    fn add(&mut self, sample: FlowSample) {
+        if !self.flows.contains(&sample.tuple) && self.flows.len() >= self.flows.cap().get() {
+            self.evict_oldest();
+        }
+        let flow = self.flows.get_or_insert_mut(sample.tuple, || Flow {
+            first: sample.at,
+            last: sample.at,
+            packets: 0,
+            bytes: 0,
+            tcp_flags: 0,
+            tos: sample.tos,
+        });
+        flow.last = flow.last.max(sample.at);
+        flow.packets += 1;
+        flow.bytes += sample.bytes;
+        flow.tcp_flags |= sample.tcp_flags;
+    }
+
+    fn evict_oldest(&mut self) {
+        if let Some((tuple, flow)) = self.flows.pop_lru() {
+            self.metrics.evicted.inc();
+            self.pending.push((tuple, flow));
+        }
+    }
+
+    fn expire(&mut self, now: Instant) {
+        let done: Vec<FlowTuple> = self
+            .flows
+            .iter()
+            .filter(|(_, flow)| {
+                now.duration_since(flow.last) >= self.settings.inactive_timeout
+                    || now.duration_since(flow.first) >= self.settings.active_timeout
+            })
+            .map(|(tuple, _)| *tuple)
+            .collect();
+        for tuple in done {
+            if let Some(flow) = self.flows.pop(&tuple) {
+                self.pending.push((tuple, flow));
+            }
+        }
+        self.metrics.active.set(self.flows.len() as i64);
+    }
+
+    fn uptime_ms(&self, at: Instant) -> u32 {
+        at.saturating_duration_since(self.boot).as_millis() as u32
+    }
+
+    fn encode(&self, records: &[(FlowTuple, Flow)]) -> Vec<u8> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut packet = Vec::with_capacity(V5_HEADER_LEN + records.len() * V5_RECORD_LEN);
+        packet.extend_from_slice(&5u16.to_be_bytes());
+        packet.extend_from_slice(&(records.len() as u16).to_be_bytes());
+        packet.extend_from_slice(&self.uptime_ms(Instant::now()).to_be_bytes());
+        packet.extend_from_slice(&(now.as_secs() as u32).to_be_bytes());
+        packet.extend_from_slice(&now.subsec_nanos().to_be_bytes());
+        packet.extend_from_slice(&self.sequence.to_be_bytes());
+        // engine type/id, then sampling mode (01: deterministic) and interval
+        packet.extend_from_slice(&[0, 0]);
+        let sampling = match self.settings.sample_rate {
+            0 | 1 => 0,
+            n => 0x4000 | (n.min(0x3fff) as u16),
+        };
+        packet.extend_from_slice(&sampling.to_be_bytes());
+
+        for (tuple, flow) in records {
+            packet.extend_from_slice(&tuple.src.octets());
+            packet.extend_from_slice(&tuple.dst.octets());
+            // next hop, input and output ifIndex are unknown
+            packet.extend_from_slice(&[0; 8]);
+            packet.extend_from_slice(&(flow.packets.min(u32::MAX as u64) as u32).to_be_bytes());
+            packet.extend_from_slice(&(flow.bytes.min(u32::MAX as u64) as u32).to_be_bytes());
+            packet.extend_from_slice(&self.uptime_ms(flow.first).to_be_bytes());
+            packet.extend_from_slice(&self.uptime_ms(flow.last).to_be_bytes());
+            packet.extend_from_slice(&tuple.src_port.to_be_bytes());
+            packet.extend_from_slice(&tuple.dst_port.to_be_bytes());
+            packet.extend_from_slice(&[0, flow.tcp_flags, tuple.proto, flow.tos]);
+            // AS numbers, prefix masks and padding
+            packet.extend_from_slice(&[0; 8]);
+        }
+        packet
+    }
+
+    async fn send_pending(&mut self) {
+        let pending = std::mem::take(&mut self.pending);
+        for records in pending.chunks(V5_MAX_RECORDS) {
+            let datagram = self.encode(records);
+            // The sequence counts flows, lost datagrams still advance it
+            self.sequence = self.sequence.wrapping_add(records.len() as u32);
+            match self
+                .socket
+                .send_to(&datagram, self.settings.collector)
+                .await
+            {
+                Ok(_) => self.metrics.exported.inc_by(records.len() as u64),
+                Err(e) => {
+                    self.metrics.errors.inc();
+                    error!(
+                        "Failed to send NetFlow to {}: {}",
+                        self.settings.collector, e
+                    );
+                }
+            }
+        }
+    }
+}
+
+// Sender for the capture threads and the receiving half for export_flows()
+pub fn flow_channel(metrics: &NetflowMetrics) -> (FlowSender, mpsc::Receiver<FlowSample>) {
+    let (samples, samples_rx) = mpsc::channel(FLOW_QUEUE_CAPACITY);
+    (
+        FlowSender {
+            samples,
+            dropped: metrics.dropped.clone(),
+        },
+        samples_rx,
+    )
+}
+
+// Maintain the flow table and send expired flows to the collector as NetFlow v5.
+// Flows still open when the sample channel closes are flushed before returning.
+pub async fn export_flows(
+    settings: NetflowSettings,
+    metrics: NetflowMetrics,
+    mut samples: mpsc::Receiver<FlowSample>,
+) -> std::io::Result<()> {
+    let bind: SocketAddr = if settings.collector.is_ipv4() {
+        (Ipv4Addr::UNSPECIFIED, 0).into()
+    } else {
+        (std::net::Ipv6Addr::UNSPECIFIED, 0).into()
+    };
+    let socket = UdpSocket::bind(bind).await?;
+    info!("Exporting NetFlow v5 to {}", settings.collector);
+
+    let max_flows = NonZeroUsize::new(settings.max_flows.max(1)).unwrap();
+    let mut exporter = Exporter {
+        settings,
+        metrics,
+        socket,
+        boot: Instant::now(),
+        sequence: 0,
+        flows: LruCache::new(max_flows),
+        pending: Vec::new(),
+    };
+    let mut expiry = time::interval(FLOW_EXPIRY_INTERVAL);
+    loop {
+        // Evicted flows go out once they fill a datagram, expired ones every tick
+        tokio::select! {
+            sample = samples.recv() => match sample {
+                Some(sample) => exporter.add(sample),
+                None => break,
+            },
+            _ = expiry.tick() => {
+                exporter.expire(Instant::now());
+                exporter.send_pending().await;
+            }
+        }
+        if exporter.pending.len() >= V5_MAX_RECORDS {
+            exporter.send_pending().await;
+        }
+    }
+
+    while let Some(open) = exporter.flows.pop_lru() {
+        exporter.pending.push(open);
+    }
+    exporter.send_pending().await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(src_port: u16, at: Instant) -> FlowSample {
+        FlowSample {
+            tuple: FlowTuple {
+                src: Ipv4Addr::new(192, 168, 1, 10),
+                dst: Ipv4Addr::new(203, 0, 113, 7),
+                src_port,
+                dst_port: 443,
+                proto: 6,
+            },
+            bytes: 60,
+            tcp_flags: 0x02,
+            tos: 0,
+            at,
+        }
+    }
+
+    async fn exporter(max_flows: usize) -> Exporter {
+        let settings = NetflowSettings {
+            collector: (Ipv4Addr::LOCALHOST, 2055).into(),
+            active_timeout: Duration::from_secs(60),
+            inactive_timeout: Duration::from_secs(15),
+            max_flows,
+            sample_rate: 1,
+        };
+        Exporter {
+            settings,
+            metrics: NetflowMetrics::register(&Registry::new()).unwrap(),
+            socket: UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap(),
+            boot: Instant::now(),
+            sequence: 0,
+            flows: LruCache::new(NonZeroUsize::new(max_flows).unwrap()),
+            pending: Vec::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn full_table_evicts_the_least_recently_seen_flow() {
+        let mut exporter = exporter(2).await;
+        let start = Instant::now();
+        exporter.add(sample(40001, start));
+        exporter.add(sample(40002, start + Duration::from_millis(10)));
+        // Seen again, so 40002 is now the oldest
+        exporter.add(sample(40001, start + Duration::from_millis(20)));
+        exporter.add(sample(40003, start + Duration::from_millis(30)));
+
+        assert_eq!(exporter.metrics.evicted.get(), 1);
+        assert_eq!(exporter.pending.len(), 1);
+        assert_eq!(exporter.pending[0].0.src_port, 40002);
+        assert_eq!(exporter.flows.len(), 2);
+        assert_eq!(
+            exporter
+                .flows
+                .peek(&sample(40001, start).tuple)
+                .unwrap()
+                .packets,
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn idle_and_long_running_flows_expire() {
+        let mut exporter = exporter(16).await;
+        let start = Instant::now();
+        // Active since the start and still seeing packets
+        exporter.add(sample(40001, start));
+        exporter.add(sample(40001, start + Duration::from_secs(59)));
+        // Last seen 16 s before the check
+        exporter.add(sample(40002, start + Duration::from_secs(44)));
+        exporter.add(sample(40003, start + Duration::from_secs(50)));
+
+        exporter.expire(start + Duration::from_secs(60));
+
+        let mut expired: Vec<u16> = exporter.pending.iter().map(|(t, _)| t.src_port).collect();
+        expired.sort();
+        assert_eq!(expired, [40001, 40002]);
+        assert_eq!(exporter.metrics.active.get(), 1);
+    }
+}
@@ -0,0 +1,377 @@
+use crate::metrics::{Flush, FlushSink, IpRate, NicRate, Rate};
+use opentelemetry_proto::tonic::collector::metrics::v1::ExportMetricsServiceRequest;
+use opentelemetry_proto::tonic::common::v1::{any_value, AnyValue, InstrumentationScope, KeyValue};
+use opentelemetry_proto::tonic::metrics::v1::{
+    metric, number_data_point, AggregationTemporality, Gauge, Metric, NumberDataPoint,
+    ResourceMetrics, ScopeMetrics, Sum,
+};
+use opentelemetry_proto::tonic::resource::v1::Resource;
+use prometheus::IntCounter;
+use prost::Message;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+use tracing::{error, info};
+
+// Backoff after a failed export; payloads flushed meanwhile are skipped
+const OTLP_RETRY_INITIAL: Duration = Duration::from_secs(1);
+const OTLP_RETRY_MAX: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone)]
+pub struct OtlpSettings {
+    // OTLP/HTTP metrics endpoint, e.g. http://collector:4318/v1/metrics
+    pub endpoint: String,
+    pub headers: BTreeMap<String, String>,
+    pub timeout: Duration,
+    // metric_namespace, the prefix of the traffic metrics as on /metrics
+    pub namespace: String,
+}
+
+// Pushes every flush to an OTLP/HTTP collector (protobuf encoding). Payloads are
+// built on the updater task and posted by a background task, one at a time.
+pub struct OtlpSink {
+    payloads: mpsc::Sender<Vec<u8>>,
+    namespace: String,
+    // Start of the current delta interval for the byte sums
+    last_flush: SystemTime,
+}
+
+impl OtlpSink {
+    pub fn spawn(
+        settings: OtlpSettings,
+        failures: IntCounter,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in &settings.headers {
+            headers.insert(
+                HeaderName::from_bytes(name.as_bytes())?,
+                HeaderValue::from_str(value)?,
+            );
+        }
+        let client = reqwest::Client::builder()
+            .timeout(settings.timeout)
+            .default_headers(headers)
+            .build()?;
+        let (payloads, payloads_rx) = mpsc::channel(1);
+        info!("Exporting metrics to OTLP endpoint {}", settings.endpoint);
+        tokio::spawn(export_payloads(
+            client,
+            Arc::from(settings.endpoint),
+            payloads_rx,
+            failures,
+        ));
+        Ok(Self {
+            payloads,
+            namespace: settings.namespace.trim_end_matches('_').to_string(),
+            last_flush: SystemTime::now(),
+        })
+    }
+}
+
+impl FlushSink for OtlpSink {
+    fn publish(&mut self, flush: &Flush<'_>) {
+        let payload = encode_flush(flush, &self.namespace, self.last_flush).encode_to_vec();
+        self.last_flush = flush.timestamp;
+        // Busy or backing off: this interval is skipped
+        let _ = self.payloads.try_send(payload);
+    }
+}
+
+fn unix_nanos(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+fn attributes(pairs: &[(&str, &str)]) -> Vec<KeyValue> {
+    pairs
+        .iter()
+        .map(|(key, value)| KeyValue {
+            key: key.to_string(),
+            value: Some(AnyValue {
+                value: Some(any_value::Value::StringValue(value.to_string())),
+            }),
+            ..Default::default()
+        })
+        .collect()
+}
+
+fn metric(name: &str, unit: &str, data: metric::Data) -> Metric {
+    Metric {
+        name: name.to_string(),
+        unit: unit.to_string(),
+        data: Some(data),
+        ..Default::default()
+    }
+}
+
+// One direction of a snapshot entry and one measurement of it
+type Side<T> = fn(&T) -> Option<Rate>;
+type RateValue = fn(Rate) -> f64;
+
+// OTLP ExportMetricsServiceRequest with the same measurements and names as /metrics
+fn encode_flush(
+    flush: &Flush<'_>,
+    namespace: &str,
+    start: SystemTime,
+) -> ExportMetricsServiceRequest {
+    let snapshot = flush.snapshot;
+    let name = |suffix: &str| match namespace {
+        "" => suffix.to_string(),
+        namespace => format!("{}_{}", namespace, suffix),
+    };
+    let time = unix_nanos(snapshot.timestamp);
+    let start = unix_nanos(start);
+
+    let double = |attributes: Vec<KeyValue>, value: f64| NumberDataPoint {
+        attributes,
+        time_unix_nano: time,
+        value: Some(number_data_point::Value::AsDouble(value)),
+        ..Default::default()
+    };
+    let ip_attrs = |key: &crate::stats::FlowKey| {
+        attributes(&[("local_ip", &key.ip_label()), ("nic", &key.nic[..])])
+    };
+    let ip_gauges: [(&str, &str, Side<IpRate>, RateValue); 4] = [
+        ("ip_tx_bps", "bit/s", |ip| ip.tx, |rate| rate.bps),
+        ("ip_rx_bps", "bit/s", |ip| ip.rx, |rate| rate.bps),
+        ("ip_tx_pps", "{packet}/s", |ip| ip.tx, |rate| rate.pps),
+        ("ip_rx_pps", "{packet}/s", |ip| ip.rx, |rate| rate.pps),
+    ];
+    let nic_gauges: [(&str, &str, Side<NicRate>, RateValue); 4] = [
+        ("ip_tx_bps_total", "bit/s", |nic| nic.tx, |rate| rate.bps),
+        ("ip_rx_bps_total", "bit/s", |nic| nic.rx, |rate| rate.bps),
+        (
+            "ip_tx_pps_total",
+            "{packet}/s",
+            |nic| nic.tx,
+            |rate| rate.pps,
+        ),
+        (
+            "ip_rx_pps_total",
+            "{packet}/s",
+            |nic| nic.rx,
+            |rate| rate.pps,
+        ),
+    ];
+
+    let mut metrics = Vec::new();
+    for (suffix, unit, side, value) in ip_gauges {
+        let data_points = snapshot
+            .per_ip
+            .iter()
+            .filter_map(|ip| side(ip).map(|rate| double(ip_attrs(&ip.key), value(rate))))
+            .collect();
+        metrics.push(metric(
+            &name(suffix),
+            unit,
+            metric::Data::Gauge(Gauge { data_points }),
+        ));
+    }
+    for (suffix, unit, side, value) in nic_gauges {
+        let data_points = snapshot
+            .per_nic
+            .iter()
+            .filter_map(|nic| {
+                side(nic).map(|rate| double(attributes(&[("nic", &nic.nic[..])]), value(rate)))
+            })
+            .collect();
+        metrics.push(metric(
+            &name(suffix),
+            unit,
+            metric::Data::Gauge(Gauge { data_points }),
+        ));
+    }
+    // Byte counts as delta sums, the OTLP counterpart of <namespace>_ip_*_bytes_total
+    let ip_sums: [(&str, Side<IpRate>); 2] =
+        [("ip_tx_bytes", |ip| ip.tx), ("ip_rx_bytes", |ip| ip.rx)];
+    for (suffix, side) in ip_sums {
+        let data_points = snapshot
+            .per_ip
+            .iter()
+            .filter_map(|ip| {
+                side(ip).map(|rate| NumberDataPoint {
+                    attributes: ip_attrs(&ip.key),
+                    start_time_unix_nano: start,
+                    time_unix_nano: time,
+                    value: Some(number_data_point::Value::AsInt(
+                        rate.bytes.min(i64::MAX as u64) as i64,
+                    )),
+                    ..Default::default()
+                })
+            })
+            .collect();
+        let sum = Sum {
+            data_points,
+            aggregation_temporality: AggregationTemporality::Delta as i32,
+            is_monotonic: true,
+        };
+        metrics.push(metric(&name(suffix), "By", metric::Data::Sum(sum)));
+    }
+
+    ExportMetricsServiceRequest {
+        resource_metrics: vec![ResourceMetrics {
+            resource: Some(Resource {
+                attributes: attributes(&[("service.name", "localpacketdump")]),
+                ..Default::default()
+            }),
+            scope_metrics: vec![ScopeMetrics {
+                scope: Some(InstrumentationScope {
+                    name: "localpacketdump".to_string(),
+                    ..Default::default()
+                }),
+                metrics,
+                ..Default::default()
+            }],
+            ..Default::default()
+        }],
+    }
+}
+
+async fn export_payloads(
+    client: reqwest::Client,
+    endpoint: Arc<str>,
+    mut payloads: mpsc::Receiver<Vec<u8>>,
+    failures: IntCounter,
+) {
+    let mut backoff = OTLP_RETRY_INITIAL;
+    while let Some(payload) = payloads.recv().await {
+        let result = client
+            .post(&*endpoint)
+            .header(CONTENT_TYPE, "application/x-protobuf")
+            .body(payload)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        match result {
+            Ok(_) => backoff = OTLP_RETRY_INITIAL,
+            Err(e) => {
+                failures.inc();
+                error!(
+                    "OTLP export to {} failed: {}, pausing exports for {:?}",
+                    endpoint, e, backoff
+                );
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(OTLP_RETRY_MAX);
+                // Drop what was flushed while waiting, it is stale by now
+                while payloads.try_recv().is_ok() {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::IntervalSnapshot;
+    use crate::stats::{FlowKey, TrafficStats};
+
+    fn rate(bps: f64, bytes: u64) -> Option<Rate> {
+        Some(Rate {
+            bps,
+            pps: 10.0,
+            bytes,
+        })
+    }
+
+    #[test]
+    fn flush_is_sent_as_a_protobuf_export_request() {
+        let stats = TrafficStats::new();
+        let start = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let snapshot = IntervalSnapshot {
+            timestamp: start + Duration::from_secs(1),
+            elapsed: Duration::from_secs(1),
+            per_ip: vec![IpRate {
+                key: FlowKey {
+                    nic: Arc::from("wan0"),
+                    wan: None,
+                    ip: Some("192.168.1.10".parse().unwrap()),
+                },
+                tx: rate(8000.0, 1000),
+                rx: None,
+            }],
+            per_nic: vec![NicRate {
+                nic: Arc::from("wan0"),
+                tx: rate(8000.0, 1000),
+                rx: None,
+                tx_peak_bps: None,
+                rx_peak_bps: None,
+            }],
+            per_wan: Vec::new(),
+        };
+        let flush = Flush {
+            stats: &stats,
+            snapshot: &snapshot,
+            elapsed: snapshot.elapsed,
+            now: tokio::time::Instant::now(),
+            timestamp: snapshot.timestamp,
+        };
+
+        let payload = encode_flush(&flush, "network", start).encode_to_vec();
+        let request = ExportMetricsServiceRequest::decode(&payload[..]).unwrap();
+        let metrics = &request.resource_metrics[0].scope_metrics[0].metrics;
+        let find = |name: &str| metrics.iter().find(|m| m.name == name).unwrap();
+
+        let Some(metric::Data::Gauge(gauge)) = &find("network_ip_tx_bps").data else {
+            panic!("network_ip_tx_bps is not a gauge");
+        };
+        let point = &gauge.data_points[0];
+        assert_eq!(
+            point.value,
+            Some(number_data_point::Value::AsDouble(8000.0))
+        );
+        assert_eq!(point.time_unix_nano, 1_700_000_001_000_000_000);
+        assert_eq!(
+            point.attributes,
+            attributes(&[("local_ip", "192.168.1.10"), ("nic", "wan0")])
+        );
+        // A direction without traffic has no points
+        let Some(metric::Data::Gauge(gauge)) = &find("network_ip_rx_bps").data else {
+            panic!("network_ip_rx_bps is not a gauge");
+        };
+        assert!(gauge.data_points.is_empty());
+
+        let Some(metric::Data::Sum(sum)) = &find("network_ip_tx_bytes").data else {
+            panic!("network_ip_tx_bytes is not a sum");
+        };
+        assert_eq!(
+            sum.aggregation_temporality,
+            AggregationTemporality::Delta as i32
+        );
+        assert_eq!(
+            sum.data_points[0].start_time_unix_nano,
+            1_700_000_000_000_000_000
+        );
+        assert_eq!(
+            sum.data_points[0].value,
+            Some(number_data_point::Value::AsInt(1000))
+        );
+    }
+
+    #[test]
+    fn metric_names_follow_the_namespace() {
+        let stats = TrafficStats::new();
+        let snapshot = IntervalSnapshot::empty();
+        let flush = Flush {
+            stats: &stats,
+            snapshot: &snapshot,
+            elapsed: snapshot.elapsed,
+            now: tokio::time::Instant::now(),
+            timestamp: snapshot.timestamp,
+        };
+        let names = |namespace| {
+            let request = encode_flush(&flush, namespace, snapshot.timestamp);
+            let metrics = &request.resource_metrics[0].scope_metrics[0].metrics;
+            metrics.iter().map(|m| m.name.clone()).collect::<Vec<_>>()
+        };
+
+        let lpd = names("lpd");
+        assert!(lpd.contains(&"lpd_ip_tx_bps".to_string()));
+        assert!(lpd.contains(&"lpd_ip_rx_pps_total".to_string()));
+        assert!(lpd.contains(&"lpd_ip_tx_bytes".to_string()));
+        assert!(lpd.iter().all(|name| name.starts_with("lpd_ip_")));
+        assert!(names("").contains(&"ip_tx_bps".to_string()));
+    }
+}
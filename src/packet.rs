@@ -0,0 +1,1068 @@
+use pcap::Linktype;
+use pnet::datalink::MacAddr;
+use pnet::packet::arp::{ArpHardwareTypes, ArpOperations, ArpPacket};
+use pnet::packet::ethernet::{EtherType, EtherTypes, EthernetPacket};
+use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+use pnet::packet::ipv4::{Ipv4Flags, Ipv4Packet};
+use pnet::packet::ipv6::Ipv6Packet;
+use pnet::packet::tcp::TcpPacket;
+use pnet::packet::udp::UdpPacket;
+use pnet::packet::Packet;
+use std::collections::BTreeMap;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::Arc;
+
+// Linux cooked capture header ("any" device): packet type, ARPHRD type, address
+// length, 8 bytes of address, then the ethertype
+const SLL_HEADER_LEN: usize = 16;
+const SLL_PROTOCOL_OFFSET: usize = 14;
+const SLL_ARPHRD_OFFSET: usize = 2;
+// Version 2: ethertype, reserved, ifindex, ARPHRD type, packet type, address length
+// and 8 bytes of address
+const SLL2_HEADER_LEN: usize = 20;
+const SLL2_IFINDEX_OFFSET: usize = 4;
+const SLL2_ARPHRD_OFFSET: usize = 8;
+const SLL2_PACKET_TYPE_OFFSET: usize = 10;
+// Packet types of the cooked headers: sent to this host, sent by it
+pub const SLL_HOST: u8 = 0;
+pub const SLL_OUTGOING: u8 = 4;
+// Devices whose own capture has an Ethernet header (Linux lo does too)
+const ARPHRD_ETHER: u16 = 1;
+const ARPHRD_LOOPBACK: u16 = 772;
+const ETHERNET_HEADER_LEN: u64 = 14;
+// BSD loopback header: the address family, in the capturing host's byte order for
+// DLT_NULL and in network byte order for DLT_LOOP
+const LOOPBACK_HEADER_LEN: usize = 4;
+const AF_INET: u32 = 2;
+// Linux, FreeBSD, macOS and OpenBSD/NetBSD number AF_INET6 differently
+const AF_INET6: [u32; 4] = [10, 28, 30, 24];
+// DLT_RAW is 12 on most systems, 14 on OpenBSD; 101 is its LINKTYPE_ value in files
+const DLT_RAW: [i32; 3] = [12, 14, 101];
+// 802.1Q tag: 2 bytes TCI + 2 bytes inner ethertype
+const VLAN_TAG_LEN: usize = 4;
+// Single tag or QinQ (outer + inner)
+const MAX_VLAN_TAGS: usize = 2;
+// Fixed IPv6 header, not included in its payload length field
+const IPV6_HEADER_LEN: u64 = 40;
+// IPv6 extension headers skipped on the way to the upper-layer protocol (RFC 8200)
+const IPV6_HOP_BY_HOP: u8 = 0;
+const IPV6_ROUTING: u8 = 43;
+const IPV6_FRAGMENT: u8 = 44;
+const IPV6_AUTHENTICATION: u8 = 51;
+const IPV6_DESTINATION_OPTIONS: u8 = 60;
+const IPV6_MOBILITY: u8 = 135;
+const IPV6_HIP: u8 = 139;
+const IPV6_SHIM6: u8 = 140;
+const MAX_IPV6_EXTENSION_HEADERS: usize = 8;
+const IPV6_FRAGMENT_HEADER_LEN: usize = 8;
+// Fragment offset in 8-byte units, above the two reserved bits and the M flag
+const IPV6_FRAGMENT_OFFSET_MASK: u16 = 0xfff8;
+const IPV6_MORE_FRAGMENTS: u16 = 0x0001;
+// IPv4 header without options (IHL 5)
+const IPV4_MIN_HEADER_LEN: usize = 20;
+// TCP header without options; the flags are its 14th byte
+const TCP_MIN_HEADER_LEN: usize = 20;
+const TCP_FLAGS_OFFSET: usize = 13;
+// GRE flags and version, then the protocol type of the payload (RFC 2784/2890)
+const GRE_MIN_HEADER_LEN: usize = 4;
+const GRE_CHECKSUM_PRESENT: u16 = 0x8000;
+const GRE_ROUTING_PRESENT: u16 = 0x4000;
+const GRE_KEY_PRESENT: u16 = 0x2000;
+const GRE_SEQUENCE_PRESENT: u16 = 0x1000;
+// Version 1 is the enhanced GRE of PPTP, which carries PPP
+const GRE_VERSION: u16 = 0x0007;
+pub const UDP_HEADER_LEN: usize = 8;
+// Flags, reserved, 24-bit VNI, reserved (RFC 7348)
+const VXLAN_HEADER_LEN: usize = 8;
+const VXLAN_VNI_VALID: u8 = 0x08;
+// PPPoE session header: version/type, code, session ID, length (RFC 2516), then
+// the PPP protocol number of the payload
+const PPPOE_HEADER_LEN: usize = 6;
+const PPP_PROTOCOL_LEN: usize = 2;
+const PPP_IPV4: u16 = 0x0021;
+const PPP_IPV6: u16 = 0x0057;
+// Label stack entry: 20-bit label, traffic class, bottom of stack bit, TTL
+const MPLS_LABEL_LEN: usize = 4;
+const MPLS_BOTTOM_OF_STACK: u32 = 0x100;
+const MAX_MPLS_LABELS: usize = 8;
+// Pseudowire control word in front of an Ethernet frame (RFC 4385)
+const PW_CONTROL_WORD_LEN: usize = 4;
+
+pub const TCP_FIN: u8 = 0x01;
+pub const TCP_SYN: u8 = 0x02;
+pub const TCP_RST: u8 = 0x04;
+pub const TCP_ACK: u8 = 0x10;
+
+// Skip up to MAX_VLAN_TAGS 802.1Q/802.1ad tags and return the inner ethertype, the
+// payload following the tags and the outermost VLAN ID. Returns None if a tag is truncated.
+pub fn strip_vlan_tags(
+    mut ethertype: EtherType,
+    mut payload: &[u8],
+) -> Option<(EtherType, &[u8], Option<u16>)> {
+    let mut vlan_id = None;
+    for _ in 0..MAX_VLAN_TAGS {
+        if !matches!(
+            ethertype,
+            EtherTypes::Vlan | EtherTypes::PBridge | EtherTypes::QinQ
+        ) {
+            break;
+        }
+        if payload.len() < VLAN_TAG_LEN {
+            return None;
+        }
+        let tci = u16::from_be_bytes([payload[0], payload[1]]);
+        vlan_id.get_or_insert(tci & 0x0fff);
+        ethertype = EtherType(u16::from_be_bytes([payload[2], payload[3]]));
+        payload = &payload[VLAN_TAG_LEN..];
+    }
+    Some((ethertype, payload, vlan_id))
+}
+
+// The ethertype and payload of the IP packet in a PPPoE session frame. Frames of
+// the PPP control protocols (LCP, IPCP, ...) keep the PPPoE session ethertype.
+fn strip_pppoe(payload: &[u8]) -> Result<(EtherType, &[u8]), FrameError> {
+    let header = payload
+        .get(..PPPOE_HEADER_LEN + PPP_PROTOCOL_LEN)
+        .ok_or(FrameError::Malformed("short_pppoe_header"))?;
+    let ethertype =
+        match u16::from_be_bytes([header[PPPOE_HEADER_LEN], header[PPPOE_HEADER_LEN + 1]]) {
+            PPP_IPV4 => EtherTypes::Ipv4,
+            PPP_IPV6 => EtherTypes::Ipv6,
+            _ => return Err(FrameError::OtherEthertype(EtherTypes::PppoeSession)),
+        };
+    Ok((ethertype, &payload[header.len()..]))
+}
+
+// Skip the MPLS label stack up to its bottom entry and guess the payload from its
+// first nibble: IPv4, IPv6 or an Ethernet pseudowire with a control word. Returns
+// the ethertype and payload of the packet inside with the label of the top entry;
+// payloads that are none of these keep the MPLS ethertype.
+fn strip_mpls(mpls: EtherType, mut payload: &[u8]) -> Result<(EtherType, &[u8], u32), FrameError> {
+    let mut top_label = None;
+    for _ in 0..MAX_MPLS_LABELS {
+        let entry = payload
+            .get(..MPLS_LABEL_LEN)
+            .ok_or(FrameError::Malformed("truncated_mpls_label"))?;
+        let entry = u32::from_be_bytes([entry[0], entry[1], entry[2], entry[3]]);
+        let label = *top_label.get_or_insert(entry >> 12);
+        payload = &payload[MPLS_LABEL_LEN..];
+        if entry & MPLS_BOTTOM_OF_STACK == 0 {
+            continue;
+        }
+        let &first = payload.first().ok_or(FrameError::OtherEthertype(mpls))?;
+        return match first >> 4 {
+            4 => Ok((EtherTypes::Ipv4, payload, label)),
+            6 => Ok((EtherTypes::Ipv6, payload, label)),
+            0 => {
+                let ethernet = payload
+                    .get(PW_CONTROL_WORD_LEN..)
+                    .and_then(EthernetPacket::new)
+                    .ok_or(FrameError::Malformed("short_pseudowire_ethernet"))?;
+                let (ethertype, inner, _) =
+                    strip_vlan_tags(ethernet.get_ethertype(), ethernet.payload())
+                        .ok_or(FrameError::Malformed("truncated_vlan_tag"))?;
+                Ok((ethertype, &payload[payload.len() - inner.len()..], label))
+            }
+            _ => Err(FrameError::OtherEthertype(mpls)),
+        };
+    }
+    Err(FrameError::Malformed("mpls_stack_too_deep"))
+}
+
+pub fn vlan_label(vlan_id: Option<u16>) -> String {
+    match vlan_id {
+        Some(id) => id.to_string(),
+        None => "none".to_string(),
+    }
+}
+
+// proto label of UDP packets to or from one of the quic_ports
+pub const QUIC_PROTO_LABEL: &str = "quic";
+
+pub fn proto_label(proto: IpNextHeaderProtocol) -> &'static str {
+    match proto {
+        IpNextHeaderProtocols::Tcp => "tcp",
+        IpNextHeaderProtocols::Udp => "udp",
+        IpNextHeaderProtocols::Icmp | IpNextHeaderProtocols::Icmpv6 => "icmp",
+        _ => "other",
+    }
+}
+
+// dscp label of code points that no configured class lists
+pub const OTHER_DSCP_LABEL: &str = "other";
+
+// Class label of each of the 64 DSCP code points, from the dscp_classes setting.
+// Only the configured classes and "other" become label values.
+#[derive(Debug, Clone)]
+pub struct DscpClasses {
+    labels: Vec<Arc<str>>,
+}
+
+impl DscpClasses {
+    pub fn new(classes: &BTreeMap<String, Vec<u8>>) -> Result<Self, String> {
+        let other: Arc<str> = Arc::from(OTHER_DSCP_LABEL);
+        let mut labels = vec![other.clone(); 64];
+        for (class, code_points) in classes {
+            if class == OTHER_DSCP_LABEL {
+                return Err(format!("dscp class name {} is reserved", OTHER_DSCP_LABEL));
+            }
+            let label: Arc<str> = Arc::from(class.as_str());
+            for &dscp in code_points {
+                let Some(slot) = labels.get_mut(dscp as usize) else {
+                    return Err(format!(
+                        "dscp class {}: {} is not a DSCP value (0-63)",
+                        class, dscp
+                    ));
+                };
+                if !Arc::ptr_eq(slot, &other) {
+                    return Err(format!(
+                        "DSCP {} is listed in both {} and {}",
+                        dscp, slot, class
+                    ));
+                }
+                *slot = label.clone();
+            }
+        }
+        Ok(Self { labels })
+    }
+
+    // The DSCP is the upper six bits of the IPv4 TOS / IPv6 traffic class byte
+    pub fn label(&self, tos: u8) -> Arc<str> {
+        self.labels[(tos >> 2) as usize].clone()
+    }
+}
+
+// port label bucket of a packet. Non-first fragments carry no L4 header, so
+// their port is not known rather than untracked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PortBucket {
+    Tracked(u16),
+    Other,
+    Fragment,
+}
+
+pub fn port_label(port: PortBucket) -> String {
+    match port {
+        PortBucket::Tracked(port) => port.to_string(),
+        PortBucket::Other => "other".to_string(),
+        PortBucket::Fragment => "fragment".to_string(),
+    }
+}
+
+// Flags byte of a complete TCP header, 0 if the header is cut short
+pub fn tcp_flags(proto: IpNextHeaderProtocol, payload: &[u8]) -> u8 {
+    match proto {
+        IpNextHeaderProtocols::Tcp if payload.len() >= TCP_MIN_HEADER_LEN => {
+            payload[TCP_FLAGS_OFFSET]
+        }
+        _ => 0,
+    }
+}
+
+// Category of an ICMP or ICMPv6 message from its type byte, for network_ip_icmp_pps.
+// Only the type and code are read; a header shorter than that is "other".
+pub fn icmp_type(proto: IpNextHeaderProtocol, payload: &[u8]) -> Option<&'static str> {
+    let icmpv6 = match proto {
+        IpNextHeaderProtocols::Icmp => false,
+        IpNextHeaderProtocols::Icmpv6 => true,
+        _ => return None,
+    };
+    let &[kind, _code, ..] = payload else {
+        return Some("other");
+    };
+    Some(match (icmpv6, kind) {
+        (false, 8) | (true, 128) => "echo-request",
+        (false, 0) | (true, 129) => "echo-reply",
+        (false, 3) | (true, 1) => "unreachable",
+        (false, 5) | (true, 137) => "redirect",
+        (false, 11) | (true, 3) => "ttl-exceeded",
+        _ => "other",
+    })
+}
+
+// The upper-layer protocol behind the extension headers at the start of an IPv6
+// payload and where its header starts in `payload`, with the position of the
+// packet when a fragment header was passed. The offset is None for non-first
+// fragments, for chains longer than MAX_IPV6_EXTENSION_HEADERS and for headers
+// cut short by snaplen; ESP ends the walk as its contents are encrypted.
+fn ipv6_upper_layer(
+    mut next: u8,
+    payload: &[u8],
+) -> (IpNextHeaderProtocol, Option<usize>, Option<Fragment>) {
+    let mut offset = 0;
+    let mut fragment = None;
+    for _ in 0..MAX_IPV6_EXTENSION_HEADERS {
+        let header_len = match next {
+            IPV6_FRAGMENT => IPV6_FRAGMENT_HEADER_LEN,
+            IPV6_AUTHENTICATION => match payload.get(offset + 1) {
+                Some(&len) => (len as usize + 2) * 4,
+                None => return (IpNextHeaderProtocol(next), None, fragment),
+            },
+            IPV6_HOP_BY_HOP
+            | IPV6_ROUTING
+            | IPV6_DESTINATION_OPTIONS
+            | IPV6_MOBILITY
+            | IPV6_HIP
+            | IPV6_SHIM6 => match payload.get(offset + 1) {
+                Some(&len) => (len as usize + 1) * 8,
+                None => return (IpNextHeaderProtocol(next), None, fragment),
+            },
+            _ => return (IpNextHeaderProtocol(next), Some(offset), fragment),
+        };
+        let Some(header) = payload.get(offset..offset + header_len) else {
+            return (IpNextHeaderProtocol(next), None, fragment);
+        };
+        if next == IPV6_FRAGMENT {
+            let fragment_offset = u16::from_be_bytes([header[2], header[3]]);
+            if fragment_offset & IPV6_FRAGMENT_OFFSET_MASK != 0 {
+                return (
+                    IpNextHeaderProtocol(header[0]),
+                    None,
+                    Some(Fragment::NonFirst),
+                );
+            }
+            // Offset 0 without M is an atomic fragment (RFC 6946), a whole packet
+            if fragment_offset & IPV6_MORE_FRAGMENTS != 0 {
+                fragment = Some(Fragment::First);
+            }
+        }
+        next = header[0];
+        offset += header_len;
+    }
+    (IpNextHeaderProtocol(next), None, fragment)
+}
+
+// Source and destination port of a TCP or UDP header
+pub fn l4_ports(proto: IpNextHeaderProtocol, payload: &[u8]) -> Option<(u16, u16)> {
+    match proto {
+        IpNextHeaderProtocols::Tcp => {
+            let tcp = TcpPacket::new(payload)?;
+            Some((tcp.get_source(), tcp.get_destination()))
+        }
+        IpNextHeaderProtocols::Udp => {
+            let udp = UdpPacket::new(payload)?;
+            Some((udp.get_source(), udp.get_destination()))
+        }
+        _ => None,
+    }
+}
+
+// Position of an IPv4 or IPv6 packet in a fragmented datagram
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fragment {
+    // Offset 0 with the MF flag: carries the L4 header
+    First,
+    NonFirst,
+}
+
+// Fields of a captured frame needed for accounting
+#[derive(Debug, Clone)]
+pub struct PacketInfo {
+    pub src_ip: IpAddr,
+    pub dst_ip: IpAddr,
+    pub frame_len: u64,
+    pub ip_len: u64,
+    pub proto: &'static str,
+    // IPv4 protocol / IPv6 next header number
+    pub ip_proto: u8,
+    // (source, destination) TCP/UDP ports, None if there is no L4 header
+    pub ports: Option<(u16, u16)>,
+    // Flags byte of the TCP header, 0 for everything else
+    pub tcp_flags: u8,
+    // ICMP message category, None for other protocols and non-first fragments
+    pub icmp_type: Option<&'static str>,
+    pub tos: u8,
+    pub vlan_id: Option<u16>,
+    // Where the L4 header starts in the frame, None for non-first fragments
+    pub l4_offset: Option<usize>,
+    // VXLAN network identifier of a packet unwrapped from VXLAN
+    pub vni: Option<u32>,
+    // Carried in a PPPoE session frame
+    pub pppoe: bool,
+    // Label of the top MPLS label stack entry
+    pub mpls_label: Option<u32>,
+    // None for unfragmented IPv4 packets and IPv6
+    pub fragment: Option<Fragment>,
+}
+
+// Ethertype and offset in the frame of an encapsulated packet, with the VNI of VXLAN
+type TunnelPayload = (EtherType, usize, Option<u32>);
+
+// Where the packet carried by a GRE, IPIP or VXLAN packet starts in the frame, with
+// the VNI of VXLAN. None for other packets and payloads other than IPv4 and IPv6,
+// the reason label if the encapsulation headers are cut short.
+fn tunnel_payload(
+    data: &[u8],
+    outer: &PacketInfo,
+    vxlan_ports: &[u16],
+) -> Result<Option<TunnelPayload>, &'static str> {
+    // Non-first fragments are not reassembled
+    let Some(offset) = outer.l4_offset else {
+        return Ok(None);
+    };
+    match IpNextHeaderProtocol(outer.ip_proto) {
+        IpNextHeaderProtocols::Ipv4 => Ok(Some((EtherTypes::Ipv4, offset, None))),
+        IpNextHeaderProtocols::Gre => {
+            let header = data
+                .get(offset..offset + GRE_MIN_HEADER_LEN)
+                .ok_or("short_gre_header")?;
+            let flags = u16::from_be_bytes([header[0], header[1]]);
+            if flags & (GRE_ROUTING_PRESENT | GRE_VERSION) != 0 {
+                return Ok(None);
+            }
+            let optional = [GRE_CHECKSUM_PRESENT, GRE_KEY_PRESENT, GRE_SEQUENCE_PRESENT]
+                .into_iter()
+                .filter(|bit| flags & bit != 0)
+                .count();
+            let ethertype = EtherType(u16::from_be_bytes([header[2], header[3]]));
+            if ethertype != EtherTypes::Ipv4 && ethertype != EtherTypes::Ipv6 {
+                return Ok(None);
+            }
+            Ok(Some((
+                ethertype,
+                offset + GRE_MIN_HEADER_LEN + 4 * optional,
+                None,
+            )))
+        }
+        IpNextHeaderProtocols::Udp
+            if outer
+                .ports
+                .is_some_and(|(_, dst)| vxlan_ports.contains(&dst)) =>
+        {
+            let start = offset + UDP_HEADER_LEN;
+            let header = data
+                .get(start..start + VXLAN_HEADER_LEN)
+                .ok_or("short_vxlan_header")?;
+            // Without the I flag the VNI is not valid; not VXLAN after all
+            if header[0] & VXLAN_VNI_VALID == 0 {
+                return Ok(None);
+            }
+            let vni = u32::from_be_bytes([0, header[4], header[5], header[6]]);
+            let inner = &data[start + VXLAN_HEADER_LEN..];
+            let ethernet = EthernetPacket::new(inner).ok_or("short_inner_ethernet")?;
+            let (ethertype, payload, _) =
+                strip_vlan_tags(ethernet.get_ethertype(), ethernet.payload())
+                    .ok_or("truncated_inner_vlan_tag")?;
+            if ethertype != EtherTypes::Ipv4 && ethertype != EtherTypes::Ipv6 {
+                return Ok(None);
+            }
+            Ok(Some((ethertype, data.len() - payload.len(), Some(vni))))
+        }
+        _ => Ok(None),
+    }
+}
+
+// The packet inside a GRE, IPIP or VXLAN packet, with the outer frame length and
+// VLAN ID. None for other packets, the reason label if the tunnel packet is cut
+// short or the packet inside is inconsistent.
+pub fn parse_tunnel(
+    data: &[u8],
+    wire_len: u64,
+    outer: &PacketInfo,
+    vxlan_ports: &[u16],
+) -> Result<Option<PacketInfo>, &'static str> {
+    let Some((ethertype, offset, vni)) = tunnel_payload(data, outer, vxlan_ports)? else {
+        return Ok(None);
+    };
+    if offset >= data.len() {
+        return Err("truncated_tunnel_packet");
+    }
+    let mut inner = match parse_ip(ethertype, data, offset, wire_len) {
+        Ok(inner) => inner,
+        Err(FrameError::Malformed(reason)) => return Err(reason),
+        Err(FrameError::OtherEthertype(_)) => return Ok(None),
+    };
+    inner.frame_len = outer.frame_len;
+    inner.vlan_id = outer.vlan_id;
+    inner.vni = vni.or(outer.vni);
+    inner.mpls_label = outer.mpls_label;
+    Ok(Some(inner))
+}
+
+// Header in front of the IP packets of a capture, from its pcap datalink type
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkLayer {
+    Ethernet,
+    // DLT_LINUX_SLL, e.g. the "any" device
+    LinuxSll,
+    // DLT_LINUX_SLL2, which also carries the interface of each frame
+    LinuxSll2,
+    // DLT_RAW (tun interfaces), DLT_IPV4 and DLT_IPV6: no link-layer header at all
+    Raw,
+    // DLT_NULL and DLT_LOOP: the address family word of BSD loopback
+    Loopback { network_order: bool },
+}
+
+// Datalink types LinkLayer::from_linktype() accepts, for error messages
+pub const SUPPORTED_LINKTYPES: &str = "EN10MB, LINUX_SLL, LINUX_SLL2, RAW, IPV4, IPV6, NULL, LOOP";
+
+impl LinkLayer {
+    pub fn from_linktype(linktype: Linktype) -> Option<Self> {
+        match linktype {
+            Linktype::ETHERNET => Some(Self::Ethernet),
+            Linktype::LINUX_SLL => Some(Self::LinuxSll),
+            Linktype::LINUX_SLL2 => Some(Self::LinuxSll2),
+            Linktype::IPV4 | Linktype::IPV6 => Some(Self::Raw),
+            Linktype(dlt) if DLT_RAW.contains(&dlt) => Some(Self::Raw),
+            Linktype::NULL => Some(Self::Loopback {
+                network_order: false,
+            }),
+            Linktype::LOOP => Some(Self::Loopback {
+                network_order: true,
+            }),
+            _ => None,
+        }
+    }
+}
+
+// The ethertype of the packet in a frame after its link-layer header and any VLAN
+// tags, that packet and the outermost VLAN ID. Headers without an ethertype get the
+// one of the IP version they carry.
+pub fn link_payload(
+    link: LinkLayer,
+    data: &[u8],
+) -> Result<(EtherType, &[u8], Option<u16>), FrameError> {
+    let (ethertype, payload) = match link {
+        LinkLayer::Ethernet => {
+            let ethernet =
+                EthernetPacket::new(data).ok_or(FrameError::Malformed("short_ethernet"))?;
+            let payload = &data[data.len() - ethernet.payload().len()..];
+            (ethernet.get_ethertype(), payload)
+        }
+        LinkLayer::LinuxSll => {
+            let header = data
+                .get(..SLL_HEADER_LEN)
+                .ok_or(FrameError::Malformed("short_sll_header"))?;
+            let protocol = [header[SLL_PROTOCOL_OFFSET], header[SLL_PROTOCOL_OFFSET + 1]];
+            (
+                EtherType(u16::from_be_bytes(protocol)),
+                &data[SLL_HEADER_LEN..],
+            )
+        }
+        LinkLayer::LinuxSll2 => {
+            let header = data
+                .get(..SLL2_HEADER_LEN)
+                .ok_or(FrameError::Malformed("short_sll_header"))?;
+            (
+                EtherType(u16::from_be_bytes([header[0], header[1]])),
+                &data[SLL2_HEADER_LEN..],
+            )
+        }
+        LinkLayer::Raw => {
+            let ethertype = match data.first().map(|byte| byte >> 4) {
+                Some(4) => EtherTypes::Ipv4,
+                Some(6) => EtherTypes::Ipv6,
+                Some(_) => return Err(FrameError::Malformed("bad_ip_version")),
+                None => return Err(FrameError::Malformed("short_ipv4_header")),
+            };
+            return Ok((ethertype, data, None));
+        }
+        LinkLayer::Loopback { network_order } => {
+            let header: [u8; LOOPBACK_HEADER_LEN] = data
+                .get(..LOOPBACK_HEADER_LEN)
+                .and_then(|header| header.try_into().ok())
+                .ok_or(FrameError::Malformed("short_loopback_header"))?;
+            let family = if network_order {
+                u32::from_be_bytes(header)
+            } else {
+                u32::from_ne_bytes(header)
+            };
+            let ethertype = match family {
+                AF_INET => EtherTypes::Ipv4,
+                family if AF_INET6.contains(&family) => EtherTypes::Ipv6,
+                _ => return Err(FrameError::Malformed("unknown_address_family")),
+            };
+            return Ok((ethertype, &data[LOOPBACK_HEADER_LEN..], None));
+        }
+    };
+    strip_vlan_tags(ethertype, payload).ok_or(FrameError::Malformed("truncated_vlan_tag"))
+}
+
+// The ifindex and packet type in the header of a DLT_LINUX_SLL2 frame
+pub fn sll2_origin(data: &[u8]) -> Option<(u32, u8)> {
+    let header = data.get(..SLL2_HEADER_LEN)?;
+    let ifindex = &header[SLL2_IFINDEX_OFFSET..SLL2_IFINDEX_OFFSET + 4];
+    Some((
+        u32::from_be_bytes([ifindex[0], ifindex[1], ifindex[2], ifindex[3]]),
+        header[SLL2_PACKET_TYPE_OFFSET],
+    ))
+}
+
+// How much longer a cooked frame is than the same frame captured on its own
+// interface, which has an Ethernet header or, for tun-like devices, none at all
+pub fn cooked_header_overhead(link: LinkLayer, data: &[u8]) -> u64 {
+    let (header_len, arphrd_offset) = match link {
+        LinkLayer::LinuxSll => (SLL_HEADER_LEN, SLL_ARPHRD_OFFSET),
+        LinkLayer::LinuxSll2 => (SLL2_HEADER_LEN, SLL2_ARPHRD_OFFSET),
+        _ => return 0,
+    };
+    let Some(arphrd) = data.get(arphrd_offset..arphrd_offset + 2) else {
+        return 0;
+    };
+    match u16::from_be_bytes([arphrd[0], arphrd[1]]) {
+        ARPHRD_ETHER | ARPHRD_LOOPBACK => header_len as u64 - ETHERNET_HEADER_LEN,
+        _ => header_len as u64,
+    }
+}
+
+// Why parse_frame() could not account a frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameError {
+    // Headers cut short or inconsistent with the frame, with the reason label
+    Malformed(&'static str),
+    // Neither IPv4 nor IPv6 after the VLAN tags
+    OtherEthertype(EtherType),
+}
+
+pub fn ethertype_label(ethertype: EtherType) -> String {
+    format!("0x{:04x}", ethertype.0)
+}
+
+// `wire_len` is the frame length before snaplen truncation, so the IP length fields
+// are checked against what was on the wire rather than what was captured
+pub fn parse_frame(link: LinkLayer, data: &[u8], wire_len: u64) -> Result<PacketInfo, FrameError> {
+    let (ethertype, payload, vlan_id) = link_payload(link, data)?;
+    let (ethertype, payload, mpls_label) =
+        if matches!(ethertype, EtherTypes::Mpls | EtherTypes::MplsMcast) {
+            let (ethertype, payload, label) = strip_mpls(ethertype, payload)?;
+            (ethertype, payload, Some(label))
+        } else {
+            (ethertype, payload, None)
+        };
+    let pppoe = ethertype == EtherTypes::PppoeSession;
+    let (ethertype, payload) = if pppoe {
+        strip_pppoe(payload)?
+    } else {
+        (ethertype, payload)
+    };
+    let ip_offset = data.len() - payload.len();
+    let mut info = parse_ip(ethertype, data, ip_offset, wire_len)?;
+    info.vlan_id = vlan_id;
+    info.pppoe = pppoe;
+    info.mpls_label = mpls_label;
+    info.frame_len -= cooked_header_overhead(link, data);
+    Ok(info)
+}
+
+// An ARP packet for IPv4 over Ethernet
+#[derive(Debug, Clone, Copy)]
+pub struct ArpInfo {
+    // "request", "reply" or "other"
+    pub op: &'static str,
+    pub sender_mac: MacAddr,
+    pub sender_ip: Ipv4Addr,
+    pub target_ip: Ipv4Addr,
+}
+
+impl ArpInfo {
+    // A request or reply announcing the sender's own IP. Probes (RFC 5227) have
+    // sender 0.0.0.0 and are not.
+    pub fn is_gratuitous(&self) -> bool {
+        self.sender_ip == self.target_ip && !self.sender_ip.is_unspecified()
+    }
+}
+
+// The ARP packet of a frame whose ethertype is ARP; ARP for other hardware and
+// protocol types is OtherEthertype like any other non-IP frame
+pub fn parse_arp(link: LinkLayer, data: &[u8]) -> Result<ArpInfo, FrameError> {
+    let (ethertype, payload, _) = link_payload(link, data)?;
+    if ethertype != EtherTypes::Arp {
+        return Err(FrameError::OtherEthertype(ethertype));
+    }
+    let arp = ArpPacket::new(payload).ok_or(FrameError::Malformed("short_arp_packet"))?;
+    if arp.get_hardware_type() != ArpHardwareTypes::Ethernet
+        || arp.get_protocol_type() != EtherTypes::Ipv4
+        || arp.get_hw_addr_len() != 6
+        || arp.get_proto_addr_len() != 4
+    {
+        return Err(FrameError::OtherEthertype(ethertype));
+    }
+    let op = match arp.get_operation() {
+        ArpOperations::Request => "request",
+        ArpOperations::Reply => "reply",
+        _ => "other",
+    };
+    Ok(ArpInfo {
+        op,
+        sender_mac: arp.get_sender_hw_addr(),
+        sender_ip: arp.get_sender_proto_addr(),
+        target_ip: arp.get_target_proto_addr(),
+    })
+}
+
+// The IPv4 or IPv6 packet at `ip_offset` of the frame
+fn parse_ip(
+    ethertype: EtherType,
+    data: &[u8],
+    ip_offset: usize,
+    wire_len: u64,
+) -> Result<PacketInfo, FrameError> {
+    let payload = &data[ip_offset..];
+    let wire_payload_len = payload.len() as u64 + wire_len.saturating_sub(data.len() as u64);
+
+    // A zero length field (TSO segments, jumbograms) falls back to the captured payload
+    let (src_ip, dst_ip, ip_proto, ip_len, ports, tcp_flags, icmp, tos, l4_offset, fragment) =
+        match ethertype {
+            EtherTypes::Ipv4 => {
+                let ipv4 =
+                    Ipv4Packet::new(payload).ok_or(FrameError::Malformed("short_ipv4_header"))?;
+                let header_len = ipv4.get_header_length() as usize * 4;
+                if header_len < IPV4_MIN_HEADER_LEN {
+                    return Err(FrameError::Malformed("bad_ipv4_header_length"));
+                }
+                if header_len > payload.len() {
+                    return Err(FrameError::Malformed("truncated_ipv4_header"));
+                }
+                let total_len = match ipv4.get_total_length() {
+                    0 => payload.len() as u64,
+                    len if (len as usize) < header_len => {
+                        return Err(FrameError::Malformed("bad_ipv4_total_length"))
+                    }
+                    len if len as u64 > wire_payload_len => {
+                        return Err(FrameError::Malformed("truncated_ipv4_packet"))
+                    }
+                    len => len as u64,
+                };
+                // Only the first fragment carries the L4 header
+                let next = ipv4.get_next_level_protocol();
+                let (ports, flags, icmp, l4_offset) = if ipv4.get_fragment_offset() == 0 {
+                    (
+                        l4_ports(next, ipv4.payload()),
+                        tcp_flags(next, ipv4.payload()),
+                        icmp_type(next, ipv4.payload()),
+                        Some(ip_offset + header_len),
+                    )
+                } else {
+                    (None, 0, None, None)
+                };
+                let fragment = match ipv4.get_fragment_offset() {
+                    0 if ipv4.get_flags() & Ipv4Flags::MoreFragments == 0 => None,
+                    0 => Some(Fragment::First),
+                    _ => Some(Fragment::NonFirst),
+                };
+                (
+                    IpAddr::V4(ipv4.get_source()),
+                    IpAddr::V4(ipv4.get_destination()),
+                    next,
+                    total_len,
+                    ports,
+                    flags,
+                    icmp,
+                    (ipv4.get_dscp() << 2) | ipv4.get_ecn(),
+                    l4_offset,
+                    fragment,
+                )
+            }
+            EtherTypes::Ipv6 => {
+                let ipv6 =
+                    Ipv6Packet::new(payload).ok_or(FrameError::Malformed("short_ipv6_header"))?;
+                let total_len = match ipv6.get_payload_length() {
+                    0 => payload.len() as u64,
+                    len if IPV6_HEADER_LEN + len as u64 > wire_payload_len => {
+                        return Err(FrameError::Malformed("truncated_ipv6_packet"))
+                    }
+                    len => IPV6_HEADER_LEN + len as u64,
+                };
+                // Like IPv4, non-first fragments have the protocol but no L4 header
+                let (next, l4, fragment) =
+                    ipv6_upper_layer(ipv6.get_next_header().0, ipv6.payload());
+                let l4_payload = l4.and_then(|offset| ipv6.payload().get(offset..));
+                (
+                    IpAddr::V6(ipv6.get_source()),
+                    IpAddr::V6(ipv6.get_destination()),
+                    next,
+                    total_len,
+                    l4_payload.and_then(|payload| l4_ports(next, payload)),
+                    l4_payload.map_or(0, |payload| tcp_flags(next, payload)),
+                    l4_payload.and_then(|payload| icmp_type(next, payload)),
+                    ipv6.get_traffic_class(),
+                    l4.map(|offset| ip_offset + IPV6_HEADER_LEN as usize + offset),
+                    fragment,
+                )
+            }
+            other => return Err(FrameError::OtherEthertype(other)),
+        };
+
+    Ok(PacketInfo {
+        src_ip,
+        dst_ip,
+        frame_len: data.len() as u64,
+        ip_len,
+        proto: proto_label(ip_proto),
+        ip_proto: ip_proto.0,
+        ports,
+        tcp_flags,
+        icmp_type: icmp,
+        tos,
+        vlan_id: None,
+        l4_offset,
+        vni: None,
+        pppoe: false,
+        mpls_label: None,
+        fragment,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 20-byte IPv4 header without options around `payload`
+    fn ipv4(protocol: u8, payload: &[u8]) -> Vec<u8> {
+        let total_len = (20 + payload.len()) as u16;
+        let mut packet = vec![0x45, 0];
+        packet.extend_from_slice(&total_len.to_be_bytes());
+        packet.extend_from_slice(&[0, 1, 0, 0, 64, protocol, 0, 0]);
+        packet.extend_from_slice(&[10, 40, 0, 5, 93, 184, 216, 34]);
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    // 2001:db8::5 -> 2001:db8:1::1
+    fn ipv6(next_header: u8, payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0x60, 0, 0, 0];
+        packet.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        packet.extend_from_slice(&[next_header, 64]);
+        packet.extend_from_slice(&[0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 5]);
+        packet.extend_from_slice(&[0x20, 0x01, 0x0d, 0xb8, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    fn udp(src_port: u16, dst_port: u16) -> Vec<u8> {
+        let mut segment = src_port.to_be_bytes().to_vec();
+        segment.extend_from_slice(&dst_port.to_be_bytes());
+        segment.extend_from_slice(&[0, 8, 0, 0]);
+        segment
+    }
+
+    fn ethernet(ethertype: u16, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0x02, 0, 0, 0, 0, 1, 0x02, 0, 0, 0, 0, 2];
+        frame.extend_from_slice(&ethertype.to_be_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    fn vlan_tag(vlan_id: u16, ethertype: u16) -> Vec<u8> {
+        let mut tag = vlan_id.to_be_bytes().to_vec();
+        tag.extend_from_slice(&ethertype.to_be_bytes());
+        tag
+    }
+
+    // PPPoE session header of session 0x1234 and the PPP protocol field
+    fn pppoe(protocol: u16, payload: &[u8]) -> Vec<u8> {
+        let mut session = vec![0x11, 0x00, 0x12, 0x34];
+        session.extend_from_slice(&((PPP_PROTOCOL_LEN + payload.len()) as u16).to_be_bytes());
+        session.extend_from_slice(&protocol.to_be_bytes());
+        session.extend_from_slice(payload);
+        session
+    }
+
+    // An 8-byte extension header (hop-by-hop, routing, destination options)
+    fn extension_header(next_header: u8) -> Vec<u8> {
+        vec![next_header, 0, 0, 0, 0, 0, 0, 0]
+    }
+
+    // `offset` in bytes, a multiple of 8
+    fn fragment_header(next_header: u8, offset: u16, more: bool) -> Vec<u8> {
+        let field = offset | u16::from(more);
+        let mut header = vec![next_header, 0];
+        header.extend_from_slice(&field.to_be_bytes());
+        header.extend_from_slice(&[0, 0, 0x12, 0x34]);
+        header
+    }
+
+    fn tcp_syn(src_port: u16, dst_port: u16) -> Vec<u8> {
+        let mut segment = src_port.to_be_bytes().to_vec();
+        segment.extend_from_slice(&dst_port.to_be_bytes());
+        segment.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 0, 0x50, TCP_SYN, 0xff, 0xff]);
+        segment.extend_from_slice(&[0, 0, 0, 0]);
+        segment
+    }
+
+    fn parse_ethernet(frame: &[u8]) -> Result<PacketInfo, FrameError> {
+        parse_frame(LinkLayer::Ethernet, frame, frame.len() as u64)
+    }
+
+    #[test]
+    fn strips_single_vlan_tag() {
+        let ip = ipv4(17, &udp(5353, 53));
+        let mut payload = vlan_tag(0x2000 | 100, 0x0800);
+        payload.extend_from_slice(&ip);
+        let (ethertype, inner, vlan_id) = strip_vlan_tags(EtherTypes::Vlan, &payload).unwrap();
+        assert_eq!(ethertype, EtherTypes::Ipv4);
+        assert_eq!(inner, &ip[..]);
+        // The priority bits are not part of the ID
+        assert_eq!(vlan_id, Some(100));
+    }
+
+    #[test]
+    fn strips_qinq_tags_keeping_the_outer_id() {
+        let ip = ipv4(17, &udp(5353, 53));
+        let mut payload = vlan_tag(200, 0x8100);
+        payload.extend_from_slice(&vlan_tag(300, 0x0800));
+        payload.extend_from_slice(&ip);
+        let (ethertype, inner, vlan_id) = strip_vlan_tags(EtherTypes::PBridge, &payload).unwrap();
+        assert_eq!(ethertype, EtherTypes::Ipv4);
+        assert_eq!(inner, &ip[..]);
+        assert_eq!(vlan_id, Some(200));
+
+        let frame = ethernet(0x88a8, &payload);
+        let info = parse_ethernet(&frame).unwrap();
+        assert_eq!(info.vlan_id, Some(200));
+        assert_eq!(info.ports, Some((5353, 53)));
+    }
+
+    #[test]
+    fn untagged_payload_is_returned_as_is() {
+        let ip = ipv4(17, &udp(5353, 53));
+        let (ethertype, inner, vlan_id) = strip_vlan_tags(EtherTypes::Ipv4, &ip).unwrap();
+        assert_eq!(ethertype, EtherTypes::Ipv4);
+        assert_eq!(inner, &ip[..]);
+        assert_eq!(vlan_id, None);
+    }
+
+    #[test]
+    fn truncated_vlan_tag_is_rejected() {
+        assert!(strip_vlan_tags(EtherTypes::Vlan, &[0, 100, 8]).is_none());
+        let frame = ethernet(0x8100, &[0, 100, 8]);
+        assert_eq!(
+            parse_ethernet(&frame).unwrap_err(),
+            FrameError::Malformed("truncated_vlan_tag")
+        );
+    }
+
+    #[test]
+    fn pppoe_session_ipv4_is_unwrapped() {
+        let ip = ipv4(17, &udp(5353, 53));
+        let session = pppoe(PPP_IPV4, &ip);
+        let (ethertype, inner) = strip_pppoe(&session).unwrap();
+        assert_eq!(ethertype, EtherTypes::Ipv4);
+        assert_eq!(inner, &ip[..]);
+
+        let frame = ethernet(0x8864, &session);
+        let info = parse_ethernet(&frame).unwrap();
+        assert!(info.pppoe);
+        assert_eq!(info.src_ip, IpAddr::from([10, 40, 0, 5]));
+        assert_eq!(info.ip_len, 28);
+        assert_eq!(info.ports, Some((5353, 53)));
+    }
+
+    #[test]
+    fn pppoe_session_ipv6_is_unwrapped() {
+        let ip = ipv6(17, &udp(5353, 53));
+        let session = pppoe(PPP_IPV6, &ip);
+        let (ethertype, inner) = strip_pppoe(&session).unwrap();
+        assert_eq!(ethertype, EtherTypes::Ipv6);
+        assert_eq!(inner, &ip[..]);
+    }
+
+    #[test]
+    fn ppp_control_frames_keep_the_session_ethertype() {
+        // LCP echo request
+        let session = pppoe(0xc021, &[0x09, 0x01, 0x00, 0x08, 0, 0, 0, 0]);
+        assert_eq!(
+            strip_pppoe(&session).unwrap_err(),
+            FrameError::OtherEthertype(EtherTypes::PppoeSession)
+        );
+    }
+
+    #[test]
+    fn short_pppoe_header_is_rejected() {
+        let session = pppoe(PPP_IPV4, &[]);
+        assert_eq!(
+            strip_pppoe(&session[..PPPOE_HEADER_LEN + 1]).unwrap_err(),
+            FrameError::Malformed("short_pppoe_header")
+        );
+    }
+
+    #[test]
+    fn truncated_ppp_payload_is_rejected() {
+        let ip = ipv4(17, &udp(5353, 53));
+        // Cut inside the IPv4 header
+        let frame = ethernet(0x8864, &pppoe(PPP_IPV4, &ip[..12]));
+        assert_eq!(
+            parse_ethernet(&frame).unwrap_err(),
+            FrameError::Malformed("short_ipv4_header")
+        );
+        // A whole header, but less than its total length on the wire
+        let frame = ethernet(0x8864, &pppoe(PPP_IPV4, &ip[..24]));
+        assert_eq!(
+            parse_ethernet(&frame).unwrap_err(),
+            FrameError::Malformed("truncated_ipv4_packet")
+        );
+    }
+
+    #[test]
+    fn walks_hop_by_hop_and_routing_headers_to_tcp() {
+        let mut payload = extension_header(IPV6_ROUTING);
+        payload.extend_from_slice(&extension_header(6));
+        payload.extend_from_slice(&tcp_syn(50000, 443));
+        assert_eq!(
+            ipv6_upper_layer(IPV6_HOP_BY_HOP, &payload),
+            (IpNextHeaderProtocols::Tcp, Some(16), None)
+        );
+
+        let frame = ethernet(0x86dd, &ipv6(IPV6_HOP_BY_HOP, &payload));
+        let info = parse_ethernet(&frame).unwrap();
+        assert_eq!(info.ip_proto, 6);
+        assert_eq!(info.ports, Some((50000, 443)));
+        assert_eq!(info.tcp_flags, TCP_SYN);
+        assert_eq!(info.l4_offset, Some(14 + 40 + 16));
+        assert_eq!(info.fragment, None);
+    }
+
+    #[test]
+    fn non_first_ipv6_fragment_has_no_l4_header() {
+        let mut payload = fragment_header(17, 1448, true);
+        payload.extend_from_slice(&[0xab; 64]);
+        assert_eq!(
+            ipv6_upper_layer(IPV6_FRAGMENT, &payload),
+            (IpNextHeaderProtocols::Udp, None, Some(Fragment::NonFirst))
+        );
+
+        let frame = ethernet(0x86dd, &ipv6(IPV6_FRAGMENT, &payload));
+        let info = parse_ethernet(&frame).unwrap();
+        assert_eq!(info.proto, "udp");
+        assert_eq!(info.ports, None);
+        assert_eq!(info.fragment, Some(Fragment::NonFirst));
+    }
+
+    #[test]
+    fn first_ipv6_fragment_keeps_its_ports() {
+        let mut payload = extension_header(IPV6_FRAGMENT);
+        payload.extend_from_slice(&fragment_header(17, 0, true));
+        payload.extend_from_slice(&udp(5353, 53));
+        assert_eq!(
+            ipv6_upper_layer(IPV6_HOP_BY_HOP, &payload),
+            (IpNextHeaderProtocols::Udp, Some(16), Some(Fragment::First))
+        );
+
+        // An atomic fragment is a whole packet
+        let mut payload = fragment_header(17, 0, false);
+        payload.extend_from_slice(&udp(5353, 53));
+        assert_eq!(
+            ipv6_upper_layer(IPV6_FRAGMENT, &payload),
+            (IpNextHeaderProtocols::Udp, Some(8), None)
+        );
+    }
+
+    #[test]
+    fn cut_or_overlong_extension_chains_have_no_l4_offset() {
+        let payload = extension_header(6);
+        assert_eq!(
+            ipv6_upper_layer(IPV6_ROUTING, &payload[..4]),
+            (IpNextHeaderProtocol(IPV6_ROUTING), None, None)
+        );
+
+        let mut payload = Vec::new();
+        for _ in 0..MAX_IPV6_EXTENSION_HEADERS {
+            payload.extend_from_slice(&extension_header(IPV6_DESTINATION_OPTIONS));
+        }
+        payload.extend_from_slice(&udp(5353, 53));
+        assert_eq!(
+            ipv6_upper_layer(IPV6_DESTINATION_OPTIONS, &payload),
+            (IpNextHeaderProtocol(IPV6_DESTINATION_OPTIONS), None, None)
+        );
+    }
+}
@@ -0,0 +1,87 @@
+use std::ffi::CString;
+use std::io;
+
+// A user or group name, or a numeric id
+pub fn lookup_id(name: &str, user: bool) -> io::Result<u32> {
+    if let Ok(id) = name.parse() {
+        return Ok(id);
+    }
+    let c_name = CString::new(name)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "name contains a NUL byte"))?;
+    // Only called at startup before any other thread looks up users
+    let id = unsafe {
+        if user {
+            let entry = libc::getpwnam(c_name.as_ptr());
+            (!entry.is_null()).then(|| (*entry).pw_uid)
+        } else {
+            let entry = libc::getgrnam(c_name.as_ptr());
+            (!entry.is_null()).then(|| (*entry).gr_gid)
+        }
+    };
+    id.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no such {} '{}'", if user { "user" } else { "group" }, name),
+        )
+    })
+}
+
+// Primary group of a user id, the default group of --user
+fn primary_group(uid: u32) -> io::Result<u32> {
+    // Only called at startup before any other thread looks up users
+    let gid = unsafe {
+        let entry = libc::getpwuid(uid);
+        (!entry.is_null()).then(|| (*entry).pw_gid)
+    };
+    gid.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no passwd entry for uid {}", uid),
+        )
+    })
+}
+
+fn check(result: libc::c_int, call: &str) -> io::Result<()> {
+    if result == 0 {
+        Ok(())
+    } else {
+        let e = io::Error::last_os_error();
+        Err(io::Error::new(e.kind(), format!("{} failed: {}", call, e)))
+    }
+}
+
+// Switch the whole process to `user` and `group` (the user's primary group by
+// default) for good, dropping the supplementary groups. glibc applies set*id to
+// every thread, so the capture threads and the runtime's workers follow.
+pub fn drop_privileges(user: &str, group: Option<&str>) -> io::Result<(u32, u32)> {
+    let uid = lookup_id(user, true)?;
+    let gid = match group {
+        Some(group) => lookup_id(group, false)?,
+        None => primary_group(uid)?,
+    };
+    unsafe {
+        check(libc::setgroups(1, &gid), "setgroups")?;
+        check(libc::setgid(gid), "setgid")?;
+        check(libc::setuid(uid), "setuid")?;
+    }
+
+    let (ruid, euid, rgid, egid) = unsafe {
+        (
+            libc::getuid(),
+            libc::geteuid(),
+            libc::getgid(),
+            libc::getegid(),
+        )
+    };
+    if (ruid, euid, rgid, egid) != (uid, uid, gid, gid) {
+        return Err(io::Error::other(format!(
+            "still running as uid {}/{} gid {}/{}",
+            ruid, euid, rgid, egid
+        )));
+    }
+    // Being able to switch back would mean nothing was dropped
+    if uid != 0 && unsafe { libc::setuid(0) } == 0 {
+        return Err(io::Error::other("could regain root after setuid"));
+    }
+    Ok((uid, gid))
+}
@@ -0,0 +1,128 @@
+use crate::capture::{open_capture, CaptureSettings};
+use crate::config::default_bpf_filter;
+use crate::packet::{ethertype_label, link_payload, parse_frame, LinkLayer};
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+// Rows printed per table of the probe summary
+const PROBE_TOP: usize = 10;
+
+// Short read timeout so the probe ends close to the requested duration on a quiet link
+const PROBE_READ_TIMEOUT_MS: i32 = 100;
+
+// What `localpacketdump probe` saw on one interface
+#[derive(Debug, Default)]
+pub struct ProbeSummary {
+    pub interface: String,
+    pub filter: String,
+    pub elapsed: Duration,
+    pub packets: u64,
+    pub bytes: u64,
+    // Frames by the ethertype after any VLAN tags
+    pub ethertypes: HashMap<String, u64>,
+    pub sources: HashMap<IpAddr, u64>,
+    // Frames tagged with at least one VLAN header
+    pub vlan_tagged: u64,
+}
+
+impl ProbeSummary {
+    fn observe(&mut self, link: LinkLayer, data: &[u8], wire_len: u32) {
+        self.packets += 1;
+        self.bytes += wire_len as u64;
+        let ethertype = match link_payload(link, data) {
+            Ok((ethertype, _, vlan_id)) => {
+                if vlan_id.is_some() {
+                    self.vlan_tagged += 1;
+                }
+                ethertype
+            }
+            Err(_) => {
+                *self.ethertypes.entry("truncated".to_string()).or_default() += 1;
+                return;
+            }
+        };
+        let label = format!("{} ({})", ethertype_label(ethertype), ethertype);
+        *self.ethertypes.entry(label).or_default() += 1;
+        if let Ok(info) = parse_frame(link, data, wire_len as u64) {
+            *self.sources.entry(info.src_ip).or_default() += 1;
+        }
+    }
+}
+
+// Largest counts first, ties in key order so the output is stable
+fn top<K: Ord + Clone>(counts: &HashMap<K, u64>) -> Vec<(K, u64)> {
+    let mut rows: Vec<(K, u64)> = counts.iter().map(|(k, v)| (k.clone(), *v)).collect();
+    rows.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    rows.truncate(PROBE_TOP);
+    rows
+}
+
+impl fmt::Display for ProbeSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let secs = self.elapsed.as_secs_f64();
+        writeln!(
+            f,
+            "{}: {} packets, {} bytes in {:.1}s ({:.0} pps, {:.0} bps)",
+            self.interface,
+            self.packets,
+            self.bytes,
+            secs,
+            self.packets as f64 / secs.max(f64::EPSILON),
+            self.bytes as f64 * 8.0 / secs.max(f64::EPSILON)
+        )?;
+        if self.filter.is_empty() {
+            writeln!(f, "filter: none")?;
+        } else {
+            writeln!(f, "filter: {}", self.filter)?;
+        }
+        writeln!(f, "vlan tagged: {}", self.vlan_tagged)?;
+        writeln!(f, "top ethertypes:")?;
+        for (label, count) in top(&self.ethertypes) {
+            writeln!(f, "    {:>10}  {}", count, label)?;
+        }
+        writeln!(f, "top source IPs:")?;
+        for (ip, count) in top(&self.sources) {
+            writeln!(f, "    {:>10}  {}", count, ip)?;
+        }
+        Ok(())
+    }
+}
+
+// Captures on `interface` for `duration` with the same device lookup and BPF
+// filter as the exporter, without touching the metrics or the status service
+pub fn probe(
+    interface: &str,
+    settings: &CaptureSettings,
+    // None for the default filter of the device's datalink type
+    filter: Option<&str>,
+    duration: Duration,
+) -> Result<ProbeSummary, Box<dyn std::error::Error>> {
+    let settings = CaptureSettings {
+        timeout_ms: settings.timeout_ms.min(PROBE_READ_TIMEOUT_MS),
+        ..*settings
+    };
+    let mut cap = open_capture(interface, &settings)?;
+    let link = LinkLayer::from_linktype(cap.get_datalink()).unwrap_or(LinkLayer::Ethernet);
+    let filter = filter.unwrap_or_else(|| default_bpf_filter(link));
+    if !filter.is_empty() {
+        cap.filter(filter, true)?;
+    }
+
+    let mut summary = ProbeSummary {
+        interface: interface.to_string(),
+        filter: filter.to_string(),
+        ..Default::default()
+    };
+    let start = Instant::now();
+    while start.elapsed() < duration {
+        match cap.next_packet() {
+            Ok(packet) => summary.observe(link, packet.data, packet.header.len),
+            Err(pcap::Error::TimeoutExpired) => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    summary.elapsed = start.elapsed();
+    Ok(summary)
+}
@@ -0,0 +1,351 @@
+use crate::sni::{parse_handshake, take, take_u8_len};
+use ring::aead::quic::{HeaderProtectionKey, AES_128};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_128_GCM};
+use ring::hkdf;
+
+const QUIC_V1: u32 = 0x0000_0001;
+const QUIC_V2: u32 = 0x6b33_43cf;
+
+// RFC 9001 section 5.2 and RFC 9369 section 3.3.1
+const INITIAL_SALT_V1: [u8; 20] = [
+    0x38, 0x76, 0x2c, 0xf7, 0xf5, 0x59, 0x34, 0xb3, 0x4d, 0x17, 0x9a, 0xe6, 0xa4, 0xc8, 0x0c, 0xad,
+    0xcc, 0xbb, 0x7f, 0x0a,
+];
+const INITIAL_SALT_V2: [u8; 20] = [
+    0x0d, 0xed, 0xe3, 0xde, 0xf7, 0x00, 0xa6, 0xdb, 0x81, 0x93, 0x81, 0xbe, 0x6e, 0x26, 0x9d, 0xcb,
+    0xf9, 0xbd, 0x2e, 0xd9,
+];
+
+const MAX_CONNECTION_ID_LEN: usize = 20;
+
+// Header protection samples 16 bytes starting 4 bytes after the packet number
+const SAMPLE_OFFSET: usize = 4;
+const SAMPLE_LEN: usize = 16;
+
+const PADDING_FRAME: u64 = 0x00;
+const PING_FRAME: u64 = 0x01;
+const CRYPTO_FRAME: u64 = 0x06;
+
+// How Initial packets and their key labels differ between the versions
+struct Version {
+    salt: &'static [u8; 20],
+    initial_type: u8,
+    key_label: &'static [u8],
+    iv_label: &'static [u8],
+    hp_label: &'static [u8],
+}
+
+const VERSION_1: Version = Version {
+    salt: &INITIAL_SALT_V1,
+    initial_type: 0,
+    key_label: b"quic key",
+    iv_label: b"quic iv",
+    hp_label: b"quic hp",
+};
+
+const VERSION_2: Version = Version {
+    salt: &INITIAL_SALT_V2,
+    initial_type: 1,
+    key_label: b"quicv2 key",
+    iv_label: b"quicv2 iv",
+    hp_label: b"quicv2 hp",
+};
+
+fn varint(buf: &mut &[u8]) -> Option<u64> {
+    let first = *buf.first()?;
+    let len = 1 << (first >> 6);
+    let bytes = take(buf, len)?;
+    let mut value = u64::from(first & 0x3f);
+    for &byte in &bytes[1..] {
+        value = (value << 8) | u64::from(byte);
+    }
+    Some(value)
+}
+
+struct OutputLen(usize);
+
+impl hkdf::KeyType for OutputLen {
+    fn len(&self) -> usize {
+        self.0
+    }
+}
+
+// HKDF-Expand-Label of TLS 1.3 with an empty context
+fn expand_label(secret: &hkdf::Prk, label: &[u8], out: &mut [u8]) -> Option<()> {
+    let out_len = (out.len() as u16).to_be_bytes();
+    let label_len = [(b"tls13 ".len() + label.len()) as u8];
+    let info: [&[u8]; 5] = [&out_len, &label_len, b"tls13 ", label, &[0]];
+    secret
+        .expand(&info, OutputLen(out.len()))
+        .ok()?
+        .fill(out)
+        .ok()
+}
+
+// The client's Initial keys, derived from the destination connection ID alone
+struct InitialKeys {
+    key: LessSafeKey,
+    iv: [u8; 12],
+    hp: HeaderProtectionKey,
+}
+
+impl InitialKeys {
+    fn client(version: &Version, dcid: &[u8]) -> Option<Self> {
+        let initial = hkdf::Salt::new(hkdf::HKDF_SHA256, version.salt).extract(dcid);
+        let mut client = [0; 32];
+        expand_label(&initial, b"client in", &mut client)?;
+        let client = hkdf::Prk::new_less_safe(hkdf::HKDF_SHA256, &client);
+        let (mut key, mut iv, mut hp) = ([0; 16], [0; 12], [0; 16]);
+        expand_label(&client, version.key_label, &mut key)?;
+        expand_label(&client, version.iv_label, &mut iv)?;
+        expand_label(&client, version.hp_label, &mut hp)?;
+        Some(Self {
+            key: LessSafeKey::new(UnboundKey::new(&AES_128_GCM, &key).ok()?),
+            iv,
+            hp: HeaderProtectionKey::new(&AES_128, &hp).ok()?,
+        })
+    }
+}
+
+// The start of the CRYPTO stream in a decrypted Initial payload, as far as it is
+// contiguous from offset 0. Clients may split and reorder the ClientHello across
+// frames; the part in later packets is not seen.
+fn crypto_stream(mut frames: &[u8]) -> Option<Vec<u8>> {
+    let mut chunks = Vec::new();
+    while !frames.is_empty() {
+        match varint(&mut frames)? {
+            PADDING_FRAME | PING_FRAME => {}
+            CRYPTO_FRAME => {
+                let offset = varint(&mut frames)? as usize;
+                let len = varint(&mut frames)? as usize;
+                chunks.push((offset, take(&mut frames, len)?));
+            }
+            // ACK and the rest carry lengths this does not walk
+            _ => break,
+        }
+    }
+    chunks.sort_unstable_by_key(|(offset, _)| *offset);
+    let mut stream = Vec::new();
+    for (offset, data) in chunks {
+        if offset > stream.len() {
+            break;
+        }
+        if let Some(new) = data.get(stream.len() - offset..) {
+            stream.extend_from_slice(new);
+        }
+    }
+    (!stream.is_empty()).then_some(stream)
+}
+
+// The server_name of the ClientHello in a client's QUIC v1 or v2 Initial packet at
+// the start of `payload`, None for anything else. Packets coalesced after it are
+// ignored.
+pub fn initial_server_name(payload: &[u8]) -> Option<String> {
+    let first = *payload.first()?;
+    // Long header with the fixed bit
+    if first & 0xc0 != 0xc0 {
+        return None;
+    }
+    let version = match u32::from_be_bytes(payload.get(1..5)?.try_into().ok()?) {
+        QUIC_V1 => &VERSION_1,
+        QUIC_V2 => &VERSION_2,
+        _ => return None,
+    };
+    if (first >> 4) & 0x03 != version.initial_type {
+        return None;
+    }
+    let mut rest = &payload[5..];
+    let dcid = take_u8_len(&mut rest)?;
+    if dcid.len() > MAX_CONNECTION_ID_LEN {
+        return None;
+    }
+    let scid = take_u8_len(&mut rest)?;
+    if scid.len() > MAX_CONNECTION_ID_LEN {
+        return None;
+    }
+    let token_len = varint(&mut rest)? as usize;
+    take(&mut rest, token_len)?;
+    let len = varint(&mut rest)? as usize;
+    let pn_offset = payload.len() - rest.len();
+    let packet = payload.get(..pn_offset.checked_add(len)?)?;
+
+    let keys = InitialKeys::client(version, dcid)?;
+    let sample = packet.get(pn_offset + SAMPLE_OFFSET..pn_offset + SAMPLE_OFFSET + SAMPLE_LEN)?;
+    let mask = keys.hp.new_mask(sample).ok()?;
+    let mut packet = packet.to_vec();
+    packet[0] ^= mask[0] & 0x0f;
+    let pn_len = (packet[0] & 0x03) as usize + 1;
+    let mut nonce = keys.iv;
+    let mut pn = 0u64;
+    for i in 0..pn_len {
+        packet[pn_offset + i] ^= mask[1 + i];
+        pn = (pn << 8) | u64::from(packet[pn_offset + i]);
+    }
+    for (byte, pn) in nonce[4..].iter_mut().zip(pn.to_be_bytes()) {
+        *byte ^= pn;
+    }
+    let (header, body) = packet.split_at_mut(pn_offset + pn_len);
+    let frames = keys
+        .key
+        .open_in_place(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::from(&*header),
+            body,
+        )
+        .ok()?;
+    let hello = crypto_stream(frames)?;
+    parse_handshake(&hello).map(|name| name.to_ascii_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // RFC 9001 appendix A.2, the protected client Initial for example.com
+    const RFC9001_CLIENT_INITIAL: &str = concat!(
+        "c000000001088394c8f03e5157080000449e7b9aec34d1b1c98dd7689fb8ec11",
+        "d242b123dc9bd8bab936b47d92ec356c0bab7df5976d27cd449f63300099f399",
+        "1c260ec4c60d17b31f8429157bb35a1282a643a8d2262cad67500cadb8e7378c",
+        "8eb7539ec4d4905fed1bee1fc8aafba17c750e2c7ace01e6005f80fcb7df6212",
+        "30c83711b39343fa028cea7f7fb5ff89eac2308249a02252155e2347b63d58c5",
+        "457afd84d05dfffdb20392844ae812154682e9cf012f9021a6f0be17ddd0c208",
+        "4dce25ff9b06cde535d0f920a2db1bf362c23e596d11a4f5a6cf3948838a3aec",
+        "4e15daf8500a6ef69ec4e3feb6b1d98e610ac8b7ec3faf6ad760b7bad1db4ba3",
+        "485e8a94dc250ae3fdb41ed15fb6a8e5eba0fc3dd60bc8e30c5c4287e53805db",
+        "059ae0648db2f64264ed5e39be2e20d82df566da8dd5998ccabdae053060ae6c",
+        "7b4378e846d29f37ed7b4ea9ec5d82e7961b7f25a9323851f681d582363aa5f8",
+        "9937f5a67258bf63ad6f1a0b1d96dbd4faddfcefc5266ba6611722395c906556",
+        "be52afe3f565636ad1b17d508b73d8743eeb524be22b3dcbc2c7468d54119c74",
+        "68449a13d8e3b95811a198f3491de3e7fe942b330407abf82a4ed7c1b311663a",
+        "c69890f4157015853d91e923037c227a33cdd5ec281ca3f79c44546b9d90ca00",
+        "f064c99e3dd97911d39fe9c5d0b23a229a234cb36186c4819e8b9c5927726632",
+        "291d6a418211cc2962e20fe47feb3edf330f2c603a9d48c0fcb5699dbfe58964",
+        "25c5bac4aee82e57a85aaf4e2513e4f05796b07ba2ee47d80506f8d2c25e50fd",
+        "14de71e6c418559302f939b0e1abd576f279c4b2e0feb85c1f28ff18f58891ff",
+        "ef132eef2fa09346aee33c28eb130ff28f5b766953334113211996d20011a198",
+        "e3fc433f9f2541010ae17c1bf202580f6047472fb36857fe843b19f5984009dd",
+        "c324044e847a4f4a0ab34f719595de37252d6235365e9b84392b061085349d73",
+        "203a4a13e96f5432ec0fd4a1ee65accdd5e3904df54c1da510b0ff20dcc0c77f",
+        "cb2c0e0eb605cb0504db87632cf3d8b4dae6e705769d1de354270123cb11450e",
+        "fc60ac47683d7b8d0f811365565fd98c4c8eb936bcab8d069fc33bd801b03ade",
+        "a2e1fbc5aa463d08ca19896d2bf59a071b851e6c239052172f296bfb5e724047",
+        "90a2181014f3b94a4e97d117b438130368cc39dbb2d198065ae3986547926cd2",
+        "162f40a29f0c3c8745c0f50fba3852e566d44575c29d39a03f0cda721984b6f4",
+        "40591f355e12d439ff150aab7613499dbd49adabc8676eef023b15b65bfc5ca0",
+        "6948109f23f350db82123535eb8a7433bdabcb909271a6ecbcb58b936a88cd4e",
+        "8f2e6ff5800175f113253d8fa9ca8885c2f552e657dc603f252e1a8e308f76f0",
+        "be79e2fb8f5d5fbbe2e30ecadd220723c8c0aea8078cdfcb3868263ff8f09400",
+        "54da48781893a7e49ad5aff4af300cd804a6b6279ab3ff3afb64491c85194aab",
+        "760d58a606654f9f4400e8b38591356fbf6425aca26dc85244259ff2b19c41b9",
+        "f96f3ca9ec1dde434da7d2d392b905ddf3d1f9af93d1af5950bd493f5aa731b4",
+        "056df31bd267b6b90a079831aaf579be0a39013137aac6d404f518cfd4684064",
+        "7e78bfe706ca4cf5e9c5453e9f7cfd2b8b4c8d169a44e55c88d4a9a7f9474241",
+        "e221af44860018ab0856972e194cd934",
+    );
+
+    // RFC 9369 appendix A.2, the same ClientHello as a QUIC v2 Initial
+    const RFC9369_CLIENT_INITIAL: &str = concat!(
+        "d76b3343cf088394c8f03e5157080000449ea0c95e82ffe67b6abcdb4298b485",
+        "dd04de806071bf03dceebfa162e75d6c96058bdbfb127cdfcbf903388e99ad04",
+        "9f9a3dd4425ae4d0992cfff18ecf0fdb5a842d09747052f17ac2053d21f57c5d",
+        "250f2c4f0e0202b70785b7946e992e58a59ac52dea6774d4f03b55545243cf1a",
+        "12834e3f249a78d395e0d18f4d766004f1a2674802a747eaa901c3f10cda5500",
+        "cb9122faa9f1df66c392079a1b40f0de1c6054196a11cbea40afb6ef5253cd68",
+        "18f6625efce3b6def6ba7e4b37a40f7732e093daa7d52190935b8da58976ff33",
+        "12ae50b187c1433c0f028edcc4c2838b6a9bfc226ca4b4530e7a4ccee1bfa2a3",
+        "d396ae5a3fb512384b2fdd851f784a65e03f2c4fbe11a53c7777c023462239dd",
+        "6f7521a3f6c7d5dd3ec9b3f233773d4b46d23cc375eb198c63301c21801f6520",
+        "bcfb7966fc49b393f0061d974a2706df8c4a9449f11d7f3d2dcbb90c6b877045",
+        "636e7c0c0fe4eb0f697545460c806910d2c355f1d253bc9d2452aaa549e27a1f",
+        "ac7cf4ed77f322e8fa894b6a83810a34b361901751a6f5eb65a0326e07de7c12",
+        "16ccce2d0193f958bb3850a833f7ae432b65bc5a53975c155aa4bcb4f7b2c4e5",
+        "4df16efaf6ddea94e2c50b4cd1dfe06017e0e9d02900cffe1935e0491d77ffb4",
+        "fdf85290fdd893d577b1131a610ef6a5c32b2ee0293617a37cbb08b847741c3b",
+        "8017c25ca9052ca1079d8b78aebd47876d330a30f6a8c6d61dd1ab5589329de7",
+        "14d19d61370f8149748c72f132f0fc99f34d766c6938597040d8f9e2bb522ff9",
+        "9c63a344d6a2ae8aa8e51b7b90a4a806105fcbca31506c446151adfeceb51b91",
+        "abfe43960977c87471cf9ad4074d30e10d6a7f03c63bd5d4317f68ff325ba3bd",
+        "80bf4dc8b52a0ba031758022eb025cdd770b44d6d6cf0670f4e990b22347a7db",
+        "848265e3e5eb72dfe8299ad7481a408322cac55786e52f633b2fb6b614eaed18",
+        "d703dd84045a274ae8bfa73379661388d6991fe39b0d93debb41700b41f90a15",
+        "c4d526250235ddcd6776fc77bc97e7a417ebcb31600d01e57f32162a8560cacc",
+        "7e27a096d37a1a86952ec71bd89a3e9a30a2a26162984d7740f81193e8238e61",
+        "f6b5b984d4d3dfa033c1bb7e4f0037febf406d91c0dccf32acf423cfa1e70710",
+        "10d3f270121b493ce85054ef58bada42310138fe081adb04e2bd901f2f13458b",
+        "3d6758158197107c14ebb193230cd1157380aa79cae1374a7c1e5bbcb80ee23e",
+        "06ebfde206bfb0fcbc0edc4ebec309661bdd908d532eb0c6adc38b7ca7331dce",
+        "8dfce39ab71e7c32d318d136b6100671a1ae6a6600e3899f31f0eed19e3417d1",
+        "34b90c9058f8632c798d4490da4987307cba922d61c39805d072b589bd52fdf1",
+        "e86215c2d54e6670e07383a27bbffb5addf47d66aa85a0c6f9f32e59d85a44dd",
+        "5d3b22dc2be80919b490437ae4f36a0ae55edf1d0b5cb4e9a3ecabee93dfc6e3",
+        "8d209d0fa6536d27a5d6fbb17641cde27525d61093f1b28072d111b2b4ae5f89",
+        "d5974ee12e5cf7d5da4d6a31123041f33e61407e76cffcdcfd7e19ba58cf4b53",
+        "6f4c4938ae79324dc402894b44faf8afbab35282ab659d13c93f70412e85cb19",
+        "9a37ddec600545473cfb5a05e08d0b209973b2172b4d21fb69745a262ccde96b",
+        "a18b2faa745b6fe189cf772a9f84cbfc",
+    );
+
+    fn unhex(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn rfc9001_client_initial_names_example_com() {
+        let packet = unhex(RFC9001_CLIENT_INITIAL);
+        assert_eq!(packet.len(), 1200);
+        assert_eq!(initial_server_name(&packet).as_deref(), Some("example.com"));
+    }
+
+    #[test]
+    fn rfc9369_v2_client_initial_names_example_com() {
+        let packet = unhex(RFC9369_CLIENT_INITIAL);
+        assert_eq!(initial_server_name(&packet).as_deref(), Some("example.com"));
+    }
+
+    #[test]
+    fn truncated_initials_have_no_name() {
+        let packet = unhex(RFC9001_CLIENT_INITIAL);
+        // Before the tag ends, inside the sample, inside the header
+        for len in [0, 1, 5, 14, 21, 30, 1199] {
+            assert_eq!(initial_server_name(&packet[..len]), None, "{} bytes", len);
+        }
+    }
+
+    #[test]
+    fn garbage_and_other_packets_have_no_name() {
+        let packet = unhex(RFC9001_CLIENT_INITIAL);
+
+        // A flipped ciphertext byte fails authentication
+        let mut corrupted = packet.clone();
+        corrupted[600] ^= 0x01;
+        assert_eq!(initial_server_name(&corrupted), None);
+
+        // v2 keys do not open a v1 packet, nor does an unknown version parse
+        let mut relabeled = packet.clone();
+        relabeled[1..5].copy_from_slice(&QUIC_V2.to_be_bytes());
+        relabeled[0] = (relabeled[0] & 0xcf) | 0x10;
+        assert_eq!(initial_server_name(&relabeled), None);
+        let mut unknown = packet.clone();
+        unknown[1..5].copy_from_slice(&[0x1a, 0x2a, 0x3a, 0x4a]);
+        assert_eq!(initial_server_name(&unknown), None);
+
+        // Short header, and a long header Handshake packet
+        let mut short = packet.clone();
+        short[0] = 0x40;
+        assert_eq!(initial_server_name(&short), None);
+        let mut handshake = packet.clone();
+        handshake[0] |= 0x20;
+        assert_eq!(initial_server_name(&handshake), None);
+
+        // A connection ID longer than QUIC allows
+        let mut long_dcid = packet;
+        long_dcid[5] = 21;
+        assert_eq!(initial_server_name(&long_dcid), None);
+
+        let noise: Vec<u8> = (0..1200u32).map(|i| (i * 7 + 0xc3) as u8).collect();
+        assert_eq!(initial_server_name(&noise), None);
+    }
+}
@@ -0,0 +1,312 @@
+use crate::devices::write_atomic;
+use crate::metrics::{Flush, FlushSink};
+use crate::runtime::spawn_blocking;
+use chrono::{DateTime, Local, NaiveTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use prometheus::{IntGaugeVec, Opts, Registry};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tracing::{info, warn};
+
+#[derive(Debug, Clone, Copy)]
+enum Zone {
+    // The system time zone, from TZ or /etc/localtime
+    Local,
+    Named(Tz),
+}
+
+// When the daily counters start over: reset_time in the configured time zone
+#[derive(Debug, Clone, Copy)]
+pub struct DailySchedule {
+    zone: Zone,
+    reset: NaiveTime,
+}
+
+// Unix time of the last reset boundary at or before `now`. A boundary that falls
+// into a DST gap is taken as UTC rather than skipped.
+fn period_start_in<Z: TimeZone>(zone: &Z, now: DateTime<Utc>, reset: NaiveTime) -> i64 {
+    let local = now.with_timezone(zone).naive_local();
+    let mut day = local.date();
+    if local.time() < reset {
+        day = day.pred_opt().unwrap_or(day);
+    }
+    let start = day.and_time(reset);
+    match zone.from_local_datetime(&start).earliest() {
+        Some(start) => start.timestamp(),
+        None => start.and_utc().timestamp(),
+    }
+}
+
+impl DailySchedule {
+    // `timezone` is an IANA name such as Asia/Tokyo, `reset_time` is HH:MM
+    pub fn new(timezone: Option<&str>, reset_time: &str) -> Result<Self, String> {
+        let zone = match timezone {
+            None => Zone::Local,
+            Some(name) => Zone::Named(
+                name.parse()
+                    .map_err(|_| format!("unknown time zone '{}'", name))?,
+            ),
+        };
+        let reset = NaiveTime::parse_from_str(reset_time, "%H:%M")
+            .map_err(|_| format!("invalid reset time '{}', expected HH:MM", reset_time))?;
+        Ok(Self { zone, reset })
+    }
+
+    pub fn period_start(&self, now: SystemTime) -> i64 {
+        let now = DateTime::<Utc>::from(now);
+        match &self.zone {
+            Zone::Local => period_start_in(&Local, now, self.reset),
+            Zone::Named(tz) => period_start_in(tz, now, self.reset),
+        }
+    }
+}
+
+pub struct QuotaMetrics {
+    pub tx_bytes_today: IntGaugeVec,
+    pub rx_bytes_today: IntGaugeVec,
+    pub quota_exceeded: IntGaugeVec,
+}
+
+impl QuotaMetrics {
+    pub fn register(registry: &Registry, namespace: &str) -> prometheus::Result<Self> {
+        let namespace = namespace.trim_end_matches('_');
+        let gauge = |name: &str, help: &str| {
+            IntGaugeVec::new(Opts::new(name, help).namespace(namespace), &["local_ip"])
+        };
+        let metrics = Self {
+            tx_bytes_today: gauge(
+                "ip_tx_bytes_today",
+                "TX bytes per IP since the last daily_reset_time",
+            )?,
+            rx_bytes_today: gauge(
+                "ip_rx_bytes_today",
+                "RX bytes per IP since the last daily_reset_time",
+            )?,
+            quota_exceeded: gauge(
+                "ip_quota_exceeded",
+                "1 when the TX+RX bytes of today reached the daily quota of the IP, only for IPs with a quota",
+            )?,
+        };
+        registry.register(Box::new(metrics.tx_bytes_today.clone()))?;
+        registry.register(Box::new(metrics.rx_bytes_today.clone()))?;
+        registry.register(Box::new(metrics.quota_exceeded.clone()))?;
+        Ok(metrics)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+struct DailyBytes {
+    tx_bytes: u64,
+    rx_bytes: u64,
+}
+
+// What daily_checkpoint_file holds
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct DailyTotals {
+    period_start_unix: i64,
+    ips: HashMap<IpAddr, DailyBytes>,
+}
+
+// Per-IP bytes since the last reset boundary, kept apart from TrafficStats which
+// starts over every interval. Shared between DailySink and checkpoint_daily.
+pub struct DailyCounters {
+    totals: Mutex<DailyTotals>,
+    checkpoint: Option<PathBuf>,
+    // Changed since the last checkpoint
+    dirty: AtomicBool,
+}
+
+impl DailyCounters {
+    // A checkpoint from an earlier period is ignored, so a restart only keeps the
+    // counters of the day it happened on
+    pub fn load(checkpoint: Option<PathBuf>, schedule: &DailySchedule) -> Result<Self, String> {
+        let period_start = schedule.period_start(SystemTime::now());
+        let mut totals = DailyTotals {
+            period_start_unix: period_start,
+            ips: HashMap::new(),
+        };
+        if let Some(path) = &checkpoint {
+            match std::fs::read(path) {
+                Ok(data) => {
+                    let saved: DailyTotals = serde_json::from_slice(&data)
+                        .map_err(|e| format!("failed to parse {}: {}", path.display(), e))?;
+                    if saved.period_start_unix == period_start {
+                        info!(
+                            "Restored today's counters of {} IPs from {}",
+                            saved.ips.len(),
+                            path.display()
+                        );
+                        totals = saved;
+                    }
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(format!("failed to read {}: {}", path.display(), e)),
+            }
+        }
+        Ok(Self {
+            totals: Mutex::new(totals),
+            checkpoint,
+            dirty: AtomicBool::new(false),
+        })
+    }
+
+    // Blocks on the file system
+    pub fn save(&self) -> Result<(), String> {
+        let Some(path) = &self.checkpoint else {
+            return Ok(());
+        };
+        if !self.dirty.swap(false, Ordering::Relaxed) {
+            return Ok(());
+        }
+        let data = serde_json::to_vec(&*self.totals.lock().unwrap()).map_err(|e| e.to_string());
+        let result = data.and_then(|data| write_atomic(path, &data));
+        if result.is_err() {
+            self.dirty.store(true, Ordering::Relaxed);
+        }
+        result
+    }
+}
+
+// Daily quotas in bytes, TX and RX combined
+#[derive(Debug, Clone, Default)]
+pub struct Quotas {
+    default: Option<u64>,
+    per_ip: HashMap<IpAddr, u64>,
+}
+
+impl Quotas {
+    // Quotas are given in GB (10^9 bytes), per-IP entries override the default
+    pub fn new(default_gb: Option<f64>, per_ip_gb: &BTreeMap<String, f64>) -> Result<Self, String> {
+        let bytes = |gb: f64| {
+            (gb.is_finite() && gb > 0.0)
+                .then_some((gb * 1e9) as u64)
+                .ok_or_else(|| format!("quota {} must be positive", gb))
+        };
+        let per_ip = per_ip_gb
+            .iter()
+            .map(|(ip, gb)| {
+                let ip: IpAddr = ip
+                    .parse()
+                    .map_err(|_| format!("invalid IP address '{}' in daily_quotas_gb", ip))?;
+                Ok((ip, bytes(*gb)?))
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(Self {
+            default: default_gb.map(bytes).transpose()?,
+            per_ip,
+        })
+    }
+
+    fn get(&self, ip: &IpAddr) -> Option<u64> {
+        self.per_ip.get(ip).copied().or(self.default)
+    }
+}
+
+pub struct DailySink {
+    counters: Arc<DailyCounters>,
+    schedule: DailySchedule,
+    quotas: Quotas,
+    metrics: QuotaMetrics,
+    // Publish every restored IP on the first flush, not only the ones with traffic
+    restored: bool,
+}
+
+impl DailySink {
+    pub fn new(
+        counters: Arc<DailyCounters>,
+        schedule: DailySchedule,
+        quotas: Quotas,
+        metrics: QuotaMetrics,
+    ) -> Self {
+        Self {
+            counters,
+            schedule,
+            quotas,
+            metrics,
+            restored: true,
+        }
+    }
+
+    fn set(&self, ip: &IpAddr, bytes: DailyBytes) {
+        let label = ip.to_string();
+        let labels = [label.as_str()];
+        let m = &self.metrics;
+        m.tx_bytes_today
+            .with_label_values(&labels)
+            .set(bytes.tx_bytes as i64);
+        m.rx_bytes_today
+            .with_label_values(&labels)
+            .set(bytes.rx_bytes as i64);
+        if let Some(quota) = self.quotas.get(ip) {
+            let exceeded = bytes.tx_bytes + bytes.rx_bytes >= quota;
+            m.quota_exceeded
+                .with_label_values(&labels)
+                .set(exceeded as i64);
+        }
+    }
+
+    fn remove(&self, ip: &IpAddr) {
+        let label = ip.to_string();
+        let labels = [label.as_str()];
+        let _ = self.metrics.tx_bytes_today.remove_label_values(&labels);
+        let _ = self.metrics.rx_bytes_today.remove_label_values(&labels);
+        let _ = self.metrics.quota_exceeded.remove_label_values(&labels);
+    }
+}
+
+impl FlushSink for DailySink {
+    fn publish(&mut self, flush: &Flush<'_>) {
+        let period_start = self.schedule.period_start(flush.timestamp);
+        let counters = self.counters.clone();
+        let mut totals = counters.totals.lock().unwrap();
+        if totals.period_start_unix != period_start {
+            info!("Daily counters of {} IPs reset", totals.ips.len());
+            for ip in totals.ips.keys() {
+                self.remove(ip);
+            }
+            totals.ips.clear();
+            totals.period_start_unix = period_start;
+            counters.dirty.store(true, Ordering::Relaxed);
+        }
+
+        let mut changed = HashSet::new();
+        for rate in &flush.snapshot.per_ip {
+            // local_ip="other" folds many IPs together
+            let Some(ip) = rate.key.ip else {
+                continue;
+            };
+            let bytes = totals.ips.entry(ip).or_default();
+            bytes.tx_bytes += rate.tx.map_or(0, |rate| rate.bytes);
+            bytes.rx_bytes += rate.rx.map_or(0, |rate| rate.bytes);
+            changed.insert(ip);
+        }
+        if !changed.is_empty() {
+            counters.dirty.store(true, Ordering::Relaxed);
+        }
+        if std::mem::take(&mut self.restored) {
+            changed.extend(totals.ips.keys().copied());
+        }
+        for ip in changed {
+            self.set(&ip, totals.ips[&ip]);
+        }
+    }
+}
+
+pub async fn checkpoint_daily(counters: Arc<DailyCounters>, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let counters = counters.clone();
+        match spawn_blocking(move || counters.save()).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => warn!("Failed to checkpoint daily counters: {}", e),
+            Err(e) => warn!("Daily checkpoint task failed: {}", e),
+        }
+    }
+}
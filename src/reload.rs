@@ -0,0 +1,142 @@
+use crate::alerts::validate_rules;
+use crate::capture::validate_bpf_filter;
+use crate::config::{read_config, Config};
+use crate::subnets::{load_subnets_file, LocalSubnets, SubnetControl};
+use prometheus::IntCounterVec;
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use tracing::{error, info, warn};
+use tracing_subscriber::{reload, EnvFilter, Registry};
+
+// Without RUST_LOG or log_level
+pub const DEFAULT_LOG_LEVEL: &str = "info";
+
+// Top-level keys a reload applies; changes to any other key need a restart
+const LIVE_KEYS: &[&str] = &[
+    "subnets",
+    "subnets_file",
+    "exclude_link_local",
+    "bpf_filter",
+    "alerts",
+    "update_interval",
+    "log_level",
+];
+
+pub type LogFilter = reload::Handle<EnvFilter, Registry>;
+
+pub struct Reloader {
+    // --config; without it there is nothing to reload
+    pub path: Option<PathBuf>,
+    // Top-level table of the config in effect, compared to tell what changed
+    pub table: toml::Table,
+    pub config: watch::Sender<Arc<Config>>,
+    pub subnets: Arc<SubnetControl>,
+    pub log_filter: LogFilter,
+    pub auto_subnets: bool,
+    // --update-interval and RUST_LOG keep winning over the reloaded config
+    pub update_interval_override: Option<Duration>,
+    pub log_level_override: bool,
+    pub reloads: IntCounterVec,
+}
+
+// Like at startup, subnets_file wins when it exists, but an entry that does not
+// parse rejects the reload rather than being skipped
+fn configured_subnets(config: &Config, auto_subnets: bool) -> Result<LocalSubnets, String> {
+    let saved = match &config.subnets_file {
+        Some(path) => load_subnets_file(path)?,
+        None => None,
+    };
+    let mut subnets = LocalSubnets::new();
+    subnets.exclude_link_local = config.exclude_link_local;
+    for subnet in saved.unwrap_or_else(|| config.subnets(auto_subnets)) {
+        subnets
+            .add_subnet(&subnet)
+            .map_err(|e| format!("invalid subnet '{}': {}", subnet, e))?;
+    }
+    Ok(subnets)
+}
+
+impl Reloader {
+    // Everything is checked before anything is applied. Returns the changed keys
+    // that only take effect after a restart.
+    fn reload(&mut self, path: &Path) -> Result<Vec<String>, String> {
+        let (mut config, table) = read_config(path).map_err(|e| e.to_string())?;
+        if self.update_interval_override.is_some() {
+            config.update_interval = self.update_interval_override;
+        }
+        validate_bpf_filter(config.bpf_filter())
+            .map_err(|e| format!("invalid BPF filter '{}': {}", config.bpf_filter(), e))?;
+        validate_rules(&config.alerts)?;
+        let log_filter = if self.log_level_override {
+            None
+        } else {
+            let level = config.log_level.as_deref().unwrap_or(DEFAULT_LOG_LEVEL);
+            Some(
+                EnvFilter::try_new(level)
+                    .map_err(|e| format!("invalid log_level '{}': {}", level, e))?,
+            )
+        };
+        let subnets = configured_subnets(&config, self.auto_subnets)?;
+
+        self.subnets.set_configured(subnets)?;
+        self.subnets.set_state_file(config.subnets_file.clone());
+        if let Some(filter) = log_filter {
+            self.log_filter.reload(filter).map_err(|e| e.to_string())?;
+        }
+        let keys: BTreeSet<&String> = self.table.keys().chain(table.keys()).collect();
+        let restart = keys
+            .into_iter()
+            .filter(|key| !LIVE_KEYS.contains(&key.as_str()))
+            .filter(|key| self.table.get(*key) != table.get(*key))
+            .cloned()
+            .collect();
+        self.table = table;
+        self.config.send_replace(Arc::new(config));
+        Ok(restart)
+    }
+}
+
+// Handles SIGHUP for the life of the process, so `systemctl reload` never falls
+// back to the default action of terminating it
+pub async fn reload_on_sighup(mut reloader: Reloader) {
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            error!(
+                "Failed to install the SIGHUP handler, reloads disabled: {}",
+                e
+            );
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        let Some(path) = reloader.path.clone() else {
+            warn!("SIGHUP received without --config, nothing to reload");
+            continue;
+        };
+        info!("SIGHUP received, reloading {}", path.display());
+        match reloader.reload(&path) {
+            Ok(restart) => {
+                reloader.reloads.with_label_values(&["success"]).inc();
+                info!("Configuration reloaded");
+                if !restart.is_empty() {
+                    warn!(
+                        "Changed settings that need a restart to take effect: {}",
+                        restart.join(", ")
+                    );
+                }
+            }
+            Err(e) => {
+                reloader.reloads.with_label_values(&["failure"]).inc();
+                error!(
+                    "Failed to reload the configuration, keeping the current one: {}",
+                    e
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,33 @@
+use std::sync::atomic::{AtomicI64, Ordering};
+use tokio::task::JoinHandle;
+
+// spawn_blocking tasks queued or running; tokio only counts its blocking threads
+// when built with --cfg tokio_unstable
+static BLOCKING_TASKS: AtomicI64 = AtomicI64::new(0);
+
+struct BlockingTask;
+
+impl Drop for BlockingTask {
+    fn drop(&mut self) {
+        BLOCKING_TASKS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// tokio::task::spawn_blocking, counted in localpacketdump_tokio_blocking_tasks.
+// Capture threads run here for their whole lifetime.
+pub fn spawn_blocking<F, R>(f: F) -> JoinHandle<R>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    BLOCKING_TASKS.fetch_add(1, Ordering::Relaxed);
+    let task = BlockingTask;
+    tokio::task::spawn_blocking(move || {
+        let _task = task;
+        f()
+    })
+}
+
+pub fn blocking_tasks() -> i64 {
+    BLOCKING_TASKS.load(Ordering::Relaxed)
+}
@@ -0,0 +1,1062 @@
+use crate::auth::{require_auth, HttpAuth};
+use crate::capture::validate_bpf_filter;
+use crate::devices::{Device, DeviceTable};
+use crate::dns::{DnsQueries, TopDomain};
+use crate::download::{download_channel, open_download_capture, stream_capture, PcapDownload};
+use crate::dump::DumpControl;
+use crate::feed::{PacketFeed, PacketFilter, PacketSubscription};
+use crate::health::{component_status, HealthState};
+use crate::history::{HistorySeries, IntervalHistory};
+use crate::mapping::StatusResponse;
+use crate::metrics::{IntervalSnapshot, IpRate, LiveSnapshots, Metrics, Rate, SharedSnapshot};
+use crate::privileges::lookup_id;
+use crate::subnets::{LocalSubnets, SubnetControl, SubnetList};
+use crate::tls::TlsConfig;
+#[cfg(feature = "sqlite")]
+use crate::usage::{is_day, UsageStore};
+use axum::body::Body;
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{Query, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{middleware, Json, Router};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use futures_util::stream::{self, Stream};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use hyper_util::server::graceful::GracefulShutdown;
+use hyper_util::service::TowerToHyperService;
+use serde::{Deserialize, Serialize};
+use std::convert::Infallible;
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, UNIX_EPOCH};
+use tokio::net::{TcpListener, UnixListener};
+use tokio::sync::{broadcast, watch};
+use tracing::{debug, error, info};
+
+// Bounds for the n parameter of /top
+const TOP_DEFAULT_ENTRIES: usize = 10;
+const TOP_MAX_ENTRIES: usize = 1000;
+
+// /dns/top without an n parameter, bounded by TOP_MAX_ENTRIES as well
+const DNS_TOP_DEFAULT_ENTRIES: usize = 20;
+
+// Capture length of /pcap without a seconds parameter
+const PCAP_DEFAULT_SECONDS: u64 = 10;
+
+// The dashboard on /, built into the binary since routers are often offline
+const DASHBOARD_HTML: &str = include_str!("../assets/dashboard.html");
+
+// FNV-1a of the page, its ETag
+const DASHBOARD_HASH: u64 = {
+    let bytes = DASHBOARD_HTML.as_bytes();
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    let mut i = 0;
+    while i < bytes.len() {
+        hash = (hash ^ bytes[i] as u64).wrapping_mul(0x0100_0000_01b3);
+        i += 1;
+    }
+    hash
+};
+
+// JSON that the dashboard polls is never served from a cache
+const NO_STORE: (header::HeaderName, &str) = (header::CACHE_CONTROL, "no-store");
+
+// Shared state of the HTTP handlers
+#[derive(Clone)]
+pub struct AppState {
+    pub metrics: Arc<Metrics>,
+    pub health: Arc<HealthState>,
+    pub last_interval: SharedSnapshot,
+    pub live: LiveSnapshots,
+    // Set when the server stops; /stream and /ws/packets responses end then
+    pub stopping: watch::Receiver<bool>,
+    pub status: Arc<Mutex<StatusResponse>>,
+    pub local_subnets: Arc<RwLock<LocalSubnets>>,
+    // Changes the configured subnets behind local_subnets for /api/subnets
+    pub subnets: Arc<SubnetControl>,
+    pub capture_interfaces: Arc<[String]>,
+    pub dump: Option<Arc<DumpControl>>,
+    pub pcap_download: Option<Arc<PcapDownload>>,
+    // None with packet_stream_max_clients = 0
+    pub packet_feed: Option<Arc<PacketFeed>>,
+    // None with history_intervals = 0
+    pub history: Option<Arc<IntervalHistory>>,
+    // None without track_devices or devices_file
+    pub devices: Option<Arc<DeviceTable>>,
+    // None without dns_queries
+    pub dns: Option<Arc<DnsQueries>>,
+    // None without usage_db
+    #[cfg(feature = "sqlite")]
+    pub usage: Option<Arc<UsageStore>>,
+    // Checked before every route, None without http_auth_* settings
+    pub auth: Option<Arc<HttpAuth>>,
+}
+
+async fn status_handler(State(state): State<AppState>) -> Json<serde_json::Value> {
+    let status = state.status.lock().unwrap().clone();
+    let last_refresh = match state.health.mapping_last_ok_unix.load(Ordering::Relaxed) {
+        0 => None,
+        secs => Some(secs),
+    };
+    Json(serde_json::json!({
+        "nic": status,
+        "local_subnets": state.local_subnets.read().unwrap().to_strings(),
+        "capture_interfaces": state.capture_interfaces,
+        "last_refresh_unix": last_refresh,
+    }))
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum TopSort {
+    #[default]
+    Tx,
+    Rx,
+    Total,
+}
+
+#[derive(Debug, Deserialize)]
+struct TopQuery {
+    n: Option<usize>,
+    #[serde(default)]
+    sort: TopSort,
+}
+
+#[derive(Debug, Serialize)]
+struct TopEntry {
+    ip: String,
+    nic: Arc<str>,
+    tx_bps: f64,
+    rx_bps: f64,
+}
+
+// The n busiest IPs of a snapshot by `sort`
+fn top_entries(snapshot: &IntervalSnapshot, query: &TopQuery) -> Vec<TopEntry> {
+    let n = query
+        .n
+        .unwrap_or(TOP_DEFAULT_ENTRIES)
+        .clamp(1, TOP_MAX_ENTRIES);
+    let key = |rate: &IpRate| match query.sort {
+        TopSort::Tx => rate.tx_bps(),
+        TopSort::Rx => rate.rx_bps(),
+        TopSort::Total => rate.tx_bps() + rate.rx_bps(),
+    };
+
+    let mut entries: Vec<&IpRate> = snapshot.per_ip.iter().collect();
+    entries.sort_by(|a, b| key(b).total_cmp(&key(a)));
+    entries.truncate(n);
+    entries
+        .into_iter()
+        .map(|rate| TopEntry {
+            ip: rate.key.ip_label(),
+            nic: rate.key.nic.clone(),
+            tx_bps: rate.tx_bps(),
+            rx_bps: rate.rx_bps(),
+        })
+        .collect()
+}
+
+async fn top_handler(State(state): State<AppState>, Query(query): Query<TopQuery>) -> Response {
+    let snapshot = state.last_interval.read().unwrap().clone();
+    ([NO_STORE], Json(top_entries(&snapshot, &query))).into_response()
+}
+
+#[derive(Debug, Serialize)]
+struct NicEntry {
+    nic: Arc<str>,
+    tx_bps: f64,
+    rx_bps: f64,
+    tx_pps: f64,
+    rx_pps: f64,
+}
+
+// One /stream event per flush
+#[derive(Debug, Serialize)]
+struct LiveEvent {
+    // End of the interval, unix seconds
+    timestamp: f64,
+    interval_secs: f64,
+    nics: Vec<NicEntry>,
+    top: Vec<TopEntry>,
+}
+
+// Per-NIC totals of the last interval on /totals
+#[derive(Debug, Serialize)]
+struct TotalsResponse {
+    timestamp: f64,
+    interval_secs: f64,
+    nics: Vec<NicEntry>,
+}
+
+fn totals(snapshot: &IntervalSnapshot) -> TotalsResponse {
+    let side = |rate: Option<Rate>| rate.unwrap_or_default();
+    TotalsResponse {
+        timestamp: snapshot
+            .timestamp
+            .duration_since(UNIX_EPOCH)
+            .map_or(0.0, |d| d.as_secs_f64()),
+        interval_secs: snapshot.elapsed.as_secs_f64(),
+        nics: snapshot
+            .per_nic
+            .iter()
+            .map(|rate| NicEntry {
+                nic: rate.nic.clone(),
+                tx_bps: side(rate.tx).bps,
+                rx_bps: side(rate.rx).bps,
+                tx_pps: side(rate.tx).pps,
+                rx_pps: side(rate.rx).pps,
+            })
+            .collect(),
+    }
+}
+
+fn live_event(snapshot: &IntervalSnapshot, query: &TopQuery) -> LiveEvent {
+    let totals = totals(snapshot);
+    LiveEvent {
+        timestamp: totals.timestamp,
+        interval_secs: totals.interval_secs,
+        nics: totals.nics,
+        top: top_entries(snapshot, query),
+    }
+}
+
+async fn totals_handler(State(state): State<AppState>) -> Response {
+    let snapshot = state.last_interval.read().unwrap().clone();
+    ([NO_STORE], Json(totals(&snapshot))).into_response()
+}
+
+// The page is revalidated on every load, so a new binary takes effect right away
+// while an unchanged one costs a 304
+async fn dashboard_handler(headers: HeaderMap) -> Response {
+    let etag = format!("\"{:016x}\"", DASHBOARD_HASH);
+    let cache = [
+        (header::ETAG, etag.clone()),
+        (header::CACHE_CONTROL, "no-cache".to_string()),
+    ];
+    let matched = headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.split(',').any(|tag| tag.trim() == etag));
+    if matched {
+        return (StatusCode::NOT_MODIFIED, cache).into_response();
+    }
+    (
+        cache,
+        [(header::CONTENT_TYPE, "text/html; charset=utf-8")],
+        DASHBOARD_HTML,
+    )
+        .into_response()
+}
+
+// Server-Sent Events, one `interval` event per flush with the same n and sort
+// parameters as /top. A client that falls behind skips the snapshots it missed.
+async fn stream_handler(
+    State(state): State<AppState>,
+    Query(query): Query<TopQuery>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let snapshots = state.live.subscribe();
+    let stopping = state.stopping.clone();
+    let events = stream::unfold(
+        (snapshots, stopping, query),
+        |(mut snapshots, mut stopping, query)| async move {
+            loop {
+                let snapshot = tokio::select! {
+                    snapshot = snapshots.recv() => snapshot,
+                    _ = stopping.wait_for(|stop| *stop) => return None,
+                };
+                match snapshot {
+                    Ok(snapshot) => {
+                        let event = Event::default()
+                            .event("interval")
+                            .json_data(live_event(&snapshot, &query))
+                            .unwrap_or_default();
+                        return Some((Ok(event), (snapshots, stopping, query)));
+                    }
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        debug!("/stream client skipped {} snapshots", skipped);
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        },
+    );
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
+
+async fn packets_ws_handler(
+    State(state): State<AppState>,
+    Query(filter): Query<PacketFilter>,
+    ws: WebSocketUpgrade,
+) -> Response {
+    let Some(feed) = &state.packet_feed else {
+        return (
+            StatusCode::NOT_FOUND,
+            "the packet stream is disabled, see packet_stream_max_clients",
+        )
+            .into_response();
+    };
+    let Some(subscription) = feed.subscribe() else {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "too many /ws/packets clients",
+        )
+            .into_response();
+    };
+    let max_rate = feed.max_rate;
+    let stopping = state.stopping.clone();
+    ws.on_upgrade(move |socket| stream_packets(socket, subscription, filter, max_rate, stopping))
+}
+
+// Sends up to max_rate matching packets per second; the number of packets left
+// out since the last message, over the rate or behind the broadcast, follows
+// every second as {"skipped": n}
+async fn stream_packets(
+    mut socket: WebSocket,
+    mut subscription: PacketSubscription,
+    filter: PacketFilter,
+    max_rate: u32,
+    mut stopping: watch::Receiver<bool>,
+) {
+    let mut window = tokio::time::interval(Duration::from_secs(1));
+    let mut sent = 0u32;
+    let mut skipped = 0u64;
+    loop {
+        let message = tokio::select! {
+            event = subscription.events.recv() => match event {
+                Ok(event) => {
+                    if !filter.matches(&event) {
+                        continue;
+                    }
+                    if max_rate > 0 && sent >= max_rate {
+                        skipped += 1;
+                        continue;
+                    }
+                    sent += 1;
+                    match serde_json::to_string(&*event) {
+                        Ok(json) => Message::Text(json),
+                        Err(_) => continue,
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    skipped += n;
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            _ = window.tick() => {
+                sent = 0;
+                if skipped == 0 {
+                    continue;
+                }
+                Message::Text(serde_json::json!({ "skipped": std::mem::take(&mut skipped) }).to_string())
+            }
+            received = socket.recv() => match received {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return,
+                // Pings are answered by axum
+                Some(Ok(_)) => continue,
+            },
+            _ = stopping.wait_for(|stop| *stop) => break,
+        };
+        if socket.send(message).await.is_err() {
+            return;
+        }
+    }
+    let _ = socket.send(Message::Close(None)).await;
+}
+
+async fn devices_handler(State(state): State<AppState>) -> Response {
+    let Some(devices) = &state.devices else {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(serde_json::json!({ "error": "device tracking is disabled, see track_devices" })),
+        )
+            .into_response();
+    };
+    Json::<Vec<Device>>(devices.list()).into_response()
+}
+
+#[derive(Debug, Deserialize)]
+struct DnsTopQuery {
+    n: Option<usize>,
+    // Only the queries of this local IP
+    ip: Option<IpAddr>,
+}
+
+async fn dns_top_handler(
+    State(state): State<AppState>,
+    Query(query): Query<DnsTopQuery>,
+) -> Response {
+    let Some(dns) = &state.dns else {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(serde_json::json!({ "error": "DNS query tracking is disabled, see dns_queries" })),
+        )
+            .into_response();
+    };
+    let n = query
+        .n
+        .unwrap_or(DNS_TOP_DEFAULT_ENTRIES)
+        .clamp(1, TOP_MAX_ENTRIES);
+    ([NO_STORE], Json::<Vec<TopDomain>>(dns.top(n, query.ip))).into_response()
+}
+
+// from and to are inclusive UTC days, YYYY-MM-DD
+#[cfg(feature = "sqlite")]
+#[derive(Debug, Deserialize)]
+struct UsageQuery {
+    ip: Option<IpAddr>,
+    from: Option<String>,
+    to: Option<String>,
+}
+
+#[cfg(feature = "sqlite")]
+async fn usage_handler(State(state): State<AppState>, Query(query): Query<UsageQuery>) -> Response {
+    let Some(usage) = state.usage.clone() else {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(serde_json::json!({ "error": "usage accounting is disabled, see usage_db" })),
+        )
+            .into_response();
+    };
+    if let Some(day) = [&query.from, &query.to]
+        .into_iter()
+        .flatten()
+        .find(|day| !is_day(day))
+    {
+        return (
+            StatusCode::BAD_REQUEST,
+            format!("invalid day '{}', expected YYYY-MM-DD", day),
+        )
+            .into_response();
+    }
+    let report = crate::runtime::spawn_blocking(move || {
+        usage.usage(query.ip, query.from.as_deref(), query.to.as_deref())
+    })
+    .await;
+    match report {
+        Ok(Ok(report)) => Json(report).into_response(),
+        Ok(Err(e)) => {
+            error!("Failed to query usage: {}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("failed to query usage: {}\n", e),
+            )
+                .into_response()
+        }
+        Err(e) => {
+            error!("Usage query task failed: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+#[cfg(not(feature = "sqlite"))]
+async fn usage_handler() -> Response {
+    (
+        StatusCode::NOT_FOUND,
+        Json(serde_json::json!({ "error": "this build has no sqlite feature" })),
+    )
+        .into_response()
+}
+
+#[derive(Debug, Deserialize)]
+struct HistoryQuery {
+    ip: IpAddr,
+    seconds: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct HistoryTotalsQuery {
+    nic: Option<String>,
+    seconds: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+struct IpHistory {
+    ip: IpAddr,
+    #[serde(flatten)]
+    series: HistorySeries,
+}
+
+#[derive(Debug, Serialize)]
+struct TotalsHistory {
+    // null: summed over every NIC
+    nic: Option<String>,
+    #[serde(flatten)]
+    series: HistorySeries,
+}
+
+fn history_disabled() -> Response {
+    (
+        StatusCode::NOT_FOUND,
+        Json(serde_json::json!({ "error": "history is disabled, history_intervals = 0" })),
+    )
+        .into_response()
+}
+
+async fn history_handler(
+    State(state): State<AppState>,
+    Query(query): Query<HistoryQuery>,
+) -> Response {
+    let Some(history) = &state.history else {
+        return history_disabled();
+    };
+    let series = history.ip(query.ip, query.seconds.map(Duration::from_secs));
+    Json(IpHistory {
+        ip: query.ip,
+        series,
+    })
+    .into_response()
+}
+
+async fn history_totals_handler(
+    State(state): State<AppState>,
+    Query(query): Query<HistoryTotalsQuery>,
+) -> Response {
+    let Some(history) = &state.history else {
+        return history_disabled();
+    };
+    let series = history.totals(query.nic.as_deref(), query.seconds.map(Duration::from_secs));
+    Json(TotalsHistory {
+        nic: query.nic,
+        series,
+    })
+    .into_response()
+}
+
+fn dump_state(dump: Option<&DumpControl>) -> (StatusCode, Json<serde_json::Value>) {
+    match dump {
+        Some(dump) => (
+            StatusCode::OK,
+            Json(serde_json::json!({
+                "active": dump.is_active(),
+                "dir": dump.settings.dir,
+            })),
+        ),
+        None => (
+            StatusCode::NOT_FOUND,
+            Json(
+                serde_json::json!({ "error": "packet dump is not enabled, start with --dump-dir" }),
+            ),
+        ),
+    }
+}
+
+// Body of POST and DELETE /api/subnets
+#[derive(Debug, Deserialize)]
+struct SubnetRequest {
+    subnet: String,
+}
+
+async fn subnets_handler(State(state): State<AppState>) -> Json<SubnetList> {
+    Json(state.subnets.list())
+}
+
+fn subnet_error(code: StatusCode, error: String) -> Response {
+    (code, Json(serde_json::json!({ "error": error }))).into_response()
+}
+
+fn parse_subnet(subnet: &str) -> Result<ipnet::IpNet, String> {
+    subnet
+        .trim()
+        .parse()
+        .map_err(|e| format!("invalid subnet '{}': {}", subnet, e))
+}
+
+// Writes subnets_file, if any, and answers with the new list
+async fn saved_subnets(state: &AppState, code: StatusCode) -> Response {
+    let subnets = state.subnets.clone();
+    let saved = match crate::runtime::spawn_blocking(move || subnets.save()).await {
+        Ok(result) => result,
+        Err(e) => Err(e.to_string()),
+    };
+    if let Err(e) = saved {
+        error!("Failed to save the subnets: {}", e);
+        return subnet_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("applied, but failed to save subnets_file: {}", e),
+        );
+    }
+    (code, Json(state.subnets.list())).into_response()
+}
+
+async fn subnet_add_handler(
+    State(state): State<AppState>,
+    Json(request): Json<SubnetRequest>,
+) -> Response {
+    let subnet = match parse_subnet(&request.subnet) {
+        Ok(subnet) => subnet,
+        Err(e) => return subnet_error(StatusCode::BAD_REQUEST, e),
+    };
+    if !state.subnets.add(subnet) {
+        return Json(state.subnets.list()).into_response();
+    }
+    info!("Local subnet {} added through /api/subnets", subnet.trunc());
+    saved_subnets(&state, StatusCode::CREATED).await
+}
+
+async fn subnet_remove_handler(
+    State(state): State<AppState>,
+    Json(request): Json<SubnetRequest>,
+) -> Response {
+    let subnet = match parse_subnet(&request.subnet) {
+        Ok(subnet) => subnet,
+        Err(e) => return subnet_error(StatusCode::BAD_REQUEST, e),
+    };
+    match state.subnets.remove(subnet) {
+        Ok(true) => {
+            info!(
+                "Local subnet {} removed through /api/subnets",
+                subnet.trunc()
+            );
+            saved_subnets(&state, StatusCode::OK).await
+        }
+        Ok(false) => subnet_error(
+            StatusCode::NOT_FOUND,
+            format!("{} is not a configured subnet", subnet.trunc()),
+        ),
+        Err(e) => subnet_error(StatusCode::CONFLICT, e),
+    }
+}
+
+async fn dump_handler(State(state): State<AppState>) -> (StatusCode, Json<serde_json::Value>) {
+    dump_state(state.dump.as_deref())
+}
+
+async fn dump_start_handler(
+    State(state): State<AppState>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    if let Some(dump) = &state.dump {
+        dump.set_active(true);
+    }
+    dump_state(state.dump.as_deref())
+}
+
+async fn dump_stop_handler(State(state): State<AppState>) -> (StatusCode, Json<serde_json::Value>) {
+    if let Some(dump) = &state.dump {
+        dump.set_active(false);
+    }
+    dump_state(state.dump.as_deref())
+}
+
+#[derive(Debug, Deserialize)]
+struct PcapQuery {
+    seconds: Option<u64>,
+    bpf: Option<String>,
+}
+
+async fn pcap_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<PcapQuery>,
+) -> Response {
+    let Some(download) = state.pcap_download.clone() else {
+        return (
+            StatusCode::NOT_FOUND,
+            "pcap download is not enabled, set pcap_download = true",
+        )
+            .into_response();
+    };
+    let authorization = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok());
+    if !download.authorized(authorization) {
+        return (StatusCode::UNAUTHORIZED, "missing or wrong bearer token").into_response();
+    }
+
+    let filter = query.bpf.unwrap_or_default();
+    if let Err(e) = validate_bpf_filter(&filter) {
+        return (
+            StatusCode::BAD_REQUEST,
+            format!("invalid BPF filter: {}", e),
+        )
+            .into_response();
+    }
+    let duration = Duration::from_secs(query.seconds.unwrap_or(PCAP_DEFAULT_SECONDS).max(1))
+        .min(download.max_duration);
+
+    let Some(guard) = download.try_start() else {
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            "another pcap download is running",
+        )
+            .into_response();
+    };
+
+    let interface = download.interface.clone();
+    let promisc = download.promisc;
+    let opened =
+        crate::runtime::spawn_blocking(move || open_download_capture(&interface, &filter, promisc))
+            .await;
+    let cap = match opened {
+        Ok(Ok(cap)) => cap,
+        Ok(Err(e)) => {
+            error!(
+                "Failed to open pcap download on {}: {}",
+                download.interface, e
+            );
+            return (
+                StatusCode::SERVICE_UNAVAILABLE,
+                format!("failed to open {}: {}", download.interface, e),
+            )
+                .into_response();
+        }
+        Err(e) => {
+            error!("pcap download task failed: {}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    info!(
+        "pcap download on {} for {}s started",
+        download.interface,
+        duration.as_secs()
+    );
+    let (chunks, chunks_rx) = download_channel();
+    crate::runtime::spawn_blocking(move || stream_capture(cap, duration, chunks, guard));
+    let body = Body::from_stream(futures_util::stream::unfold(chunks_rx, |mut rx| async {
+        rx.recv()
+            .await
+            .map(|chunk| (Ok::<_, std::io::Error>(chunk), rx))
+    }));
+    (
+        [
+            (header::CONTENT_TYPE, "application/vnd.tcpdump.pcap"),
+            (
+                header::CONTENT_DISPOSITION,
+                "attachment; filename=\"capture.pcap\"",
+            ),
+        ],
+        body,
+    )
+        .into_response()
+}
+
+async fn healthz_handler(State(state): State<AppState>) -> (StatusCode, Json<serde_json::Value>) {
+    let health = &state.health;
+    let mut captures_ok = true;
+    let mut interfaces = serde_json::Map::new();
+    for (name, capture) in health.captures.lock().unwrap().iter() {
+        let finished = capture.finished.load(Ordering::Relaxed);
+        let (fresh, ago) = health.fresh(capture.last_poll_ms.load(Ordering::Relaxed));
+        let ok = finished || fresh;
+        captures_ok &= ok;
+        interfaces.insert(
+            name.clone(),
+            serde_json::json!({
+                "status": if finished { "finished" } else { component_status(ok) },
+                "last_poll_secs_ago": ago,
+            }),
+        );
+    }
+    captures_ok &= !interfaces.is_empty();
+
+    let mapping_ok = health.mapping_fetch_ok.load(Ordering::Relaxed);
+    let (updater_ok, updater_ago) =
+        health.fresh(health.updater_last_flush_ms.load(Ordering::Relaxed));
+
+    let healthy = captures_ok && mapping_ok && updater_ok;
+    let body = serde_json::json!({
+        "status": component_status(healthy),
+        "components": {
+            "capture": {
+                "status": component_status(captures_ok),
+                "interfaces": interfaces,
+            },
+            "mapping_fetch": {
+                "status": component_status(mapping_ok),
+            },
+            "metrics_updater": {
+                "status": component_status(updater_ok),
+                "last_flush_secs_ago": updater_ago,
+            },
+        },
+    });
+
+    let code = if healthy {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (code, Json(body))
+}
+
+// Readiness: 503 until a capture handle is open and NIC mappings are available
+async fn ready_handler(State(state): State<AppState>) -> (StatusCode, Json<serde_json::Value>) {
+    let (capture_ready, mappings_ready) = state.health.readiness();
+    let ready = capture_ready && mappings_ready;
+    let body = serde_json::json!({
+        "ready": ready,
+        "capture_opened": capture_ready,
+        "mappings": mappings_ready,
+    });
+    let code = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (code, Json(body))
+}
+
+// Content types of /metrics
+const PROMETHEUS_TEXT: &str = "text/plain; version=0.0.4; charset=utf-8";
+const OPENMETRICS_TEXT: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+// Highest q value the Accept / Accept-Encoding header gives `value`, 0 if not listed
+fn accept_quality(headers: &HeaderMap, name: header::HeaderName, value: &str) -> f32 {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|header| header.to_str().ok())
+        .flat_map(|header| header.split(','))
+        .filter_map(|entry| {
+            let mut params = entry.split(';').map(str::trim);
+            if !params.next()?.eq_ignore_ascii_case(value) {
+                return None;
+            }
+            let q = params
+                .filter_map(|param| param.strip_prefix("q="))
+                .find_map(|q| q.parse().ok())
+                .unwrap_or(1.0);
+            Some(q)
+        })
+        .fold(0.0, f32::max)
+}
+
+// Prometheus text format unless the scraper prefers OpenMetrics, gzipped on request
+async fn metrics_handler(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    let openmetrics = accept_quality(&headers, header::ACCEPT, "application/openmetrics-text");
+    let text = accept_quality(&headers, header::ACCEPT, "text/plain");
+    let (encoded, content_type) = if openmetrics > 0.0 && openmetrics >= text {
+        (state.metrics.encode_openmetrics(), OPENMETRICS_TEXT)
+    } else {
+        (state.metrics.encode(), PROMETHEUS_TEXT)
+    };
+    let body = match encoded {
+        Ok(body) => body,
+        Err(e) => {
+            error!("Failed to encode metrics: {}", e);
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("failed to encode metrics: {}\n", e),
+            )
+                .into_response();
+        }
+    };
+
+    let vary = (header::VARY, "Accept, Accept-Encoding");
+    if accept_quality(&headers, header::ACCEPT_ENCODING, "gzip") > 0.0 {
+        let mut gzip = GzEncoder::new(Vec::new(), Compression::fast());
+        match gzip.write_all(body.as_bytes()).and_then(|_| gzip.finish()) {
+            Ok(compressed) => {
+                return (
+                    [
+                        (header::CONTENT_TYPE, content_type),
+                        (header::CONTENT_ENCODING, "gzip"),
+                        vary,
+                    ],
+                    compressed,
+                )
+                    .into_response();
+            }
+            Err(e) => error!(
+                "Failed to compress metrics, sending them uncompressed: {}",
+                e
+            ),
+        }
+    }
+    ([(header::CONTENT_TYPE, content_type), vary], body).into_response()
+}
+
+pub fn router(state: AppState) -> Router {
+    let auth = state.auth.clone();
+    let router = Router::new()
+        .route("/", get(dashboard_handler))
+        .route("/metrics", get(metrics_handler))
+        .route("/healthz", get(healthz_handler))
+        .route("/ready", get(ready_handler))
+        .route("/status", get(status_handler))
+        .route("/top", get(top_handler))
+        .route("/totals", get(totals_handler))
+        .route("/stream", get(stream_handler))
+        .route("/ws/packets", get(packets_ws_handler))
+        .route("/devices", get(devices_handler))
+        .route("/dns/top", get(dns_top_handler))
+        .route("/usage", get(usage_handler))
+        .route("/history", get(history_handler))
+        .route("/history/totals", get(history_totals_handler))
+        .route("/pcap", get(pcap_handler))
+        .route(
+            "/api/subnets",
+            get(subnets_handler)
+                .post(subnet_add_handler)
+                .delete(subnet_remove_handler),
+        )
+        .route("/dump", get(dump_handler))
+        .route("/dump/start", post(dump_start_handler))
+        .route("/dump/stop", post(dump_stop_handler))
+        .with_state(state);
+    match auth {
+        Some(auth) => router.layer(middleware::from_fn_with_state(auth, require_auth)),
+        None => router,
+    }
+}
+
+// Clients that do not finish the TLS handshake in time are dropped
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Pause after a failed accept(), e.g. when out of file descriptors
+const ACCEPT_RETRY: Duration = Duration::from_millis(100);
+
+// --listen-unix: the socket path and the ownership applied after binding
+#[derive(Debug, Clone)]
+pub struct UnixSocketSettings {
+    pub path: PathBuf,
+    pub mode: Option<u32>,
+    // User and group names or numeric ids
+    pub owner: Option<String>,
+    pub group: Option<String>,
+}
+
+// Bind the socket, replacing one left behind by a previous run, then apply mode and owner
+pub fn bind_unix(settings: &UnixSocketSettings) -> io::Result<UnixListener> {
+    let path = &settings.path;
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => {
+            info!("Removing stale socket {}", path.display());
+            std::fs::remove_file(path)?;
+        }
+        Ok(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket", path.display()),
+            ))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    let listener = UnixListener::bind(path)?;
+    if let Some(mode) = settings.mode {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    }
+    if settings.owner.is_some() || settings.group.is_some() {
+        let uid = settings
+            .owner
+            .as_deref()
+            .map(|owner| lookup_id(owner, true))
+            .transpose()?;
+        let gid = settings
+            .group
+            .as_deref()
+            .map(|group| lookup_id(group, false))
+            .transpose()?;
+        std::os::unix::fs::chown(path, uid, gid)?;
+    }
+    Ok(listener)
+}
+
+// Drops the watch guard right away so the caller stays Send
+async fn stopped(stop: &mut watch::Receiver<bool>) {
+    let _ = stop.wait_for(|stop| *stop).await;
+}
+
+// HTTP/1 only like axum::serve, through the auto builder since its connections
+// keep /ws/packets upgrades working and still shut down gracefully
+fn http1_builder() -> auto::Builder<TokioExecutor> {
+    auto::Builder::new(TokioExecutor::new()).http1_only()
+}
+
+// Serve `app` on the Unix socket until `stop` turns true, then wait for open
+// connections to finish
+pub async fn serve_unix(listener: UnixListener, app: Router, mut stop: watch::Receiver<bool>) {
+    let graceful = GracefulShutdown::new();
+    loop {
+        let stream = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    error!("Failed to accept on the Unix socket: {}", e);
+                    tokio::time::sleep(ACCEPT_RETRY).await;
+                    continue;
+                }
+            },
+            _ = stopped(&mut stop) => break,
+        };
+        let connection = http1_builder()
+            .serve_connection_with_upgrades(
+                TokioIo::new(stream),
+                TowerToHyperService::new(app.clone()),
+            )
+            .into_owned();
+        let connection = graceful.watch(connection);
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                debug!("Unix socket connection ended with an error: {}", e);
+            }
+        });
+    }
+    graceful.shutdown().await;
+}
+
+// Serve `app` over TLS until `stop` turns true, then wait for open connections
+pub async fn serve_tls(
+    listener: TcpListener,
+    tls: Arc<TlsConfig>,
+    app: Router,
+    mut stop: watch::Receiver<bool>,
+) {
+    let graceful = GracefulShutdown::new();
+    loop {
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    error!("Failed to accept a TLS connection: {}", e);
+                    tokio::time::sleep(ACCEPT_RETRY).await;
+                    continue;
+                }
+            },
+            _ = stopped(&mut stop) => break,
+        };
+        let acceptor = tls.acceptor();
+        let watcher = graceful.watcher();
+        let app = app.clone();
+        tokio::spawn(async move {
+            let stream =
+                match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => stream,
+                    Ok(Err(e)) => {
+                        debug!("TLS handshake with {} failed: {}", peer, e);
+                        return;
+                    }
+                    Err(_) => {
+                        debug!("TLS handshake with {} timed out", peer);
+                        return;
+                    }
+                };
+            let (_, session) = stream.get_ref();
+            debug!(
+                "TLS connection from {}: {:?}, {:?}",
+                peer,
+                session.protocol_version(),
+                session.negotiated_cipher_suite().map(|suite| suite.suite())
+            );
+            let connection = http1_builder()
+                .serve_connection_with_upgrades(TokioIo::new(stream), TowerToHyperService::new(app))
+                .into_owned();
+            if let Err(e) = watcher.watch(connection).await {
+                debug!("TLS connection from {} ended with an error: {}", peer, e);
+            }
+        });
+    }
+    graceful.shutdown().await;
+}
@@ -0,0 +1,283 @@
+use crate::packet::{PacketInfo, UDP_HEADER_LEN};
+use crate::stats::{Direction, UNKNOWN_DOMAIN_LABEL};
+use lru::LruCache;
+use pnet::packet::ip::IpNextHeaderProtocols;
+use std::net::IpAddr;
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+
+pub const HTTPS_PORT: u16 = 443;
+
+const MAX_NAME_LEN: usize = 253;
+
+const TLS_HANDSHAKE: u8 = 0x16;
+const CLIENT_HELLO: u8 = 0x01;
+const SERVER_NAME_EXTENSION: u16 = 0;
+const HOST_NAME: u8 = 0;
+
+pub fn take<'a>(buf: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    if buf.len() < len {
+        return None;
+    }
+    let (head, tail) = buf.split_at(len);
+    *buf = tail;
+    Some(head)
+}
+
+pub fn take_u8_len<'a>(buf: &mut &'a [u8]) -> Option<&'a [u8]> {
+    let len = take(buf, 1)?[0] as usize;
+    take(buf, len)
+}
+
+pub fn take_u16(buf: &mut &[u8]) -> Option<u16> {
+    let bytes = take(buf, 2)?;
+    Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+pub fn take_u16_len<'a>(buf: &mut &'a [u8]) -> Option<&'a [u8]> {
+    let len = take_u16(buf)? as usize;
+    take(buf, len)
+}
+
+// The server_name of a TLS ClientHello at the start of `payload`, None for anything
+// else. Only one segment is read: a name in extensions beyond it is not found.
+pub fn parse_client_hello(payload: &[u8]) -> Option<&str> {
+    let mut record = payload;
+    let header = take(&mut record, 5)?;
+    if header[0] != TLS_HANDSHAKE || header[1] != 3 {
+        return None;
+    }
+    // The record and the handshake may continue in the next segments
+    let record_len = u16::from_be_bytes([header[3], header[4]]) as usize;
+    parse_handshake(&record[..record_len.min(record.len())])
+}
+
+// The server_name of a ClientHello handshake message, also carried in the CRYPTO
+// frames of QUIC. The message may be cut short after the name.
+pub fn parse_handshake(mut handshake: &[u8]) -> Option<&str> {
+    let header = take(&mut handshake, 4)?;
+    if header[0] != CLIENT_HELLO {
+        return None;
+    }
+    let hello_len = u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize;
+    let mut hello = &handshake[..hello_len.min(handshake.len())];
+
+    // Version and random, session ID, cipher suites, compression methods
+    take(&mut hello, 2 + 32)?;
+    take_u8_len(&mut hello)?;
+    take_u16_len(&mut hello)?;
+    take_u8_len(&mut hello)?;
+    let extensions_len = take_u16(&mut hello)? as usize;
+    let mut extensions = &hello[..extensions_len.min(hello.len())];
+    while !extensions.is_empty() {
+        let kind = take_u16(&mut extensions)?;
+        let mut data = take_u16_len(&mut extensions)?;
+        if kind != SERVER_NAME_EXTENSION {
+            continue;
+        }
+        let mut names = take_u16_len(&mut data)?;
+        while !names.is_empty() {
+            let name_type = take(&mut names, 1)?[0];
+            let name = take_u16_len(&mut names)?;
+            if name_type == HOST_NAME {
+                return valid_host_name(name);
+            }
+        }
+        return None;
+    }
+    None
+}
+
+fn valid_host_name(name: &[u8]) -> Option<&str> {
+    if name.is_empty() || name.len() > MAX_NAME_LEN {
+        return None;
+    }
+    if !name
+        .iter()
+        .all(|&c| c.is_ascii_alphanumeric() || matches!(c, b'-' | b'.' | b'_'))
+    {
+        return None;
+    }
+    std::str::from_utf8(name).ok()
+}
+
+#[cfg(feature = "quic-sni")]
+use crate::quic::initial_server_name as quic_server_name;
+
+#[cfg(not(feature = "quic-sni"))]
+fn quic_server_name(_payload: &[u8]) -> Option<String> {
+    None
+}
+
+// (local IP, local port, remote IP, remote port) of a TCP connection or, for
+// QUIC, of its UDP socket pair
+type FlowKey = (IpAddr, u16, IpAddr, u16);
+
+// Domains of the TCP connections to port 443 whose ClientHello a capture thread
+// saw, so the rest of each connection is attributed to it as well. With the
+// quic-sni feature the same goes for QUIC connections to one of the quic_ports.
+pub struct SniFlows {
+    flows: Mutex<LruCache<FlowKey, Arc<str>>>,
+    quic_ports: Arc<[u16]>,
+    unknown: Arc<str>,
+}
+
+impl SniFlows {
+    pub fn new(max_flows: usize, quic_ports: Arc<[u16]>) -> Self {
+        Self {
+            flows: Mutex::new(LruCache::new(NonZeroUsize::new(max_flows.max(1)).unwrap())),
+            quic_ports,
+            unknown: Arc::from(UNKNOWN_DOMAIN_LABEL),
+        }
+    }
+
+    // Domain of a packet to or from a remote HTTPS port given from the local IP's
+    // view, None for other traffic
+    pub fn classify(
+        &self,
+        packet: &PacketInfo,
+        data: &[u8],
+        direction: Direction,
+    ) -> Option<Arc<str>> {
+        let (src_port, dst_port) = packet.ports?;
+        let key = match direction {
+            Direction::Tx => (packet.src_ip, src_port, packet.dst_ip, dst_port),
+            Direction::Rx => (packet.dst_ip, dst_port, packet.src_ip, src_port),
+        };
+        let tls = packet.ip_proto == IpNextHeaderProtocols::Tcp.0 && key.3 == HTTPS_PORT;
+        let quic = cfg!(feature = "quic-sni")
+            && packet.ip_proto == IpNextHeaderProtocols::Udp.0
+            && self.quic_ports.contains(&key.3);
+        if !tls && !quic {
+            return None;
+        }
+        let mut flows = self.flows.lock().unwrap();
+        // A ClientHello starts a new connection, even on a reused port
+        if direction == Direction::Tx {
+            let name = if tls {
+                tcp_payload(packet, data)
+                    .and_then(parse_client_hello)
+                    .map(|name| name.to_ascii_lowercase())
+            } else {
+                udp_payload(packet, data).and_then(quic_server_name)
+            };
+            if let Some(name) = name {
+                let domain: Arc<str> = Arc::from(name);
+                flows.put(key, domain.clone());
+                return Some(domain);
+            }
+        }
+        Some(
+            flows
+                .get(&key)
+                .cloned()
+                .unwrap_or_else(|| self.unknown.clone()),
+        )
+    }
+}
+
+fn tcp_payload<'a>(packet: &PacketInfo, data: &'a [u8]) -> Option<&'a [u8]> {
+    let segment = data.get(packet.l4_offset?..)?;
+    let header_len = (*segment.get(12)? >> 4) as usize * 4;
+    segment.get(header_len..)
+}
+
+fn udp_payload<'a>(packet: &PacketInfo, data: &'a [u8]) -> Option<&'a [u8]> {
+    data.get(packet.l4_offset? + UDP_HEADER_LEN..)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u16_len(body: &[u8]) -> Vec<u8> {
+        let mut out = (body.len() as u16).to_be_bytes().to_vec();
+        out.extend_from_slice(body);
+        out
+    }
+
+    fn extension(kind: u16, data: &[u8]) -> Vec<u8> {
+        let mut out = kind.to_be_bytes().to_vec();
+        out.extend_from_slice(&u16_len(data));
+        out
+    }
+
+    fn server_name(name: &[u8]) -> Vec<u8> {
+        let mut entry = vec![HOST_NAME];
+        entry.extend_from_slice(&u16_len(name));
+        extension(SERVER_NAME_EXTENSION, &u16_len(&entry))
+    }
+
+    // A TLS record with one ClientHello carrying `extensions`
+    fn client_hello(extensions: &[u8]) -> Vec<u8> {
+        let mut hello = vec![3, 3];
+        hello.extend_from_slice(&[0x5a; 32]);
+        // Session ID, two cipher suites, the null compression method
+        hello.extend_from_slice(&[0, 0, 4, 0x13, 0x01, 0x13, 0x02, 1, 0]);
+        hello.extend_from_slice(&u16_len(extensions));
+        let mut handshake = vec![CLIENT_HELLO, 0];
+        handshake.extend_from_slice(&(hello.len() as u16).to_be_bytes());
+        handshake.extend_from_slice(&hello);
+        let mut record = vec![TLS_HANDSHAKE, 3, 1];
+        record.extend_from_slice(&u16_len(&handshake));
+        record
+    }
+
+    #[test]
+    fn server_name_is_found_after_other_extensions() {
+        // supported_versions comes first
+        let mut extensions = extension(43, &[2, 3, 4]);
+        extensions.extend_from_slice(&server_name(b"example.com"));
+        let record = client_hello(&extensions);
+        assert_eq!(parse_client_hello(&record), Some("example.com"));
+        // The handshake alone, as QUIC carries it
+        assert_eq!(parse_handshake(&record[5..]), Some("example.com"));
+    }
+
+    #[test]
+    fn client_hello_without_server_name_has_none() {
+        let record = client_hello(&extension(43, &[2, 3, 4]));
+        assert_eq!(parse_client_hello(&record), None);
+        assert_eq!(parse_client_hello(&client_hello(&[])), None);
+        // Not a host name
+        assert_eq!(
+            parse_client_hello(&client_hello(&server_name(b"exa mple"))),
+            None
+        );
+        assert_eq!(parse_client_hello(&client_hello(&server_name(b""))), None);
+    }
+
+    #[test]
+    fn truncated_or_overlong_lengths_have_none() {
+        let record = client_hello(&server_name(b"example.com"));
+        assert_eq!(parse_client_hello(&record[..4]), None);
+        // Cut in the handshake header, the random, and the name itself
+        assert_eq!(parse_client_hello(&record[..7]), None);
+        assert_eq!(parse_client_hello(&record[..30]), None);
+        assert_eq!(parse_client_hello(&record[..record.len() - 1]), None);
+
+        // The record, handshake and extensions claim more than there is
+        let mut record_len = record.clone();
+        record_len[3..5].copy_from_slice(&0x4000u16.to_be_bytes());
+        assert_eq!(parse_client_hello(&record_len), Some("example.com"));
+        let mut extension_len = record.clone();
+        let at = record.len() - 11 - 7;
+        extension_len[at..at + 2].copy_from_slice(&0x00ffu16.to_be_bytes());
+        assert_eq!(parse_client_hello(&extension_len), None);
+        let mut name_list_len = record;
+        let at = name_list_len.len() - 11 - 5;
+        name_list_len[at..at + 2].copy_from_slice(&0x00ffu16.to_be_bytes());
+        assert_eq!(parse_client_hello(&name_list_len), None);
+    }
+
+    #[test]
+    fn other_records_and_handshakes_have_none() {
+        let mut record = client_hello(&server_name(b"example.com"));
+        record[0] = 0x17;
+        assert_eq!(parse_client_hello(&record), None);
+        let mut record = client_hello(&server_name(b"example.com"));
+        // ServerHello
+        record[5] = 0x02;
+        assert_eq!(parse_client_hello(&record), None);
+    }
+}
@@ -0,0 +1,84 @@
+use std::collections::VecDeque;
+use std::path::Path;
+use std::time::Duration;
+
+pub type CaptureError = pcap::Error;
+
+// A captured frame with its capture timestamp (time since the epoch)
+#[derive(Debug, Clone)]
+pub struct OwnedPacket {
+    pub timestamp: Duration,
+    pub data: Vec<u8>,
+    // Length on the wire, larger than data when the capture was truncated
+    pub len: u32,
+}
+
+// Anything that yields frames: a pcap handle, or a fixed list of crafted frames.
+// Sources signal the end with CaptureError::NoMorePackets.
+pub trait PacketSource {
+    fn next(&mut self) -> Result<OwnedPacket, CaptureError>;
+}
+
+pub struct PcapSource<T: pcap::State + ?Sized> {
+    capture: pcap::Capture<T>,
+}
+
+impl<T: pcap::Activated + ?Sized> PcapSource<T> {
+    pub fn new(capture: pcap::Capture<T>) -> Self {
+        Self { capture }
+    }
+}
+
+impl PcapSource<pcap::Offline> {
+    pub fn from_file(path: &Path) -> Result<Self, CaptureError> {
+        Ok(Self::new(pcap::Capture::from_file(path)?))
+    }
+}
+
+impl<T: pcap::Activated + ?Sized> PacketSource for PcapSource<T> {
+    fn next(&mut self) -> Result<OwnedPacket, CaptureError> {
+        let packet = self.capture.next_packet()?;
+        Ok(OwnedPacket {
+            timestamp: Duration::new(
+                packet.header.ts.tv_sec as u64,
+                packet.header.ts.tv_usec as u32 * 1000,
+            ),
+            data: packet.data.to_vec(),
+            len: packet.header.len,
+        })
+    }
+}
+
+// Yields the given frames in order, then NoMorePackets
+#[derive(Debug, Clone, Default)]
+pub struct VecSource {
+    packets: VecDeque<OwnedPacket>,
+}
+
+impl VecSource {
+    pub fn new(packets: Vec<OwnedPacket>) -> Self {
+        Self {
+            packets: packets.into(),
+        }
+    }
+
+    // Frames without timestamps, for tests that do not care about pacing
+    pub fn from_frames<I: IntoIterator<Item = Vec<u8>>>(frames: I) -> Self {
+        Self {
+            packets: frames
+                .into_iter()
+                .map(|data| OwnedPacket {
+                    timestamp: Duration::ZERO,
+                    len: data.len() as u32,
+                    data,
+                })
+                .collect(),
+        }
+    }
+}
+
+impl PacketSource for VecSource {
+    fn next(&mut self) -> Result<OwnedPacket, CaptureError> {
+        self.packets.pop_front().ok_or(CaptureError::NoMorePackets)
+    }
+}
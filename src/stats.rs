@@ -0,0 +1,1053 @@
+use crate::packet::{PortBucket, TCP_ACK, TCP_FIN, TCP_RST, TCP_SYN};
+use crate::subnets::Cast;
+use serde::Deserialize;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::net::IpAddr;
+use std::ops::AddAssign;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, oneshot};
+
+// Records buffered between the capture threads and the aggregator
+pub const RECORD_CHANNEL_CAPACITY: usize = 65536;
+
+// local_ip label of the series that collects IPs beyond max_tracked_ips
+pub const OVERFLOW_IP_LABEL: &str = "other";
+
+// remote_ip label of the series that collects remotes beyond track_remotes_max
+pub const OTHER_REMOTE_LABEL: &str = "other";
+
+// asn and as_org label of the series that collects ASNs beyond geoip_asn_max
+pub const OTHER_ASN_LABEL: &str = "other";
+
+// asn and as_org label of remote IPs the ASN database has no entry for
+pub const UNKNOWN_ASN_LABEL: &str = "unknown";
+
+// domain label of the series that collects domains beyond domain_max
+pub const OTHER_DOMAIN_LABEL: &str = "other";
+
+// domain label of connections whose ClientHello was not seen, e.g. started before
+// the exporter, or whose server name was not in its first segment
+pub const UNKNOWN_DOMAIN_LABEL: &str = "unknown";
+
+// Per-IP accounting key. `ip` is None for the overflow bucket of the cardinality guard,
+// `wan` is None for LAN-internal traffic and unless wan_labels is set.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct FlowKey {
+    pub nic: Arc<str>,
+    pub wan: Option<Arc<str>>,
+    pub ip: Option<IpAddr>,
+}
+
+impl FlowKey {
+    pub fn ip_label(&self) -> String {
+        match self.ip {
+            Some(ip) => ip.to_string(),
+            None => OVERFLOW_IP_LABEL.to_string(),
+        }
+    }
+}
+
+// Upper bounds of network_packet_size_bytes, in frame bytes
+pub const PACKET_SIZE_BUCKETS: [u64; 7] = [64, 128, 256, 512, 1024, 1514, 9000];
+
+// Frame sizes of one interval, bucketed in the aggregator so the capture threads
+// never touch the histogram. `counts` is per bucket (not cumulative) plus +Inf.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PacketSizes {
+    pub counts: [u64; PACKET_SIZE_BUCKETS.len() + 1],
+    pub sum: u64,
+}
+
+impl PacketSizes {
+    // `frames` is more than one when the frame stands for a sampled share of the traffic
+    pub fn observe(&mut self, frame_len: u64, frames: u64) {
+        let bucket = PACKET_SIZE_BUCKETS
+            .iter()
+            .position(|&bound| frame_len <= bound)
+            .unwrap_or(PACKET_SIZE_BUCKETS.len());
+        self.counts[bucket] += frames;
+        self.sum += frame_len * frames;
+    }
+
+    pub fn merge(&mut self, other: &PacketSizes) {
+        for (count, add) in self.counts.iter_mut().zip(other.counts) {
+            *count += add;
+        }
+        self.sum += other.sum;
+    }
+}
+
+// Keyed by (nic, direction)
+pub type PacketSizeMap = HashMap<(Arc<str>, Direction), PacketSizes>;
+
+// Remote peers counted exactly per local IP and direction; a set that grows beyond
+// this switches to a HyperLogLog of PEER_SKETCH_REGISTERS bytes (about 3% error)
+pub const PEER_SET_EXACT_LIMIT: usize = 256;
+const PEER_SKETCH_BITS: u32 = 10;
+const PEER_SKETCH_REGISTERS: usize = 1 << PEER_SKETCH_BITS;
+
+// Distinct remote addresses of one interval, bounded to PEER_SET_EXACT_LIMIT
+// addresses or one PEER_SKETCH_REGISTERS byte sketch
+#[derive(Debug, Clone)]
+pub enum PeerSet {
+    Exact(HashSet<IpAddr>),
+    Sketch(Box<[u8; PEER_SKETCH_REGISTERS]>),
+}
+
+impl Default for PeerSet {
+    fn default() -> Self {
+        PeerSet::Exact(HashSet::new())
+    }
+}
+
+impl PeerSet {
+    pub fn insert(&mut self, ip: IpAddr) {
+        match self {
+            PeerSet::Exact(peers) => {
+                peers.insert(ip);
+                if peers.len() > PEER_SET_EXACT_LIMIT {
+                    let mut registers = Box::new([0u8; PEER_SKETCH_REGISTERS]);
+                    for peer in peers.iter() {
+                        sketch_insert(&mut registers, peer);
+                    }
+                    *self = PeerSet::Sketch(registers);
+                }
+            }
+            PeerSet::Sketch(registers) => sketch_insert(registers, &ip),
+        }
+    }
+
+    pub fn merge(&mut self, other: &PeerSet) {
+        match other {
+            PeerSet::Exact(peers) => {
+                for peer in peers {
+                    self.insert(*peer);
+                }
+            }
+            PeerSet::Sketch(other_registers) => {
+                if let PeerSet::Exact(peers) = self {
+                    let mut registers = Box::new([0u8; PEER_SKETCH_REGISTERS]);
+                    for peer in peers.iter() {
+                        sketch_insert(&mut registers, peer);
+                    }
+                    *self = PeerSet::Sketch(registers);
+                }
+                if let PeerSet::Sketch(registers) = self {
+                    for (register, other) in registers.iter_mut().zip(other_registers.iter()) {
+                        *register = (*register).max(*other);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn count(&self) -> f64 {
+        match self {
+            PeerSet::Exact(peers) => peers.len() as f64,
+            PeerSet::Sketch(registers) => sketch_estimate(registers),
+        }
+    }
+}
+
+fn sketch_insert(registers: &mut [u8; PEER_SKETCH_REGISTERS], ip: &IpAddr) {
+    let mut hasher = DefaultHasher::new();
+    ip.hash(&mut hasher);
+    let hash = hasher.finish();
+    let index = (hash >> (64 - PEER_SKETCH_BITS)) as usize;
+    // Position of the first set bit in the rest of the hash
+    let rank =
+        ((hash << PEER_SKETCH_BITS).leading_zeros() + 1).min(64 - PEER_SKETCH_BITS + 1) as u8;
+    registers[index] = registers[index].max(rank);
+}
+
+// HyperLogLog estimate with the small range (linear counting) correction
+fn sketch_estimate(registers: &[u8; PEER_SKETCH_REGISTERS]) -> f64 {
+    let m = PEER_SKETCH_REGISTERS as f64;
+    let alpha = 0.7213 / (1.0 + 1.079 / m);
+    let sum: f64 = registers.iter().map(|&r| 2f64.powi(-(r as i32))).sum();
+    let estimate = alpha * m * m / sum;
+    let zeros = registers.iter().filter(|&&r| r == 0).count();
+    if estimate <= 2.5 * m && zeros > 0 {
+        m * (m / zeros as f64).ln()
+    } else {
+        estimate
+    }
+}
+
+// TCP packets of one interval by the flags that mark connection setup and teardown
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TcpFlagCounts {
+    // SYN without ACK: connection attempts
+    pub syn: u64,
+    pub syn_ack: u64,
+    pub rst: u64,
+    pub fin: u64,
+}
+
+impl TcpFlagCounts {
+    pub fn observe(&mut self, flags: u8, packets: u64) {
+        if flags & TCP_SYN != 0 {
+            if flags & TCP_ACK != 0 {
+                self.syn_ack += packets;
+            } else {
+                self.syn += packets;
+            }
+        }
+        if flags & TCP_RST != 0 {
+            self.rst += packets;
+        }
+        if flags & TCP_FIN != 0 {
+            self.fin += packets;
+        }
+    }
+}
+
+impl AddAssign for TcpFlagCounts {
+    fn add_assign(&mut self, other: Self) {
+        self.syn += other.syn;
+        self.syn_ack += other.syn_ack;
+        self.rst += other.rst;
+        self.fin += other.fin;
+    }
+}
+
+// Merge the values of keys that `remap` rewrites into the rewritten key
+fn fold_keys<K: Eq + Hash, V: AddAssign + Default>(
+    map: &mut HashMap<K, V>,
+    remap: impl Fn(&K) -> Option<K>,
+) {
+    let mut folded = HashMap::with_capacity(map.len());
+    for (key, value) in map.drain() {
+        let key = remap(&key).unwrap_or(key);
+        *folded.entry(key).or_default() += value;
+    }
+    *map = folded;
+}
+
+#[derive(Debug, Clone)]
+pub struct TrafficStats {
+    pub tx_bytes: HashMap<FlowKey, u64>,
+    pub rx_bytes: HashMap<FlowKey, u64>,
+    pub nic_tx_total: HashMap<Arc<str>, u64>,
+    pub nic_rx_total: HashMap<Arc<str>, u64>,
+    pub tx_packets: HashMap<FlowKey, u64>,
+    pub rx_packets: HashMap<FlowKey, u64>,
+    pub nic_tx_packets: HashMap<Arc<str>, u64>,
+    pub nic_rx_packets: HashMap<Arc<str>, u64>,
+    pub tx_bytes_by_proto: HashMap<(FlowKey, &'static str), u64>,
+    pub rx_bytes_by_proto: HashMap<(FlowKey, &'static str), u64>,
+    pub tx_bytes_by_port: HashMap<(FlowKey, PortBucket), u64>,
+    pub rx_bytes_by_port: HashMap<(FlowKey, PortBucket), u64>,
+    // Keyed by DSCP class label, only with dscp_metrics
+    pub tx_bytes_by_dscp: HashMap<(FlowKey, Arc<str>), u64>,
+    pub rx_bytes_by_dscp: HashMap<(FlowKey, Arc<str>), u64>,
+    pub nic_tx_bytes_by_dscp: HashMap<(Arc<str>, Arc<str>), u64>, // key: (nic, dscp)
+    pub nic_rx_bytes_by_dscp: HashMap<(Arc<str>, Arc<str>), u64>, // key: (nic, dscp)
+    // LAN-internal traffic, kept out of the NIC totals above
+    pub internal_tx_bytes: HashMap<FlowKey, u64>,
+    pub internal_rx_bytes: HashMap<FlowKey, u64>,
+    // Multicast and broadcast traffic, kept out of the per-IP maps and totals above
+    pub multicast_total: HashMap<(Arc<str>, Direction), u64>, // key: (nic, direction)
+    pub broadcast_total: HashMap<Arc<str>, u64>,
+    // Multicast and broadcast TX per local sender, only with cast_metrics
+    pub tx_bytes_by_cast: HashMap<(FlowKey, Cast), u64>,
+    pub arp_packets: HashMap<(Arc<str>, &'static str), u64>, // key: (nic, op)
+    pub capture_tx_total: HashMap<Arc<str>, u64>,            // key: capture interface
+    pub capture_rx_total: HashMap<Arc<str>, u64>,            // key: capture interface
+    pub vlan_tx_total: HashMap<(Arc<str>, Option<u16>), u64>, // key: (nic, vlan)
+    pub vlan_rx_total: HashMap<(Arc<str>, Option<u16>), u64>, // key: (nic, vlan)
+    // NIC totals split by wan, only filled with wan_labels
+    pub wan_tx_total: HashMap<(Arc<str>, Arc<str>), u64>, // key: (nic, wan)
+    pub wan_rx_total: HashMap<(Arc<str>, Arc<str>), u64>, // key: (nic, wan)
+    pub wan_tx_packets: HashMap<(Arc<str>, Arc<str>), u64>, // key: (nic, wan)
+    pub wan_rx_packets: HashMap<(Arc<str>, Arc<str>), u64>, // key: (nic, wan)
+    // Rate of the busiest peak bucket of the interval per NIC
+    pub nic_tx_peak_bps: HashMap<Arc<str>, f64>,
+    pub nic_rx_peak_bps: HashMap<Arc<str>, f64>,
+    pub packet_sizes: PacketSizeMap,
+    // Distinct remote IPs per local IP, WAN traffic only
+    pub tx_peers: HashMap<FlowKey, PeerSet>,
+    pub rx_peers: HashMap<FlowKey, PeerSet>,
+    // TCP packets with SYN/RST/FIN set, WAN traffic only
+    pub tx_tcp_flags: HashMap<FlowKey, TcpFlagCounts>,
+    pub rx_tcp_flags: HashMap<FlowKey, TcpFlagCounts>,
+    // IPv4 and IPv6 fragments, first ones included, WAN traffic only
+    pub tx_fragments: HashMap<FlowKey, u64>,
+    pub rx_fragments: HashMap<FlowKey, u64>,
+    // ICMP packets per local IP and message category, both directions together
+    pub icmp_packets: HashMap<(FlowKey, &'static str), u64>,
+    // NIC totals by the country of the remote IP, only with geoip_database
+    pub tx_bytes_by_country: HashMap<(Arc<str>, Arc<str>), u64>, // key: (nic, country)
+    pub rx_bytes_by_country: HashMap<(Arc<str>, Arc<str>), u64>, // key: (nic, country)
+    // NIC totals by the AS of the remote IP, only with geoip_asn_database. None is
+    // the "other" bucket of limit_asns.
+    pub tx_bytes_by_asn: HashMap<(Arc<str>, Option<u32>), u64>, // key: (nic, asn)
+    pub rx_bytes_by_asn: HashMap<(Arc<str>, Option<u32>), u64>, // key: (nic, asn)
+    // Organization of every AS number above
+    pub asn_orgs: HashMap<u32, Arc<str>>,
+    // WAN bytes of TCP port 443 connections by their TLS server name, only with
+    // domain_metrics. None is the "other" bucket of limit_domains.
+    pub tx_bytes_by_domain: HashMap<Option<Arc<str>>, u64>,
+    pub rx_bytes_by_domain: HashMap<Option<Arc<str>>, u64>,
+    // NIC totals per VXLAN network identifier, only with vni_metrics
+    pub vni_tx_total: HashMap<(Arc<str>, u32), u64>, // key: (nic, vni)
+    pub vni_rx_total: HashMap<(Arc<str>, u32), u64>, // key: (nic, vni)
+    // NIC totals per top MPLS label, only with mpls_metrics
+    pub mpls_tx_total: HashMap<(Arc<str>, u32), u64>, // key: (nic, label)
+    pub mpls_rx_total: HashMap<(Arc<str>, u32), u64>, // key: (nic, label)
+    // Bytes per remote IP of the local IPs in track_remotes_for, WAN traffic only
+    pub remote_bytes: HashMap<(IpAddr, IpAddr, Direction), u64>, // key: (local, remote, direction)
+}
+
+impl Default for TrafficStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TrafficStats {
+    // Entries per map group, published as localpacketdump_traffic_stats_entries
+    pub fn map_sizes(&self) -> [(&'static str, usize); 19] {
+        [
+            ("flows", self.tx_bytes.len() + self.rx_bytes.len()),
+            (
+                "proto",
+                self.tx_bytes_by_proto.len() + self.rx_bytes_by_proto.len(),
+            ),
+            (
+                "port",
+                self.tx_bytes_by_port.len() + self.rx_bytes_by_port.len(),
+            ),
+            (
+                "internal",
+                self.internal_tx_bytes.len() + self.internal_rx_bytes.len(),
+            ),
+            (
+                "cast",
+                self.multicast_total.len()
+                    + self.broadcast_total.len()
+                    + self.tx_bytes_by_cast.len(),
+            ),
+            ("peers", self.tx_peers.len() + self.rx_peers.len()),
+            (
+                "tcp_flags",
+                self.tx_tcp_flags.len() + self.rx_tcp_flags.len(),
+            ),
+            (
+                "fragments",
+                self.tx_fragments.len() + self.rx_fragments.len(),
+            ),
+            ("vlan", self.vlan_tx_total.len() + self.vlan_rx_total.len()),
+            ("vni", self.vni_tx_total.len() + self.vni_rx_total.len()),
+            ("mpls", self.mpls_tx_total.len() + self.mpls_rx_total.len()),
+            ("wan", self.wan_tx_total.len() + self.wan_rx_total.len()),
+            (
+                "dscp",
+                self.tx_bytes_by_dscp.len() + self.rx_bytes_by_dscp.len(),
+            ),
+            ("icmp", self.icmp_packets.len()),
+            ("arp", self.arp_packets.len()),
+            (
+                "country",
+                self.tx_bytes_by_country.len() + self.rx_bytes_by_country.len(),
+            ),
+            (
+                "asn",
+                self.tx_bytes_by_asn.len() + self.rx_bytes_by_asn.len(),
+            ),
+            ("remotes", self.remote_bytes.len()),
+            (
+                "domain",
+                self.tx_bytes_by_domain.len() + self.rx_bytes_by_domain.len(),
+            ),
+        ]
+    }
+
+    pub fn new() -> Self {
+        Self {
+            tx_bytes: HashMap::new(),
+            rx_bytes: HashMap::new(),
+            nic_tx_total: HashMap::new(),
+            nic_rx_total: HashMap::new(),
+            tx_packets: HashMap::new(),
+            rx_packets: HashMap::new(),
+            nic_tx_packets: HashMap::new(),
+            nic_rx_packets: HashMap::new(),
+            tx_bytes_by_proto: HashMap::new(),
+            rx_bytes_by_proto: HashMap::new(),
+            tx_bytes_by_port: HashMap::new(),
+            rx_bytes_by_port: HashMap::new(),
+            tx_bytes_by_dscp: HashMap::new(),
+            rx_bytes_by_dscp: HashMap::new(),
+            nic_tx_bytes_by_dscp: HashMap::new(),
+            nic_rx_bytes_by_dscp: HashMap::new(),
+            internal_tx_bytes: HashMap::new(),
+            internal_rx_bytes: HashMap::new(),
+            multicast_total: HashMap::new(),
+            broadcast_total: HashMap::new(),
+            tx_bytes_by_cast: HashMap::new(),
+            arp_packets: HashMap::new(),
+            capture_tx_total: HashMap::new(),
+            capture_rx_total: HashMap::new(),
+            vlan_tx_total: HashMap::new(),
+            vlan_rx_total: HashMap::new(),
+            wan_tx_total: HashMap::new(),
+            wan_rx_total: HashMap::new(),
+            wan_tx_packets: HashMap::new(),
+            wan_rx_packets: HashMap::new(),
+            nic_tx_peak_bps: HashMap::new(),
+            nic_rx_peak_bps: HashMap::new(),
+            packet_sizes: HashMap::new(),
+            tx_peers: HashMap::new(),
+            rx_peers: HashMap::new(),
+            tx_tcp_flags: HashMap::new(),
+            rx_tcp_flags: HashMap::new(),
+            tx_fragments: HashMap::new(),
+            rx_fragments: HashMap::new(),
+            icmp_packets: HashMap::new(),
+            tx_bytes_by_country: HashMap::new(),
+            rx_bytes_by_country: HashMap::new(),
+            tx_bytes_by_asn: HashMap::new(),
+            rx_bytes_by_asn: HashMap::new(),
+            asn_orgs: HashMap::new(),
+            tx_bytes_by_domain: HashMap::new(),
+            rx_bytes_by_domain: HashMap::new(),
+            vni_tx_total: HashMap::new(),
+            vni_rx_total: HashMap::new(),
+            mpls_tx_total: HashMap::new(),
+            mpls_rx_total: HashMap::new(),
+            remote_bytes: HashMap::new(),
+        }
+    }
+
+    // With --sample 1/N each record stands for `sample_rate` frames, so bytes and
+    // packets are scaled back up here. `track_remotes` are the local IPs whose
+    // traffic is also counted per remote IP.
+    pub fn record(
+        &mut self,
+        record: PacketRecord,
+        vlan_metrics: bool,
+        sample_rate: u64,
+        track_remotes: &[IpAddr],
+    ) {
+        match record {
+            PacketRecord::Ip {
+                nic,
+                wan,
+                ip,
+                remote,
+                direction,
+                bytes,
+                frame_len,
+                proto,
+                tcp_flags,
+                icmp_type,
+                port,
+                vlan_id,
+                dscp,
+                country,
+                asn,
+                domain,
+                vni,
+                mpls_label,
+                fragment,
+            } => {
+                let bytes = bytes * sample_rate;
+                self.packet_sizes
+                    .entry((nic.clone(), direction))
+                    .or_default()
+                    .observe(frame_len, sample_rate);
+                let (
+                    flow_bytes,
+                    flow_packets,
+                    by_proto,
+                    by_port,
+                    by_dscp,
+                    nic_dscp,
+                    nic_bytes,
+                    nic_packets,
+                    vlan_bytes,
+                    wan_bytes,
+                    wan_packets,
+                    peers,
+                    flag_counts,
+                    fragments,
+                ) = match direction {
+                    Direction::Tx => (
+                        &mut self.tx_bytes,
+                        &mut self.tx_packets,
+                        &mut self.tx_bytes_by_proto,
+                        &mut self.tx_bytes_by_port,
+                        &mut self.tx_bytes_by_dscp,
+                        &mut self.nic_tx_bytes_by_dscp,
+                        &mut self.nic_tx_total,
+                        &mut self.nic_tx_packets,
+                        &mut self.vlan_tx_total,
+                        &mut self.wan_tx_total,
+                        &mut self.wan_tx_packets,
+                        &mut self.tx_peers,
+                        &mut self.tx_tcp_flags,
+                        &mut self.tx_fragments,
+                    ),
+                    Direction::Rx => (
+                        &mut self.rx_bytes,
+                        &mut self.rx_packets,
+                        &mut self.rx_bytes_by_proto,
+                        &mut self.rx_bytes_by_port,
+                        &mut self.rx_bytes_by_dscp,
+                        &mut self.nic_rx_bytes_by_dscp,
+                        &mut self.nic_rx_total,
+                        &mut self.nic_rx_packets,
+                        &mut self.vlan_rx_total,
+                        &mut self.wan_rx_total,
+                        &mut self.wan_rx_packets,
+                        &mut self.rx_peers,
+                        &mut self.rx_tcp_flags,
+                        &mut self.rx_fragments,
+                    ),
+                };
+                if let Some(country) = country {
+                    let totals = match direction {
+                        Direction::Tx => &mut self.tx_bytes_by_country,
+                        Direction::Rx => &mut self.rx_bytes_by_country,
+                    };
+                    *totals.entry((nic.clone(), country)).or_insert(0) += bytes;
+                }
+                if let Some((asn, org)) = asn {
+                    let totals = match direction {
+                        Direction::Tx => &mut self.tx_bytes_by_asn,
+                        Direction::Rx => &mut self.rx_bytes_by_asn,
+                    };
+                    *totals.entry((nic.clone(), Some(asn))).or_insert(0) += bytes;
+                    self.asn_orgs.entry(asn).or_insert(org);
+                }
+                if let Some(domain) = domain {
+                    let totals = match direction {
+                        Direction::Tx => &mut self.tx_bytes_by_domain,
+                        Direction::Rx => &mut self.rx_bytes_by_domain,
+                    };
+                    *totals.entry(Some(domain)).or_insert(0) += bytes;
+                }
+                if let Some(vni) = vni {
+                    let totals = match direction {
+                        Direction::Tx => &mut self.vni_tx_total,
+                        Direction::Rx => &mut self.vni_rx_total,
+                    };
+                    *totals.entry((nic.clone(), vni)).or_insert(0) += bytes;
+                }
+                if let Some(label) = mpls_label {
+                    let totals = match direction {
+                        Direction::Tx => &mut self.mpls_tx_total,
+                        Direction::Rx => &mut self.mpls_rx_total,
+                    };
+                    *totals.entry((nic.clone(), label)).or_insert(0) += bytes;
+                }
+                if track_remotes.contains(&ip) {
+                    *self
+                        .remote_bytes
+                        .entry((ip, remote, direction))
+                        .or_insert(0) += bytes;
+                }
+                if let Some(wan) = &wan {
+                    *wan_bytes.entry((nic.clone(), wan.clone())).or_insert(0) += bytes;
+                    *wan_packets.entry((nic.clone(), wan.clone())).or_insert(0) += sample_rate;
+                }
+                let key = FlowKey {
+                    nic: nic.clone(),
+                    wan,
+                    ip: Some(ip),
+                };
+                *flow_bytes.entry(key.clone()).or_insert(0) += bytes;
+                *flow_packets.entry(key.clone()).or_insert(0) += sample_rate;
+                peers.entry(key.clone()).or_default().insert(remote);
+                if tcp_flags & (TCP_SYN | TCP_RST | TCP_FIN) != 0 {
+                    flag_counts
+                        .entry(key.clone())
+                        .or_default()
+                        .observe(tcp_flags, sample_rate);
+                }
+                if fragment {
+                    *fragments.entry(key.clone()).or_insert(0) += sample_rate;
+                }
+                *by_proto.entry((key.clone(), proto)).or_insert(0) += bytes;
+                if let Some(icmp_type) = icmp_type {
+                    *self
+                        .icmp_packets
+                        .entry((key.clone(), icmp_type))
+                        .or_insert(0) += sample_rate;
+                }
+                if let Some(dscp) = dscp {
+                    *nic_dscp.entry((nic.clone(), dscp.clone())).or_insert(0) += bytes;
+                    *by_dscp.entry((key.clone(), dscp)).or_insert(0) += bytes;
+                }
+                *by_port.entry((key, port)).or_insert(0) += bytes;
+                if vlan_metrics {
+                    *vlan_bytes.entry((nic.clone(), vlan_id)).or_insert(0) += bytes;
+                }
+                *nic_bytes.entry(nic.clone()).or_insert(0) += bytes;
+                *nic_packets.entry(nic).or_insert(0) += sample_rate;
+            }
+            PacketRecord::Capture {
+                interface,
+                direction,
+                bytes,
+            } => {
+                let totals = match direction {
+                    Direction::Tx => &mut self.capture_tx_total,
+                    Direction::Rx => &mut self.capture_rx_total,
+                };
+                *totals.entry(interface).or_insert(0) += bytes * sample_rate;
+            }
+            PacketRecord::Internal {
+                nic,
+                ip,
+                direction,
+                bytes,
+                frame_len,
+            } => {
+                self.packet_sizes
+                    .entry((nic.clone(), direction))
+                    .or_default()
+                    .observe(frame_len, sample_rate);
+                let totals = match direction {
+                    Direction::Tx => &mut self.internal_tx_bytes,
+                    Direction::Rx => &mut self.internal_rx_bytes,
+                };
+                let key = FlowKey {
+                    nic,
+                    wan: None,
+                    ip: Some(ip),
+                };
+                *totals.entry(key).or_insert(0) += bytes * sample_rate;
+            }
+            PacketRecord::Arp { nic, op } => {
+                *self.arp_packets.entry((nic, op)).or_insert(0) += sample_rate;
+            }
+            PacketRecord::Cast {
+                nic,
+                cast,
+                direction,
+                sender,
+                bytes,
+                frame_len,
+            } => {
+                let bytes = bytes * sample_rate;
+                self.packet_sizes
+                    .entry((nic.clone(), direction))
+                    .or_default()
+                    .observe(frame_len, sample_rate);
+                if let Some(ip) = sender {
+                    let key = FlowKey {
+                        nic: nic.clone(),
+                        wan: None,
+                        ip: Some(ip),
+                    };
+                    *self.tx_bytes_by_cast.entry((key, cast)).or_insert(0) += bytes;
+                }
+                match cast {
+                    Cast::Multicast => {
+                        *self.multicast_total.entry((nic, direction)).or_insert(0) += bytes
+                    }
+                    Cast::Broadcast => *self.broadcast_total.entry(nic).or_insert(0) += bytes,
+                }
+            }
+        }
+    }
+
+    // Keep the `max` highest-volume flows of this interval and fold the rest into
+    // one overflow series per NIC (and wan). Returns how many flows were folded.
+    pub fn limit_flows(&mut self, max: usize) -> usize {
+        let mut volumes: HashMap<&FlowKey, u64> = HashMap::new();
+        let flows = self
+            .tx_bytes
+            .iter()
+            .chain(&self.rx_bytes)
+            .chain(&self.internal_tx_bytes)
+            .chain(&self.internal_rx_bytes)
+            .chain(
+                self.tx_bytes_by_cast
+                    .iter()
+                    .map(|((key, _), bytes)| (key, bytes)),
+            );
+        for (key, &bytes) in flows {
+            *volumes.entry(key).or_insert(0) += bytes;
+        }
+        if volumes.len() <= max {
+            return 0;
+        }
+
+        let mut ranked: Vec<(&FlowKey, u64)> = volumes.into_iter().collect();
+        ranked.sort_unstable_by_key(|(_, volume)| std::cmp::Reverse(*volume));
+        let overflow: HashSet<FlowKey> = ranked[max..].iter().map(|(k, _)| (*k).clone()).collect();
+
+        let remap = |key: &FlowKey| {
+            overflow.contains(key).then(|| FlowKey {
+                nic: key.nic.clone(),
+                wan: key.wan.clone(),
+                ip: None,
+            })
+        };
+        let remap_proto =
+            |(key, proto): &(FlowKey, &'static str)| remap(key).map(|key| (key, *proto));
+        fold_keys(&mut self.tx_bytes, remap);
+        fold_keys(&mut self.rx_bytes, remap);
+        fold_keys(&mut self.tx_packets, remap);
+        fold_keys(&mut self.rx_packets, remap);
+        fold_keys(&mut self.internal_tx_bytes, remap);
+        fold_keys(&mut self.internal_rx_bytes, remap);
+        let remap_port = |(key, port): &(FlowKey, PortBucket)| remap(key).map(|key| (key, *port));
+        fold_keys(&mut self.tx_bytes_by_proto, remap_proto);
+        fold_keys(&mut self.rx_bytes_by_proto, remap_proto);
+        fold_keys(&mut self.icmp_packets, remap_proto);
+        fold_keys(&mut self.tx_bytes_by_port, remap_port);
+        fold_keys(&mut self.rx_bytes_by_port, remap_port);
+        let remap_cast = |(key, cast): &(FlowKey, Cast)| remap(key).map(|key| (key, *cast));
+        fold_keys(&mut self.tx_bytes_by_cast, remap_cast);
+        let remap_dscp =
+            |(key, dscp): &(FlowKey, Arc<str>)| remap(key).map(|key| (key, dscp.clone()));
+        fold_keys(&mut self.tx_bytes_by_dscp, remap_dscp);
+        fold_keys(&mut self.rx_bytes_by_dscp, remap_dscp);
+        fold_keys(&mut self.tx_tcp_flags, remap);
+        fold_keys(&mut self.rx_tcp_flags, remap);
+        fold_keys(&mut self.tx_fragments, remap);
+        fold_keys(&mut self.rx_fragments, remap);
+        for peers in [&mut self.tx_peers, &mut self.rx_peers] {
+            let mut folded: HashMap<FlowKey, PeerSet> = HashMap::with_capacity(peers.len());
+            for (key, set) in peers.drain() {
+                match remap(&key) {
+                    Some(key) => folded.entry(key).or_default().merge(&set),
+                    None => {
+                        folded.insert(key, set);
+                    }
+                }
+            }
+            *peers = folded;
+        }
+        overflow.len()
+    }
+
+    // Keep the `max` ASNs with the most bytes in both directions over all NICs and
+    // fold the rest into one "other" bucket per NIC. Returns how many were folded.
+    pub fn limit_asns(&mut self, max: usize) -> usize {
+        let mut volumes: HashMap<u32, u64> = HashMap::new();
+        for ((_, asn), &bytes) in self.tx_bytes_by_asn.iter().chain(&self.rx_bytes_by_asn) {
+            if let Some(asn) = asn {
+                *volumes.entry(*asn).or_insert(0) += bytes;
+            }
+        }
+        if volumes.len() <= max {
+            return 0;
+        }
+        let mut ranked: Vec<(u32, u64)> = volumes.into_iter().collect();
+        ranked.sort_unstable_by_key(|(_, volume)| std::cmp::Reverse(*volume));
+        let overflow: HashSet<u32> = ranked[max..].iter().map(|(asn, _)| *asn).collect();
+        let remap = |(nic, asn): &(Arc<str>, Option<u32>)| {
+            asn.is_some_and(|asn| overflow.contains(&asn))
+                .then(|| (nic.clone(), None))
+        };
+        fold_keys(&mut self.tx_bytes_by_asn, remap);
+        fold_keys(&mut self.rx_bytes_by_asn, remap);
+        self.asn_orgs.retain(|asn, _| !overflow.contains(asn));
+        overflow.len()
+    }
+
+    // Keep the `max` domains with the most bytes in both directions and fold the
+    // rest into "other"; flows without a known name keep their own series
+    pub fn limit_domains(&mut self, max: usize) -> usize {
+        let mut volumes: HashMap<Arc<str>, u64> = HashMap::new();
+        for (domain, &bytes) in self
+            .tx_bytes_by_domain
+            .iter()
+            .chain(&self.rx_bytes_by_domain)
+        {
+            if let Some(domain) = domain
+                .as_ref()
+                .filter(|domain| ***domain != *UNKNOWN_DOMAIN_LABEL)
+            {
+                *volumes.entry(domain.clone()).or_insert(0) += bytes;
+            }
+        }
+        if volumes.len() <= max {
+            return 0;
+        }
+        let mut ranked: Vec<(Arc<str>, u64)> = volumes.into_iter().collect();
+        ranked.sort_unstable_by_key(|(_, volume)| std::cmp::Reverse(*volume));
+        let overflow: HashSet<Arc<str>> = ranked.drain(max..).map(|(domain, _)| domain).collect();
+        let remap = |domain: &Option<Arc<str>>| {
+            domain
+                .as_ref()
+                .is_some_and(|domain| overflow.contains(domain))
+                .then_some(None)
+        };
+        fold_keys(&mut self.tx_bytes_by_domain, remap);
+        fold_keys(&mut self.rx_bytes_by_domain, remap);
+        overflow.len()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Tx,
+    Rx,
+}
+
+impl Direction {
+    pub fn label(self) -> &'static str {
+        match self {
+            Direction::Tx => "tx",
+            Direction::Rx => "rx",
+        }
+    }
+}
+
+// One accounting event sent from a capture thread to the aggregator
+#[derive(Debug)]
+pub enum PacketRecord {
+    // Traffic of a local IP, attributed to its mapped NIC
+    Ip {
+        nic: Arc<str>,
+        // Mapped wan name, None unless wan_labels is set
+        wan: Option<Arc<str>>,
+        ip: IpAddr,
+        // The non-local end of the packet
+        remote: IpAddr,
+        direction: Direction,
+        bytes: u64,
+        // Captured frame length for network_packet_size_bytes, regardless of count_mode
+        frame_len: u64,
+        proto: &'static str,
+        // Flags byte of the TCP header, 0 for other protocols and non-first fragments
+        tcp_flags: u8,
+        // ICMP message category, None for other protocols
+        icmp_type: Option<&'static str>,
+        // Tracked TCP/UDP port, "other" or "fragment"
+        port: PortBucket,
+        vlan_id: Option<u16>,
+        // DSCP class label, None unless dscp_metrics is set
+        dscp: Option<Arc<str>>,
+        // Country code of `remote`, None without a GeoIP database
+        country: Option<Arc<str>>,
+        // AS number and organization of `remote`, None without an ASN database
+        asn: Option<(u32, Arc<str>)>,
+        // TLS server name of the connection for TCP port 443 of `remote`, None for
+        // other traffic and without domain_metrics
+        domain: Option<Arc<str>>,
+        // VXLAN network identifier, None unless unwrapped from VXLAN with vni_metrics
+        vni: Option<u32>,
+        // Top MPLS label, None unless the frame had an MPLS label stack and mpls_metrics
+        mpls_label: Option<u32>,
+        // IPv4 or IPv6 fragment, first or not
+        fragment: bool,
+    },
+    // Traffic between two local IPs, attributed to the LAN NIC
+    Internal {
+        nic: Arc<str>,
+        ip: IpAddr,
+        direction: Direction,
+        bytes: u64,
+        frame_len: u64,
+    },
+    // Packet to a multicast or broadcast address, attributed to the LAN NIC
+    Cast {
+        nic: Arc<str>,
+        cast: Cast,
+        // Tx if the source is local
+        direction: Direction,
+        // The local source, None for remote ones and unless cast_metrics is set
+        sender: Option<IpAddr>,
+        bytes: u64,
+        frame_len: u64,
+    },
+    // ARP packet of a primary capture
+    Arp {
+        // The capture interface
+        nic: Arc<str>,
+        op: &'static str,
+    },
+    // Frame sent or received by this host on a capture interface
+    Capture {
+        interface: Arc<str>,
+        direction: Direction,
+        bytes: u64,
+    },
+}
+
+// Reply channel the updater uses to take the stats accumulated so far
+pub type SnapshotRequest = oneshot::Sender<TrafficStats>;
+
+// Splits the interval into fixed buckets and keeps the busiest bucket per NIC, so a
+// burst filling the uplink for a fraction of a second is visible next to the average.
+// Buckets are timed by record arrival in the aggregator.
+#[derive(Debug)]
+pub struct PeakTracker {
+    bucket: Duration,
+    bucket_start: Instant,
+    tx: HashMap<Arc<str>, u64>,
+    rx: HashMap<Arc<str>, u64>,
+}
+
+impl PeakTracker {
+    pub fn new(bucket: Duration, now: Instant) -> Self {
+        Self {
+            bucket,
+            bucket_start: now,
+            tx: HashMap::new(),
+            rx: HashMap::new(),
+        }
+    }
+
+    pub fn add(
+        &mut self,
+        stats: &mut TrafficStats,
+        nic: &Arc<str>,
+        direction: Direction,
+        bytes: u64,
+        now: Instant,
+    ) {
+        if now.duration_since(self.bucket_start) >= self.bucket {
+            self.close_bucket(stats);
+            // Skip empty buckets in one step
+            let buckets = now.duration_since(self.bucket_start).as_nanos() / self.bucket.as_nanos();
+            self.bucket_start += self.bucket * buckets as u32;
+        }
+        let current = match direction {
+            Direction::Tx => &mut self.tx,
+            Direction::Rx => &mut self.rx,
+        };
+        *current.entry(nic.clone()).or_insert(0) += bytes;
+    }
+
+    // Fold the running bucket into `stats` and start the next interval at `now`
+    pub fn finish_interval(&mut self, stats: &mut TrafficStats, now: Instant) {
+        self.close_bucket(stats);
+        self.bucket_start = now;
+    }
+
+    fn close_bucket(&mut self, stats: &mut TrafficStats) {
+        let secs = self.bucket.as_secs_f64();
+        for (current, peaks) in [
+            (&mut self.tx, &mut stats.nic_tx_peak_bps),
+            (&mut self.rx, &mut stats.nic_rx_peak_bps),
+        ] {
+            for (nic, bytes) in current.drain() {
+                let bps = (bytes * 8) as f64 / secs;
+                let peak = peaks.entry(nic).or_insert(0.0);
+                *peak = peak.max(bps);
+            }
+        }
+    }
+}
+
+fn add_record(
+    stats: &mut TrafficStats,
+    peaks: Option<&mut PeakTracker>,
+    record: PacketRecord,
+    vlan_metrics: bool,
+    sample_rate: u64,
+    track_remotes: &[IpAddr],
+) {
+    if let (
+        Some(peaks),
+        PacketRecord::Ip {
+            nic,
+            direction,
+            bytes,
+            ..
+        },
+    ) = (peaks, &record)
+    {
+        peaks.add(stats, nic, *direction, *bytes * sample_rate, Instant::now());
+    }
+    stats.record(record, vlan_metrics, sample_rate, track_remotes);
+}
+
+// Owns the TrafficStats of the current interval. Capture threads only send records
+// here, so the hot path never contends with the updater on a lock.
+pub async fn aggregate_records(
+    mut records: mpsc::Receiver<PacketRecord>,
+    mut snapshots: mpsc::Receiver<SnapshotRequest>,
+    vlan_metrics: bool,
+    peak_bucket: Option<Duration>,
+    sample_rate: u64,
+    track_remotes: Vec<IpAddr>,
+) {
+    let mut stats = TrafficStats::new();
+    let mut peaks = peak_bucket.map(|bucket| PeakTracker::new(bucket, Instant::now()));
+
+    loop {
+        tokio::select! {
+            biased;
+            Some(reply) = snapshots.recv() => {
+                // Fold in what is already queued so the snapshot covers the whole interval
+                for _ in 0..records.len() {
+                    match records.try_recv() {
+                        Ok(next) => add_record(&mut stats, peaks.as_mut(), next, vlan_metrics, sample_rate, &track_remotes),
+                        Err(_) => break,
+                    }
+                }
+                if let Some(peaks) = peaks.as_mut() {
+                    peaks.finish_interval(&mut stats, Instant::now());
+                }
+                let _ = reply.send(std::mem::take(&mut stats));
+            }
+            Some(next) = records.recv() => add_record(&mut stats, peaks.as_mut(), next, vlan_metrics, sample_rate, &track_remotes),
+            else => break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_burst_peaks_far_above_the_interval_average() {
+        let start = Instant::now();
+        let nic: Arc<str> = Arc::from("eth0");
+        let mut stats = TrafficStats::new();
+        let mut peaks = PeakTracker::new(Duration::from_millis(100), start);
+
+        // 125 kB within the first 100 ms bucket, then a trickle late in the interval
+        for i in 0..100 {
+            let at = start + Duration::from_millis(i * 90 / 100);
+            peaks.add(&mut stats, &nic, Direction::Tx, 1_250, at);
+        }
+        peaks.add(
+            &mut stats,
+            &nic,
+            Direction::Tx,
+            1_250,
+            start + Duration::from_millis(750),
+        );
+        peaks.finish_interval(&mut stats, start + Duration::from_secs(1));
+
+        // Over the whole 1 s interval
+        let average = (126_250 * 8) as f64;
+        let peak = stats.nic_tx_peak_bps[&nic];
+        assert_eq!(peak, 10_000_000.0);
+        assert!(peak > 9.0 * average, "peak {peak} vs average {average}");
+        assert!(stats.nic_rx_peak_bps.is_empty());
+    }
+
+    #[test]
+    fn peaks_restart_with_each_interval() {
+        let start = Instant::now();
+        let nic: Arc<str> = Arc::from("eth0");
+        let mut peaks = PeakTracker::new(Duration::from_millis(100), start);
+
+        let mut first = TrafficStats::new();
+        peaks.add(&mut first, &nic, Direction::Rx, 125_000, start);
+        peaks.finish_interval(&mut first, start + Duration::from_secs(1));
+        assert_eq!(first.nic_rx_peak_bps[&nic], 10_000_000.0);
+
+        let mut second = TrafficStats::new();
+        let next = start + Duration::from_secs(1);
+        peaks.add(&mut second, &nic, Direction::Rx, 1_250, next);
+        peaks.finish_interval(&mut second, next + Duration::from_secs(1));
+        assert_eq!(second.nic_rx_peak_bps[&nic], 100_000.0);
+    }
+}
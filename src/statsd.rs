@@ -0,0 +1,170 @@
+use crate::metrics::{Flush, FlushSink, Rate};
+use prometheus::IntCounter;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tracing::{error, info, warn};
+
+// Metric lines are packed into datagrams of at most this size, below the usual
+// 1500 byte MTU after IP and UDP headers
+const MAX_DATAGRAM: usize = 1432;
+
+// Stand-in for {local_ip} in plain names of the per-NIC totals
+const TOTAL_IP_LABEL: &str = "all";
+
+#[derive(Debug, Clone)]
+pub struct StatsdSettings {
+    pub address: SocketAddr,
+    // First component of every metric name, e.g. lpd.ip.tx_bps
+    pub prefix: String,
+    // Plain names instead of Datadog tags, e.g. "{metric}.{nic}.{local_ip}"
+    pub template: Option<String>,
+}
+
+// Sends every flush as statsd gauges over UDP. Datagrams are sent by a background
+// task; a flush that arrives while the previous one is still being sent is skipped.
+pub struct StatsdSink {
+    prefix: String,
+    template: Option<String>,
+    datagrams: mpsc::Sender<Vec<String>>,
+}
+
+impl StatsdSink {
+    pub fn spawn(settings: StatsdSettings, errors: IntCounter) -> Result<Self, String> {
+        if let Some(template) = &settings.template {
+            if !template.contains("{metric}") {
+                return Err("statsd_template must contain {metric}".into());
+            }
+        }
+        let (datagrams, datagrams_rx) = mpsc::channel(1);
+        info!(
+            "Sending metrics to statsd {} ({})",
+            settings.address,
+            match &settings.template {
+                Some(template) => format!("template {}", template),
+                None => "Datadog tags".to_string(),
+            }
+        );
+        tokio::spawn(send_datagrams(settings.address, datagrams_rx, errors));
+        Ok(Self {
+            prefix: settings.prefix,
+            template: settings.template,
+            datagrams,
+        })
+    }
+
+    // One gauge line; `ip` is None for the per-NIC totals
+    fn line(&self, metric: &str, value: f64, nic: &str, ip: Option<&str>) -> String {
+        match &self.template {
+            Some(template) => {
+                // Dots and colons would split the name into extra components
+                let component = |value: &str| value.replace(['.', ':'], "_");
+                let name = template
+                    .replace("{metric}", metric)
+                    .replace("{nic}", &component(nic))
+                    .replace("{local_ip}", &component(ip.unwrap_or(TOTAL_IP_LABEL)));
+                format!("{}.{}:{}|g", self.prefix, name, value)
+            }
+            None => {
+                let tag = |value: &str| value.replace([',', '|', '#'], "_");
+                let mut line = format!("{}.{}:{}|g|#", self.prefix, metric, value);
+                if let Some(ip) = ip {
+                    let _ = write!(line, "local_ip:{},", tag(ip));
+                }
+                let _ = write!(line, "nic:{}", tag(nic));
+                line
+            }
+        }
+    }
+
+    fn encode_flush(&self, flush: &Flush<'_>) -> Vec<String> {
+        let mut lines = Vec::new();
+        let mut gauges =
+            |kind: &str, tx: Option<Rate>, rx: Option<Rate>, nic: &str, ip: Option<&str>| {
+                let (tx, rx) = (tx.unwrap_or_default(), rx.unwrap_or_default());
+                for (metric, value) in [
+                    ("tx_bps", tx.bps),
+                    ("rx_bps", rx.bps),
+                    ("tx_pps", tx.pps),
+                    ("rx_pps", rx.pps),
+                ] {
+                    lines.push(self.line(&format!("{}.{}", kind, metric), value, nic, ip));
+                }
+            };
+        for rate in &flush.snapshot.per_ip {
+            let ip = rate.key.ip_label();
+            gauges("ip", rate.tx, rate.rx, &rate.key.nic, Some(ip.as_str()));
+        }
+        for rate in &flush.snapshot.per_nic {
+            if rate.tx.is_none() && rate.rx.is_none() {
+                continue;
+            }
+            gauges("nic", rate.tx, rate.rx, &rate.nic, None);
+        }
+        pack(lines)
+    }
+}
+
+// Newline-separated lines, a line longer than MAX_DATAGRAM goes out on its own
+fn pack(lines: Vec<String>) -> Vec<String> {
+    let mut datagrams: Vec<String> = Vec::new();
+    let mut current = String::new();
+    for line in lines {
+        if !current.is_empty() && current.len() + 1 + line.len() > MAX_DATAGRAM {
+            datagrams.push(std::mem::take(&mut current));
+        }
+        if !current.is_empty() {
+            current.push('\n');
+        }
+        current.push_str(&line);
+    }
+    if !current.is_empty() {
+        datagrams.push(current);
+    }
+    datagrams
+}
+
+impl FlushSink for StatsdSink {
+    fn publish(&mut self, flush: &Flush<'_>) {
+        let datagrams = self.encode_flush(flush);
+        if !datagrams.is_empty() {
+            let _ = self.datagrams.try_send(datagrams);
+        }
+    }
+}
+
+async fn send_datagrams(
+    address: SocketAddr,
+    mut datagrams: mpsc::Receiver<Vec<String>>,
+    errors: IntCounter,
+) {
+    let bind: SocketAddr = match address {
+        SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+        SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+    };
+    let socket = match UdpSocket::bind(bind).await {
+        Ok(socket) => socket,
+        Err(e) => {
+            errors.inc();
+            error!(
+                "Failed to open the statsd socket, statsd output disabled: {}",
+                e
+            );
+            return;
+        }
+    };
+    // Reported once per flush, an unreachable listener fails every datagram
+    while let Some(batch) = datagrams.recv().await {
+        let mut failed = None;
+        for datagram in &batch {
+            if let Err(e) = socket.send_to(datagram.as_bytes(), address).await {
+                errors.inc();
+                failed = Some(e);
+            }
+        }
+        if let Some(e) = failed {
+            warn!("Failed to send statsd metrics to {}: {}", address, e);
+        }
+    }
+}
@@ -0,0 +1,409 @@
+use crate::devices::write_atomic;
+use serde::Serialize;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tokio::time;
+use tracing::{info, warn};
+
+// Destination of a packet sent to a group rather than one host
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Cast {
+    Multicast,
+    // 255.255.255.255 or the broadcast address of a local subnet
+    Broadcast,
+}
+
+impl Cast {
+    pub fn label(self) -> &'static str {
+        match self {
+            Cast::Multicast => "multicast",
+            Cast::Broadcast => "broadcast",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LocalSubnets {
+    pub subnets: Vec<ipnet::Ipv4Net>,
+    pub subnets_v6: Vec<ipnet::Ipv6Net>,
+    pub exclude_link_local: bool,
+}
+
+impl Default for LocalSubnets {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LocalSubnets {
+    pub fn new() -> Self {
+        Self {
+            subnets: Vec::new(),
+            subnets_v6: Vec::new(),
+            exclude_link_local: false,
+        }
+    }
+
+    pub fn add_subnet(&mut self, subnet: &str) -> Result<(), Box<dyn std::error::Error>> {
+        match subnet.parse::<ipnet::IpNet>()? {
+            ipnet::IpNet::V4(net) => self.subnets.push(net),
+            ipnet::IpNet::V6(net) => self.subnets_v6.push(net),
+        }
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.subnets.is_empty() && self.subnets_v6.is_empty()
+    }
+
+    pub fn to_strings(&self) -> Vec<String> {
+        self.subnets
+            .iter()
+            .map(|net| net.to_string())
+            .chain(self.subnets_v6.iter().map(|net| net.to_string()))
+            .collect()
+    }
+
+    // This set plus the prefixes of `extra` it does not list yet
+    pub fn merged(&self, extra: &[ipnet::IpNet]) -> LocalSubnets {
+        let mut merged = self.clone();
+        for net in extra {
+            match net {
+                ipnet::IpNet::V4(net) if !merged.subnets.contains(net) => merged.subnets.push(*net),
+                ipnet::IpNet::V6(net) if !merged.subnets_v6.contains(net) => {
+                    merged.subnets_v6.push(*net)
+                }
+                _ => {}
+            }
+        }
+        merged
+    }
+
+    pub fn is_local(&self, ip: &IpAddr) -> bool {
+        match ip {
+            IpAddr::V4(addr) => self.subnets.iter().any(|subnet| subnet.contains(addr)),
+            IpAddr::V6(addr) => {
+                if self.exclude_link_local && is_link_local_v6(addr) {
+                    return false;
+                }
+                self.subnets_v6.iter().any(|subnet| subnet.contains(addr))
+            }
+        }
+    }
+
+    // The local subnet `ip` is the directed broadcast address of. /31 and /32 have
+    // none (RFC 3021).
+    pub fn broadcast_of(&self, ip: &IpAddr) -> Option<ipnet::Ipv4Net> {
+        let IpAddr::V4(addr) = ip else {
+            return None;
+        };
+        self.subnets
+            .iter()
+            .find(|subnet| subnet.prefix_len() < 31 && subnet.broadcast() == *addr)
+            .copied()
+    }
+
+    // Multicast (224.0.0.0/4, ff00::/8) and broadcast destinations, None for unicast
+    pub fn cast_of(&self, ip: &IpAddr) -> Option<Cast> {
+        if ip.is_multicast() {
+            return Some(Cast::Multicast);
+        }
+        if *ip == IpAddr::V4(Ipv4Addr::BROADCAST) || self.broadcast_of(ip).is_some() {
+            return Some(Cast::Broadcast);
+        }
+        None
+    }
+}
+
+pub fn is_link_local_v6(addr: &Ipv6Addr) -> bool {
+    (addr.segments()[0] & 0xffc0) == 0xfe80
+}
+
+// Prefixes assigned to `interface`, without loopback and IPv6 link-local ones.
+// None if the interface does not exist.
+pub fn interface_subnets(interface: &str) -> Option<Vec<ipnet::IpNet>> {
+    let iface = pnet::datalink::interfaces()
+        .into_iter()
+        .find(|iface| iface.name == interface)?;
+    let nets = iface
+        .ips
+        .iter()
+        .filter(|ip| match ip.ip() {
+            IpAddr::V4(addr) => !addr.is_loopback(),
+            IpAddr::V6(addr) => !addr.is_loopback() && !is_link_local_v6(&addr),
+        })
+        .filter_map(|ip| ipnet::IpNet::new(ip.ip(), ip.prefix()).ok())
+        .map(|net| net.trunc())
+        .collect();
+    Some(nets)
+}
+
+// What /api/subnets returns
+#[derive(Debug, Clone, Serialize)]
+pub struct SubnetList {
+    // From the config or subnets_file, changed through /api/subnets
+    pub configured: Vec<String>,
+    // Found by --auto-subnets
+    pub detected: Vec<String>,
+    // The union of both that is used for accounting
+    pub active: Vec<String>,
+}
+
+struct SubnetSources {
+    configured: LocalSubnets,
+    detected: Vec<ipnet::IpNet>,
+}
+
+// Owns the two sources of the local subnets and rebuilds the shared set read by
+// the capture threads whenever one of them changes
+pub struct SubnetControl {
+    active: Arc<RwLock<LocalSubnets>>,
+    sources: Mutex<SubnetSources>,
+    // Where the configured subnets are written after every change, subnets_file
+    state_file: Mutex<Option<PathBuf>>,
+}
+
+// The subnets saved by an earlier run, None if there is no file yet
+pub fn load_subnets_file(path: &Path) -> Result<Option<Vec<String>>, String> {
+    match std::fs::read(path) {
+        Ok(data) => serde_json::from_slice(&data)
+            .map(Some)
+            .map_err(|e| format!("failed to parse {}: {}", path.display(), e)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(format!("failed to read {}: {}", path.display(), e)),
+    }
+}
+
+impl SubnetControl {
+    pub fn new(
+        active: Arc<RwLock<LocalSubnets>>,
+        configured: LocalSubnets,
+        detected: Vec<ipnet::IpNet>,
+        state_file: Option<PathBuf>,
+    ) -> Self {
+        Self {
+            active,
+            sources: Mutex::new(SubnetSources {
+                configured,
+                detected,
+            }),
+            state_file: Mutex::new(state_file),
+        }
+    }
+
+    pub fn list(&self) -> SubnetList {
+        let sources = self.sources.lock().unwrap();
+        SubnetList {
+            configured: sources.configured.to_strings(),
+            detected: sources.detected.iter().map(|net| net.to_string()).collect(),
+            active: self.active.read().unwrap().to_strings(),
+        }
+    }
+
+    // Swap in the union of both sources unless it is empty (nothing would be
+    // accounted) or unchanged; false when it is empty
+    fn apply(&self, sources: &SubnetSources) -> bool {
+        let next = sources.configured.merged(&sources.detected);
+        if next.is_empty() {
+            return false;
+        }
+        let mut current = self.active.write().unwrap();
+        if current.to_strings() != next.to_strings()
+            || current.exclude_link_local != next.exclude_link_local
+        {
+            info!(subnets = ?next.to_strings(), "Local subnets changed");
+            *current = next;
+        }
+        true
+    }
+
+    // False if the subnet is configured already
+    pub fn add(&self, subnet: ipnet::IpNet) -> bool {
+        let subnet = subnet.trunc();
+        let mut sources = self.sources.lock().unwrap();
+        let configured = &mut sources.configured;
+        let added = match subnet {
+            ipnet::IpNet::V4(net) if !configured.subnets.iter().any(|n| n.trunc() == net) => {
+                configured.subnets.push(net);
+                true
+            }
+            ipnet::IpNet::V6(net) if !configured.subnets_v6.iter().any(|n| n.trunc() == net) => {
+                configured.subnets_v6.push(net);
+                true
+            }
+            _ => false,
+        };
+        if added {
+            self.apply(&sources);
+        }
+        added
+    }
+
+    // Ok(false) if the subnet is not configured, an error if removing it would
+    // leave no local subnets at all
+    pub fn remove(&self, subnet: ipnet::IpNet) -> Result<bool, String> {
+        let subnet = subnet.trunc();
+        let mut sources = self.sources.lock().unwrap();
+        let mut next = sources.configured.clone();
+        match subnet {
+            ipnet::IpNet::V4(net) => next.subnets.retain(|n| n.trunc() != net),
+            ipnet::IpNet::V6(net) => next.subnets_v6.retain(|n| n.trunc() != net),
+        }
+        if next.to_strings() == sources.configured.to_strings() {
+            return Ok(false);
+        }
+        let previous = std::mem::replace(&mut sources.configured, next);
+        if !self.apply(&sources) {
+            sources.configured = previous;
+            return Err(format!("{} is the last local subnet", subnet));
+        }
+        Ok(true)
+    }
+
+    // Replace the configured subnets after a reload, refused if nothing would be
+    // local afterwards
+    pub fn set_configured(&self, configured: LocalSubnets) -> Result<(), String> {
+        let mut sources = self.sources.lock().unwrap();
+        let previous = std::mem::replace(&mut sources.configured, configured);
+        if !self.apply(&sources) {
+            sources.configured = previous;
+            return Err("no local subnets left".into());
+        }
+        Ok(())
+    }
+
+    // A reloaded subnets_file, where the next change is written
+    pub fn set_state_file(&self, state_file: Option<PathBuf>) {
+        *self.state_file.lock().unwrap() = state_file;
+    }
+
+    fn set_detected(&self, detected: Vec<ipnet::IpNet>) -> bool {
+        let mut sources = self.sources.lock().unwrap();
+        let previous = std::mem::replace(&mut sources.detected, detected);
+        let applied = self.apply(&sources);
+        if !applied {
+            sources.detected = previous;
+        }
+        applied
+    }
+
+    // Blocks on the file system
+    pub fn save(&self) -> Result<(), String> {
+        let Some(path) = self.state_file.lock().unwrap().clone() else {
+            return Ok(());
+        };
+        let subnets = self.sources.lock().unwrap().configured.to_strings();
+        let data = serde_json::to_vec_pretty(&subnets).map_err(|e| e.to_string())?;
+        write_atomic(&path, &data)
+    }
+}
+
+// Re-read the prefixes of `interface` every `interval` and merge them with the
+// configured subnets, so renumbering is picked up without a restart
+pub async fn refresh_auto_subnets(
+    subnets: Arc<SubnetControl>,
+    interface: String,
+    interval: Duration,
+) {
+    let mut ticker = time::interval(interval);
+    // The first tick completes immediately, main already did that detection
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        let Some(detected) = interface_subnets(&interface) else {
+            warn!(interface = %interface, "Interface for auto subnets not found, keeping the current subnets");
+            continue;
+        };
+        if !subnets.set_detected(detected) {
+            warn!(interface = %interface, "No subnets detected, keeping the current subnets");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn subnets(nets: &[&str]) -> LocalSubnets {
+        let mut subnets = LocalSubnets::new();
+        for net in nets {
+            subnets.add_subnet(net).unwrap();
+        }
+        subnets
+    }
+
+    fn ip(addr: &str) -> IpAddr {
+        addr.parse().unwrap()
+    }
+
+    #[test]
+    fn prefix_boundaries_are_inclusive() {
+        let local = subnets(&["10.40.0.0/20"]);
+        assert!(local.is_local(&ip("10.40.0.0")));
+        assert!(local.is_local(&ip("10.40.15.255")));
+        assert!(!local.is_local(&ip("10.40.16.0")));
+        assert!(!local.is_local(&ip("10.39.255.255")));
+    }
+
+    #[test]
+    fn host_bits_in_a_configured_subnet_are_ignored() {
+        let local = subnets(&["10.40.3.7/20"]);
+        assert!(local.is_local(&ip("10.40.0.1")));
+        assert!(!local.is_local(&ip("10.40.16.1")));
+    }
+
+    #[test]
+    fn families_do_not_mix() {
+        let local = subnets(&["0.0.0.0/0"]);
+        assert!(local.is_local(&ip("203.0.113.1")));
+        assert!(!local.is_local(&ip("2001:db8::1")));
+        assert!(!LocalSubnets::new().is_local(&ip("10.40.0.5")));
+    }
+
+    #[test]
+    fn link_local_v6_is_excluded_on_request() {
+        let mut local = subnets(&["::/0"]);
+        assert!(local.is_local(&ip("fe80::1")));
+        local.exclude_link_local = true;
+        assert!(!local.is_local(&ip("fe80::1")));
+        assert!(!local.is_local(&ip("febf::1")));
+        assert!(local.is_local(&ip("fec0::1")));
+        assert!(local.is_local(&ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn invalid_subnets_are_rejected() {
+        let mut local = LocalSubnets::new();
+        assert!(local.add_subnet("10.40.0.0").is_err());
+        assert!(local.add_subnet("10.40.0.0/33").is_err());
+        assert!(local.add_subnet("lan").is_err());
+        assert!(local.is_empty());
+    }
+
+    #[test]
+    fn merged_skips_prefixes_already_listed() {
+        let local = subnets(&["10.40.0.0/20", "2001:db8::/64"]);
+        let extra = [
+            "10.40.0.0/20".parse().unwrap(),
+            "192.168.1.0/24".parse().unwrap(),
+        ];
+        assert_eq!(
+            local.merged(&extra).to_strings(),
+            ["10.40.0.0/20", "192.168.1.0/24", "2001:db8::/64"]
+        );
+    }
+
+    #[test]
+    fn point_to_point_subnets_have_no_broadcast() {
+        let local = subnets(&["10.40.0.0/24", "10.50.0.0/31", "10.60.0.1/32"]);
+        assert!(local.broadcast_of(&ip("10.40.0.255")).is_some());
+        assert_eq!(local.broadcast_of(&ip("10.50.0.1")), None);
+        assert_eq!(local.broadcast_of(&ip("10.60.0.1")), None);
+        assert_eq!(local.cast_of(&ip("255.255.255.255")), Some(Cast::Broadcast));
+        assert_eq!(local.cast_of(&ip("224.0.0.251")), Some(Cast::Multicast));
+        assert_eq!(local.cast_of(&ip("ff02::1")), Some(Cast::Multicast));
+        assert_eq!(local.cast_of(&ip("10.40.0.254")), None);
+    }
+}
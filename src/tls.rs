@@ -0,0 +1,129 @@
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio_rustls::rustls::{self, Error as RustlsError, InconsistentKeys, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+use tracing::{error, info};
+
+#[derive(Debug, Clone)]
+pub struct TlsSettings {
+    // PEM certificate chain, leaf first, and its private key
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    // How often the files are checked for changes
+    pub reload_interval: Duration,
+}
+
+// The server config in use, replaced when the certificate or key file changes.
+// Connections pick up the current one when they are accepted.
+pub struct TlsConfig {
+    settings: TlsSettings,
+    current: RwLock<Arc<ServerConfig>>,
+    // Modification times of the cert and key files at the last load
+    modified: Mutex<(Option<SystemTime>, Option<SystemTime>)>,
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .ok()
+}
+
+fn load_server_config(cert: &Path, key: &Path) -> Result<(ServerConfig, usize), String> {
+    let open = |path: &Path| {
+        std::fs::File::open(path)
+            .map(BufReader::new)
+            .map_err(|e| format!("failed to open {}: {}", path.display(), e))
+    };
+    let chain = rustls_pemfile::certs(&mut open(cert)?)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("failed to read {}: {}", cert.display(), e))?;
+    if chain.is_empty() {
+        return Err(format!("no certificate in {}", cert.display()));
+    }
+    let chain_len = chain.len();
+    let private_key = rustls_pemfile::private_key(&mut open(key)?)
+        .map_err(|e| format!("failed to read {}: {}", key.display(), e))?
+        .ok_or_else(|| format!("no private key in {}", key.display()))?;
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let mut config = ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(|e| e.to_string())?
+        .with_no_client_auth()
+        .with_single_cert(chain, private_key)
+        .map_err(|e| match e {
+            RustlsError::InconsistentKeys(InconsistentKeys::KeyMismatch) => format!(
+                "the private key in {} does not match the certificate in {}",
+                key.display(),
+                cert.display()
+            ),
+            e => format!("unusable certificate or key: {}", e),
+        })?;
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok((config, chain_len))
+}
+
+impl TlsConfig {
+    pub fn load(settings: TlsSettings) -> Result<Self, String> {
+        let times = (modified(&settings.cert), modified(&settings.key));
+        let (config, chain_len) = load_server_config(&settings.cert, &settings.key)?;
+        let suites: Vec<String> = config
+            .crypto_provider()
+            .cipher_suites
+            .iter()
+            .map(|suite| format!("{:?}", suite.suite()))
+            .collect();
+        info!(
+            "TLS enabled: certificate {} ({} in chain), key {}, TLS 1.3 and 1.2, ALPN http/1.1, cipher suites {}",
+            settings.cert.display(),
+            chain_len,
+            settings.key.display(),
+            suites.join(", ")
+        );
+        Ok(Self {
+            settings,
+            current: RwLock::new(Arc::new(config)),
+            modified: Mutex::new(times),
+        })
+    }
+
+    pub fn acceptor(&self) -> TlsAcceptor {
+        TlsAcceptor::from(self.current.read().unwrap().clone())
+    }
+
+    // Reload when either file's modification time changed. A bad pair (e.g. the
+    // certificate replaced before the key) keeps the previous config in use.
+    fn reload_if_changed(&self) {
+        let cert = &self.settings.cert;
+        let key = &self.settings.key;
+        let times = (modified(cert), modified(key));
+        {
+            let mut last = self.modified.lock().unwrap();
+            if *last == times {
+                return;
+            }
+            *last = times;
+        }
+        match load_server_config(cert, key) {
+            Ok((config, _)) => {
+                *self.current.write().unwrap() = Arc::new(config);
+                info!("Reloaded TLS certificate {}", cert.display());
+            }
+            Err(e) => error!(
+                "Failed to reload TLS certificate, keeping the previous one: {}",
+                e
+            ),
+        }
+    }
+}
+
+pub async fn watch_certificate(tls: Arc<TlsConfig>) {
+    let mut interval = tokio::time::interval(tls.settings.reload_interval);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        tls.reload_if_changed();
+    }
+}
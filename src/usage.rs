@@ -0,0 +1,248 @@
+use crate::metrics::{Flush, FlushSink};
+use crate::runtime::spawn_blocking;
+use prometheus::IntCounter;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+use tracing::{info, warn};
+
+// Old days are deleted at most this often
+const PRUNE_INTERVAL: Duration = Duration::from_secs(3600);
+
+// Days covered by /usage without a from parameter, ending today
+const USAGE_DEFAULT_DAYS: u32 = 30;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS usage_daily (
+    day TEXT NOT NULL,
+    ip TEXT NOT NULL,
+    tx_bytes INTEGER NOT NULL,
+    rx_bytes INTEGER NOT NULL,
+    PRIMARY KEY (day, ip)
+) WITHOUT ROWID;
+";
+
+// Bytes per (UTC day number, local IP) not yet written
+type UsageBatch = HashMap<(u64, IpAddr), (u64, u64)>;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct IpUsage {
+    pub ip: String,
+    pub tx_bytes: u64,
+    pub rx_bytes: u64,
+}
+
+// Summed bytes over [from, to], UTC days as YYYY-MM-DD
+#[derive(Debug, Clone, Serialize)]
+pub struct UsageReport {
+    pub from: String,
+    pub to: String,
+    pub ips: Vec<IpUsage>,
+}
+
+// Daily per-IP byte totals in a SQLite file. Every call blocks on the database and
+// must run on a blocking task.
+pub struct UsageStore {
+    conn: Mutex<Connection>,
+    // Days kept, 0 keeps everything
+    retention_days: u32,
+}
+
+// YYYY-MM-DD, the format of the day column
+pub fn is_day(s: &str) -> bool {
+    s.len() == 10
+        && s.bytes().enumerate().all(|(i, b)| match i {
+            4 | 7 => b == b'-',
+            _ => b.is_ascii_digit(),
+        })
+}
+
+impl UsageStore {
+    pub fn open(path: &Path, retention_days: u32) -> Result<Self, rusqlite::Error> {
+        let conn = Connection::open(path)?;
+        // Readers of /usage do not wait for a write transaction to finish
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.pragma_update(None, "synchronous", "NORMAL")?;
+        conn.execute_batch(SCHEMA)?;
+        info!(
+            "Recording per-IP usage in {} (retention {})",
+            path.display(),
+            match retention_days {
+                0 => "unlimited".to_string(),
+                days => format!("{} days", days),
+            }
+        );
+        Ok(Self {
+            conn: Mutex::new(conn),
+            retention_days,
+        })
+    }
+
+    // One transaction per batch
+    fn write(&self, batch: &UsageBatch) -> Result<(), rusqlite::Error> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        {
+            let mut upsert = tx.prepare_cached(
+                "INSERT INTO usage_daily (day, ip, tx_bytes, rx_bytes)
+                 VALUES (date(?1 * 86400, 'unixepoch'), ?2, ?3, ?4)
+                 ON CONFLICT (day, ip) DO UPDATE SET
+                     tx_bytes = tx_bytes + excluded.tx_bytes,
+                     rx_bytes = rx_bytes + excluded.rx_bytes",
+            )?;
+            for ((day, ip), (tx_bytes, rx_bytes)) in batch {
+                upsert.execute(params![
+                    *day as i64,
+                    ip.to_string(),
+                    *tx_bytes as i64,
+                    *rx_bytes as i64
+                ])?;
+            }
+        }
+        tx.commit()
+    }
+
+    fn prune(&self) -> Result<usize, rusqlite::Error> {
+        if self.retention_days == 0 {
+            return Ok(0);
+        }
+        self.conn.lock().unwrap().execute(
+            "DELETE FROM usage_daily WHERE day < date('now', ?1)",
+            params![format!("-{} days", self.retention_days)],
+        )
+    }
+
+    // Both bounds are inclusive; from defaults to USAGE_DEFAULT_DAYS before to,
+    // to defaults to today. Largest total first.
+    pub fn usage(
+        &self,
+        ip: Option<IpAddr>,
+        from: Option<&str>,
+        to: Option<&str>,
+    ) -> Result<UsageReport, rusqlite::Error> {
+        let conn = self.conn.lock().unwrap();
+        let to: String =
+            conn.query_row("SELECT COALESCE(?1, date('now'))", [to], |row| row.get(0))?;
+        let from: String = conn.query_row(
+            "SELECT COALESCE(?1, date(?2, ?3))",
+            params![from, to, format!("-{} days", USAGE_DEFAULT_DAYS - 1)],
+            |row| row.get(0),
+        )?;
+        let row = |row: &rusqlite::Row<'_>| {
+            Ok(IpUsage {
+                ip: row.get(0)?,
+                tx_bytes: row.get::<_, i64>(1)? as u64,
+                rx_bytes: row.get::<_, i64>(2)? as u64,
+            })
+        };
+        let ips = match ip {
+            Some(ip) => conn
+                .query_row(
+                    "SELECT ip, SUM(tx_bytes), SUM(rx_bytes) FROM usage_daily
+                     WHERE ip = ?1 AND day BETWEEN ?2 AND ?3 GROUP BY ip",
+                    params![ip.to_string(), from, to],
+                    row,
+                )
+                .optional()?
+                .into_iter()
+                .collect(),
+            None => conn
+                .prepare(
+                    "SELECT ip, SUM(tx_bytes), SUM(rx_bytes) FROM usage_daily
+                     WHERE day BETWEEN ?1 AND ?2 GROUP BY ip
+                     ORDER BY SUM(tx_bytes) + SUM(rx_bytes) DESC",
+                )?
+                .query_map(params![from, to], row)?
+                .collect::<Result<_, _>>()?,
+        };
+        Ok(UsageReport { from, to, ips })
+    }
+}
+
+// Adds every interval to the pending batch and hands it to the writer when it is
+// idle, so a slow disk delays the rows instead of dropping them
+pub struct UsageSink {
+    pending: UsageBatch,
+    batches: mpsc::Sender<UsageBatch>,
+}
+
+impl UsageSink {
+    pub fn spawn(store: Arc<UsageStore>, failures: IntCounter) -> Self {
+        let (batches, batches_rx) = mpsc::channel(1);
+        tokio::spawn(write_usage(store, batches_rx, failures));
+        Self {
+            pending: HashMap::new(),
+            batches,
+        }
+    }
+}
+
+impl FlushSink for UsageSink {
+    fn publish(&mut self, flush: &Flush<'_>) {
+        let day = flush
+            .timestamp
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs() / 86400);
+        for rate in &flush.snapshot.per_ip {
+            // local_ip="other" is not a tenant
+            let Some(ip) = rate.key.ip else {
+                continue;
+            };
+            let bytes = self.pending.entry((day, ip)).or_default();
+            bytes.0 += rate.tx.map_or(0, |rate| rate.bytes);
+            bytes.1 += rate.rx.map_or(0, |rate| rate.bytes);
+        }
+        if self.pending.is_empty() {
+            return;
+        }
+        match self.batches.try_send(std::mem::take(&mut self.pending)) {
+            Ok(()) => {}
+            Err(TrySendError::Full(batch)) | Err(TrySendError::Closed(batch)) => {
+                self.pending = batch;
+            }
+        }
+    }
+}
+
+async fn write_usage(
+    store: Arc<UsageStore>,
+    mut batches: mpsc::Receiver<UsageBatch>,
+    failures: IntCounter,
+) {
+    let mut last_prune: Option<Instant> = None;
+    while let Some(batch) = batches.recv().await {
+        let prune = last_prune.is_none_or(|at| at.elapsed() >= PRUNE_INTERVAL);
+        if prune {
+            last_prune = Some(Instant::now());
+        }
+        let task_store = store.clone();
+        let result = spawn_blocking(move || {
+            task_store.write(&batch)?;
+            if prune {
+                let deleted = task_store.prune()?;
+                if deleted > 0 {
+                    info!("Pruned {} usage rows past the retention", deleted);
+                }
+            }
+            Ok::<_, rusqlite::Error>(())
+        })
+        .await;
+        match result {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => {
+                failures.inc();
+                warn!("Failed to write usage: {}", e);
+            }
+            Err(e) => {
+                failures.inc();
+                warn!("Usage write task failed: {}", e);
+            }
+        }
+    }
+}
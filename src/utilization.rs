@@ -0,0 +1,114 @@
+use crate::metrics::{Flush, FlushSink, Rate};
+use prometheus::GaugeVec;
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::info;
+
+// Link speeds read from sysfs are re-read this often, e.g. after a renegotiation
+const LINK_SPEED_REFRESH: Duration = Duration::from_secs(60);
+
+// Nominal speed in Mbit/s as reported by the kernel; -1 or unreadable when the
+// driver does not know it (virtual and down interfaces)
+fn read_link_speed(nic: &str) -> Option<f64> {
+    let path = Path::new("/sys/class/net").join(nic).join("speed");
+    let mbps: i64 = std::fs::read_to_string(path).ok()?.trim().parse().ok()?;
+    (mbps > 0).then_some(mbps as f64 * 1e6)
+}
+
+#[derive(Debug)]
+struct LinkSpeed {
+    bps: Option<f64>,
+    checked: Instant,
+}
+
+// Publishes network_nic_{tx,rx}_utilization_ratio: the NIC totals of every interval
+// divided by the nominal link speed. NICs without a configured or readable speed
+// get no series, values above 1 (overcommitted virtual links) are not clamped.
+pub struct UtilizationSink {
+    configured: BTreeMap<String, f64>,
+    // Read /sys/class/net/<nic>/speed for NICs missing from `configured`
+    auto: bool,
+    speeds: HashMap<Arc<str>, LinkSpeed>,
+    tx: GaugeVec,
+    rx: GaugeVec,
+}
+
+impl UtilizationSink {
+    // `speeds_mbps` maps interface names to their speed in Mbit/s
+    pub fn new(
+        speeds_mbps: &BTreeMap<String, f64>,
+        auto: bool,
+        tx: GaugeVec,
+        rx: GaugeVec,
+    ) -> Result<Self, String> {
+        for (nic, mbps) in speeds_mbps {
+            if !(mbps.is_finite() && *mbps > 0.0) {
+                return Err(format!("link speed of {} must be positive", nic));
+            }
+        }
+        if !speeds_mbps.is_empty() {
+            info!("Configured link speeds (Mbit/s): {:?}", speeds_mbps);
+        }
+        Ok(Self {
+            configured: speeds_mbps
+                .iter()
+                .map(|(nic, mbps)| (nic.clone(), mbps * 1e6))
+                .collect(),
+            auto,
+            speeds: HashMap::new(),
+            tx,
+            rx,
+        })
+    }
+
+    fn speed(&mut self, nic: &Arc<str>, now: Instant) -> Option<f64> {
+        if let Some(bps) = self.configured.get(&**nic) {
+            return Some(*bps);
+        }
+        if !self.auto {
+            return None;
+        }
+        let speed = self.speeds.entry(nic.clone()).or_insert_with(|| LinkSpeed {
+            bps: read_link_speed(nic),
+            checked: now,
+        });
+        if now.duration_since(speed.checked) >= LINK_SPEED_REFRESH {
+            speed.bps = read_link_speed(nic);
+            speed.checked = now;
+        }
+        speed.bps
+    }
+}
+
+impl FlushSink for UtilizationSink {
+    fn publish(&mut self, flush: &Flush<'_>) {
+        let now = flush.now.into_std();
+        // Idle NICs drop out of the snapshot but stay at 0 rather than going stale
+        let mut nics: BTreeMap<Arc<str>, (f64, f64)> = self
+            .configured
+            .keys()
+            .map(|nic| (Arc::from(nic.as_str()), (0.0, 0.0)))
+            .chain(self.speeds.keys().map(|nic| (nic.clone(), (0.0, 0.0))))
+            .collect();
+        for rate in &flush.snapshot.per_nic {
+            let bps = |side: Option<Rate>| side.map_or(0.0, |rate| rate.bps);
+            nics.insert(rate.nic.clone(), (bps(rate.tx), bps(rate.rx)));
+        }
+
+        for (nic, (tx_bps, rx_bps)) in nics {
+            let labels = [&nic[..]];
+            match self.speed(&nic, now) {
+                Some(speed) => {
+                    self.tx.with_label_values(&labels).set(tx_bps / speed);
+                    self.rx.with_label_values(&labels).set(rx_bps / speed);
+                }
+                None => {
+                    let _ = self.tx.remove_label_values(&labels);
+                    let _ = self.rx.remove_label_values(&labels);
+                }
+            }
+        }
+    }
+}